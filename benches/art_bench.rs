@@ -1,11 +1,12 @@
 use std::time::Instant;
 
-use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
 use rand::prelude::SliceRandom;
 use rand::{thread_rng, Rng};
 
-use art::art::Tree;
-use art::FixedKey;
+use art::art::{Tree, TreeOptions};
+use art::node::TwigNode;
+use art::{FixedKey, VariableKey};
 
 pub fn seq_insert(c: &mut Criterion) {
     let mut group = c.benchmark_group("seq_insert");
@@ -14,7 +15,7 @@ pub fn seq_insert(c: &mut Criterion) {
         let mut tree = Tree::<FixedKey<16>, _>::new();
         let mut key = 0u64;
         b.iter(|| {
-            tree.insert(&key.into(), key, 0, 0);
+            let _ = tree.insert(&key.into(), key, 0, 0);
             key += 1;
         })
     });
@@ -33,7 +34,7 @@ pub fn rand_insert(c: &mut Criterion) {
         let mut rng = thread_rng();
         b.iter(|| {
             let key = &keys[rng.gen_range(0..keys.len())];
-            tree.insert(&key.into(), key.clone(), 0, 0);
+            let _ = tree.insert(&key.into(), key.clone(), 0, 0);
         })
     });
 
@@ -47,11 +48,11 @@ pub fn seq_delete(c: &mut Criterion) {
         let mut tree = Tree::<FixedKey<16>, _>::new();
         b.iter_custom(|iters| {
             for i in 0..iters {
-                tree.insert(&i.into(), i, 0, 0);
+                let _ = tree.insert(&i.into(), i, 0, 0);
             }
             let start = Instant::now();
             for i in 0..iters {
-                tree.remove(&i.into());
+                let _ = tree.remove(&i.into());
             }
             start.elapsed()
         })
@@ -60,6 +61,36 @@ pub fn seq_delete(c: &mut Criterion) {
     group.finish();
 }
 
+pub fn insert_owned(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_owned");
+    group.throughput(Throughput::Elements(1));
+
+    // Long keys, so that copying them is a noticeable part of an insert
+    let keys: Vec<String> = gen_keys(3, 2, 3)
+        .into_iter()
+        .map(|key| key.repeat(16))
+        .collect();
+
+    group.bench_function("borrowed", |b| {
+        let mut tree = Tree::<VariableKey, _>::new();
+        let mut rng = thread_rng();
+        b.iter(|| {
+            let key = VariableKey::from_str(&keys[rng.gen_range(0..keys.len())]);
+            let _ = tree.insert(&key, 0, 0, 0);
+        })
+    });
+    group.bench_function("owned", |b| {
+        let mut tree = Tree::<VariableKey, _>::new();
+        let mut rng = thread_rng();
+        b.iter(|| {
+            let key = VariableKey::from_str(&keys[rng.gen_range(0..keys.len())]);
+            let _ = tree.insert_owned(key, 0, 0, 0);
+        })
+    });
+
+    group.finish();
+}
+
 pub fn rand_delete(c: &mut Criterion) {
     let mut group = c.benchmark_group("rand_delete");
     let keys = gen_keys(3, 2, 3);
@@ -69,11 +100,11 @@ pub fn rand_delete(c: &mut Criterion) {
         let mut tree = Tree::<FixedKey<16>, _>::new();
         let mut rng = thread_rng();
         for key in &keys {
-            tree.insert(&key.into(), key, 0, 0);
+            let _ = tree.insert(&key.into(), key, 0, 0);
         }
         b.iter(|| {
             let key = &keys[rng.gen_range(0..keys.len())];
-            criterion::black_box(tree.remove(&key.into()));
+            let _ = criterion::black_box(tree.remove(&key.into()));
         })
     });
 
@@ -84,17 +115,20 @@ pub fn rand_get(c: &mut Criterion) {
     let mut group = c.benchmark_group("random_get");
 
     group.throughput(Throughput::Elements(1));
-    {
-        let size = 1_000_000;
-        let mut tree = Tree::<FixedKey<16>, _>::new();
-        for i in 0..size as u64 {
+    for (name, options) in [
+        ("art", TreeOptions::new()),
+        ("art_inline", TreeOptions::new().with_inline_values(true)),
+    ] {
+        let size = 1_000_000u64;
+        let mut tree = Tree::<FixedKey<16>, _>::with_options(options);
+        for i in 0..size {
             tree.insert(&i.into(), i, 0, 0).unwrap();
         }
-        group.bench_with_input(BenchmarkId::new("art", size), &size, |b, size| {
+        group.bench_with_input(BenchmarkId::new(name, size), &size, |b, size| {
             let mut rng = thread_rng();
             b.iter(|| {
                 let key: u64 = rng.gen_range(0..*size);
-                criterion::black_box(tree.get(&key.into(), 0));
+                let _ = criterion::black_box(tree.get(&key.into(), 0));
             })
         });
     }
@@ -116,7 +150,7 @@ pub fn rand_get_str(c: &mut Criterion) {
             let mut rng = thread_rng();
             b.iter(|| {
                 let key = &keys[rng.gen_range(0..keys.len())];
-                criterion::black_box(tree.get(&key.into(), 0));
+                let _ = criterion::black_box(tree.get(&key.into(), 0));
             })
         });
     }
@@ -129,15 +163,15 @@ pub fn seq_get(c: &mut Criterion) {
 
     group.throughput(Throughput::Elements(1));
     {
-        let size = 1_000_000;
+        let size = 1_000_000u64;
         let mut tree = Tree::<FixedKey<16>, _>::new();
-        for i in 0..size as u64 {
+        for i in 0..size {
             tree.insert(&i.into(), i, 0, 0).unwrap();
         }
-        group.bench_with_input(BenchmarkId::new("art", size), &size, |b, size| {
+        group.bench_with_input(BenchmarkId::new("art", size), &size, |b, _size| {
             let mut key = 0u64;
             b.iter(|| {
-                criterion::black_box(tree.get(&key.into(), 0));
+                let _ = criterion::black_box(tree.get(&key.into(), 0));
                 key += 1;
             })
         });
@@ -146,6 +180,195 @@ pub fn seq_get(c: &mut Criterion) {
     group.finish();
 }
 
+pub fn rand_get_miss(c: &mut Criterion) {
+    let mut group = c.benchmark_group("random_get_miss");
+
+    group.throughput(Throughput::Elements(1));
+    {
+        // One in ten looked up keys is present
+        let size = 100_000u64;
+        #[allow(unused_mut)]
+        let mut trees = vec![("art", Tree::<FixedKey<16>, _>::new())];
+        #[cfg(feature = "bloom")]
+        trees.push((
+            "art_bloom",
+            Tree::with_options(TreeOptions::new().with_bloom_filter(size as usize)),
+        ));
+
+        for (name, mut tree) in trees {
+            for i in 0..size {
+                tree.insert(&(i * 10).into(), i, 0, 0).unwrap();
+            }
+            group.bench_with_input(BenchmarkId::new(name, size), &size, |b, size| {
+                let mut rng = thread_rng();
+                b.iter(|| {
+                    let key: u64 = rng.gen_range(0..*size * 10);
+                    let _ = criterion::black_box(tree.get(&key.into(), 0));
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+pub fn rand_get_node16(c: &mut Criterion) {
+    let mut group = c.benchmark_group("random_get_node16");
+
+    group.throughput(Throughput::Elements(1));
+    {
+        // Every inner node has 5 to 16 children
+        let size = 12u64.pow(5);
+        let mut tree = Tree::<FixedKey<16>, _>::new();
+        let mut keys = Vec::new();
+        for i in 0..size {
+            let mut key = [0u8; 5];
+            let mut rest = i;
+            for byte in key.iter_mut().rev() {
+                *byte = (rest % 12) as u8 * 20;
+                rest /= 12;
+            }
+            tree.insert(&key[..].into(), i, 0, 0).unwrap();
+            keys.push(key);
+        }
+        group.bench_with_input(BenchmarkId::new("art", size), &size, |b, _size| {
+            let mut rng = thread_rng();
+            b.iter(|| {
+                let key = &keys[rng.gen_range(0..keys.len())];
+                let _ = criterion::black_box(tree.get(&key[..].into(), 0));
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// The container holding the versions of a twig, which the smallvec feature switches
+fn twig_values_container() -> &'static str {
+    if cfg!(feature = "smallvec") {
+        "smallvec"
+    } else {
+        "vec"
+    }
+}
+
+pub fn single_version_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_version_insert");
+
+    // Every key is written once, so every twig holds a single version
+    let size = 10_000u64;
+    group.throughput(Throughput::Elements(size));
+    group.bench_function(twig_values_container(), |b| {
+        b.iter_batched(
+            Tree::<FixedKey<16>, u64>::new,
+            |mut tree| {
+                for i in 0..size {
+                    tree.insert(&i.into(), i, 0, 0).unwrap();
+                }
+                tree
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+pub fn single_version_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_version_get");
+
+    group.throughput(Throughput::Elements(1));
+    {
+        // Nine in ten keys hold a single version, the rest two
+        let size = 100_000u64;
+        let mut tree = Tree::<FixedKey<16>, _>::new();
+        for i in 0..size {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
+        }
+        for i in (0..size).step_by(10) {
+            tree.insert(&i.into(), i + 1, 0, 0).unwrap();
+        }
+        group.bench_with_input(
+            BenchmarkId::new(twig_values_container(), size),
+            &size,
+            |b, size| {
+                let mut rng = thread_rng();
+                b.iter(|| {
+                    let key: u64 = rng.gen_range(0..*size);
+                    let _ = criterion::black_box(tree.get(&key.into(), 0));
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+pub fn twig_get_by_version(c: &mut Criterion) {
+    let mut group = c.benchmark_group("twig_get_by_version");
+
+    group.throughput(Throughput::Elements(1));
+    for versions in [16u64, 1024] {
+        // A hot key with many versions
+        let key: FixedKey<16> = 0u64.into();
+        let mut twig = TwigNode::<FixedKey<16>, u64>::new(key.clone(), key);
+        for version in 1..=versions {
+            twig.insert_mut(version, version, 0);
+        }
+        group.bench_with_input(
+            BenchmarkId::new("art", versions),
+            &versions,
+            |b, versions| {
+                let mut rng = thread_rng();
+                b.iter(|| {
+                    let version: u64 = rng.gen_range(1..=*versions);
+                    let _ = criterion::black_box(twig.get_leaf_by_version(version));
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+pub fn scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan");
+
+    let size = 100_000u64;
+    let mut tree = Tree::<FixedKey<16>, _>::new();
+    for i in 0..size {
+        tree.insert(&i.into(), i, 0, 0).unwrap();
+    }
+    group.throughput(Throughput::Elements(size));
+    group.bench_with_input(BenchmarkId::new("iter", size), &size, |b, _size| {
+        b.iter(|| {
+            let sum: u64 = tree
+                .iter()
+                .map(|(key, value, _, _)| key[7] as u64 + value)
+                .sum();
+            criterion::black_box(sum)
+        })
+    });
+    group.bench_with_input(BenchmarkId::new("iter_fixed", size), &size, |b, _size| {
+        b.iter(|| {
+            let sum: u64 = tree
+                .iter_fixed()
+                .map(|(key, value, _)| key[7] as u64 + value)
+                .sum();
+            criterion::black_box(sum)
+        })
+    });
+    group.bench_with_input(BenchmarkId::new("for_each", size), &size, |b, _size| {
+        b.iter(|| {
+            let mut sum = 0u64;
+            tree.for_each(|key, value, _| sum += key[7] as u64 + value);
+            criterion::black_box(sum)
+        })
+    });
+
+    group.finish();
+}
+
 fn gen_keys(l1_prefix: usize, l2_prefix: usize, suffix: usize) -> Vec<String> {
     let mut keys = Vec::new();
     let chars: Vec<char> = ('a'..='z').collect();
@@ -169,6 +392,22 @@ fn gen_keys(l1_prefix: usize, l2_prefix: usize, suffix: usize) -> Vec<String> {
 }
 
 criterion_group!(delete_benches, seq_delete, rand_delete);
-criterion_group!(insert_benches, seq_insert, rand_insert);
-criterion_group!(read_benches, seq_get, rand_get, rand_get_str);
-criterion_main!(insert_benches, read_benches);
+criterion_group!(
+    insert_benches,
+    seq_insert,
+    rand_insert,
+    insert_owned,
+    single_version_insert
+);
+criterion_group!(
+    read_benches,
+    seq_get,
+    rand_get,
+    rand_get_str,
+    rand_get_miss,
+    rand_get_node16,
+    single_version_get,
+    twig_get_by_version
+);
+criterion_group!(scan_benches, scan);
+criterion_main!(insert_benches, read_benches, scan_benches);
@@ -1,24 +1,39 @@
+use std::borrow::Cow;
 use std::cmp::min;
+use std::collections::BinaryHeap;
 use std::error::Error;
 use std::fmt;
-use std::ops::RangeBounds;
+use std::ops::{Bound, RangeBounds};
 use std::panic;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use hashbrown::HashSet;
-
-use crate::iter::{Iter, Range};
-use crate::node::{FlatNode, Node256, Node48, NodeTrait, TwigNode, Version};
+use hashbrown::{HashMap, HashSet};
+
+#[cfg(feature = "bloom")]
+use crate::bloom::BloomFilter;
+#[cfg(feature = "stream")]
+use crate::iter::IterStream;
+use crate::iter::{
+    Cursor, DistinctPrefix, Drain, Iter, MergeIter, NodeWalk, OwnedIter, Range, TryIter, TwigIter,
+    VersionScan,
+};
+use crate::node::{FlatNode, LeafValue, Node256, Node48, NodeTrait, TwigNode, Version};
 use crate::snapshot::Snapshot;
-use crate::KeyTrait;
+use crate::{FixedKey, Key, KeyTrait, Ts, VariableKey, VersionId};
 
 // Minimum and maximum number of children for Node4
 const NODE4MIN: usize = 2;
 const NODE4MAX: usize = 4;
 
-// Minimum and maximum number of children for Node16
-const NODE16MIN: usize = NODE4MAX + 1;
+// Maximum number of children for Node8, which replaces Node4 when configured
+const NODE8MAX: usize = 8;
+
+// Number of items a stream produces before yielding to the async runtime
+#[cfg(feature = "stream")]
+const STREAM_YIELD_INTERVAL: usize = 128;
+
+// Maximum number of children for Node16, whose minimum is one more than the small node width
 const NODE16MAX: usize = 16;
 
 // Minimum and maximum number of children for Node48
@@ -31,6 +46,23 @@ const NODE256MIN: usize = NODE48MAX + 1;
 // Maximum number of active snapshots
 pub(crate) const DEFAULT_MAX_ACTIVE_SNAPSHOTS: u64 = 10000;
 
+// Number of root-to-leaf walks sampled when estimating the number of keys
+const ESTIMATE_SAMPLE_WALKS: u64 = 32;
+
+// Number of inner node levels descended along the bounds when approximating a range count
+const APPROX_COUNT_LEVELS: usize = 2;
+
+// Offset basis and prime of the 64-bit FNV-1a hash used by `Tree::content_hash`
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// Folds the bytes into a 64-bit FNV-1a hash.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
 // Define a custom error enum representing different error cases for the Trie
 #[derive(Clone, Debug)]
 pub enum TrieError {
@@ -43,6 +75,8 @@ pub enum TrieError {
     SnapshotAlreadyClosed,
     SnapshotReadersNotClosed,
     TreeAlreadyClosed,
+    KeyTooLong,
+    PrefixConflict,
     Other(String),
 }
 
@@ -62,12 +96,238 @@ impl fmt::Display for TrieError {
                 write!(f, "Readers in the snapshot are not closed")
             }
             TrieError::TreeAlreadyClosed => write!(f, "Tree already closed"),
+            TrieError::KeyTooLong => write!(f, "Key too long"),
+            TrieError::PrefixConflict => {
+                write!(f, "Key is a prefix of a stored key or has one as a prefix")
+            }
             TrieError::Other(ref message) => write!(f, "Other error: {}", message),
             TrieError::SnapshotEmpty => write!(f, "Snapshot is empty"),
         }
     }
 }
 
+/// A randomized substitution of key bytes, see `TreeOptions::with_byte_permutation`.
+#[derive(Clone)]
+pub(crate) struct BytePermutation {
+    forward: [u8; 256],
+    inverse: [u8; 256],
+}
+
+impl BytePermutation {
+    // Shuffles the byte values with a Fisher-Yates shuffle driven by a splitmix64 generator,
+    // so that the same seed always gives the same permutation.
+    fn new(seed: u64) -> Self {
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+
+        let mut forward = [0u8; 256];
+        for (i, byte) in forward.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        for i in (1..forward.len()).rev() {
+            let j = (next() % (i as u64 + 1)) as usize;
+            forward.swap(i, j);
+        }
+        let mut inverse = [0u8; 256];
+        for (i, &byte) in forward.iter().enumerate() {
+            inverse[byte as usize] = i as u8;
+        }
+        BytePermutation { forward, inverse }
+    }
+
+    pub(crate) fn encode(&self, bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|&b| self.forward[b as usize]).collect()
+    }
+
+    pub(crate) fn decode(&self, bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|&b| self.inverse[b as usize]).collect()
+    }
+}
+
+impl fmt::Debug for BytePermutation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BytePermutation").finish_non_exhaustive()
+    }
+}
+
+/// Options controlling how a `Tree` lays out its nodes.
+///
+/// The defaults follow the classic Adaptive Radix Trie progression of Node4, Node16, Node48 and
+/// Node256. Workloads with unusual fan-out can trade memory for lookup speed by choosing a wider
+/// small node or by growing Node16 straight into Node256.
+///
+#[derive(Clone, Debug)]
+pub struct TreeOptions {
+    /// The width of the smallest multi-child node, either 4 or 8.
+    pub(crate) small_node_width: usize,
+    /// Whether Node16 grows directly into Node256, skipping Node48.
+    pub(crate) skip_node48: bool,
+    /// Whether older versions of a key are kept when a new value is inserted.
+    pub(crate) keep_history: bool,
+    /// The number of keys to size the Bloom filter for, or `None` to disable the filter.
+    #[cfg(feature = "bloom")]
+    pub(crate) bloom_expected_keys: Option<usize>,
+    /// The length of the longest key that can be inserted, or `None` for no limit.
+    pub(crate) max_key_len: Option<usize>,
+    /// Whether values are stored inline in the twigs rather than behind an `Arc`.
+    pub(crate) inline_values: bool,
+    /// The substitution applied to every key byte, or `None` to store keys as they are.
+    pub(crate) byte_permutation: Option<Arc<BytePermutation>>,
+}
+
+impl Default for TreeOptions {
+    fn default() -> Self {
+        TreeOptions::new()
+    }
+}
+
+impl TreeOptions {
+    pub fn new() -> Self {
+        TreeOptions {
+            small_node_width: NODE4MAX,
+            skip_node48: false,
+            keep_history: true,
+            #[cfg(feature = "bloom")]
+            bloom_expected_keys: None,
+            max_key_len: None,
+            inline_values: false,
+            byte_permutation: None,
+        }
+    }
+
+    /// Sets the width of the smallest multi-child node, which must be 4 or 8.
+    pub fn with_small_node_width(mut self, width: usize) -> Self {
+        assert!(
+            width == NODE4MAX || width == NODE8MAX,
+            "small node width must be 4 or 8"
+        );
+        self.small_node_width = width;
+        self
+    }
+
+    /// Sets whether Node16 grows directly into Node256, skipping Node48.
+    pub fn with_skip_node48(mut self, skip_node48: bool) -> Self {
+        self.skip_node48 = skip_node48;
+        self
+    }
+
+    /// Sets whether older versions of a key are kept when a new value is inserted.
+    ///
+    /// Without history the tree behaves as a plain map: each key holds only its latest value,
+    /// which is replaced on every insert, and reads at any version return that value.
+    pub fn with_keep_history(mut self, keep_history: bool) -> Self {
+        self.keep_history = keep_history;
+        self
+    }
+
+    /// Enables a Bloom filter over inserted keys, sized for the expected number of keys.
+    ///
+    /// Lookups of keys the filter has never seen return without descending the tree. Removed
+    /// keys cannot be cleared from the filter, so it only grows staler until it is rebuilt with
+    /// `Tree::rebuild_bloom_filter`.
+    #[cfg(feature = "bloom")]
+    pub fn with_bloom_filter(mut self, expected_keys: usize) -> Self {
+        self.bloom_expected_keys = Some(expected_keys);
+        self
+    }
+
+    /// Sets the length of the longest key that can be inserted.
+    ///
+    /// Inserting a longer key fails with `TrieError::KeyTooLong`, which guards against keys
+    /// from untrusted input using up memory. The length is that of the encoded key, including
+    /// the terminator a `VariableKey` built from a string ends with.
+    pub fn with_max_key_len(mut self, max_key_len: usize) -> Self {
+        self.max_key_len = Some(max_key_len);
+        self
+    }
+
+    /// Sets whether values are stored inline in the twigs rather than behind an `Arc`.
+    ///
+    /// By default every version of a value sits in its own reference-counted allocation, so
+    /// that copying a twig on write never copies the values. For small values that are cheap to
+    /// copy, such as integers, storing them inline saves an allocation per version and a pointer
+    /// chase per read, at the cost of copying the values whenever the twig is copied.
+    pub fn with_inline_values(mut self, inline_values: bool) -> Self {
+        self.inline_values = inline_values;
+        self
+    }
+
+    /// Scrambles the layout of the tree with a byte permutation derived from a seed.
+    ///
+    /// Every key byte is substituted on the way into the tree and restored on the way out, so
+    /// the shape of the tree, and the child slots keys end up in, cannot be predicted from the
+    /// raw keys. As the substitution does not preserve byte order, the tree iterates in the
+    /// order of the substituted keys rather than of the keys themselves. Keys sharing a prefix
+    /// still share one after the substitution, so it does not bound the depth of the tree.
+    ///
+    /// Every operation taking or returning keys, including those of snapshots and transactions,
+    /// translates them both ways. Positions, as used by `nth`, `rank`, `select` and `cursor`,
+    /// follow the iteration order. A key range is no longer a contiguous part of the tree, so
+    /// range scans, counts and copies visit every key and test it against the range.
+    pub fn with_byte_permutation(mut self, seed: u64) -> Self {
+        self.byte_permutation = Some(Arc::new(BytePermutation::new(seed)));
+        self
+    }
+
+    // Substitutes the bytes of a key on its way into the tree, if a byte permutation is set
+    pub(crate) fn encode_key<'a, K: KeyTrait>(&self, key: Cow<'a, K>) -> Cow<'a, K> {
+        match &self.byte_permutation {
+            Some(permutation) => Cow::Owned(K::from(permutation.encode(key.as_slice()).as_slice())),
+            None => key,
+        }
+    }
+
+    // Restores the bytes of a stored key on its way out of the tree, if a byte permutation is set
+    pub(crate) fn decode_key<'a, K: KeyTrait>(&self, key: Cow<'a, K>) -> Cow<'a, K> {
+        match &self.byte_permutation {
+            Some(permutation) => Cow::Owned(K::from(permutation.decode(key.as_slice()).as_slice())),
+            None => key,
+        }
+    }
+
+    // Copies the bytes of a stored key, restoring them if a byte permutation is set
+    pub(crate) fn decode_bytes(&self, key: &[u8]) -> Vec<u8> {
+        match &self.byte_permutation {
+            Some(permutation) => permutation.decode(key),
+            None => key.to_vec(),
+        }
+    }
+
+    // Checks that a key can be stored, rejecting empty keys and keys over the length limit
+    pub(crate) fn check_key<K: Key>(&self, key: &K) -> Result<(), TrieError> {
+        // Zero-length keys are a prefix of every other key and cannot be stored
+        if key.is_empty() {
+            return Err(TrieError::IllegalArguments);
+        }
+        match self.max_key_len {
+            Some(max_key_len) if key.len() > max_key_len => Err(TrieError::KeyTooLong),
+            _ => Ok(()),
+        }
+    }
+
+    // Minimum number of children for Node16 before it shrinks into the small node
+    #[inline]
+    fn node16_min(&self) -> usize {
+        self.small_node_width + 1
+    }
+
+    // Minimum number of children for Node256 before it shrinks
+    #[inline]
+    fn node256_min(&self) -> usize {
+        if self.skip_node48 {
+            NODE48MIN
+        } else {
+            NODE256MIN
+        }
+    }
+}
+
 /// A struct representing a node in an Adaptive Radix Trie.
 ///
 /// The `Node` struct encapsulates a single node within the adaptive radix trie structure.
@@ -82,7 +342,7 @@ impl fmt::Display for TrieError {
 /// # Fields
 ///
 /// - `node_type`: The `NodeType` variant representing the type of the node, containing its
-///                specific structure and associated data.
+///   specific structure and associated data.
 ///
 pub struct Node<P: KeyTrait + Clone, V: Clone> {
     pub(crate) node_type: NodeType<P, V>, // Type of the node
@@ -94,11 +354,36 @@ impl<P: KeyTrait + Clone, V: Clone> Version for Node<P, V> {
             NodeType::Twig(twig) => twig.version(),
             NodeType::Node1(n) => n.version(),
             NodeType::Node4(n) => n.version(),
+            NodeType::Node8(n) => n.version(),
             NodeType::Node16(n) => n.version(),
             NodeType::Node48(n) => n.version(),
             NodeType::Node256(n) => n.version(),
         }
     }
+
+    fn min_version(&self) -> u64 {
+        match &self.node_type {
+            NodeType::Twig(twig) => twig.min_version(),
+            NodeType::Node1(n) => n.min_version(),
+            NodeType::Node4(n) => n.min_version(),
+            NodeType::Node8(n) => n.min_version(),
+            NodeType::Node16(n) => n.min_version(),
+            NodeType::Node48(n) => n.min_version(),
+            NodeType::Node256(n) => n.min_version(),
+        }
+    }
+
+    fn leaf_count(&self) -> usize {
+        match &self.node_type {
+            NodeType::Twig(twig) => twig.leaf_count(),
+            NodeType::Node1(n) => n.leaf_count(),
+            NodeType::Node4(n) => n.leaf_count(),
+            NodeType::Node8(n) => n.leaf_count(),
+            NodeType::Node16(n) => n.leaf_count(),
+            NodeType::Node48(n) => n.leaf_count(),
+            NodeType::Node256(n) => n.leaf_count(),
+        }
+    }
 }
 
 /// An enumeration representing different types of nodes in an Adaptive Radix Trie.
@@ -116,6 +401,7 @@ impl<P: KeyTrait + Clone, V: Clone> Version for Node<P, V> {
 ///
 /// - `Twig(TwigNode<P, V>)`: Represents a Twig node, which is a leaf node in the adaptive radix trie.
 /// - `Node4(FlatNode<P, Node<P, V>, 4>)`: Represents an inner node with 4 keys and 4 children.
+/// - `Node8(FlatNode<P, Node<P, V>, 8>)`: Represents an inner node with 8 keys and 8 children.
 /// - `Node16(FlatNode<P, Node<P, V>, 16>)`: Represents an inner node with 16 keys and 16 children.
 /// - `Node48(Node48<P, Node<P, V>>)`: Represents an inner node with 256 keys and 48 children.
 /// - `Node256(Node256<P, Node<P, V>>)`: Represents an inner node with 256 keys and 256 children.
@@ -126,11 +412,99 @@ pub(crate) enum NodeType<P: KeyTrait + Clone, V: Clone> {
     // Inner node of the adaptive radix trie
     Node1(FlatNode<P, Node<P, V>, 1>), // Node with 1 key and 1 children
     Node4(FlatNode<P, Node<P, V>, 4>), // Node with 4 keys and 4 children
+    Node8(FlatNode<P, Node<P, V>, 8>), // Node with 8 keys and 8 children
     Node16(FlatNode<P, Node<P, V>, 16>), // Node with 16 keys and 16 children
     Node48(Node48<P, Node<P, V>>),     // Node with 256 keys and 48 children
     Node256(Node256<P, Node<P, V>>),   // Node with 256 keys and 256 children
 }
 
+/// The kind of a node in an Adaptive Radix Trie.
+///
+/// `NodeKind` mirrors the variants of the internal `NodeType` enum without exposing the node
+/// contents, so callers can inspect the shape of the trie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    Twig,
+    Node1,
+    Node4,
+    Node8,
+    Node16,
+    Node48,
+    Node256,
+}
+
+// Number of occupancy buckets in a FillHistogram, each a quarter of the node's capacity wide
+pub const FILL_BUCKETS: usize = 4;
+
+/// A histogram of how full the inner nodes of a Trie are, by node kind.
+///
+/// The occupancy of a node is its number of children relative to its capacity. Bucket `i`
+/// counts the nodes whose occupancy is at least `i / FILL_BUCKETS` and below
+/// `(i + 1) / FILL_BUCKETS`, with completely full nodes counted in the last bucket. Many nodes
+/// of a kind in the low buckets suggest the growth thresholds do not suit the data.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FillHistogram {
+    buckets: HashMap<NodeKind, [usize; FILL_BUCKETS]>,
+}
+
+impl FillHistogram {
+    fn record(&mut self, kind: NodeKind, num_children: usize, capacity: usize) {
+        let bucket = min(num_children * FILL_BUCKETS / capacity, FILL_BUCKETS - 1);
+        self.buckets.entry(kind).or_default()[bucket] += 1;
+    }
+
+    /// Returns the number of nodes of the given kind in each occupancy bucket.
+    pub fn buckets(&self, kind: NodeKind) -> [usize; FILL_BUCKETS] {
+        self.buckets.get(&kind).copied().unwrap_or_default()
+    }
+
+    /// Returns the number of nodes of the given kind.
+    pub fn nodes(&self, kind: NodeKind) -> usize {
+        self.buckets(kind).iter().sum()
+    }
+}
+
+/// A single step of a traced lookup, describing one node visited on the way to a key.
+#[cfg(feature = "trace")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+    /// The kind of the node visited.
+    pub kind: NodeKind,
+    /// The bytes of the node's prefix that matched the key.
+    pub matched: Vec<u8>,
+    /// The key byte used to pick the next child, if the lookup continued past this node.
+    pub byte: Option<u8>,
+    /// Whether a child was found for `byte`.
+    pub child_found: bool,
+}
+
+/// A difference in the live contents of two Tries, as yielded by `Tree::diff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Diff<V> {
+    /// The key is only live in the Trie `diff` was called on, with the given value.
+    Added(Vec<u8>, V),
+    /// The key is only live in the other Trie, with the given value.
+    Removed(Vec<u8>, V),
+    /// The key is live in both Tries with different values, the other Trie's value first.
+    Updated(Vec<u8>, V, V),
+}
+
+/// The outcome of a single `Tree::gc_step`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GcProgress {
+    /// The number of versions the step reclaimed.
+    pub reclaimed: usize,
+    /// Where the next step resumes, or `None` once the walk has covered the whole Trie.
+    pub cursor: Option<GcCursor>,
+}
+
+/// The position a `Tree::gc_step` walk stopped at, handed to the next step to resume it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GcCursor {
+    // The stored form of the first key the walk has not visited yet
+    next_key: Vec<u8>,
+}
+
 impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// Creates a new Twig node with a given prefix, key, value, and version.
     ///
@@ -141,20 +515,25 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     ///
     /// - `prefix`: The common prefix for the node.
     /// - `key`: The key associated with the Twig node.
-    /// - `value`: The value to be associated with the key.
-    /// - `ts`: The version when the value was inserted.
+    /// - `leaf`: The value to be associated with the key, with its version and timestamp.
+    /// - `options`: The options of the tree, which decide how the value is stored.
     ///
     /// # Returns
     ///
     /// Returns a new `Node` instance with a Twig node containing the provided key, value, and version.
     ///
     #[inline]
-    pub(crate) fn new_twig(prefix: P, key: P, value: V, version: u64, ts: u64) -> Node<P, V> {
+    pub(crate) fn new_twig(
+        prefix: P,
+        key: P,
+        leaf: LeafValue<V>,
+        options: &TreeOptions,
+    ) -> Node<P, V> {
         // Create a new TwigNode instance using the provided prefix and key.
-        let mut twig = TwigNode::new(prefix, key);
+        let mut twig = TwigNode::new(prefix, key).with_inline_values(options.inline_values);
 
         // Insert the provided value into the TwigNode along with the version.
-        twig.insert_mut(value, version, ts);
+        twig.insert_leaf_mut(leaf);
 
         // Return a new Node instance encapsulating the constructed Twig node.
         Self {
@@ -187,6 +566,47 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         }
     }
 
+    /// Creates a new inner node of the smallest multi-child kind configured in the options.
+    ///
+    /// # Parameters
+    ///
+    /// - `prefix`: The common prefix for the node.
+    /// - `options`: The options of the tree the node belongs to.
+    ///
+    /// # Returns
+    ///
+    /// Returns a new `Node` instance with an empty Node4 or Node8 node.
+    ///
+    #[inline]
+    pub(crate) fn new_node(prefix: P, options: &TreeOptions) -> Self {
+        if options.small_node_width == NODE8MAX {
+            Self {
+                node_type: NodeType::Node8(FlatNode::new(prefix)),
+            }
+        } else {
+            Self::new_node4(prefix)
+        }
+    }
+
+    /// Returns the maximum number of children the current node can hold.
+    ///
+    /// # Returns
+    ///
+    /// Returns the capacity of the node, or `0` for a Twig node, which has no children.
+    ///
+    #[inline]
+    pub(crate) fn size(&self) -> usize {
+        match &self.node_type {
+            NodeType::Node1(n) => n.size(),
+            NodeType::Node4(n) => n.size(),
+            NodeType::Node8(n) => n.size(),
+            NodeType::Node16(n) => n.size(),
+            NodeType::Node48(n) => n.size(),
+            NodeType::Node256(n) => n.size(),
+            NodeType::Twig(_) => 0,
+        }
+    }
+
     /// Checks if the current node is full based on its type.
     ///
     /// Determines if the current node is full by comparing the number of children to its
@@ -201,6 +621,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         match &self.node_type {
             NodeType::Node1(n) => self.num_children() >= n.size(),
             NodeType::Node4(n) => self.num_children() >= n.size(),
+            NodeType::Node8(n) => self.num_children() >= n.size(),
             NodeType::Node16(n) => self.num_children() >= n.size(),
             NodeType::Node48(n) => self.num_children() >= n.size(),
             NodeType::Node256(n) => self.num_children() > n.size(),
@@ -217,13 +638,22 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     ///
     /// - `key`: The key associated with the child node.
     /// - `child`: The child node to be added.
+    /// - `options`: The options deciding which node type to grow into.
     ///
     /// # Returns
     ///
     /// Returns a new `Node` instance with the added child node.
     ///
     #[inline]
-    fn add_child(&self, key: u8, child: Node<P, V>) -> Self {
+    fn add_child(&self, key: u8, child: Node<P, V>, options: &TreeOptions) -> Self {
+        // Nodes are grown as soon as they fill up, but never hand a full node to the
+        // underlying add_child, which has no slot left for the new key.
+        if self.is_full() {
+            let mut grown = self.clone_node();
+            grown.grow(options);
+            return grown.add_child(key, child, options);
+        }
+
         match &self.node_type {
             NodeType::Node1(n) => {
                 // Add the child node to the Node1 instance.
@@ -234,7 +664,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
-                    new_node.grow();
+                    new_node.grow(options);
                 }
 
                 new_node
@@ -248,7 +678,21 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
-                    new_node.grow();
+                    new_node.grow(options);
+                }
+
+                new_node
+            }
+            NodeType::Node8(n) => {
+                // Add the child node to the Node8 instance.
+                let node = NodeType::Node8(n.add_child(key, child));
+
+                // Create a new Node instance with the updated NodeType.
+                let mut new_node = Self { node_type: node };
+
+                // Check if the node has become full and needs to be grown.
+                if new_node.is_full() {
+                    new_node.grow(options);
                 }
 
                 new_node
@@ -262,7 +706,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
-                    new_node.grow();
+                    new_node.grow(options);
                 }
 
                 new_node
@@ -276,7 +720,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
-                    new_node.grow();
+                    new_node.grow(options);
                 }
 
                 new_node
@@ -290,7 +734,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 
                 // Check if the node has become full and needs to be grown.
                 if new_node.is_full() {
-                    new_node.grow();
+                    new_node.grow(options);
                 }
 
                 new_node
@@ -304,27 +748,41 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// Grows the current node to a larger size based on its type.
     /// This method is typically used to upgrade nodes when they become full.
     ///
-    /// ArtNodes of type NODE4 will grow to NODE16
-    /// ArtNodes of type NODE16 will grow to NODE48.
+    /// ArtNodes of type NODE1 will grow to NODE4, or NODE8 if configured.
+    /// ArtNodes of type NODE4 and NODE8 will grow to NODE16
+    /// ArtNodes of type NODE16 will grow to NODE48, or NODE256 if NODE48 is skipped.
     /// ArtNodes of type NODE48 will grow to NODE256.
     /// ArtNodes of type NODE256 will not grow, as they are the biggest type of ArtNodes
     #[inline]
-    fn grow(&mut self) {
+    fn grow(&mut self, options: &TreeOptions) {
         match &mut self.node_type {
             NodeType::Node1(n) => {
-                // Grow a Node4 to a Node16 by resizing.
-                let n4 = NodeType::Node4(n.resize());
-                self.node_type = n4;
+                // Grow a Node1 to the configured small node by resizing.
+                if options.small_node_width == NODE8MAX {
+                    self.node_type = NodeType::Node8(n.resize());
+                } else {
+                    self.node_type = NodeType::Node4(n.resize());
+                }
             }
             NodeType::Node4(n) => {
                 // Grow a Node4 to a Node16 by resizing.
                 let n16 = NodeType::Node16(n.resize());
                 self.node_type = n16;
             }
+            NodeType::Node8(n) => {
+                // Grow a Node8 to a Node16 by resizing.
+                let n16 = NodeType::Node16(n.resize());
+                self.node_type = n16;
+            }
             NodeType::Node16(n) => {
-                // Grow a Node16 to a Node48 by performing growth.
-                let n48 = NodeType::Node48(n.grow());
-                self.node_type = n48;
+                if options.skip_node48 {
+                    // Grow a Node16 straight to a Node256.
+                    self.node_type = NodeType::Node256(n.grow_node256());
+                } else {
+                    // Grow a Node16 to a Node48 by performing growth.
+                    let n48 = NodeType::Node48(n.grow());
+                    self.node_type = n48;
+                }
             }
             NodeType::Node48(n) => {
                 // Grow a Node48 to a Node256 by performing growth.
@@ -362,6 +820,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         match &self.node_type {
             NodeType::Node1(n) => n.find_child(key),
             NodeType::Node4(n) => n.find_child(key),
+            NodeType::Node8(n) => n.find_child(key),
             NodeType::Node16(n) => n.find_child(key),
             NodeType::Node48(n) => n.find_child(key),
             NodeType::Node256(n) => n.find_child(key),
@@ -369,6 +828,38 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         }
     }
 
+    /// Finds a child node by key, along with the slot it occupies in this node.
+    ///
+    /// The returned position can be passed to `replace_child_at` to swap the child
+    /// without searching for the key a second time.
+    ///
+    #[inline]
+    fn find_child_pos(&self, key: u8) -> Option<(usize, &Arc<Node<P, V>>)> {
+        match &self.node_type {
+            NodeType::Node1(n) => n.find_child_pos(key),
+            NodeType::Node4(n) => n.find_child_pos(key),
+            NodeType::Node8(n) => n.find_child_pos(key),
+            NodeType::Node16(n) => n.find_child_pos(key),
+            NodeType::Node48(n) => n.find_child_pos(key),
+            NodeType::Node256(n) => n.find_child_pos(key),
+            NodeType::Twig(_) => None,
+        }
+    }
+
+    /// Replaces the child stored at `pos`, as returned by `find_child_pos`.
+    fn replace_child_at(&self, pos: usize, node: Arc<Node<P, V>>) -> Self {
+        let node_type = match &self.node_type {
+            NodeType::Node1(n) => NodeType::Node1(n.replace_child_at(pos, node)),
+            NodeType::Node4(n) => NodeType::Node4(n.replace_child_at(pos, node)),
+            NodeType::Node8(n) => NodeType::Node8(n.replace_child_at(pos, node)),
+            NodeType::Node16(n) => NodeType::Node16(n.replace_child_at(pos, node)),
+            NodeType::Node48(n) => NodeType::Node48(n.replace_child_at(pos, node)),
+            NodeType::Node256(n) => NodeType::Node256(n.replace_child_at(pos, node)),
+            NodeType::Twig(_) => panic!("Unexpected Twig node encountered in replace_child_at()"),
+        };
+        Self { node_type }
+    }
+
     /// Replaces a child node with a new node for the given key.
     ///
     /// Replaces the child node associated with the specified key with the provided new node.
@@ -394,6 +885,11 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 let node = NodeType::Node4(n.replace_child(key, node));
                 Self { node_type: node }
             }
+            NodeType::Node8(n) => {
+                // Replace the child node in the Node8 instance and update the NodeType.
+                let node = NodeType::Node8(n.replace_child(key, node));
+                Self { node_type: node }
+            }
             NodeType::Node16(n) => {
                 // Replace the child node in the Node16 instance and update the NodeType.
                 let node = NodeType::Node16(n.replace_child(key, node));
@@ -421,13 +917,14 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// # Parameters
     ///
     /// - `key`: The key associated with the child node to be removed.
+    /// - `options`: The options deciding which node type to shrink into.
     ///
     /// # Returns
     ///
     /// Returns a new `Node` instance with the child node removed.
     ///
     #[inline]
-    fn delete_child(&self, key: u8) -> Self {
+    fn delete_child(&self, key: u8, options: &TreeOptions) -> Self {
         match &self.node_type {
             NodeType::Node1(n) => {
                 // Delete the child node from the Node1 instance and update the NodeType.
@@ -442,7 +939,19 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 
                 // Check if the number of remaining children is below the threshold.
                 if new_node.num_children() < NODE4MIN {
-                    new_node.shrink();
+                    new_node.shrink(options);
+                }
+
+                new_node
+            }
+            NodeType::Node8(n) => {
+                // Delete the child node from the Node8 instance and update the NodeType.
+                let node = NodeType::Node8(n.delete_child(key));
+                let mut new_node = Self { node_type: node };
+
+                // Check if the number of remaining children is below the threshold.
+                if new_node.num_children() < NODE4MIN {
+                    new_node.shrink(options);
                 }
 
                 new_node
@@ -453,8 +962,8 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 let mut new_node = Self { node_type: node };
 
                 // Check if the number of remaining children is below the threshold.
-                if new_node.num_children() < NODE16MIN {
-                    new_node.shrink();
+                if new_node.num_children() < options.node16_min() {
+                    new_node.shrink(options);
                 }
 
                 new_node
@@ -466,7 +975,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 
                 // Check if the number of remaining children is below the threshold.
                 if new_node.num_children() < NODE48MIN {
-                    new_node.shrink();
+                    new_node.shrink(options);
                 }
 
                 new_node
@@ -477,8 +986,8 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 let mut new_node = Self { node_type: node };
 
                 // Check if the number of remaining children is below the threshold.
-                if new_node.num_children() < NODE256MIN {
-                    new_node.shrink();
+                if new_node.num_children() < options.node256_min() {
+                    new_node.shrink(options);
                 }
 
                 new_node
@@ -487,6 +996,45 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         }
     }
 
+    // Builds a node of the same type and with the same prefix as this one, holding the given
+    // children in one pass, and shrinks it as deleting the other children one at a time would.
+    // The children must be sorted by key, and there must be at least one.
+    fn with_children(&self, children: &[(u8, Arc<Node<P, V>>)], options: &TreeOptions) -> Self {
+        let prefix = self.prefix().clone();
+        let node_type = match &self.node_type {
+            NodeType::Node1(_) => NodeType::Node1(FlatNode::from_sorted_children(prefix, children)),
+            NodeType::Node4(_) => NodeType::Node4(FlatNode::from_sorted_children(prefix, children)),
+            NodeType::Node8(_) => NodeType::Node8(FlatNode::from_sorted_children(prefix, children)),
+            NodeType::Node16(_) => {
+                NodeType::Node16(FlatNode::from_sorted_children(prefix, children))
+            }
+            NodeType::Node48(_) => NodeType::Node48(Node48::from_children(prefix, children)),
+            NodeType::Node256(_) => NodeType::Node256(Node256::from_children(prefix, children)),
+            NodeType::Twig(_) => panic!("Unexpected Twig node encountered in with_children()"),
+        };
+        let mut new_node = Self { node_type };
+
+        // Step down for as long as the node is below the threshold of its type
+        loop {
+            let min_children = match &new_node.node_type {
+                NodeType::Node4(_) | NodeType::Node8(_) => NODE4MIN,
+                NodeType::Node16(_) => options.node16_min(),
+                NodeType::Node48(_) => NODE48MIN,
+                NodeType::Node256(_) => options.node256_min(),
+                NodeType::Node1(_) | NodeType::Twig(_) => break,
+            };
+            let kind = new_node.node_kind();
+            if new_node.num_children() >= min_children {
+                break;
+            }
+            new_node.shrink(options);
+            if new_node.node_kind() == kind {
+                break;
+            }
+        }
+        new_node
+    }
+
     /// Checks if the node type is a Twig node.
     ///
     /// Determines whether the current node is a Twig node based on its node type.
@@ -510,6 +1058,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// Returns `true` if the node type is an inner node, otherwise returns `false`.
     ///
     #[inline]
+    #[allow(dead_code)]
     pub(crate) fn is_inner(&self) -> bool {
         !self.is_twig()
     }
@@ -527,6 +1076,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         match &self.node_type {
             NodeType::Node1(n) => &n.prefix,
             NodeType::Node4(n) => &n.prefix,
+            NodeType::Node8(n) => &n.prefix,
             NodeType::Node16(n) => &n.prefix,
             NodeType::Node48(n) => &n.prefix,
             NodeType::Node256(n) => &n.prefix,
@@ -547,6 +1097,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         match &mut self.node_type {
             NodeType::Node1(n) => n.prefix = prefix,
             NodeType::Node4(n) => n.prefix = prefix,
+            NodeType::Node8(n) => n.prefix = prefix,
             NodeType::Node16(n) => n.prefix = prefix,
             NodeType::Node48(n) => n.prefix = prefix,
             NodeType::Node256(n) => n.prefix = prefix,
@@ -559,26 +1110,36 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// Shrinks the current node to a smaller size based on its type.
     /// This method is typically used to downgrade nodes when the number of children becomes low.
     ///
-    /// ArtNodes of type NODE256 will shrink to NODE48
+    /// ArtNodes of type NODE256 will shrink to NODE48, or NODE16 if NODE48 is skipped.
     /// ArtNodes of type NODE48 will shrink to NODE16.
-    /// ArtNodes of type NODE16 will shrink to NODE4.
-    /// ArtNodes of type NODE4 will collapse into its first child.
+    /// ArtNodes of type NODE16 will shrink to NODE4, or NODE8 if configured.
+    /// ArtNodes of type NODE4 and NODE8 will shrink to NODE1.
     ///
-    /// If that child is not a twig, it will concatenate its current prefix with that of its childs
-    /// before replacing itself.
-    fn shrink(&mut self) {
+    /// A flat node is only shrunk if its children fit the smaller width, otherwise it is left
+    /// as it is.
+    fn shrink(&mut self, options: &TreeOptions) {
         match &mut self.node_type {
-            NodeType::Node1(n) => {
-                // Shrink Node1 to Node1 by resizing it.
-                self.node_type = NodeType::Node1(n.resize());
+            NodeType::Node1(_) => {
+                // A Node1 is already the smallest node.
             }
-            NodeType::Node4(n) => {
+            NodeType::Node4(n) if n.num_children() <= 1 => {
                 // Shrink Node4 to Node1 by resizing it.
                 self.node_type = NodeType::Node1(n.resize());
             }
-            NodeType::Node16(n) => {
-                // Shrink Node16 to Node4 by resizing it.
-                self.node_type = NodeType::Node4(n.resize());
+            NodeType::Node8(n) if n.num_children() <= 1 => {
+                // Shrink Node8 to Node1 by resizing it.
+                self.node_type = NodeType::Node1(n.resize());
+            }
+            NodeType::Node16(n) if n.num_children() <= options.small_node_width => {
+                // Shrink Node16 to the configured small node by resizing it.
+                if options.small_node_width == NODE8MAX {
+                    self.node_type = NodeType::Node8(n.resize());
+                } else {
+                    self.node_type = NodeType::Node4(n.resize());
+                }
+            }
+            NodeType::Node4(_) | NodeType::Node8(_) | NodeType::Node16(_) => {
+                // Too many children to fit the smaller node.
             }
             NodeType::Node48(n) => {
                 // Shrink Node48 to Node16 by obtaining the shrunken Node16 instance.
@@ -589,11 +1150,16 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
                 self.node_type = new_node;
             }
             NodeType::Node256(n) => {
-                // Shrink Node256 to Node48 by obtaining the shrunken Node48 instance.
-                let n48 = n.shrink();
+                if options.skip_node48 {
+                    // Shrink Node256 straight to Node16.
+                    self.node_type = NodeType::Node16(n.shrink_flat());
+                } else {
+                    // Shrink Node256 to Node48 by obtaining the shrunken Node48 instance.
+                    let n48 = n.shrink();
 
-                // Update the node type to Node48 after the shrinking operation.
-                self.node_type = NodeType::Node48(n48);
+                    // Update the node type to Node48 after the shrinking operation.
+                    self.node_type = NodeType::Node48(n48);
+                }
             }
             NodeType::Twig(_) => panic!("Twig node encountered in shrink()"),
         }
@@ -604,6 +1170,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         match &self.node_type {
             NodeType::Node1(n) => n.num_children(),
             NodeType::Node4(n) => n.num_children(),
+            NodeType::Node8(n) => n.num_children(),
             NodeType::Node16(n) => n.num_children(),
             NodeType::Node48(n) => n.num_children(),
             NodeType::Node256(n) => n.num_children(),
@@ -633,19 +1200,31 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         };
 
         // Get the value from the TwigNode instance by the specified version.
-        let Some(val) = twig.get_leaf_by_version(version) else {
-            return None;
-        };
+        let val = twig.get_leaf_by_version(version)?;
 
         // Return the retrieved key, value, and version as a tuple.
         // TODO: should return copy of value or reference?
         Some((twig.key.clone(), val.value.clone(), val.version, val.ts))
     }
 
+    /// Returns the kind of the node.
+    pub fn node_kind(&self) -> NodeKind {
+        match &self.node_type {
+            NodeType::Node1(_) => NodeKind::Node1,
+            NodeType::Node4(_) => NodeKind::Node4,
+            NodeType::Node8(_) => NodeKind::Node8,
+            NodeType::Node16(_) => NodeKind::Node16,
+            NodeType::Node48(_) => NodeKind::Node48,
+            NodeType::Node256(_) => NodeKind::Node256,
+            NodeType::Twig(_) => NodeKind::Twig,
+        }
+    }
+
     pub fn node_type_name(&self) -> String {
         match &self.node_type {
             NodeType::Node1(_) => "Node1".to_string(),
             NodeType::Node4(_) => "Node4".to_string(),
+            NodeType::Node8(_) => "Node8".to_string(),
             NodeType::Node16(_) => "Node16".to_string(),
             NodeType::Node48(_) => "Node48".to_string(),
             NodeType::Node256(_) => "Node256".to_string(),
@@ -675,22 +1254,24 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// # Parameters
     ///
     /// - `cur_node`: A reference to the current node.
-    /// - `key`: The key to be inserted.
-    /// - `value`: The value associated with the key.
-    /// - `commit_version`: The version when the value was inserted.
+    /// - `key`: The key to be inserted. An owned key is moved into the new twig rather than
+    ///   copied.
+    /// - `leaf`: The value associated with the key, with the version it is committed at, its
+    ///   timestamp and its expiry.
     /// - `depth`: The depth of the insertion process.
+    /// - `options`: The options of the tree being inserted into.
     ///
     /// # Returns
     ///
     /// Returns the updated node and the old value (if any) for the given key.
     ///
+    #[allow(clippy::type_complexity)]
     pub(crate) fn insert_recurse(
         cur_node: &Arc<Node<P, V>>,
-        key: &P,
-        value: V,
-        commit_version: u64,
-        ts: u64,
+        key: Cow<'_, P>,
+        leaf: LeafValue<V>,
         depth: usize,
+        options: &TreeOptions,
     ) -> Result<(Arc<Node<P, V>>, Option<V>), TrieError> {
         // Obtain the current node's prefix and its length.
         let cur_node_prefix = cur_node.prefix().clone();
@@ -713,50 +1294,54 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         // update the existing value in the Twig node.
         if let NodeType::Twig(ref twig) = &cur_node.node_type {
             if is_prefix_match && cur_node_prefix.len() == key_prefix.len() {
-                let old_val = twig.get_leaf_by_version(commit_version).unwrap();
-                let new_twig = twig.insert(value, commit_version, ts);
+                let old_val = twig.get_leaf_by_version(leaf.version);
+                let new_twig = if options.keep_history {
+                    twig.insert_leaf(leaf)
+                } else {
+                    twig.replace_leaf(leaf)
+                };
                 return Ok((
                     Arc::new(Node {
                         node_type: NodeType::Twig(new_twig),
                     }),
-                    Some(old_val.value.clone()),
+                    old_val.map(|leaf| leaf.value.clone()),
                 ));
             }
         }
 
-        // If the prefixes don't match, create a new Node4 with the old node and a new Twig as children.
+        // A key ending on the path of the current node, or running past a Twig node, is a proper
+        // prefix of a stored key or has one as a prefix. The two cannot share the path, which is
+        // what terminated keys such as `VariableKey::from_str` avoid.
+        if is_prefix_match && (longest_common_prefix == key_prefix.len() || cur_node.is_twig()) {
+            return Err(TrieError::PrefixConflict);
+        }
+
+        // If the prefixes don't match, create a new inner node with the old node and a new Twig as children.
         if !is_prefix_match {
             let mut old_node = cur_node.clone_node();
             old_node.set_prefix(new_key);
-            let mut n4 = Node::new_node4(prefix);
+            let mut n4 = Node::new_node(prefix, options);
 
             let k1 = cur_node_prefix.at(longest_common_prefix);
             let k2 = key_prefix[longest_common_prefix];
             let new_twig = Node::new_twig(
                 key_prefix[longest_common_prefix..].into(),
-                key.as_slice().into(),
-                value,
-                commit_version,
-                ts,
+                key.into_owned(),
+                leaf,
+                options,
             );
-            n4 = n4.add_child(k1, old_node).add_child(k2, new_twig);
+            n4 = n4
+                .add_child(k1, old_node, options)
+                .add_child(k2, new_twig, options);
             return Ok((Arc::new(n4), None));
         }
 
         // Continue the insertion process by finding or creating the appropriate child node for the next character.
         let k = key_prefix[longest_common_prefix];
-        let child_for_key = cur_node.find_child(k);
-        if let Some(child) = child_for_key {
-            match Node::insert_recurse(
-                child,
-                key,
-                value,
-                commit_version,
-                ts,
-                depth + longest_common_prefix,
-            ) {
+        if let Some((pos, child)) = cur_node.find_child_pos(k) {
+            match Node::insert_recurse(child, key, leaf, depth + longest_common_prefix, options) {
                 Ok((new_child, old_value)) => {
-                    let new_node = cur_node.replace_child(k, new_child);
+                    let new_node = cur_node.replace_child_at(pos, new_child);
                     return Ok((Arc::new(new_node), old_value));
                 }
                 Err(err) => {
@@ -768,12 +1353,11 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         // If no child exists for the key's character, create a new Twig node and add it as a child.
         let new_twig = Node::new_twig(
             key_prefix[longest_common_prefix..].into(),
-            key.as_slice().into(),
-            value,
-            commit_version,
-            ts,
+            key.into_owned(),
+            leaf,
+            options,
         );
-        let new_node = cur_node.add_child(k, new_twig);
+        let new_node = cur_node.add_child(k, new_twig, options);
         Ok((Arc::new(new_node), None))
     }
 
@@ -786,6 +1370,7 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
     /// - `cur_node`: A reference to the current node.
     /// - `key`: The key to be removed.
     /// - `depth`: The depth of the removal process.
+    /// - `options`: The options of the tree being removed from.
     ///
     /// # Returns
     ///
@@ -795,63 +1380,638 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
         cur_node: &Arc<Node<P, V>>,
         key: &P,
         depth: usize,
+        options: &TreeOptions,
     ) -> (Option<Arc<Node<P, V>>>, bool) {
-        // Obtain the prefix of the current node.
-        let prefix = cur_node.prefix().clone();
-
-        // Determine the prefix of the key after the current depth.
-        let key_prefix = key.prefix_after(depth);
-        let key_prefix = key_prefix.as_slice();
-        // Find the longest common prefix between the current node's prefix and the key's prefix.
-        let longest_common_prefix = prefix.longest_common_prefix(key_prefix);
-        // Determine whether the current node's prefix and the key's prefix match up to the common prefix.
-        let is_prefix_match = min(prefix.len(), key_prefix.len()) == longest_common_prefix;
+        let (new_node, removed) =
+            Node::update_twig_recurse(cur_node, key, depth, options, |_| Some((None, ())));
+        (new_node, removed.is_some())
+    }
 
-        // If the current node's prefix and the key's prefix match up to the end of both prefixes,
-        // the key has been found and should be removed.
-        if is_prefix_match && prefix.len() == key_prefix.len() {
-            return (None, true);
+    /// Removes several keys from the node and its children in a single pass.
+    ///
+    /// The keys below each child are handled together, so keys sharing a path descend it only
+    /// once and every node on it is copied only once. Inner nodes left without children are
+    /// dropped, and the others shrink to fit their remaining children.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `keys`: The keys to remove, sorted and distinct, each paired with its slot in `removed`.
+    /// - `depth`: The depth of the removal process.
+    /// - `options`: The options of the tree being removed from.
+    /// - `removed`: Receives the latest value of every key removed, in the slot of the key.
+    ///
+    /// # Returns
+    ///
+    /// Returns a tuple containing the updated node (or `None`) and a flag indicating if any key
+    /// was removed.
+    ///
+    pub(crate) fn remove_many_recurse(
+        cur_node: &Arc<Node<P, V>>,
+        keys: &[(&P, usize)],
+        depth: usize,
+        options: &TreeOptions,
+        removed: &mut [Option<V>],
+    ) -> (Option<Arc<Node<P, V>>>, bool) {
+        // Twig nodes hold the full key, so they can be compared directly.
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            let found = keys
+                .iter()
+                .find(|(key, _)| key.as_slice() == twig.key.as_slice());
+            return match found {
+                Some(&(_, slot)) => {
+                    removed[slot] = twig.get_latest_value().cloned();
+                    (None, true)
+                }
+                None => (Some(cur_node.clone()), false),
+            };
         }
 
-        // Determine the character at the common prefix position.
-        let k = key_prefix[longest_common_prefix];
+        // The byte picking the child a key lies below, if the key extends the node's prefix.
+        let prefix = cur_node.prefix();
+        let branch = |key: &P| {
+            let key_slice = key.as_slice();
+            let key_prefix = &key_slice[min(depth, key_slice.len())..];
+            let longest_common_prefix = prefix.longest_common_prefix(key_prefix);
+            (longest_common_prefix == prefix.len() && longest_common_prefix < key_prefix.len())
+                .then(|| key_prefix[longest_common_prefix])
+        };
 
-        // Search for a child node corresponding to the key's character.
-        let child = cur_node.find_child(k);
-        if let Some(child_node) = child {
-            // Recursively attempt to remove the key from the child node.
-            let (_new_child, removed) =
-                Node::remove_recurse(child_node, key, depth + longest_common_prefix);
-            if removed {
-                // If the key was successfully removed from the child node, update the current node's child pointer.
-                let new_node = cur_node.delete_child(k);
-                return (Some(Arc::new(new_node)), true);
+        // The keys are sorted, so the keys below each child follow one another.
+        let mut new_node: Option<Node<P, V>> = None;
+        let mut rest = keys;
+        while let Some(&(key, _)) = rest.first() {
+            let Some(k) = branch(key) else {
+                rest = &rest[1..];
+                continue;
+            };
+            let len = rest
+                .iter()
+                .take_while(|(other, _)| branch(other) == Some(k))
+                .count();
+            let (group, tail) = rest.split_at(len);
+            rest = tail;
+
+            let Some(child) = cur_node.find_child(k) else {
+                continue;
+            };
+            let (new_child, changed) =
+                Node::remove_many_recurse(child, group, depth + prefix.len(), options, removed);
+            if changed {
+                let node = new_node.as_ref().unwrap_or(cur_node);
+                new_node = Some(match new_child {
+                    Some(new_child) if new_child.is_twig() || new_child.num_children() > 0 => {
+                        node.replace_child(k, new_child)
+                    }
+                    _ => node.delete_child(k, options),
+                });
             }
         }
 
-        // If the key was not found at this level, return the current node as-is.
-        (Some(cur_node.clone()), false)
+        match new_node {
+            None => (Some(cur_node.clone()), false),
+            Some(node) if node.num_children() == 0 => (None, true),
+            Some(node) => (Some(Arc::new(node)), true),
+        }
     }
 
-    /// Recursively searches for a key in the node and its children.
+    /// Rewrites the Twig node holding a key, recursively copying the path down to it.
     ///
-    /// Recursively searches for a key in the current node and its child nodes, considering versions.
+    /// The twig is handed to `update`, which either leaves it alone by returning `None`, or
+    /// returns the twig to put in its place, or `None` to detach it, along with a result to pass
+    /// back to the caller. Inner nodes left without children on the way up are dropped.
     ///
     /// # Parameters
     ///
     /// - `cur_node`: A reference to the current node.
-    /// - `key`: The key to be searched for.
-    /// - `ts`: The version for which to retrieve the value.
+    /// - `key`: The key whose twig is rewritten.
+    /// - `depth`: The depth of the rewrite process.
+    /// - `options`: The options of the tree being rewritten.
+    /// - `update`: The rewrite to apply to the twig.
     ///
     /// # Returns
     ///
-    /// Returns a result containing the prefix, value, and version if the key is found, or Error if not.
+    /// Returns a tuple containing the updated node (or `None`) and the result of `update`, or
+    /// `None` if the key was not found or `update` left the twig alone.
     ///
-    pub fn get_recurse(
-        cur_node: &Node<P, V>,
+    pub(crate) fn update_twig_recurse<F, T>(
+        cur_node: &Arc<Node<P, V>>,
+        key: &P,
+        depth: usize,
+        options: &TreeOptions,
+        update: F,
+    ) -> (Option<Arc<Node<P, V>>>, Option<T>)
+    where
+        F: FnOnce(&TwigNode<P, V>) -> Option<(Option<TwigNode<P, V>>, T)>,
+    {
+        // Twig nodes hold the full key, so they can be compared directly.
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            if twig.key.as_slice() != key.as_slice() {
+                return (Some(cur_node.clone()), None);
+            }
+            return match update(twig) {
+                None => (Some(cur_node.clone()), None),
+                Some((new_twig, result)) => {
+                    let new_node = new_twig.map(|twig| {
+                        Arc::new(Node {
+                            node_type: NodeType::Twig(twig),
+                        })
+                    });
+                    (new_node, Some(result))
+                }
+            };
+        }
+
+        // Obtain the prefix of the current node.
+        let prefix = cur_node.prefix();
+
+        // Determine the prefix of the key after the current depth.
+        let key_prefix = key.prefix_after(depth);
+        let key_prefix = key_prefix.as_slice();
+        // Find the longest common prefix between the current node's prefix and the key's prefix.
+        let longest_common_prefix = prefix.longest_common_prefix(key_prefix);
+
+        // The key can only be below this node if it extends the node's whole prefix.
+        if longest_common_prefix < prefix.len() || longest_common_prefix >= key_prefix.len() {
+            return (Some(cur_node.clone()), None);
+        }
+
+        // Determine the character at the common prefix position.
+        let k = key_prefix[longest_common_prefix];
+
+        // Search for a child node corresponding to the key's character.
+        if let Some((pos, child_node)) = cur_node.find_child_pos(k) {
+            // Recursively attempt to rewrite the twig below the child node.
+            let (new_child, result) = Node::update_twig_recurse(
+                child_node,
+                key,
+                depth + longest_common_prefix,
+                options,
+                update,
+            );
+            if result.is_some() {
+                // If the twig was rewritten, update the current node's child pointer, dropping
+                // the child if nothing is left below it.
+                let new_node = match new_child {
+                    Some(new_child) if new_child.is_twig() || new_child.num_children() > 0 => {
+                        cur_node.replace_child_at(pos, new_child)
+                    }
+                    _ => cur_node.delete_child(k, options),
+                };
+                return (Some(Arc::new(new_node)), result);
+            }
+        }
+
+        // If the key was not found at this level, return the current node as-is.
+        (Some(cur_node.clone()), None)
+    }
+
+    // Collects the twigs below the given node whose keys lie in the range, in key order,
+    // skipping every subtree that lies entirely outside of it. `path` holds the key bytes
+    // leading up to the node.
+    fn collect_range_twigs<'a, R>(
+        cur_node: &'a Node<P, V>,
+        path: &mut Vec<u8>,
+        range: &R,
+        twigs: &mut Vec<&'a TwigNode<P, V>>,
+    ) where
+        R: RangeBounds<P>,
+    {
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            if range.contains(&twig.key) {
+                twigs.push(twig);
+            }
+            return;
+        }
+
+        let len = path.len();
+        path.extend_from_slice(cur_node.prefix().as_slice());
+        if Node::<P, V>::path_overlaps(path, range) {
+            for (_, child) in cur_node.iter() {
+                Node::collect_range_twigs(child, path, range, twigs);
+            }
+        }
+        path.truncate(len);
+    }
+
+    // Calls `visit` on the twigs in the range in key order, stopping as soon as it returns
+    // false, and returns whether the walk ran to the end.
+    fn visit_range_twigs<'a, R, F>(
+        cur_node: &'a Node<P, V>,
+        path: &mut Vec<u8>,
+        range: &R,
+        visit: &mut F,
+    ) -> bool
+    where
+        R: RangeBounds<P>,
+        F: FnMut(&'a TwigNode<P, V>) -> bool,
+    {
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            return !range.contains(&twig.key) || visit(twig);
+        }
+
+        let len = path.len();
+        path.extend_from_slice(cur_node.prefix().as_slice());
+        let completed = !Node::<P, V>::path_overlaps(path, range)
+            || cur_node
+                .iter()
+                .all(|(_, child)| Node::visit_range_twigs(child, path, range, visit));
+        path.truncate(len);
+        completed
+    }
+
+    // Checks whether a key starting with the given bytes can lie in the range. The bytes are
+    // compared to each bound only as far as both go, as the rest of the key is unknown.
+    fn path_overlaps<R>(path: &[u8], range: &R) -> bool
+    where
+        R: RangeBounds<P>,
+    {
+        let cmp = |bound: &P| {
+            let bound = bound.as_slice();
+            let len = path.len().min(bound.len());
+            path[..len].cmp(&bound[..len])
+        };
+        let after_start = match range.start_bound() {
+            Bound::Included(k) | Bound::Excluded(k) => cmp(k).is_ge(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match range.end_bound() {
+            Bound::Included(k) | Bound::Excluded(k) => cmp(k).is_le(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// Counts the live keys in the given range below the node.
+    ///
+    /// Subtrees whose keys all lie inside the range contribute their cached leaf count, so only
+    /// the nodes along the two bounds of the range are visited. Once `levels` inner nodes have
+    /// been descended, a subtree only partly inside the range is not descended any further and
+    /// contributes half its cached leaf count instead.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `path`: The key bytes leading to the node, excluding its own prefix.
+    /// - `range`: The range of keys to count.
+    /// - `levels`: The number of inner node levels to descend along the bounds.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of live keys in the range, which is exact unless the descent was cut
+    /// short by `levels`.
+    ///
+    pub(crate) fn range_count_recurse<R>(
+        cur_node: &Node<P, V>,
+        path: &mut Vec<u8>,
+        range: &R,
+        levels: usize,
+    ) -> usize
+    where
+        R: RangeBounds<P>,
+    {
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            return if range.contains(&twig.key) {
+                twig.leaf_count()
+            } else {
+                0
+            };
+        }
+
+        let len = path.len();
+        path.extend_from_slice(cur_node.prefix().as_slice());
+        let count = if Node::<P, V>::path_within(path, range) {
+            cur_node.leaf_count()
+        } else if !Node::<P, V>::path_overlaps(path, range) {
+            0
+        } else if levels == 0 {
+            cur_node.leaf_count() / 2
+        } else {
+            cur_node
+                .iter()
+                .map(|(_, child)| Node::range_count_recurse(child, path, range, levels - 1))
+                .sum()
+        };
+        path.truncate(len);
+        count
+    }
+
+    // Checks whether every key starting with the given bytes lies in the range. This is the
+    // counterpart of `path_overlaps`, and errs on the side of `false` at the bounds themselves.
+    fn path_within<R>(path: &[u8], range: &R) -> bool
+    where
+        R: RangeBounds<P>,
+    {
+        let cmp = |bound: &P| {
+            let bound = bound.as_slice();
+            let len = path.len().min(bound.len());
+            path[..len].cmp(&bound[..len])
+        };
+        let after_start = match range.start_bound() {
+            Bound::Included(k) => {
+                let ord = cmp(k);
+                ord.is_gt() || (ord.is_eq() && path.len() >= k.as_slice().len())
+            }
+            Bound::Excluded(k) => {
+                let ord = cmp(k);
+                ord.is_gt() || (ord.is_eq() && path.len() > k.as_slice().len())
+            }
+            Bound::Unbounded => true,
+        };
+        let before_end = match range.end_bound() {
+            Bound::Included(k) | Bound::Excluded(k) => cmp(k).is_lt(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    // Finds the twig holding the live key with the given zero-based rank, skipping every child
+    // whose cached leaf count lies wholly before it.
+    pub(crate) fn select_twig(cur_node: &Node<P, V>, mut rank: usize) -> Option<&TwigNode<P, V>> {
+        let mut cur_node = cur_node;
+        'descend: loop {
+            if let NodeType::Twig(twig) = &cur_node.node_type {
+                return (rank == 0 && twig.leaf_count() == 1).then_some(twig);
+            }
+            for (_, child) in cur_node.iter() {
+                let count = child.leaf_count();
+                if rank < count {
+                    cur_node = child;
+                    continue 'descend;
+                }
+                rank -= count;
+            }
+            return None;
+        }
+    }
+
+    /// Splits the node and its children at the given key.
+    ///
+    /// Recursively divides the subtree rooted at the current node into the part holding keys
+    /// strictly less than `key` and the part holding keys greater than or equal to `key`.
+    /// Children that fall entirely on one side are shared with the original node.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `key`: The key at which to split.
+    /// - `depth`: The depth of the split process.
+    /// - `options`: The options of the tree being split.
+    ///
+    /// # Returns
+    ///
+    /// Returns a tuple of the left (`< key`) and right (`>= key`) subtrees, either of which may be `None`.
+    ///
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn split_recurse(
+        cur_node: &Arc<Node<P, V>>,
+        key: &P,
+        depth: usize,
+        options: &TreeOptions,
+    ) -> (Option<Arc<Node<P, V>>>, Option<Arc<Node<P, V>>>) {
+        // Twig nodes hold the full key, so they can be compared directly.
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            if twig.key < *key {
+                return (Some(cur_node.clone()), None);
+            }
+            return (None, Some(cur_node.clone()));
+        }
+
+        // Determine the part of the split key that is compared against this node.
+        let key_slice = key.as_slice();
+        let key_prefix = &key_slice[min(depth, key_slice.len())..];
+        let prefix = cur_node.prefix();
+        let longest_common_prefix = prefix.longest_common_prefix(key_prefix);
+
+        // If the node's prefix diverges from the key, the whole subtree lies on one side.
+        if longest_common_prefix < prefix.len() {
+            if longest_common_prefix < key_prefix.len()
+                && prefix.at(longest_common_prefix) < key_prefix[longest_common_prefix]
+            {
+                return (Some(cur_node.clone()), None);
+            }
+            return (None, Some(cur_node.clone()));
+        }
+
+        // If the key ends at this node, every key below it is greater.
+        if key_prefix.len() == prefix.len() {
+            return (None, Some(cur_node.clone()));
+        }
+
+        // Children with a smaller byte go left, larger go right, and the matching child is split.
+        let k = key_prefix[prefix.len()];
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for (child_key, child) in cur_node.iter() {
+            match child_key.cmp(&k) {
+                std::cmp::Ordering::Less => left.push((child_key, child.clone())),
+                std::cmp::Ordering::Greater => right.push((child_key, child.clone())),
+                std::cmp::Ordering::Equal => {
+                    let (child_left, child_right) =
+                        Node::split_recurse(child, key, depth + prefix.len(), options);
+                    left.extend(child_left.map(|node| (child_key, node)));
+                    right.extend(child_right.map(|node| (child_key, node)));
+                }
+            }
+        }
+
+        // Each side is built once from its children, which come out of iter sorted by key
+        let left = (!left.is_empty()).then(|| Arc::new(cur_node.with_children(&left, options)));
+        let right = (!right.is_empty()).then(|| Arc::new(cur_node.with_children(&right, options)));
+        (left, right)
+    }
+
+    // Copies the keys in the range below the given node, with their full history, into a new
+    // subtree. Subtrees whose keys all lie inside the range are shared rather than copied, and
+    // `None` is returned if no key lies inside it. `path` holds the key bytes leading up to
+    // the node.
+    pub(crate) fn clone_range_recurse<R>(
+        cur_node: &Arc<Node<P, V>>,
+        path: &mut Vec<u8>,
+        range: &R,
+        options: &TreeOptions,
+    ) -> Option<Arc<Node<P, V>>>
+    where
+        R: RangeBounds<P>,
+    {
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            return range.contains(&twig.key).then(|| cur_node.clone());
+        }
+
+        let len = path.len();
+        path.extend_from_slice(cur_node.prefix().as_slice());
+        let result = if Node::<P, V>::path_within(path, range) {
+            Some(cur_node.clone())
+        } else if Node::<P, V>::path_overlaps(path, range) {
+            let mut children = Vec::new();
+            let mut changed = false;
+            for (key, child) in cur_node.iter() {
+                match Node::clone_range_recurse(child, path, range, options) {
+                    Some(new_child) => {
+                        changed |= !Arc::ptr_eq(&new_child, child);
+                        children.push((key, new_child));
+                    }
+                    None => changed = true,
+                }
+            }
+            if !changed {
+                Some(cur_node.clone())
+            } else {
+                (!children.is_empty()).then(|| Arc::new(cur_node.with_children(&children, options)))
+            }
+        } else {
+            None
+        };
+        path.truncate(len);
+        result
+    }
+
+    /// Recursively searches for a key in the node and its children.
+    ///
+    /// Recursively searches for a key in the current node and its child nodes, considering versions.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `key`: The key to be searched for.
+    /// - `ts`: The version for which to retrieve the value.
+    ///
+    /// # Returns
+    ///
+    /// Returns a result containing the prefix, value, and version if the key is found, or Error if not.
+    ///
+    pub fn get_recurse(
+        cur_node: &Node<P, V>,
         key: &P,
         version: u64,
     ) -> Result<(P, V, u64, u64), TrieError> {
+        // Descend to the twig holding the key.
+        let Some(twig) = Node::find_twig(cur_node, key) else {
+            return Err(TrieError::KeyNotFound);
+        };
+
+        // Get the value from the twig by the specified version.
+        let Some(val) = twig.get_leaf_by_version(version) else {
+            return Err(TrieError::KeyNotFound);
+        };
+        Ok((twig.key.clone(), val.value.clone(), val.version, val.ts))
+    }
+
+    /// Finds the subtree holding exactly the keys that start with the given bytes.
+    ///
+    /// The descent follows the prefix through the node prefixes and branching bytes, and stops at
+    /// the first node whose path covers the whole prefix. Every key below that node starts with
+    /// the prefix, and no key elsewhere does.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `prefix`: The leading bytes of the keys.
+    ///
+    /// # Returns
+    ///
+    /// Returns the root of the subtree, or `None` if no key starts with the prefix.
+    ///
+    pub(crate) fn find_prefix_node<'a>(
+        cur_node: &'a Arc<Node<P, V>>,
+        prefix: &[u8],
+    ) -> Option<&'a Arc<Node<P, V>>> {
+        Node::find_prefix_path(cur_node, prefix).map(|(node, _)| node)
+    }
+
+    // Like find_prefix_node, but also returns the number of key bytes leading to the node,
+    // excluding its own prefix.
+    pub(crate) fn find_prefix_path<'a>(
+        cur_node: &'a Arc<Node<P, V>>,
+        prefix: &[u8],
+    ) -> Option<(&'a Arc<Node<P, V>>, usize)> {
+        let mut cur_node = cur_node;
+        let mut depth = 0;
+        loop {
+            let remaining = &prefix[depth..];
+            let node_prefix = cur_node.prefix().as_slice();
+            let len = remaining.len().min(node_prefix.len());
+            if remaining[..len] != node_prefix[..len] {
+                return None;
+            }
+
+            // The node's path covers the prefix, so everything below it matches.
+            if remaining.len() <= node_prefix.len() {
+                return Some((cur_node, depth));
+            }
+
+            depth += node_prefix.len();
+            cur_node = cur_node.find_child(prefix[depth])?;
+        }
+    }
+
+    // Returns the number of leading bytes of the key that follow a path down from the node,
+    // descending until a node prefix diverges from the key or no child matches its next byte.
+    pub(crate) fn common_prefix_len(cur_node: &Arc<Node<P, V>>, key: &[u8]) -> usize {
+        let mut cur_node = cur_node;
+        let mut depth = 0;
+        loop {
+            let node_prefix = cur_node.prefix().as_slice();
+            let matched = key[depth..]
+                .iter()
+                .zip(node_prefix)
+                .take_while(|(a, b)| a == b)
+                .count();
+            depth += matched;
+            if matched < node_prefix.len() || depth == key.len() {
+                return depth;
+            }
+            match cur_node.find_child(key[depth]) {
+                Some(child) => cur_node = child,
+                None => return depth,
+            }
+        }
+    }
+
+    /// Descends from the node to the Twig node holding the given key.
+    ///
+    /// Follows the same path as a lookup but stops at the twig, so callers can inspect the
+    /// stored versions without cloning any value.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `key`: The key to be searched for.
+    ///
+    /// # Returns
+    ///
+    /// Returns a reference to the Twig node for the key, or `None` if the key is not present.
+    ///
+    #[inline]
+    pub(crate) fn find_twig<'a>(cur_node: &'a Node<P, V>, key: &P) -> Option<&'a TwigNode<P, V>> {
+        Node::find_twig_with(cur_node, key, |_, _, _, _| {})
+    }
+
+    /// Descends to the Twig node holding the given key, reporting every node visited.
+    ///
+    /// This is the descent behind `find_twig`. For every node on the path, `visit` is called with
+    /// the node, the number of its prefix bytes matched by the key, the key byte used to pick a
+    /// child (if the descent continues past the node) and whether that child exists.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `key`: The key to be searched for.
+    /// - `visit`: The callback invoked for each node visited.
+    ///
+    /// # Returns
+    ///
+    /// Returns a reference to the Twig node for the key, or `None` if the key is not present.
+    ///
+    #[inline]
+    pub(crate) fn find_twig_with<'a, F>(
+        cur_node: &'a Node<P, V>,
+        key: &P,
+        mut visit: F,
+    ) -> Option<&'a TwigNode<P, V>>
+    where
+        F: FnMut(&Node<P, V>, usize, Option<u8>, bool),
+    {
         // Initialize the traversal variables.
         let mut cur_node = cur_node;
         let mut depth = 0;
@@ -868,15 +2028,17 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 
             // If the longest common prefix does not match the entire node's prefix, the key is not present.
             if lcp != prefix.len() {
-                return Err(TrieError::KeyNotFound);
+                visit(cur_node, lcp, None, false);
+                return None;
             }
 
-            // If the current node's prefix length matches the key's prefix length, retrieve the value.
+            // If the current node's prefix length matches the key's prefix length, the twig is found.
             if prefix.len() == key_prefix.len() {
-                let Some(val) = cur_node.get_value_by_version(version) else {
-                    return Err(TrieError::KeyNotFound);
+                visit(cur_node, lcp, None, false);
+                let NodeType::Twig(twig) = &cur_node.node_type else {
+                    return None;
                 };
-                return Ok((val.0, val.1, val.2, val.3));
+                return Some(twig);
             }
 
             // Determine the character at the next position after the prefix in the key.
@@ -884,37 +2046,444 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
             // Increment the depth by the prefix length.
             depth += prefix.len();
             // Find the child node corresponding to the character and update the current node for further traversal.
-            match cur_node.find_child(k) {
-                Some(child) => cur_node = child,
-                None => return Err(TrieError::KeyNotFound),
+            let child = cur_node.find_child(k);
+            visit(cur_node, lcp, Some(k), child.is_some());
+            cur_node = child?;
+        }
+    }
+
+    /// Estimates the number of keys stored below the node from a single sampled walk.
+    ///
+    /// Follows one pseudo-randomly chosen path from the current node down to a twig, multiplying
+    /// the fan-out seen at every level. Averaged over several walks this gives an unbiased estimate
+    /// of the number of twigs in the subtree.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `seed`: The seed selecting which path is walked.
+    ///
+    /// # Returns
+    ///
+    /// Returns the estimated number of keys for the sampled path.
+    ///
+    pub(crate) fn estimate_walk(cur_node: &Node<P, V>, seed: u64) -> f64 {
+        let mut cur_node = cur_node;
+        let mut estimate = 1.0;
+        let mut state = seed.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+
+        loop {
+            let num_children = cur_node.num_children();
+            if num_children == 0 {
+                // A twig contributes one key, an empty inner node none.
+                return if cur_node.is_twig() { estimate } else { 0.0 };
+            }
+            estimate *= num_children as f64;
+
+            // Advance a xorshift generator to choose the next child.
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let idx = (state % num_children as u64) as usize;
+            match cur_node.iter().nth(idx) {
+                Some((_, child)) => cur_node = child,
+                None => return 0.0,
             }
         }
     }
 
-    /// Returns an iterator that iterates over child nodes of the current node.
+    /// Counts the nodes of a subtree that are shared with another subtree.
     ///
-    /// This function provides an iterator that traverses through the child nodes of the current node,
-    /// returning tuples of keys and references to child nodes.
+    /// Walks the current subtree alongside the subtree at the same position in another trie.
+    /// Once both point at the same node, the whole subtree below it is shared; otherwise the node
+    /// has been copied and the walk continues into the children branching on the same byte.
+    ///
+    /// # Parameters
+    ///
+    /// - `cur_node`: A reference to the current node.
+    /// - `other`: The node at the same position in the other trie, if any.
     ///
     /// # Returns
     ///
-    /// Returns a boxed iterator that yields tuples containing keys and references to child nodes.
+    /// Returns a tuple of the number of shared and unique nodes in the current subtree.
     ///
-    #[allow(dead_code)]
-    pub fn iter(&self) -> Box<dyn Iterator<Item = (u8, &Arc<Self>)> + '_> {
-        match &self.node_type {
-            NodeType::Node1(n) => Box::new(n.iter()),
-            NodeType::Node4(n) => Box::new(n.iter()),
-            NodeType::Node16(n) => Box::new(n.iter()),
-            NodeType::Node48(n) => Box::new(n.iter()),
-            NodeType::Node256(n) => Box::new(n.iter()),
-            NodeType::Twig(_) => Box::new(std::iter::empty()),
+    pub(crate) fn count_shared(
+        cur_node: &Arc<Node<P, V>>,
+        other: Option<&Arc<Node<P, V>>>,
+    ) -> (usize, usize) {
+        if let Some(other) = other {
+            if Arc::ptr_eq(cur_node, other) {
+                return (Node::count_nodes(cur_node), 0);
+            }
+        }
+
+        let mut shared = 0;
+        let mut unique = 1;
+        for (k, child) in cur_node.iter() {
+            // Only inner nodes with a matching prefix hold children at the same position.
+            let other_child = other
+                .filter(|other| !other.is_twig() && other.prefix() == cur_node.prefix())
+                .and_then(|other| other.find_child(k));
+            let (child_shared, child_unique) = Node::count_shared(child, other_child);
+            shared += child_shared;
+            unique += child_unique;
         }
+        (shared, unique)
     }
-}
 
-/// A struct representing an Adaptive Radix Trie.
-///
+    // Rebuilds the subtree with the backing stores of its Node48 and Node256 nodes trimmed to
+    // their contents. Subtrees with nothing to trim are shared rather than copied.
+    pub(crate) fn shrink_storage(cur_node: &Arc<Node<P, V>>) -> Arc<Node<P, V>> {
+        if cur_node.is_twig() {
+            return cur_node.clone();
+        }
+
+        let mut new_node = cur_node.clone_node();
+        let mut changed = false;
+        for (key, child) in cur_node.iter() {
+            let new_child = Node::shrink_storage(child);
+            if !Arc::ptr_eq(&new_child, child) {
+                new_node = new_node.replace_child(key, new_child);
+                changed = true;
+            }
+        }
+        changed |= match &mut new_node.node_type {
+            NodeType::Node48(n) => n.shrink_storage(),
+            NodeType::Node256(n) => n.shrink_storage(),
+            _ => false,
+        };
+
+        if changed {
+            Arc::new(new_node)
+        } else {
+            cur_node.clone()
+        }
+    }
+
+    // Rebuilds the subtree with every inner node in the smallest node type that holds its
+    // children, and every backing store trimmed to its contents. Subtrees with nothing to trim
+    // are shared rather than copied.
+    pub(crate) fn shrink_to_fit(
+        cur_node: &Arc<Node<P, V>>,
+        options: &TreeOptions,
+    ) -> Arc<Node<P, V>> {
+        if let NodeType::Twig(twig) = &cur_node.node_type {
+            if twig.heap_capacity() <= twig.values.len() {
+                return cur_node.clone();
+            }
+            let mut twig = twig.clone();
+            twig.values.shrink_to_fit();
+            return Arc::new(Node {
+                node_type: NodeType::Twig(twig),
+            });
+        }
+
+        let mut new_node = cur_node.clone_node();
+        let mut changed = false;
+        for (key, child) in cur_node.iter() {
+            let new_child = Node::shrink_to_fit(child, options);
+            if !Arc::ptr_eq(&new_child, child) {
+                new_node = new_node.replace_child(key, new_child);
+                changed = true;
+            }
+        }
+
+        // Step down one node type at a time for as long as the children still fit
+        loop {
+            let num_children = new_node.num_children();
+            let fits_smaller = match &new_node.node_type {
+                NodeType::Node4(_) | NodeType::Node8(_) => num_children <= 1,
+                NodeType::Node16(_) => num_children <= options.small_node_width,
+                NodeType::Node48(_) => num_children <= NODE16MAX,
+                NodeType::Node256(_) if options.skip_node48 => num_children <= NODE16MAX,
+                NodeType::Node256(_) => num_children <= NODE48MAX,
+                NodeType::Node1(_) | NodeType::Twig(_) => false,
+            };
+            if !fits_smaller {
+                break;
+            }
+            new_node.shrink(options);
+            changed = true;
+        }
+
+        changed |= match &mut new_node.node_type {
+            NodeType::Node48(n) => n.shrink_storage(),
+            NodeType::Node256(n) => n.shrink_storage(),
+            _ => false,
+        };
+
+        if changed {
+            Arc::new(new_node)
+        } else {
+            cur_node.clone()
+        }
+    }
+
+    // Estimates the memory held by the subtree rooted at the given node: the nodes themselves,
+    // their backing stores and the values of the twigs, but not any heap data owned by the keys
+    // or values.
+    pub(crate) fn estimate_memory(cur_node: &Node<P, V>) -> usize {
+        let heap_size = match &cur_node.node_type {
+            NodeType::Twig(twig) => twig.heap_size(),
+            NodeType::Node1(n) => n.heap_size(),
+            NodeType::Node4(n) => n.heap_size(),
+            NodeType::Node8(n) => n.heap_size(),
+            NodeType::Node16(n) => n.heap_size(),
+            NodeType::Node48(n) => n.heap_size(),
+            NodeType::Node256(n) => n.heap_size(),
+        };
+        // Every node lives in an Arc, which adds the two reference counts
+        let node_size = std::mem::size_of::<Node<P, V>>() + 2 * std::mem::size_of::<usize>();
+        node_size
+            + heap_size
+            + cur_node
+                .iter()
+                .map(|(_, child)| Node::estimate_memory(child))
+                .sum::<usize>()
+    }
+
+    // Rebuilds the subtree with every key and prefix re-encoded as another key type holding the
+    // same bytes. The node types are kept, and the twigs share their values with the originals.
+    pub(crate) fn convert_keys<Q: KeyTrait>(cur_node: &Node<P, V>) -> Node<Q, V> {
+        // The children come out of iter sorted by key
+        let children: Vec<_> = cur_node
+            .iter()
+            .map(|(key, child)| (key, Arc::new(Node::convert_keys(child))))
+            .collect();
+        let prefix = Q::from(cur_node.prefix().as_slice());
+        let node_type = match &cur_node.node_type {
+            NodeType::Twig(twig) => NodeType::Twig(TwigNode {
+                prefix,
+                key: Q::from(twig.key.as_slice()),
+                values: twig.values.clone(),
+                version: twig.version,
+                inline_values: twig.inline_values,
+            }),
+            NodeType::Node1(_) => {
+                NodeType::Node1(FlatNode::from_sorted_children(prefix, &children))
+            }
+            NodeType::Node4(_) => {
+                NodeType::Node4(FlatNode::from_sorted_children(prefix, &children))
+            }
+            NodeType::Node8(_) => {
+                NodeType::Node8(FlatNode::from_sorted_children(prefix, &children))
+            }
+            NodeType::Node16(_) => {
+                NodeType::Node16(FlatNode::from_sorted_children(prefix, &children))
+            }
+            NodeType::Node48(_) => NodeType::Node48(Node48::from_children(prefix, &children)),
+            NodeType::Node256(_) => NodeType::Node256(Node256::from_children(prefix, &children)),
+        };
+        Node { node_type }
+    }
+
+    // Counts all nodes in the subtree rooted at the given node, including itself.
+    fn count_nodes(cur_node: &Node<P, V>) -> usize {
+        1 + cur_node
+            .iter()
+            .map(|(_, child)| Node::count_nodes(child))
+            .sum::<usize>()
+    }
+
+    /// Returns an iterator that iterates over child nodes of the current node.
+    ///
+    /// This function provides an iterator that traverses through the child nodes of the current node,
+    /// returning tuples of keys and references to child nodes.
+    ///
+    /// # Returns
+    ///
+    /// Returns a boxed iterator that yields tuples containing keys and references to child nodes.
+    ///
+    #[allow(dead_code)]
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (u8, &Arc<Self>)> + '_> {
+        match &self.node_type {
+            NodeType::Node1(n) => Box::new(n.iter()),
+            NodeType::Node4(n) => Box::new(n.iter()),
+            NodeType::Node8(n) => Box::new(n.iter()),
+            NodeType::Node16(n) => Box::new(n.iter()),
+            NodeType::Node48(n) => Box::new(n.iter()),
+            NodeType::Node256(n) => Box::new(n.iter()),
+            NodeType::Twig(_) => Box::new(std::iter::empty()),
+        }
+    }
+
+    // Iterates over the children whose branching byte lies between lo and hi, both included,
+    // in key order. The children outside the window are not visited.
+    pub(crate) fn iter_range(
+        &self,
+        lo: u8,
+        hi: u8,
+    ) -> Box<dyn Iterator<Item = (u8, &Arc<Self>)> + '_> {
+        match &self.node_type {
+            NodeType::Node1(n) => Box::new(n.iter_range(lo, hi)),
+            NodeType::Node4(n) => Box::new(n.iter_range(lo, hi)),
+            NodeType::Node8(n) => Box::new(n.iter_range(lo, hi)),
+            NodeType::Node16(n) => Box::new(n.iter_range(lo, hi)),
+            NodeType::Node48(n) => Box::new(n.iter_range(lo, hi)),
+            NodeType::Node256(n) => Box::new(n.iter_range(lo, hi)),
+            NodeType::Twig(_) => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Returns the children of the node, in key order.
+    ///
+    /// Together with `node_kind` and `prefix_bytes` and the root returned by `Tree::root`, this
+    /// lets external code walk the structure of the Trie to implement its own traversals. The
+    /// walk is read-only, as the nodes are shared with every snapshot of the Trie.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator over the branching byte and the child node of each child. Twig nodes
+    /// have no children.
+    ///
+    /// # Examples
+    ///
+    /// A depth-first walk counting the twig nodes, i.e. the keys, of a Trie:
+    ///
+    /// ```
+    /// use art::art::{NodeKind, Tree};
+    /// use art::VariableKey;
+    ///
+    /// let mut tree = Tree::<VariableKey, usize>::new();
+    /// for (i, word) in ["apple", "apricot", "banana"].iter().enumerate() {
+    ///     tree.insert(&VariableKey::from_str(word), i, 0, 0).unwrap();
+    /// }
+    ///
+    /// let mut stack: Vec<_> = tree.root().into_iter().collect();
+    /// let mut twigs = 0;
+    /// while let Some(node) = stack.pop() {
+    ///     if node.node_kind() == NodeKind::Twig {
+    ///         twigs += 1;
+    ///     }
+    ///     stack.extend(node.children().map(|(_, child)| child));
+    /// }
+    /// assert_eq!(twigs, 3);
+    /// ```
+    ///
+    pub fn children(&self) -> impl Iterator<Item = (u8, &Arc<Self>)> + '_ {
+        self.iter()
+    }
+
+    /// Returns the prefix bytes of the node.
+    ///
+    /// The prefix of a child includes the byte it branches on, so concatenating the prefixes
+    /// along a path from the root gives the leading bytes of every key below the last node.
+    pub fn prefix_bytes(&self) -> &[u8] {
+        self.prefix().as_slice()
+    }
+}
+
+impl<P: KeyTrait + Clone, V: Clone + PartialEq> Node<P, V> {
+    /// Compares two subtrees occupying the same position in their tries.
+    ///
+    /// Shared subtrees are detected by pointer equality and skipped. Where both nodes branch on
+    /// the same bytes the comparison descends child by child; otherwise the twigs of both subtrees
+    /// are compared in key order, as node layout can differ between tries holding the same keys.
+    ///
+    /// # Parameters
+    ///
+    /// - `a`: The subtree of the first trie.
+    /// - `b`: The subtree of the second trie.
+    /// - `twig_eq`: Decides whether two twigs holding the same key are equal.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if both subtrees hold equal twigs.
+    ///
+    pub(crate) fn subtree_eq<F>(a: &Arc<Node<P, V>>, b: &Arc<Node<P, V>>, twig_eq: &F) -> bool
+    where
+        F: Fn(&TwigNode<P, V>, &TwigNode<P, V>) -> bool,
+    {
+        if Arc::ptr_eq(a, b) {
+            return true;
+        }
+
+        match (&a.node_type, &b.node_type) {
+            (NodeType::Twig(ta), NodeType::Twig(tb)) => return ta.key == tb.key && twig_eq(ta, tb),
+            (NodeType::Twig(_), _) | (_, NodeType::Twig(_)) => {}
+            _ => {
+                let same_shape = a.prefix().as_slice() == b.prefix().as_slice()
+                    && a.num_children() == b.num_children()
+                    && a.iter().map(|(k, _)| k).eq(b.iter().map(|(k, _)| k));
+                if same_shape {
+                    return a
+                        .iter()
+                        .zip(b.iter())
+                        .all(|((_, ca), (_, cb))| Node::subtree_eq(ca, cb, twig_eq));
+                }
+            }
+        }
+
+        // The layouts differ, so fall back to comparing the live twigs in key order.
+        let mut twigs_a = Vec::new();
+        let mut twigs_b = Vec::new();
+        Node::collect_twigs(a, &mut twigs_a);
+        Node::collect_twigs(b, &mut twigs_b);
+        twigs_a.len() == twigs_b.len()
+            && twigs_a
+                .iter()
+                .zip(twigs_b.iter())
+                .all(|(ta, tb)| ta.key == tb.key && twig_eq(ta, tb))
+    }
+
+    // Collects the twigs holding at least one value below the given node, in key order.
+    fn collect_twigs<'a>(cur_node: &'a Node<P, V>, twigs: &mut Vec<&'a TwigNode<P, V>>) {
+        match &cur_node.node_type {
+            NodeType::Twig(twig) => {
+                if !twig.values.is_empty() {
+                    twigs.push(twig);
+                }
+            }
+            _ => {
+                for (_, child) in cur_node.iter() {
+                    Node::collect_twigs(child, twigs);
+                }
+            }
+        }
+    }
+
+    // Collects the twigs of two subtrees occupying the same position in their tries, skipping
+    // every subtree the two share. Where both nodes branch on the same prefix the children are
+    // paired up by key byte, so a change only pulls in the twigs below the nodes it copied.
+    pub(crate) fn collect_unshared_twigs<'a>(
+        a: Option<&'a Arc<Node<P, V>>>,
+        b: Option<&'a Arc<Node<P, V>>>,
+        twigs_a: &mut Vec<&'a TwigNode<P, V>>,
+        twigs_b: &mut Vec<&'a TwigNode<P, V>>,
+    ) {
+        match (a, b) {
+            (Some(a), Some(b)) if Arc::ptr_eq(a, b) => {}
+            (Some(a), Some(b))
+                if !a.is_twig()
+                    && !b.is_twig()
+                    && a.prefix().as_slice() == b.prefix().as_slice() =>
+            {
+                let mut keys: Vec<u8> = a.iter().chain(b.iter()).map(|(k, _)| k).collect();
+                keys.sort_unstable();
+                keys.dedup();
+                for k in keys {
+                    Node::collect_unshared_twigs(
+                        a.find_child(k),
+                        b.find_child(k),
+                        twigs_a,
+                        twigs_b,
+                    );
+                }
+            }
+            _ => {
+                if let Some(a) = a {
+                    Node::collect_twigs(a, twigs_a);
+                }
+                if let Some(b) = b {
+                    Node::collect_twigs(b, twigs_b);
+                }
+            }
+        }
+    }
+}
+
+/// A struct representing an Adaptive Radix Trie.
+///
 /// The `Tree` struct encompasses the entire adaptive radix trie data structure.
 /// It manages the root node of the tree, maintains snapshots of the tree's state,
 /// and keeps track of various properties related to snapshot management.
@@ -926,11 +2495,18 @@ impl<P: KeyTrait + Clone, V: Clone> Node<P, V> {
 ///
 /// # Fields
 ///
-/// - `root`: An optional shared reference (using `Rc`) to the root node of the tree.
+/// - `root`: An optional shared reference (using `Arc`) to the root node of the tree.
 /// - `snapshots`: A `HashSet` storing snapshots of the tree's state, mapped by snapshot IDs.
 /// - `max_snapshot_id`: An `AtomicU64` representing the maximum snapshot ID assigned.
 /// - `max_active_snapshots`: The maximum number of active snapshots allowed.
 ///
+/// # Thread safety
+///
+/// A `Tree` is `Send` and `Sync` whenever `P` and `V` are. Nodes are shared through `Arc` and
+/// never mutated once shared, so any number of threads may read a `&Tree` at once, while writes
+/// need `&mut Tree` and are therefore exclusive. To write from several threads, wrap the tree in
+/// a lock or use `ConcurrentTree`.
+///
 pub struct Tree<P: KeyTrait, V: Clone> {
     /// An optional shared reference to the root node of the tree.
     pub(crate) root: Option<Arc<Node<P, V>>>,
@@ -942,6 +2518,13 @@ pub struct Tree<P: KeyTrait, V: Clone> {
     pub(crate) max_active_snapshots: u64,
     /// A flag indicating whether the tree is closed.
     pub(crate) closed: bool,
+    /// The options controlling the layout of the tree.
+    pub(crate) options: TreeOptions,
+    /// The highest timestamp inserted so far, which `insert_auto` stamps past.
+    pub(crate) max_ts: AtomicU64,
+    /// A filter over the inserted keys, used to skip lookups of absent keys.
+    #[cfg(feature = "bloom")]
+    pub(crate) bloom: Option<BloomFilter>,
 }
 
 pub struct KV<P, V> {
@@ -969,6 +2552,7 @@ impl<P: KeyTrait + Clone, V: Clone> NodeType<P, V> {
             NodeType::Twig(twig) => NodeType::Twig(twig.clone()),
             NodeType::Node1(n) => NodeType::Node1(n.clone()),
             NodeType::Node4(n) => NodeType::Node4(n.clone()),
+            NodeType::Node8(n) => NodeType::Node8(n.clone()),
             NodeType::Node16(n) => NodeType::Node16(n.clone()),
             NodeType::Node48(n) => NodeType::Node48(n.clone()),
             NodeType::Node256(n) => NodeType::Node256(n.clone()),
@@ -983,14 +2567,41 @@ impl<P: KeyTrait, V: Clone> Default for Tree<P, V> {
     }
 }
 
+// Cloning a Trie is O(1): the clone shares the root, and the two Tries diverge through
+// copy-on-write as either is modified. Snapshots stay registered with the original only.
+impl<P: KeyTrait, V: Clone> Clone for Tree<P, V> {
+    fn clone(&self) -> Self {
+        Tree {
+            root: self.root.clone(),
+            snapshots: HashSet::new(),
+            max_snapshot_id: AtomicU64::new(self.max_snapshot_id.load(Ordering::SeqCst)),
+            max_active_snapshots: self.max_active_snapshots,
+            closed: self.closed,
+            options: self.options.clone(),
+            max_ts: AtomicU64::new(self.max_ts.load(Ordering::SeqCst)),
+            #[cfg(feature = "bloom")]
+            bloom: self.bloom.clone(),
+        }
+    }
+}
+
 impl<P: KeyTrait, V: Clone> Tree<P, V> {
     pub fn new() -> Self {
+        Tree::with_options(TreeOptions::default())
+    }
+
+    /// Creates a new, empty tree laid out according to the given options.
+    pub fn with_options(options: TreeOptions) -> Self {
         Tree {
             root: None,
             max_snapshot_id: AtomicU64::new(0),
             snapshots: HashSet::new(),
             max_active_snapshots: DEFAULT_MAX_ACTIVE_SNAPSHOTS,
             closed: false,
+            max_ts: AtomicU64::new(0),
+            #[cfg(feature = "bloom")]
+            bloom: options.bloom_expected_keys.map(BloomFilter::new),
+            options,
         }
     }
 
@@ -1001,19 +2612,24 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
     /// Inserts a new key-value pair with the specified version into the Trie.
     ///
     /// This function inserts a new key-value pair into the Trie. If the key already exists,
-    /// the previous value associated with the key is returned. The version `ts` is used to
-    /// ensure proper ordering of values for versioning.
+    /// the new value is appended as a new version and the previous latest value is returned.
+    /// The version is used to ensure proper ordering of values for versioning.
     ///
     /// # Arguments
     ///
     /// * `key`: A reference to the key to be inserted.
     /// * `value`: The value to be associated with the key.
-    /// * `ts`: The version for the insertion, used for versioning.
+    /// * `version`: The version for the insertion, or `0` for the version after the latest one.
+    /// * `ts`: The timestamp for the insertion.
+    ///
+    /// Both are taken as a `VersionId` and a `Ts`, or as anything converting into them such as a
+    /// bare `u64`, so passing a typed timestamp as the version fails to compile.
     ///
     /// # Returns
     ///
-    /// Returns `Ok(None)` if the key did not exist previously. If the key already existed,
-    /// `Ok(Some(old_value))` is returned, where `old_value` is the previous value associated with the key.
+    /// Returns `Ok(None)` if the key did not exist previously, or was removed by a tombstone. If
+    /// the key already existed, `Ok(Some(old_value))` is returned, where `old_value` is the
+    /// previous latest value associated with the key.
     ///
     /// # Errors
     ///
@@ -1023,12 +2639,161 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
         &mut self,
         key: &P,
         value: V,
+        version: impl Into<VersionId>,
+        ts: impl Into<Ts>,
+    ) -> Result<Option<V>, TrieError> {
+        self.insert_key(
+            Cow::Borrowed(key),
+            value,
+            version.into().0,
+            ts.into().0,
+            None,
+        )
+    }
+
+    /// Inserts a new version of a key and returns the value it replaces.
+    ///
+    /// This is `HashMap::insert` layered on the versioned store: the new value is appended to
+    /// the history of the key, and the previous latest value is read from the same descent, so
+    /// a write yields both its before and after image, e.g. for an audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to be inserted.
+    /// * `value`: The value to be associated with the key.
+    /// * `version`: The version for the insertion, or `0` for the version after the latest one.
+    /// * `ts`: The timestamp for the insertion.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(Some(old_value))` with the previous latest value of the key, or `Ok(None)` if
+    /// the key is new or was removed by a tombstone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the insertion fails, as for `insert`.
+    ///
+    pub fn insert_returning(
+        &mut self,
+        key: &P,
+        value: V,
+        version: impl Into<VersionId>,
+        ts: impl Into<Ts>,
+    ) -> Result<Option<V>, TrieError> {
+        self.insert(key, value, version, ts)
+    }
+
+    /// Inserts a new key-value pair, taking the version and timestamp as bare numbers.
+    ///
+    /// This is `insert` for callers that keep versions and timestamps as plain `u64`s, e.g. when
+    /// they come straight from a log record.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to be inserted.
+    /// * `value`: The value to be associated with the key.
+    /// * `version`: The version for the insertion, see `insert`.
+    /// * `ts`: The timestamp for the insertion.
+    ///
+    /// # Returns
+    ///
+    /// Returns the previous value of the key, if any, see `insert`.
+    ///
+    pub fn insert_raw(
+        &mut self,
+        key: &P,
+        value: V,
+        version: u64,
+        ts: u64,
+    ) -> Result<Option<V>, TrieError> {
+        self.insert_key(Cow::Borrowed(key), value, version, ts, None)
+    }
+
+    /// Inserts a new key-value pair, taking ownership of the key.
+    ///
+    /// This behaves exactly like `insert`, but the key is moved into the twig that stores it
+    /// instead of being copied, which saves an allocation for every new key when the caller
+    /// has no further use for it, e.g. with large `VariableKey`s.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key to be inserted.
+    /// * `value`: The value to be associated with the key.
+    /// * `version`: The version for the insertion, see `insert`.
+    /// * `ts`: The timestamp for the insertion.
+    ///
+    /// # Returns
+    ///
+    /// Returns the previous value of the key, if any, see `insert`.
+    ///
+    pub fn insert_owned(
+        &mut self,
+        key: P,
+        value: V,
+        version: impl Into<VersionId>,
+        ts: impl Into<Ts>,
+    ) -> Result<Option<V>, TrieError> {
+        self.insert_key(Cow::Owned(key), value, version.into().0, ts.into().0, None)
+    }
+
+    /// Inserts a key-value pair that expires at a given timestamp.
+    ///
+    /// This behaves like `insert`, but reads by timestamp at or after `expires_at`, such as
+    /// `get_ref` and `get_at_timestamps`, treat the value as absent, as if it had been deleted
+    /// then, while reads before it still see it. `get_live` reads the latest value against the
+    /// current time the same way, whereas `get` by version cannot tell the time and still
+    /// returns it. An expired key keeps its place in the Trie, and its count in `len`, until
+    /// `purge_expired` reclaims it.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to be inserted.
+    /// * `value`: The value to be associated with the key.
+    /// * `version`: The version for the insertion, see `insert`.
+    /// * `ts`: The timestamp for the insertion.
+    /// * `expires_at`: The timestamp from which the value reads as absent.
+    ///
+    /// # Returns
+    ///
+    /// Returns the previous value of the key, if any, see `insert`.
+    ///
+    pub fn insert_with_ttl(
+        &mut self,
+        key: &P,
+        value: V,
+        version: impl Into<VersionId>,
+        ts: impl Into<Ts>,
+        expires_at: impl Into<Ts>,
+    ) -> Result<Option<V>, TrieError> {
+        self.insert_key(
+            Cow::Borrowed(key),
+            value,
+            version.into().0,
+            ts.into().0,
+            Some(expires_at.into().0),
+        )
+    }
+
+    fn insert_key(
+        &mut self,
+        key: Cow<'_, P>,
+        value: V,
         version: u64,
         ts: u64,
+        expires_at: Option<u64>,
     ) -> Result<Option<V>, TrieError> {
         // Check if the tree is already closed
         self.is_closed()?;
 
+        self.options.check_key(key.as_ref())?;
+        let key = self.options.encode_key(key);
+
+        // The key may be moved into the tree, so it is added to the filter up front
+        #[cfg(feature = "bloom")]
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(key.as_slice());
+        }
+
         let (new_root, old_node) = match &self.root {
             None => {
                 let mut commit_version = version;
@@ -1038,10 +2803,9 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
                 (
                     Arc::new(Node::new_twig(
                         key.as_slice().into(),
-                        key.as_slice().into(),
-                        value,
-                        commit_version,
-                        ts,
+                        key.into_owned(),
+                        LeafValue::new(value, commit_version, ts).with_expiry(expires_at),
+                        &self.options,
                     )),
                     None,
                 )
@@ -1058,7 +2822,8 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
                         "given version is older than root's current version".to_string(),
                     ));
                 }
-                match Node::insert_recurse(root, key, value, commit_version, ts, 0) {
+                let leaf = LeafValue::new(value, commit_version, ts).with_expiry(expires_at);
+                match Node::insert_recurse(root, key, leaf, 0, &self.options) {
                     Ok((new_node, old_node)) => (new_node, old_node),
                     Err(err) => {
                         return Err(err);
@@ -1068,31 +2833,162 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
         };
 
         self.root = Some(new_root);
+        self.max_ts.fetch_max(ts, Ordering::SeqCst);
         Ok(old_node)
     }
 
-    pub fn bulk_insert(&mut self, kv_pairs: &[KV<P, V>]) -> Result<(), TrieError> {
+    /// Inserts a key-value pair, stamping it with an internally assigned version and timestamp.
+    ///
+    /// The stamp is greater than every version and timestamp the Trie has seen so far, including
+    /// those passed to `insert`, so each call gets a strictly greater stamp than the last and
+    /// never collides with a manually supplied one.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to be inserted.
+    /// * `value`: The value to be associated with the key.
+    ///
+    /// # Returns
+    ///
+    /// Returns the stamp used as both the version and the timestamp of the insertion.
+    ///
+    pub fn insert_auto(&mut self, key: &P, value: V) -> Result<u64, TrieError> {
+        let stamp = self.max_ts.load(Ordering::SeqCst).max(self.version()) + 1;
+        self.insert(key, value, stamp, stamp)?;
+        Ok(stamp)
+    }
+
+    /// Updates the value of a key only if the key is present.
+    ///
+    /// This is the put-if-present counterpart of `insert`: the new value is added as a new
+    /// version of an existing key, while an absent or deleted key is left absent. The key is
+    /// descended to once, and nothing is copied unless it is present.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to be updated.
+    /// * `value`: The new value of the key.
+    /// * `version`: The version for the update, see `insert`.
+    /// * `ts`: The timestamp for the update.
+    ///
+    /// # Returns
+    ///
+    /// Returns the previous latest value, or `None` if the key was not present.
+    ///
+    pub fn replace(
+        &mut self,
+        key: &P,
+        value: V,
+        version: impl Into<VersionId>,
+        ts: impl Into<Ts>,
+    ) -> Result<Option<V>, TrieError> {
         // Check if the tree is already closed
         self.is_closed()?;
 
-        let curr_version = self.version();
-        let mut new_version = 0;
+        let version = version.into().0;
+        let ts = ts.into().0;
 
-        for kv in kv_pairs {
-            let k = kv.key.clone(); // Clone the key
-            let v = kv.value.clone(); // Clone the value
-            let mut t = kv.version;
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        let Some(root) = &self.root else {
+            return Ok(None);
+        };
+        let curr_version = root.version();
+        let commit_version = if version == 0 {
+            curr_version + 1
+        } else if curr_version >= version {
+            return Err(TrieError::Other(
+                "given version is older than root's current version".to_string(),
+            ));
+        } else {
+            version
+        };
 
-            if t == 0 {
-                // Zero-valued timestamps are associated with current time plus one
-                t = curr_version + 1;
-            } else if kv.version < curr_version {
-                return Err(TrieError::Other(
-                    "given version is older than root's current version".to_string(),
-                ));
-            }
+        let keep_history = self.options.keep_history;
+        let (new_root, old_value) =
+            Node::update_twig_recurse(root, &key, 0, &self.options, |twig| {
+                let old_value = twig.get_latest_live_leaf()?.value.clone();
+                let new_twig = if keep_history {
+                    twig.insert(value, commit_version, ts)
+                } else {
+                    twig.replace(value, commit_version, ts)
+                };
+                Some((Some(new_twig), old_value))
+            });
+        if old_value.is_some() {
+            self.root = new_root;
+            self.max_ts.fetch_max(ts, Ordering::SeqCst);
+        }
+        Ok(old_value)
+    }
 
-            // Create a new KV instance
+    /// Returns a new Trie holding the given key-value pair, leaving this Trie unchanged.
+    ///
+    /// This is the persistent counterpart of `insert`. Only the nodes on the path to the key
+    /// are copied; every other subtree is shared between the two Tries. The new Trie has the
+    /// same options, but no open snapshots.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to be inserted.
+    /// * `value`: The value to be associated with the key.
+    /// * `version`: The version for the insertion, see `insert`.
+    /// * `ts`: The timestamp for the insertion.
+    ///
+    /// # Returns
+    ///
+    /// Returns the new Trie.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this Trie is closed or if `insert` would fail.
+    ///
+    pub fn put(
+        &self,
+        key: &P,
+        value: V,
+        version: impl Into<VersionId>,
+        ts: impl Into<Ts>,
+    ) -> Result<Tree<P, V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let version = version.into().0;
+        let ts = ts.into().0;
+
+        let mut tree = self.clone();
+        tree.insert(key, value, version, ts)?;
+        Ok(tree)
+    }
+
+    pub fn bulk_insert(&mut self, kv_pairs: &[KV<P, V>]) -> Result<(), TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let curr_version = self.version();
+        let mut new_version = 0;
+
+        for kv in kv_pairs {
+            self.options.check_key(&kv.key)?;
+
+            let k = self.options.encode_key(Cow::Borrowed(&kv.key)).into_owned(); // Clone the key
+            let v = kv.value.clone(); // Clone the value
+            let mut t = kv.version;
+
+            if t == 0 {
+                // Zero-valued timestamps are associated with current time plus one
+                t = curr_version + 1;
+            } else if kv.version < curr_version {
+                return Err(TrieError::Other(
+                    "given version is older than root's current version".to_string(),
+                ));
+            }
+
+            #[cfg(feature = "bloom")]
+            if let Some(bloom) = &mut self.bloom {
+                bloom.insert(k.as_slice());
+            }
+
+            // Create a new KV instance
             let new_kv = KV {
                 key: k,
                 value: v,
@@ -1107,19 +3003,17 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
                     self.root = Some(Arc::new(Node::new_twig(
                         new_kv.key.as_slice().into(),
                         new_kv.key.as_slice().into(),
-                        new_kv.value,
-                        new_kv.version,
-                        new_kv.ts,
+                        LeafValue::new(new_kv.value, new_kv.version, new_kv.ts),
+                        &self.options,
                     )))
                 }
                 Some(root) => {
                     match Node::insert_recurse(
                         root,
-                        &new_kv.key,
-                        new_kv.value,
-                        new_kv.version,
-                        new_kv.ts,
+                        Cow::Owned(new_kv.key),
+                        LeafValue::new(new_kv.value, new_kv.version, new_kv.ts),
                         0,
+                        &self.options,
                     ) {
                         Ok((new_node, _)) => {
                             self.root = Some(new_node);
@@ -1131,6 +3025,8 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
                 }
             }
 
+            self.max_ts.fetch_max(kv.ts, Ordering::SeqCst);
+
             // Update new_version if necessary
             if t > new_version {
                 new_version = t;
@@ -1144,19 +3040,22 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
         // Check if the tree is already closed
         self.is_closed()?;
 
+        let key = self.options.encode_key(Cow::Borrowed(key));
+
+        // Zero-length keys are never stored
+        if key.is_empty() {
+            return Ok(false);
+        }
+
         let (new_root, is_deleted) = match &self.root {
             None => (None, false),
             Some(root) => {
-                if root.is_twig() {
-                    (None, true)
-                } else {
-                    let (new_root, removed) = Node::remove_recurse(root, key, 0);
-                    if removed {
-                        (new_root, true)
-                    } else {
-                        (self.root.clone(), true)
-                    }
-                }
+                let (new_root, removed) = Node::remove_recurse(root, &key, 0, &self.options);
+                // An inner root left without children is dropped
+                (
+                    new_root.filter(|root| root.is_twig() || root.num_children() > 0),
+                    removed,
+                )
             }
         };
 
@@ -1164,951 +3063,6595 @@ impl<P: KeyTrait, V: Clone> Tree<P, V> {
         Ok(is_deleted)
     }
 
-    pub fn get(&self, key: &P, version: u64) -> Result<(P, V, u64, u64), TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    /// Removes a key along with its whole history.
+    ///
+    /// Every version of the key, tombstones included, is detached from the Trie at once, and
+    /// inner nodes left without children are collapsed. Other keys are left untouched, even
+    /// those sharing a prefix with the key. Snapshots taken earlier still see the purged
+    /// versions until they are closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key to purge.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of versions removed, which is 0 if the key was not present.
+    ///
+    pub fn purge_key(&mut self, key: &P) -> Result<usize, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        let Some(root) = &self.root else {
+            return Ok(0);
+        };
+        let (new_root, purged) = Node::update_twig_recurse(root, &key, 0, &self.options, |twig| {
+            Some((None, twig.values.len()))
+        });
+        let Some(purged) = purged else {
+            return Ok(0);
+        };
+        // An inner root left without children is dropped
+        self.root = new_root.filter(|root| root.is_twig() || root.num_children() > 0);
+        Ok(purged)
+    }
+
+    /// Removes several keys from the Trie, returning the value each of them held.
+    ///
+    /// This behaves like calling `remove` for every key, but the keys are sorted first and
+    /// removed in a single pass, so keys sharing a path descend it only once. A key given more
+    /// than once is removed by its first occurrence, and the later ones find it gone.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys`: The keys to remove.
+    ///
+    /// # Returns
+    ///
+    /// Returns the latest value of each key before the removal, in the order of `keys`, or
+    /// `None` for keys that were not present or already deleted.
+    ///
+    pub fn multi_remove(&mut self, keys: &[P]) -> Result<Vec<Option<V>>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let mut removed = vec![None; keys.len()];
+        let Some(root) = &self.root else {
+            return Ok(removed);
+        };
+
+        // Sorting is stable, so the first occurrence of a key is the one kept
+        let encoded: Vec<Cow<'_, P>> = keys
+            .iter()
+            .map(|key| self.options.encode_key(Cow::Borrowed(key)))
+            .collect();
+        let mut sorted: Vec<(&P, usize)> = encoded
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| !key.is_empty())
+            .map(|(slot, key)| (key.as_ref(), slot))
+            .collect();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+        sorted.dedup_by(|a, b| a.0 == b.0);
+
+        let (new_root, changed) =
+            Node::remove_many_recurse(root, &sorted, 0, &self.options, &mut removed);
+        if changed {
+            // An inner root left without children is dropped
+            self.root = new_root.filter(|root| root.is_twig() || root.num_children() > 0);
+        }
+        Ok(removed)
+    }
+
+    /// Replaces every key starting with a prefix by a new set of entries, in one step.
+    ///
+    /// The subtree holding the prefix is detached, along with the whole history of its keys,
+    /// and the new entries are inserted as by `bulk_insert`. Both happen on a copy of the root
+    /// that is only published once complete, so snapshots and iterators taken before keep
+    /// seeing the old keys, and none sees a partial state. This suits reindexing a partition.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix`: The leading bytes of the keys to replace.
+    /// * `new_entries`: The entries to insert, whose keys must all start with `prefix`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of live keys removed and the number of entries inserted, or an `Err`
+    /// leaving the Trie unchanged if a new key does not start with the prefix or cannot be
+    /// inserted.
+    ///
+    pub fn replace_prefix(
+        &mut self,
+        prefix: &[u8],
+        new_entries: &[KV<P, V>],
+    ) -> Result<(usize, usize), TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        if let Some(kv) = new_entries
+            .iter()
+            .find(|kv| !kv.key.as_slice().starts_with(prefix))
+        {
+            return Err(TrieError::Other(format!(
+                "key {:?} does not start with the replaced prefix",
+                kv.key.as_slice()
+            )));
+        }
+
+        let old_root = self.root.clone();
+        let max_ts = self.max_ts.load(Ordering::SeqCst);
+        let mut removed = 0;
+        if let Some(root) = &old_root {
+            let encoded = match &self.options.byte_permutation {
+                Some(permutation) => Cow::Owned(permutation.encode(prefix)),
+                None => Cow::Borrowed(prefix),
+            };
+            // The twigs come in key order, as the removal expects
+            let twigs: Vec<_> = TwigIter::new(Node::find_prefix_node(root, &encoded)).collect();
+            removed = twigs.iter().map(|twig| twig.leaf_count()).sum();
+            let keys: Vec<(&P, usize)> = twigs
+                .iter()
+                .enumerate()
+                .map(|(slot, twig)| (&twig.key, slot))
+                .collect();
+            let mut values = vec![None; keys.len()];
+            if !keys.is_empty() {
+                let (new_root, _) =
+                    Node::remove_many_recurse(root, &keys, 0, &self.options, &mut values);
+                // An inner root left without children is dropped
+                self.root = new_root.filter(|root| root.is_twig() || root.num_children() > 0);
+            }
+        }
+
+        // The new entries get the version following every version held before the removal
+        let version = old_root.as_ref().map_or(0, |root| root.version()) + 1;
+        let entries: Vec<KV<P, V>> = new_entries
+            .iter()
+            .map(|kv| {
+                let kv_version = if kv.version == 0 { version } else { kv.version };
+                KV::new(kv.key.clone(), kv.value.clone(), kv_version, kv.ts)
+            })
+            .collect();
+        if let Err(err) = self.bulk_insert(&entries) {
+            self.root = old_root;
+            self.max_ts.store(max_ts, Ordering::SeqCst);
+            return Err(err);
+        }
+        Ok((removed, new_entries.len()))
+    }
+
+    /// Removes a single version of a key, leaving its other versions intact.
+    ///
+    /// This is meant for correcting bad writes. If the removed version was the only one left,
+    /// the key is removed altogether.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key.
+    /// * `version`: The exact version to remove.
+    ///
+    /// # Returns
+    ///
+    /// Returns the removed value, or `None` if the key holds no such version.
+    ///
+    pub fn remove_version(
+        &mut self,
+        key: &P,
+        version: impl Into<VersionId>,
+    ) -> Result<Option<V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let version = version.into().0;
+
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        let Some(root) = &self.root else {
+            return Ok(None);
+        };
+        let (new_root, removed) = Node::update_twig_recurse(root, &key, 0, &self.options, |twig| {
+            let (new_twig, removed) = twig.remove_version(version)?;
+            // A twig without any version left is detached
+            let new_twig = (!new_twig.values.is_empty()).then_some(new_twig);
+            Some((new_twig, removed))
+        });
+        let Some(removed) = removed else {
+            return Ok(None);
+        };
+
+        // An inner root left without children is dropped
+        self.root = new_root.filter(|root| root.is_twig() || root.num_children() > 0);
+        Ok(Some(removed.value.clone()))
+    }
+
+    /// Deletes a key as of a timestamp, keeping its values readable at earlier timestamps.
+    ///
+    /// A tombstone is added to the key at a new version and the given timestamp. Reads at or
+    /// after `ts` no longer see the key, while reads before it, and reads at older versions,
+    /// still return the values it held then. Since the tombstone is kept next to the value
+    /// rather than in it, this works for any value type. The key can be written again later,
+    /// and the deleted versions are reclaimed by `gc` once no read needs them any more.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key to delete.
+    /// * `ts`: The timestamp of the deletion.
+    ///
+    /// # Returns
+    ///
+    /// Returns the latest value of the key before the deletion, or `None` if the key is not
+    /// present or already deleted.
+    ///
+    pub fn delete_logical(&mut self, key: &P, ts: impl Into<Ts>) -> Result<Option<V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let ts = ts.into().0;
+
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        let Some(root) = &self.root else {
+            return Ok(None);
+        };
+        let version = root.version() + 1;
+        let (new_root, deleted) = Node::update_twig_recurse(root, &key, 0, &self.options, |twig| {
+            let value = twig.get_latest_live_leaf()?.value.clone();
+            Some((Some(twig.delete(version, ts)?), value))
+        });
+        if deleted.is_some() {
+            self.root = new_root;
+            self.max_ts.fetch_max(ts, Ordering::SeqCst);
+        }
+        Ok(deleted)
+    }
+
+    /// Deletes every key in a range as of a timestamp, keeping the deleted values readable at
+    /// earlier timestamps.
+    ///
+    /// Instead of detaching the keys, a tombstone is added to each of them at a single new
+    /// version and the given timestamp, so reads at or after `ts` no longer see the keys while
+    /// reads before it still do. Only the subtrees overlapping the range are visited. The
+    /// deleted versions are reclaimed by `gc` once no read needs them any more.
+    ///
+    /// # Arguments
+    ///
+    /// * `range`: The range of keys to delete.
+    /// * `ts`: The timestamp of the deletion.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of keys deleted.
+    ///
+    pub fn remove_range_at<R>(&mut self, range: R, ts: impl Into<Ts>) -> Result<usize, TrieError>
+    where
+        R: RangeBounds<P>,
+    {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let ts = ts.into().0;
+
+        let Some(root) = self.root.clone() else {
+            return Ok(0);
+        };
+        let mut twigs = Vec::new();
+        match &self.options.byte_permutation {
+            // The keys in the range are scattered over the tree, so every key is tested
+            Some(_) => {
+                twigs.extend(TwigIter::new(Some(&root)).filter(|twig| {
+                    range.contains(&self.options.decode_key(Cow::Borrowed(&twig.key)))
+                }))
+            }
+            None => Node::collect_range_twigs(&root, &mut Vec::new(), &range, &mut twigs),
+        }
+        let keys: Vec<P> = twigs
+            .into_iter()
+            .filter(|twig| twig.get_latest_live_leaf().is_some())
+            .map(|twig| twig.key.clone())
+            .collect();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let version = root.version() + 1;
+        let mut new_root = root;
+        for key in &keys {
+            let (updated, _) =
+                Node::update_twig_recurse(&new_root, key, 0, &self.options, |twig| {
+                    Some((Some(twig.delete(version, ts)?), ()))
+                });
+            new_root = updated.expect("a deleted key keeps its twig");
+        }
+
+        self.root = Some(new_root);
+        self.max_ts.fetch_max(ts, Ordering::SeqCst);
+        Ok(keys.len())
+    }
+
+    /// Reclaims the versions that no read at or after a watermark timestamp can see.
+    ///
+    /// For every key, only the newest version written at or before `watermark` and the
+    /// versions written after it are kept, which leaves every read at or after `watermark`
+    /// unchanged. Keys left with nothing but a tombstone are detached, so keys deleted by
+    /// `remove_range_at` are reclaimed once the watermark passes the deletion.
+    ///
+    /// # Arguments
+    ///
+    /// * `watermark`: The oldest timestamp that reads still need to see.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of versions reclaimed.
+    ///
+    pub fn gc(&mut self, watermark: impl Into<Ts>) -> Result<usize, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let watermark = watermark.into().0;
+
+        Ok(self.rewrite_twigs(|twig| twig.gc(watermark)))
+    }
+
+    /// Runs a bounded step of `gc`, resuming where the previous step stopped.
+    ///
+    /// The twigs are walked in key order from the cursor, reclaiming versions as `gc` does,
+    /// and the walk stops before the twig that would take the step past `budget` reclaimed
+    /// versions. A single twig holding more than `budget` reclaimable versions is still
+    /// reclaimed as a whole, so every step makes progress. Running steps with the same
+    /// watermark until no cursor is returned reclaims what a single `gc` would, except for keys
+    /// written behind the cursor in the meantime.
+    ///
+    /// # Arguments
+    ///
+    /// * `watermark`: The oldest timestamp that reads still need to see.
+    /// * `budget`: The number of versions a step may reclaim.
+    /// * `cursor`: The cursor returned by the previous step, or `None` to start a new walk.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of versions reclaimed, along with the cursor to resume from.
+    ///
+    pub fn gc_step(
+        &mut self,
+        watermark: impl Into<Ts>,
+        budget: usize,
+        cursor: Option<GcCursor>,
+    ) -> Result<GcProgress, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let watermark = watermark.into().0;
+
+        let Some(root) = self.root.clone() else {
+            return Ok(GcProgress {
+                reclaimed: 0,
+                cursor: None,
+            });
+        };
+        let start = match cursor {
+            Some(cursor) => Bound::Included(P::from(cursor.next_key.as_slice())),
+            None => Bound::Unbounded,
+        };
+        let mut updates = Vec::new();
+        let mut pending = 0;
+        let mut next_key = None;
+        Node::visit_range_twigs(
+            &root,
+            &mut Vec::new(),
+            &(start, Bound::Unbounded),
+            &mut |twig| {
+                let Some(new_twig) = twig.gc(watermark) else {
+                    return true;
+                };
+                let reclaimed = twig.values.len() - new_twig.values.len();
+                if pending > 0 && pending + reclaimed > budget {
+                    next_key = Some(twig.key.as_slice().to_vec());
+                    return false;
+                }
+                pending += reclaimed;
+                updates.push((twig.values.len(), new_twig));
+                true
+            },
+        );
+
+        Ok(GcProgress {
+            reclaimed: self.replace_twigs(updates),
+            cursor: next_key.map(|next_key| GcCursor { next_key }),
+        })
+    }
+
+    /// Collapses the history of every key up to a cutoff timestamp.
+    ///
+    /// For every key, only the newest version written at or before `cutoff_ts` and the
+    /// versions written after it are kept, so reads at or after `cutoff_ts` are unchanged
+    /// while reads before it see the collapsed history. Unlike `gc`, a key whose kept version
+    /// is a tombstone keeps it, so no key is detached.
+    ///
+    /// # Arguments
+    ///
+    /// * `cutoff_ts`: The timestamp up to which the history of every key is collapsed.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of versions dropped.
+    ///
+    pub fn compact_versions_before(
+        &mut self,
+        cutoff_ts: impl Into<Ts>,
+    ) -> Result<usize, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let cutoff_ts = cutoff_ts.into().0;
+
+        Ok(self.rewrite_twigs(|twig| twig.compact_before(cutoff_ts)))
+    }
+
+    /// Applies a custom retention policy to the history of every key.
+    ///
+    /// For every key, `f` is called with the key and the live versions of the key as
+    /// `(value, ts)` pairs in version order, and removes the versions that are not to be kept,
+    /// which makes it possible to apply policies such as keeping one sample per hour. The
+    /// versions left in the list are kept, while the values in it are only used to tell the
+    /// versions apart and are not written back. A tombstone is kept as long as an older
+    /// version is left for it to hide, and a key left without any version is removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `f`: A function called with each key and its versions, removing those to drop.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of versions dropped, or an `Err` without changing the Trie if `f` left
+    /// a version in the list that the key does not hold, such as one with a changed timestamp.
+    ///
+    pub fn retain_versions<F>(&mut self, mut f: F) -> Result<usize, TrieError>
+    where
+        F: FnMut(&[u8], &mut Vec<(V, u64)>),
+    {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let mut updates = Vec::new();
+        for twig in TwigIter::new(self.root.as_ref()) {
+            let mut versions: Vec<(V, u64)> = twig
+                .iter()
+                .filter(|leaf| !leaf.is_deleted())
+                .map(|leaf| (leaf.value.clone(), leaf.ts))
+                .collect();
+            let key = match &self.options.byte_permutation {
+                Some(permutation) => permutation.decode(twig.key.as_slice()),
+                None => twig.key.as_slice().to_vec(),
+            };
+            f(&key, &mut versions);
+            let new_twig = twig.retain_live(versions).ok_or_else(|| {
+                TrieError::Other("retained a version the key does not hold".to_string())
+            })?;
+            if new_twig.values.len() < twig.values.len() {
+                updates.push((twig.values.len(), new_twig));
+            }
+        }
+
+        Ok(self.replace_twigs(updates))
+    }
+
+    /// Removes every key whose latest value has expired, see `insert_with_ttl`.
+    ///
+    /// A key is removed along with its whole history once its latest value has expired as of
+    /// `now`. Older versions that expired while a newer value was written are left for `gc`,
+    /// as removing them alone would bring back the values they replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `now`: The timestamp to check expiry against.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of versions reclaimed.
+    ///
+    pub fn purge_expired(&mut self, now: impl Into<Ts>) -> Result<usize, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let now = now.into().0;
+
+        Ok(self.rewrite_twigs(|twig| twig.purge_expired(now)))
+    }
+
+    // Replaces every twig for which `rewrite` returns a new one, detaching twigs left without
+    // any version, and returns the number of versions dropped.
+    fn rewrite_twigs<F>(&mut self, rewrite: F) -> usize
+    where
+        F: Fn(&TwigNode<P, V>) -> Option<TwigNode<P, V>>,
+    {
+        let Some(root) = self.root.clone() else {
+            return 0;
+        };
+        let updates: Vec<_> = TwigIter::new(Some(&root))
+            .filter_map(|twig| rewrite(twig).map(|new_twig| (twig.values.len(), new_twig)))
+            .collect();
+        self.replace_twigs(updates)
+    }
+
+    // Detaches the twigs whose keys, as given to the tree, fail the predicate.
+    fn retain_keys<F>(&mut self, keep: F)
+    where
+        F: Fn(&P) -> bool,
+    {
+        let options = self.options.clone();
+        self.rewrite_twigs(|twig| {
+            let key = options.decode_key(Cow::Borrowed(&twig.key));
+            (!keep(&key)).then(|| TwigNode {
+                prefix: twig.prefix.clone(),
+                key: twig.key.clone(),
+                values: Default::default(),
+                version: twig.version,
+                inline_values: twig.inline_values,
+            })
+        });
+    }
+
+    // Puts the rewritten twigs in place of the ones with the same keys, each paired with the
+    // number of versions the twig held before, and returns the number of versions dropped.
+    fn replace_twigs(&mut self, updates: Vec<(usize, TwigNode<P, V>)>) -> usize {
+        let mut reclaimed = 0;
+        let mut new_root = self.root.clone();
+        for (len, new_twig) in updates {
+            let Some(cur_root) = &new_root else {
+                break;
+            };
+            reclaimed += len - new_twig.values.len();
+            let key = new_twig.key.clone();
+            // A twig without any version left is detached
+            let new_twig = (!new_twig.values.is_empty()).then_some(new_twig);
+            let (updated, _) = Node::update_twig_recurse(cur_root, &key, 0, &self.options, |_| {
+                Some((new_twig, ()))
+            });
+            // An inner root left without children is dropped
+            new_root = updated.filter(|root| root.is_twig() || root.num_children() > 0);
+        }
+
+        self.root = new_root;
+        reclaimed
+    }
+
+    pub fn get(
+        &self,
+        key: &P,
+        version: impl Into<VersionId>,
+    ) -> Result<(P, V, u64, u64), TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let version = version.into().0;
+
+        let key = self.options.encode_key(Cow::Borrowed(key));
+
+        if self.root.is_none() {
+            return Err(TrieError::Other("cannot read from empty tree".to_string()));
+        }
+
+        // Keys the filter has never seen are absent without descending the tree
+        #[cfg(feature = "bloom")]
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key.as_slice()) {
+                return Err(TrieError::KeyNotFound);
+            }
+        }
+
+        let root = self.root.as_ref().unwrap();
+        let mut commit_version = version;
+        // Without history each key only holds its latest value, which is returned for any version
+        if commit_version == 0 || !self.options.keep_history {
+            commit_version = root.version();
+        }
+
+        let (key, value, version, ts) = Node::get_recurse(root, &key, commit_version)?;
+        let key = match &self.options.byte_permutation {
+            Some(permutation) => P::from(permutation.decode(key.as_slice()).as_slice()),
+            None => key,
+        };
+        Ok((key, value, version, ts))
+    }
+
+    /// Retrieves the value for a key along with a trace of the lookup.
+    ///
+    /// Performs the same descent as `get` while recording every node visited, which helps to
+    /// find where a lookup that unexpectedly misses diverged from the expected path. The key
+    /// is translated like for `get`, while the steps show the nodes as stored, so under a byte
+    /// permutation their bytes are the substituted ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to look up.
+    /// * `version` - The version to read at, or `0` to read the latest value.
+    ///
+    /// # Returns
+    ///
+    /// Returns the value, if found, and the steps taken from the root, or an `Err` if the Trie
+    /// is closed.
+    ///
+    #[cfg(feature = "trace")]
+    pub fn get_with_trace(
+        &self,
+        key: &P,
+        version: impl Into<VersionId>,
+    ) -> Result<(Option<V>, Vec<TraceStep>), TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let version = version.into().0;
+
+        let mut steps = Vec::new();
+        let Some(root) = self.root.as_ref() else {
+            return Ok((None, steps));
+        };
+        let version = if version == 0 {
+            root.version()
+        } else {
+            version
+        };
+
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        let twig = Node::find_twig_with(root, &key, |node, matched, byte, child_found| {
+            steps.push(TraceStep {
+                kind: node.node_kind(),
+                matched: node.prefix().as_slice()[..matched].to_vec(),
+                byte,
+                child_found,
+            });
+        });
+        let value = twig
+            .and_then(|twig| twig.get_leaf_by_version(version))
+            .map(|leaf| leaf.value.clone());
+        Ok((value, steps))
+    }
+
+    /// Measures how many nodes a lookup visits on average.
+    ///
+    /// Every sample key is looked up with `get_with_trace` and the lengths of the traces are
+    /// averaged, counting the twig a successful lookup ends at. Path compression keeps this
+    /// close to the logarithm of the number of keys, so an average far above it points at a
+    /// key distribution that degenerates the Trie into long chains of nodes.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_keys` - The keys to look up, which need not be present.
+    ///
+    /// # Returns
+    ///
+    /// Returns the average number of nodes visited, or `0.0` if there are no sample keys, or an
+    /// `Err` if the Trie is closed.
+    ///
+    #[cfg(feature = "trace")]
+    pub fn avg_get_depth(&self, sample_keys: &[P]) -> Result<f64, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        if sample_keys.is_empty() {
+            return Ok(0.0);
+        }
+        let mut total = 0;
+        for key in sample_keys {
+            total += self.get_with_trace(key, 0)?.1.len();
+        }
+        Ok(total as f64 / sample_keys.len() as f64)
+    }
+
+    /// Retrieves the values of several keys as of a single version.
+    ///
+    /// The root is pinned once and every key is looked up against that same root, so the
+    /// values form a consistent view of the Trie even if it is modified afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys` - The keys to look up.
+    /// * `version` - The version to read at, or `0` to read the latest values.
+    ///
+    /// # Returns
+    ///
+    /// Returns the value of each key, in the order of `keys`, or `None` for keys that have no
+    /// value at the given version.
+    ///
+    pub fn get_all_at_ts(
+        &self,
+        keys: &[P],
+        version: impl Into<VersionId>,
+    ) -> Result<Vec<Option<V>>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let version = version.into().0;
+
+        let Some(root) = self.root.clone() else {
+            return Ok(vec![None; keys.len()]);
+        };
+        let version = if version == 0 {
+            root.version()
+        } else {
+            version
+        };
+
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let key = self.options.encode_key(Cow::Borrowed(key));
+                Node::get_recurse(&root, &key, version)
+                    .ok()
+                    .map(|(_, value, _, _)| value)
+            })
+            .collect())
+    }
+
+    /// Retrieves the values of a single key as of several timestamps.
+    ///
+    /// The key is looked up once, and each timestamp selects the newest value written at or
+    /// before it, as `snapshot_values_at` does for the whole Trie.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to look up.
+    /// * `ts_list` - The timestamps to read at.
+    ///
+    /// # Returns
+    ///
+    /// Returns the value at each timestamp, in the order of `ts_list`, or `None` for timestamps
+    /// before the key was first written or if the key is not present.
+    ///
+    pub fn get_at_timestamps(&self, key: &P, ts_list: &[u64]) -> Vec<Option<V>> {
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        let Some(twig) = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_twig(root, &key))
+        else {
+            return vec![None; ts_list.len()];
+        };
+        twig.get_leafs_by_ts(ts_list)
+            .into_iter()
+            .map(|leaf| leaf.map(|leaf| leaf.value.clone()))
+            .collect()
+    }
+
+    /// Retrieves a reference to the value of a key as of a timestamp, without cloning it.
+    ///
+    /// The newest value written at or before `ts` is returned, borrowed from the twig that
+    /// stores it, which saves copying large values. The borrow ends before the Trie can be
+    /// written to again.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to look up.
+    /// * `ts` - The timestamp to read at.
+    ///
+    /// # Returns
+    ///
+    /// Returns the value, or `None` if the key is not present or deleted as of `ts`.
+    ///
+    pub fn get_ref(&self, key: &P, ts: impl Into<Ts>) -> Option<&V> {
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        #[cfg(feature = "bloom")]
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key.as_slice()) {
+                return None;
+            }
+        }
+        let twig = Node::find_twig(self.root.as_ref()?, &key)?;
+        twig.get_leaf_by_ts(ts.into().0).map(|leaf| &leaf.value)
+    }
+
+    /// Retrieves the latest value of a key unless it has expired by the given time.
+    ///
+    /// This is the read path for keys written with `insert_with_ttl`: the latest version is
+    /// returned as by `get` with version `0`, but a value whose expiry is at or before `now`
+    /// reads as absent, like a tombstone.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to look up.
+    /// * `now` - The current time, compared against the expiry of the value.
+    ///
+    /// # Returns
+    ///
+    /// Returns the value, or `None` if the key is not present, deleted or expired as of `now`.
+    ///
+    pub fn get_live(&self, key: &P, now: impl Into<Ts>) -> Option<V> {
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        #[cfg(feature = "bloom")]
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key.as_slice()) {
+                return None;
+            }
+        }
+        let twig = Node::find_twig(self.root.as_ref()?, &key)?;
+        twig.get_latest_live_leaf()
+            .filter(|leaf| !leaf.is_expired(now.into().0))
+            .map(|leaf| leaf.value.clone())
+    }
+
+    /// Returns the value of every key as it stood at the given timestamp, in key order.
+    ///
+    /// Unlike `iter`, which always yields the latest value, each key yields its newest value
+    /// written at or before `ts`. Keys first written after `ts` are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `ts` - The timestamp to read at.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator yielding the bytes of each key and its value at `ts`.
+    ///
+    pub fn snapshot_values_at(&self, ts: impl Into<Ts>) -> impl Iterator<Item = (Vec<u8>, V)> + '_ {
+        let ts = ts.into().0;
+        TwigIter::new(self.root.as_ref()).filter_map(move |twig| {
+            twig.get_leaf_by_ts(ts).map(|leaf| {
+                let key = self.options.decode_bytes(twig.key.as_slice());
+                (key, leaf.value.clone())
+            })
+        })
+    }
+
+    /// Returns an iterator over the keys whose latest value satisfies a predicate, in key order.
+    ///
+    /// This is a full scan, but the values are borrowed from the Trie and tested as they are
+    /// reached, so filtering needs no intermediate collection. Deleted keys are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `pred` - The predicate the latest value of a key must satisfy.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator yielding the bytes of each matching key and its latest value.
+    ///
+    pub fn find<'a, F>(&'a self, pred: F) -> impl Iterator<Item = (Vec<u8>, &'a V)> + 'a
+    where
+        F: FnMut(&V) -> bool + 'a,
+    {
+        self.find_with(move |twig| twig.get_latest_live_leaf(), pred)
+    }
+
+    /// Returns an iterator over the keys whose value as of a timestamp satisfies a predicate.
+    ///
+    /// This works like `find`, but tests the newest value of each key written at or before
+    /// `ts`, so it filters the Trie as it stood at that point in time.
+    ///
+    /// # Arguments
+    ///
+    /// * `ts` - The timestamp to read at.
+    /// * `pred` - The predicate the value of a key as of `ts` must satisfy.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator yielding the bytes of each matching key and its value at `ts`.
+    ///
+    pub fn find_at<'a, F>(
+        &'a self,
+        ts: impl Into<Ts>,
+        pred: F,
+    ) -> impl Iterator<Item = (Vec<u8>, &'a V)> + 'a
+    where
+        F: FnMut(&V) -> bool + 'a,
+    {
+        let ts = ts.into().0;
+        self.find_with(move |twig| twig.get_leaf_by_ts(ts), pred)
+    }
+
+    // Yields the keys whose leaf picked by `leaf` satisfies the predicate, with the keys decoded.
+    fn find_with<'a, L, F>(
+        &'a self,
+        leaf: L,
+        mut pred: F,
+    ) -> impl Iterator<Item = (Vec<u8>, &'a V)> + 'a
+    where
+        L: Fn(&'a TwigNode<P, V>) -> Option<&'a LeafValue<V>> + 'a,
+        F: FnMut(&V) -> bool + 'a,
+    {
+        TwigIter::new(self.root.as_ref()).filter_map(move |twig| {
+            let value = &leaf(twig)?.value;
+            if !pred(value) {
+                return None;
+            }
+            let key = match &self.options.byte_permutation {
+                Some(permutation) => permutation.decode(twig.key.as_slice()),
+                None => twig.key.as_slice().to_vec(),
+            };
+            Some((key, value))
+        })
+    }
+
+    /// Rebuilds the Bloom filter from the keys currently in the Trie.
+    ///
+    /// The filter cannot forget removed keys, so after many removals it reports more and more
+    /// absent keys as possibly present. Rebuilding clears those stale entries. This is a no-op
+    /// if the filter is disabled.
+    ///
+    #[cfg(feature = "bloom")]
+    pub fn rebuild_bloom_filter(&mut self) {
+        let Some(mut bloom) = self.bloom.take() else {
+            return;
+        };
+        bloom.clear();
+        for key in self.keys() {
+            bloom.insert(&key);
+        }
+        self.bloom = Some(bloom);
+    }
+
+    /// Retrieves the timestamp of the latest version of a key.
+    ///
+    /// This function descends to the key's twig and reads the timestamp of its most recent
+    /// version without cloning the value, which makes it cheaper than `get` for large values.
+    /// As timestamps need not follow the version order, this is the timestamp of the highest
+    /// version rather than the largest timestamp stored for the key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to look up.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Some(ts)` if the key exists, or `None` if it is absent or the tree is closed.
+    ///
+    pub fn latest_ts(&self, key: &P) -> Option<u64> {
+        self.is_closed().ok()?;
+
+        let root = self.root.as_ref()?;
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        Node::find_twig(root, &key).map(|twig| twig.latest_version_ts())
+    }
+
+    /// Retrieves the latest version of a key.
+    ///
+    /// Every write of a key, deletions included, gets a version greater than any before it,
+    /// so unlike the timestamp, the version tells two writes with the same timestamp apart.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to look up.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Some(version)` if the key exists, or `None` if it is absent or the tree is
+    /// closed.
+    ///
+    pub fn latest_version(&self, key: &P) -> Option<VersionId> {
+        self.is_closed().ok()?;
+
+        let root = self.root.as_ref()?;
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        Node::find_twig(root, &key).map(|twig| VersionId(twig.version()))
+    }
+
+    /// Checks that no key of a read set was written since it was read.
+    ///
+    /// Every read is recorded as the key along with what `latest_ts` returned for it, and the
+    /// set is valid if the latest timestamp of every key is still the recorded one. Any newer
+    /// version of a key, including a deletion, invalidates the set, as does the first write of
+    /// a key recorded as absent. This is the conflict check of an optimistic transaction.
+    ///
+    /// # Arguments
+    ///
+    /// * `reads`: The keys read, each with its latest timestamp at the time of the read.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if every key still has the recorded latest timestamp, and `false` if any
+    /// key changed or the tree is closed.
+    ///
+    pub fn validate_unchanged(&self, reads: &[(P, Option<u64>)]) -> bool {
+        self.is_closed().is_ok() && reads.iter().all(|(key, ts)| self.latest_ts(key) == *ts)
+    }
+
+    /// Checks that no key of a read set was written since it was read, by version.
+    ///
+    /// This is `validate_unchanged` with every read recorded along with what `latest_version`
+    /// returned for it. Writes stamped with the timestamp of the version they replace, which
+    /// `validate_unchanged` cannot see, still invalidate the set, as every write gets a new
+    /// version.
+    ///
+    /// # Arguments
+    ///
+    /// * `reads`: The keys read, each with its latest version at the time of the read.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if every key still has the recorded latest version, and `false` if any
+    /// key changed or the tree is closed.
+    ///
+    pub fn validate_versions_unchanged(&self, reads: &[(P, Option<VersionId>)]) -> bool {
+        self.is_closed().is_ok()
+            && reads
+                .iter()
+                .all(|(key, version)| self.latest_version(key) == *version)
+    }
+
+    /// Returns the smallest and largest timestamp stored anywhere in the Trie.
+    ///
+    /// Every stored version counts, older versions and tombstones included, so the range
+    /// covers what `get_at_timestamps` and similar reads can tell apart. Inner nodes track
+    /// version bounds but not timestamps, as timestamps need not follow the version order, so
+    /// this walks every twig.
+    ///
+    /// # Returns
+    ///
+    /// Returns the smallest and largest timestamp, or `None` if the Trie is empty.
+    ///
+    pub fn ts_range(&self) -> Option<(u64, u64)> {
+        TwigIter::new(self.root.as_ref())
+            .flat_map(|twig| twig.iter())
+            .fold(None, |range, leaf| match range {
+                None => Some((leaf.ts, leaf.ts)),
+                Some((min, max)) => Some((min.min(leaf.ts), max.max(leaf.ts))),
+            })
+    }
+
+    /// Counts the versions stored for a key.
+    ///
+    /// Every version kept in the key's history counts, including tombstones, which makes this a
+    /// measure of how much history `gc` could reclaim for the key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to look up.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of versions stored for the key, or `0` if it is absent.
+    ///
+    pub fn version_count(&self, key: &P) -> usize {
+        let Some(root) = self.root.as_ref() else {
+            return 0;
+        };
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        Node::find_twig(root, &key).map_or(0, |twig| twig.values.len())
+    }
+
+    /// Splits the Trie into two at the given key.
+    ///
+    /// This function moves every key greater than or equal to `key`, along with its full version
+    /// history, into a newly returned Trie and leaves the smaller keys in `self`. Subtrees that
+    /// lie entirely on one side of the split are shared between the two Tries.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key at which to split.
+    ///
+    /// # Returns
+    ///
+    /// Returns a new `Tree` containing the keys greater than or equal to `key`.
+    ///
+    pub fn split_off(&mut self, key: &P) -> Result<Tree<P, V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let mut other = Tree::with_options(self.options.clone());
+        other.max_active_snapshots = self.max_active_snapshots;
+        other.max_ts = AtomicU64::new(self.max_ts.load(Ordering::SeqCst));
+
+        if self.options.byte_permutation.is_some() {
+            // The keys on either side of the split are scattered over the tree, so each side
+            // detaches the keys of the other
+            other.root = self.root.clone();
+            other.retain_keys(|k| k >= key);
+            self.retain_keys(|k| k < key);
+        } else if let Some(root) = &self.root {
+            let (left, right) = Node::split_recurse(root, key, 0, &self.options);
+            self.root = left;
+            other.root = right;
+        }
+
+        // The new tree starts with an empty filter, so fill it from the keys it received
+        #[cfg(feature = "bloom")]
+        other.rebuild_bloom_filter();
+
+        Ok(other)
+    }
+
+    /// Copies the keys in a range, with their full history, into a new Trie.
+    ///
+    /// The source Trie is left untouched. Subtrees whose keys all lie inside the range are
+    /// shared between the two Tries rather than copied, so cloning a large contiguous range only
+    /// copies the nodes along its two bounds.
+    ///
+    /// # Arguments
+    ///
+    /// * `range`: The range of keys to copy.
+    ///
+    /// # Returns
+    ///
+    /// Returns a new `Tree` containing the keys in the range.
+    ///
+    pub fn clone_range<R>(&self, range: R) -> Tree<P, V>
+    where
+        R: RangeBounds<P>,
+    {
+        let mut other = Tree::with_options(self.options.clone());
+        other.max_active_snapshots = self.max_active_snapshots;
+        other.max_ts = AtomicU64::new(self.max_ts.load(Ordering::SeqCst));
+        if self.options.byte_permutation.is_some() {
+            // The keys in the range are scattered over the tree, so the others are detached
+            other.root = self.root.clone();
+            other.retain_keys(|key| range.contains(key));
+        } else {
+            other.root = self.root.as_ref().and_then(|root| {
+                Node::clone_range_recurse(root, &mut Vec::new(), &range, &self.options)
+            });
+        }
+
+        // The new tree starts with an empty filter, so fill it from the keys it received
+        #[cfg(feature = "bloom")]
+        other.rebuild_bloom_filter();
+
+        other
+    }
+
+    /// Retrieves the latest version of the Trie.
+    ///
+    /// This function returns the version of the latest version of the Trie. If the Trie is empty,
+    /// it returns `0`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the version of the latest version of the Trie, or `0` if the Trie is empty.
+    ///
+    pub fn version(&self) -> u64 {
+        match &self.root {
+            None => 0,
+            Some(root) => root.version(),
+        }
+    }
+
+    /// Returns the number of live keys in the Trie.
+    ///
+    /// Inner nodes cache the number of live keys below them, so this takes constant time.
+    /// Keys whose latest version is a tombstone are not counted.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of keys the Trie holds a value for.
+    ///
+    pub fn len(&self) -> usize {
+        self.root.as_ref().map_or(0, |root| root.leaf_count())
+    }
+
+    /// Checks whether the Trie holds no live keys.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if `len` is `0`.
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimates the number of keys in the Trie without a full scan.
+    ///
+    /// This function samples a fixed number of root-to-leaf paths and extrapolates the key count
+    /// from the fan-out of the nodes along each path. The result is approximate: it is exact for
+    /// perfectly balanced tries and degrades as subtrees become skewed, so it is intended for
+    /// decisions such as choosing between an index scan and a full scan.
+    ///
+    /// # Returns
+    ///
+    /// Returns the estimated number of keys, or `0` if the Trie is empty.
+    ///
+    pub fn estimate_len(&self) -> usize {
+        let Some(root) = &self.root else {
+            return 0;
+        };
+
+        let total: f64 = (0..ESTIMATE_SAMPLE_WALKS)
+            .map(|seed| Node::estimate_walk(root, seed))
+            .sum();
+        (total / ESTIMATE_SAMPLE_WALKS as f64).round() as usize
+    }
+
+    /// Counts the keys in the given range.
+    ///
+    /// Every inner node caches the number of live keys below it, so subtrees that lie entirely
+    /// inside the range are counted without being visited. The cost is proportional to the depth
+    /// of the Trie and the fan-out along the two bounds, rather than to the number of keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `range`: The range of keys to count.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of keys in the range, the same as `self.range(range).count()`.
+    ///
+    pub fn range_count<R>(&self, range: R) -> usize
+    where
+        R: RangeBounds<P>,
+    {
+        if self.options.byte_permutation.is_some() {
+            return self.range(range).count();
+        }
+        self.root.as_ref().map_or(0, |root| {
+            Node::range_count_recurse(root, &mut Vec::new(), &range, usize::MAX)
+        })
+    }
+
+    /// Estimates the number of keys in the given range.
+    ///
+    /// This works like `range_count`, but descends only a couple of inner node levels along
+    /// the bounds. A subtree that is still only partly inside the range at that depth is not
+    /// descended, and counts for half its cached key count. At most one such subtree lies on
+    /// each bound, so the estimate is off by no more than half the keys of those two subtrees,
+    /// which on evenly spread keys is a small fraction of a large range. This suits query
+    /// planners needing the order of magnitude of a range rather than its exact size.
+    ///
+    /// # Arguments
+    ///
+    /// * `range`: The range of keys to count.
+    ///
+    /// # Returns
+    ///
+    /// Returns the estimated number of keys in the range.
+    ///
+    pub fn approx_range_count<R>(&self, range: R) -> usize
+    where
+        R: RangeBounds<P>,
+    {
+        if self.options.byte_permutation.is_some() {
+            return self.range(range).count();
+        }
+        self.root.as_ref().map_or(0, |root| {
+            Node::range_count_recurse(root, &mut Vec::new(), &range, APPROX_COUNT_LEVELS)
+        })
+    }
+
+    /// Finds the key at the given rank.
+    ///
+    /// Keys are ranked in ascending order starting from `0`, so `select(0)` is the smallest key
+    /// and `select(n)` is the key `iter().nth(n)` would return. The search skips whole subtrees
+    /// using their cached key counts.
+    ///
+    /// # Arguments
+    ///
+    /// * `rank`: The zero-based rank of the key.
+    ///
+    /// # Returns
+    ///
+    /// Returns the key with its latest value, version and timestamp, or `None` if the Trie holds
+    /// `rank` keys or fewer.
+    ///
+    pub fn select(&self, rank: usize) -> Option<(P, V, u64, u64)> {
+        let twig = Node::select_twig(self.root.as_ref()?, rank)?;
+        let leaf = twig.get_latest_live_leaf()?;
+        let key = self.options.decode_key(Cow::Borrowed(&twig.key));
+        Some((key.into_owned(), leaf.value.clone(), leaf.version, leaf.ts))
+    }
+
+    /// Folds over the keys starting with the given prefix.
+    ///
+    /// The Trie is descended straight to the subtree holding the prefix, and only the keys in
+    /// that subtree are visited, in key order. No iterator or key is allocated, unless a byte
+    /// permutation has to be undone on the keys, which makes this suitable for aggregations
+    /// such as the total size of the values stored under a tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix`: The leading bytes of the keys to visit.
+    /// * `init`: The initial value of the accumulator.
+    /// * `f`: A function called with the accumulator, and the key, latest value and timestamp of
+    ///   each key, returning the new accumulator.
+    ///
+    /// # Returns
+    ///
+    /// Returns the final accumulator, or `init` if no key starts with the prefix.
+    ///
+    pub fn fold_prefix<B, F>(&self, prefix: &[u8], init: B, mut f: F) -> B
+    where
+        F: FnMut(B, &[u8], &V, u64) -> B,
+    {
+        let permutation = self.options.byte_permutation.as_deref();
+        let prefix = match permutation {
+            Some(permutation) => Cow::Owned(permutation.encode(prefix)),
+            None => Cow::Borrowed(prefix),
+        };
+        let subtree = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_prefix_node(root, &prefix));
+        TwigIter::new(subtree).fold(init, |acc, twig| match twig.get_latest_live_leaf() {
+            Some(leaf) => match permutation {
+                Some(permutation) => f(
+                    acc,
+                    &permutation.decode(twig.key.as_slice()),
+                    &leaf.value,
+                    leaf.ts,
+                ),
+                None => f(acc, twig.key.as_slice(), &leaf.value, leaf.ts),
+            },
+            None => acc,
+        })
+    }
+
+    /// Returns how far the given key follows the structure of the Trie.
+    ///
+    /// The Trie is descended along the key until a node diverges from it or no child matches
+    /// its next byte, and the number of leading key bytes matched on the way is returned. A
+    /// stored key matches in full, and so does every prefix of a stored key. This suits
+    /// features such as autocompletion, where it tells how much of a query is known to the
+    /// Trie, and sizing prefix scans.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key to match against the Trie.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of leading bytes of the key found on a path in the Trie, or 0 if the
+    /// Trie is empty.
+    ///
+    pub fn common_prefix_len(&self, key: &P) -> usize {
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        self.root
+            .as_ref()
+            .map_or(0, |root| Node::common_prefix_len(root, key.as_slice()))
+    }
+
+    /// Returns the smallest and largest live key starting with the given prefix.
+    ///
+    /// The Trie is descended straight to the subtree holding the prefix, and from there to its
+    /// first and last live key, skipping subtrees without any live key using their cached key
+    /// counts. This gives the endpoints of a partition of keys, for instance to set up scans
+    /// over part of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix`: The leading bytes of the keys.
+    ///
+    /// # Returns
+    ///
+    /// Returns the smallest and largest key, which are the same if only one key starts with
+    /// the prefix, or `None` if no live key does.
+    ///
+    pub fn prefix_bounds(&self, prefix: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let prefix = match &self.options.byte_permutation {
+            Some(permutation) => Cow::Owned(permutation.encode(prefix)),
+            None => Cow::Borrowed(prefix),
+        };
+        let subtree = Node::find_prefix_node(self.root.as_ref()?, &prefix)?;
+        let count = subtree.leaf_count();
+        let first = Node::select_twig(subtree, 0)?;
+        let last = Node::select_twig(subtree, count - 1)?;
+        let decode = |twig: &TwigNode<P, V>| match &self.options.byte_permutation {
+            Some(permutation) => permutation.decode(twig.key.as_slice()),
+            None => twig.key.as_slice().to_vec(),
+        };
+        Some((decode(first), decode(last)))
+    }
+
+    /// Returns the key-value pair at the given in-order position.
+    ///
+    /// Unlike `iter().nth(index)`, this descends straight to the key, skipping whole subtrees
+    /// using their cached key counts, which makes it suitable for paginating by offset.
+    ///
+    /// # Arguments
+    ///
+    /// * `index`: The zero-based position of the key.
+    ///
+    /// # Returns
+    ///
+    /// Returns the key and its latest value, or `None` if the Trie holds `index` keys or fewer.
+    ///
+    pub fn nth(&self, index: usize) -> Option<(Vec<u8>, &V)> {
+        let twig = Node::select_twig(self.root.as_ref()?, index)?;
+        let leaf = twig.get_latest_live_leaf()?;
+        Some((self.options.decode_bytes(twig.key.as_slice()), &leaf.value))
+    }
+
+    /// Returns the in-order position of a key.
+    ///
+    /// This is the inverse of `nth`: for a key in the Trie, `nth(rank(key))` returns that key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key to look up.
+    ///
+    /// # Returns
+    ///
+    /// Returns the zero-based position of the key, or `None` if the key is not in the Trie.
+    ///
+    pub fn rank(&self, key: &P) -> Option<usize> {
+        let root = self.root.as_ref()?;
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        Node::find_twig(root, &key)?.get_latest_live_leaf()?;
+        let before = (Bound::Unbounded, Bound::Excluded(&*key));
+        Some(Node::range_count_recurse(
+            root,
+            &mut Vec::new(),
+            &before,
+            usize::MAX,
+        ))
+    }
+
+    /// Estimates the memory held by the Trie.
+    ///
+    /// The estimate covers the nodes, their backing stores and the versions stored in the twigs,
+    /// but not any heap data owned by the keys or values themselves. Nodes shared with snapshots
+    /// are counted as well, as they are part of this Trie too.
+    ///
+    /// # Returns
+    ///
+    /// Returns the estimated number of bytes, or `0` if the Trie is empty.
+    ///
+    pub fn estimate_memory(&self) -> usize {
+        self.root.as_deref().map_or(0, Node::estimate_memory)
+    }
+
+    /// Shrinks the Trie to fit its current contents.
+    ///
+    /// After a burst of inserts followed by removals, nodes and their backing stores may stay
+    /// sized for the peak. This rebuilds every inner node in the smallest node type that holds
+    /// its children, trims the backing stores of Node48 and Node256 nodes and the version lists
+    /// of the twigs. Parts of the Trie that are already tight are left untouched, and remain
+    /// shared with any snapshot.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of bytes reclaimed, according to `estimate_memory`.
+    ///
+    pub fn shrink_to_fit(&mut self) -> Result<usize, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let before = self.estimate_memory();
+        self.root = self
+            .root
+            .as_ref()
+            .map(|root| Node::shrink_to_fit(root, &self.options));
+        Ok(before.saturating_sub(self.estimate_memory()))
+    }
+
+    /// Creates a new snapshot of the Trie.
+    ///
+    /// This function creates a snapshot of the current state of the Trie. If successful, it returns
+    /// a `Snapshot` that can be used to interact with the newly created snapshot.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the `Snapshot` if the snapshot is created successfully,
+    /// or an `Err` with an appropriate error message if creation fails.
+    ///
+    pub fn create_snapshot(&mut self) -> Result<Snapshot<P, V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        if self.snapshots.len() >= self.max_active_snapshots as usize {
+            return Err(TrieError::Other(
+                "max number of snapshots reached".to_string(),
+            ));
+        }
+
+        // Increment the snapshot ID atomically
+        let new_snapshot_id = self.max_snapshot_id.fetch_add(1, Ordering::SeqCst);
+        self.snapshots.insert(new_snapshot_id);
+
+        let root = self.root.as_ref().cloned();
+        let version = self.root.as_ref().map_or(1, |root| root.version() + 1);
+        let new_snapshot = Snapshot::new(new_snapshot_id, root, version, self.options.clone());
+
+        Ok(new_snapshot)
+    }
+
+    /// Closes a snapshot and removes it from the list of active snapshots.
+    ///
+    /// This function takes a `snapshot_id` as an argument and closes the corresponding snapshot.
+    /// If the snapshot exists, it is removed from the active snapshots list. If the snapshot is not
+    /// found, an `Err` is returned with a `TrieError::SnapshotNotFound` variant.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot_id` - The ID of the snapshot to be closed and removed.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the snapshot is successfully closed and removed. Returns an `Err`
+    /// with `TrieError::SnapshotNotFound` if the snapshot with the given ID is not found.
+    ///
+    #[allow(dead_code)]
+    pub(crate) fn close_snapshot(&mut self, snapshot_id: u64) -> Result<(), TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        if self.snapshots.remove(&snapshot_id) {
+            Ok(())
+        } else {
+            Err(TrieError::SnapshotNotFound)
+        }
+    }
+
+    /// Returns the count of active snapshots.
+    ///
+    /// This function returns the number of currently active snapshots in the Trie.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the count of active snapshots if successful, or an `Err`
+    /// if there is an issue retrieving the snapshot count.
+    ///
+    pub fn snapshot_count(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Measures how much structure the Trie shares with a snapshot.
+    ///
+    /// Both roots are walked together, comparing nodes by pointer. A node that is the same
+    /// allocation in both is shared, along with its whole subtree; a node that was copied on
+    /// write since the snapshot was taken is unique to the Trie.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The snapshot to compare with.
+    ///
+    /// # Returns
+    ///
+    /// Returns a tuple of the number of the Trie's nodes that are shared with the snapshot and
+    /// the number that are unique to the Trie.
+    ///
+    pub fn shared_node_count(&self, other: &Snapshot<P, V>) -> (usize, usize) {
+        match &self.root {
+            None => (0, 0),
+            Some(root) => Node::count_shared(root, other.root.as_ref()),
+        }
+    }
+
+    /// Creates an iterator over the Trie's key-value pairs.
+    ///
+    /// This function creates and returns an iterator that can be used to traverse the key-value pairs
+    /// stored in the Trie. The iterator starts from the root of the Trie.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `Iter` instance that iterates over the key-value pairs in the Trie.
+    ///
+    pub fn iter(&self) -> Iter<'_, P, V> {
+        Iter::new(self.root.as_ref()).decode_keys(self.options.byte_permutation.clone())
+    }
+
+    /// Creates an iterator over the Trie's key-value pairs that does not borrow the Trie.
+    ///
+    /// The iterator pins the current root, so it keeps yielding the key-value pairs as they
+    /// were when it was created, however the Trie is written to in the meantime. Values are
+    /// cloned as they are yielded.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `OwnedIter` yielding the key, latest value and version of each key.
+    ///
+    pub fn iter_pinned(&self) -> OwnedIter<P, V> {
+        OwnedIter::new(self.root.clone(), self.options.byte_permutation.clone())
+    }
+
+    /// Calls a closure on every key-value pair of the Trie, in key order.
+    ///
+    /// Unlike `iter`, which copies every key into a new `Vec`, the closure is handed the key
+    /// as stored in its twig, so a scan allocates nothing per entry, unless a byte permutation
+    /// has to be undone on the keys. This suits aggregations over many keys that do not need
+    /// to keep them.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The closure called with the key, latest value and timestamp of each entry.
+    ///
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&[u8], &V, u64),
+    {
+        for twig in TwigIter::new(self.root.as_ref()) {
+            if let Some(leaf) = twig.get_latest_live_leaf() {
+                match &self.options.byte_permutation {
+                    Some(permutation) => f(
+                        &permutation.decode(twig.key.as_slice()),
+                        &leaf.value,
+                        leaf.ts,
+                    ),
+                    None => f(twig.key.as_slice(), &leaf.value, leaf.ts),
+                }
+            }
+        }
+    }
+
+    /// Creates an iterator over the Trie's key-value pairs starting at a key.
+    ///
+    /// The iteration starts at the first key greater than or equal to `key` and runs to the
+    /// end of the Trie. The descent seeks straight to the key, so none of the smaller keys are
+    /// visited.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to start at.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `Iter` instance over the key-value pairs from `key` onward.
+    ///
+    pub fn iter_from(&self, key: &P) -> Iter<'_, P, V> {
+        let key = self.options.encode_key(Cow::Borrowed(key));
+        Iter::from_key(self.root.as_ref(), &key).decode_keys(self.options.byte_permutation.clone())
+    }
+
+    /// Returns an iterator over the smallest key of each group of keys sharing the same first
+    /// `prefix_len` bytes, in key order.
+    ///
+    /// Once a group's first key has been yielded, the subtrees holding the rest of the group
+    /// are skipped rather than walked. Keys shorter than `prefix_len` form their own group.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix_len` - The length of the prefix keys are grouped by.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `DistinctPrefix` iterator yielding one key-value pair per group.
+    ///
+    pub fn iter_distinct_prefix(&self, prefix_len: usize) -> DistinctPrefix<'_, P, V> {
+        DistinctPrefix::new(self.root.as_ref(), prefix_len)
+            .decode_keys(self.options.byte_permutation.clone())
+    }
+
+    /// Returns the most recent writes across all keys.
+    ///
+    /// Writes are ranked by their commit version, which every node tracks as the newest version
+    /// stored below it. The Trie is traversed best-first, always descending into the subtree
+    /// holding the newest write not yet collected, so only the paths leading to the returned
+    /// writes and their neighbours are visited. Older versions of a key count as writes of
+    /// their own, while tombstones are left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The maximum number of writes to return.
+    ///
+    /// # Returns
+    ///
+    /// Returns the key, value and timestamp of up to `n` writes, newest first.
+    ///
+    pub fn recent(&self, n: usize) -> Vec<(Vec<u8>, V, u64)> {
+        // The heap holds nodes to descend into and leaves to return, each keyed on the newest
+        // version it holds. Leaves are keyed on their own version, which no node below which
+        // they are found can be older than.
+        enum Entry<'a, P: KeyTrait, V: Clone> {
+            Node(&'a Node<P, V>),
+            Leaf(&'a TwigNode<P, V>, &'a LeafValue<V>),
+        }
+
+        let mut entries = Vec::new();
+        let mut heap = BinaryHeap::new();
+        if let Some(root) = &self.root {
+            heap.push((root.version(), 0));
+            entries.push(Entry::Node(root));
+        }
+
+        let mut recent = Vec::with_capacity(n.min(64));
+        while recent.len() < n {
+            let Some((_, idx)) = heap.pop() else {
+                break;
+            };
+            match entries[idx] {
+                Entry::Leaf(twig, leaf) => {
+                    let key = self.options.decode_bytes(twig.key.as_slice());
+                    recent.push((key, leaf.value.clone(), leaf.ts));
+                }
+                Entry::Node(node) => {
+                    if let NodeType::Twig(twig) = &node.node_type {
+                        for leaf in twig.iter().filter(|leaf| !leaf.is_deleted()) {
+                            heap.push((leaf.version, entries.len()));
+                            entries.push(Entry::Leaf(twig, leaf));
+                        }
+                    }
+                    for (_, child) in node.iter() {
+                        heap.push((child.version(), entries.len()));
+                        entries.push(Entry::Node(child));
+                    }
+                }
+            }
+        }
+        recent
+    }
+
+    /// Returns an iterator over every stored version of every key with a version in `lo..=hi`.
+    ///
+    /// Each node tracks the oldest and newest version stored below it, so subtrees holding no
+    /// version inside the window are skipped without being descended into.
+    ///
+    /// # Arguments
+    ///
+    /// * `lo` - The oldest version to yield.
+    /// * `hi` - The newest version to yield.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `VersionScan` iterator yielding the matching values in key order, and in
+    /// version order for each key.
+    ///
+    pub fn scan_versions(&self, lo: u64, hi: u64) -> VersionScan<'_, P, V> {
+        VersionScan::new(self.root.as_ref(), lo, hi)
+            .decode_keys(self.options.byte_permutation.clone())
+    }
+
+    /// Returns an iterator over every key of the Trie along with its whole history, in key order.
+    ///
+    /// Unlike `iter`, which yields the latest value of each key, each key is yielded once with
+    /// all of its stored versions, read straight from its twig. Tombstones are left out of the
+    /// history, so a deleted key still appears with the values it held before its deletion.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator yielding each key and its `(value, ts)` versions in timestamp order.
+    ///
+    pub fn iter_with_history(&self) -> impl Iterator<Item = (Vec<u8>, Vec<(V, u64)>)> + '_ {
+        TwigIter::new(self.root.as_ref()).filter_map(move |twig| {
+            let mut history: Vec<(V, u64)> = twig
+                .iter()
+                .filter(|leaf| !leaf.is_deleted())
+                .map(|leaf| (leaf.value.clone(), leaf.ts))
+                .collect();
+            if history.is_empty() {
+                return None;
+            }
+            // The versions are in version order, which timestamps need not follow
+            history.sort_by_key(|(_, ts)| *ts);
+            let key = match &self.options.byte_permutation {
+                Some(permutation) => permutation.decode(twig.key.as_slice()),
+                None => twig.key.as_slice().to_vec(),
+            };
+            Some((key, history))
+        })
+    }
+
+    /// Creates an iterator over the Trie's key-value pairs that checks the structure on the way.
+    ///
+    /// This visits the same entries as `iter`, but malformed nodes, such as a twig without
+    /// any value or keys out of order, are reported as an error instead of being skipped. The
+    /// iteration ends after the first error.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `TryIter` instance yielding each key-value pair or the error found.
+    ///
+    pub fn try_iter(&self) -> TryIter<'_, P, V> {
+        TryIter::new(self.root.as_ref()).decode_keys(self.options.byte_permutation.clone())
+    }
+
+    /// Returns a stream over the key-value pairs of the Trie.
+    ///
+    /// The stream yields the same items as `iter`, but hands control back to the async runtime
+    /// every few items so that scanning a large Trie does not block a worker for the whole scan.
+    /// The stream borrows the Trie, which must outlive it.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `IterStream` over the key-value pairs of the Trie.
+    ///
+    #[cfg(feature = "stream")]
+    pub fn stream(&self) -> IterStream<'_, P, V> {
+        IterStream::new(self.iter(), STREAM_YIELD_INTERVAL)
+    }
+
+    /// Returns a cursor over the key-value pairs of the Trie.
+    ///
+    /// Unlike `iter`, the cursor can seek to a key and move both forward and backward, reading
+    /// the latest value of the key it is positioned at. The cursor starts unpositioned.
+    ///
+    /// # Returns
+    ///
+    /// Returns an unpositioned `Cursor` over the Trie.
+    ///
+    pub fn cursor(&self) -> Cursor<'_, P, V> {
+        Cursor::new(self.root.as_ref(), self.options.byte_permutation.clone())
+    }
+
+    /// Returns an iterator over the keys of the Trie in key order.
+    ///
+    /// Like `iter`, each key is yielded once regardless of how many versions it holds.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator yielding the bytes of each key.
+    ///
+    pub fn keys(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.iter().map(|(key, _, _, _)| key)
+    }
+
+    /// Returns an iterator over the latest value of each key in the Trie, in key order.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator yielding a reference to the latest value of each key.
+    ///
+    pub fn values(&self) -> impl Iterator<Item = &V> + '_ {
+        self.iter().map(|(_, value, _, _)| value)
+    }
+
+    /// Returns an iterator that removes and yields the key-value pairs of the Trie.
+    ///
+    /// Entries are yielded in key order with their latest value and version, and each entry is
+    /// removed from the Trie as it is yielded, so memory is released incrementally. Once fully
+    /// consumed the Trie is empty; if the iterator is dropped early, the remaining entries stay in
+    /// the Trie. Snapshots keep their own roots and are not affected.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Drain` iterator yielding the key, value and version of each entry.
+    ///
+    pub fn drain(&mut self) -> Drain<'_, P, V> {
+        Drain::new(self)
+    }
+
+    /// Returns an iterator merging the key-value pairs of several Tries.
+    ///
+    /// The Tries are iterated together in key order as if they were a single Trie, which suits
+    /// LSM-style layering. Each distinct key is yielded once, with the latest value and version
+    /// from the first Trie in `trees` that contains the key; entries in later Tries are shadowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `trees` - The Tries to merge, ordered from highest to lowest priority.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `MergeIter` iterator yielding the key, value and version of each distinct key.
+    ///
+    pub fn merge_iter<'a>(trees: &[&'a Tree<P, V>]) -> MergeIter<'a, P, V> {
+        let iters = trees
+            .iter()
+            .map(|tree| match tree.options.byte_permutation {
+                // Keys are merged in their decoded order, which a byte permutation does not preserve
+                Some(_) => tree.iter().sort_keys(),
+                None => tree.iter(),
+            });
+        MergeIter::new(iters.collect())
+    }
+
+    /// Creates an iterator over the inner nodes of the Trie down to a maximum depth.
+    ///
+    /// This function returns a structural iterator, distinct from the key-value `Iter`, that visits
+    /// every inner node in key order and yields its depth, prefix bytes, kind and number of
+    /// children. Nodes deeper than `max_depth` are not visited, which keeps the walk cheap when
+    /// only the top levels of the Trie are of interest, e.g. for visualization.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_depth` - The deepest level to visit, where the root is at depth `0`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `NodeWalk` iterator over the inner nodes of the Trie.
+    ///
+    pub fn iter_nodes(&self, max_depth: usize) -> NodeWalk<'_, P, V> {
+        NodeWalk::new(self.root.as_ref(), max_depth)
+    }
+
+    /// Returns the root node of the Trie, for walking its structure with `Node::children`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the root node, or `None` if the Trie is empty.
+    ///
+    pub fn root(&self) -> Option<&Arc<Node<P, V>>> {
+        self.root.as_ref()
+    }
+
+    /// Builds a histogram of how full the inner nodes of the Trie are.
+    ///
+    /// Every inner node is visited, and its number of children relative to the capacity of its
+    /// kind is counted into a `FillHistogram`, which helps to judge whether the node sizes and
+    /// growth thresholds suit the data.
+    ///
+    /// # Returns
+    ///
+    /// Returns the occupancy histogram of each node kind.
+    ///
+    pub fn fill_histogram(&self) -> FillHistogram {
+        let mut histogram = FillHistogram::default();
+        let Some(root) = self.root.as_ref() else {
+            return histogram;
+        };
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            if node.is_twig() {
+                continue;
+            }
+            histogram.record(node.node_kind(), node.num_children(), node.size());
+            stack.extend(node.iter().map(|(_, child)| child));
+        }
+        histogram
+    }
+
+    /// Returns an iterator over a range of key-value pairs within the Trie.
+    ///
+    /// This function creates and returns an iterator that iterates over key-value pairs in the Trie,
+    /// starting from the provided `start_key` and following the specified `range` bounds. The iterator
+    /// iterates within the specified key range.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - A range that specifies the bounds for iterating over key-value pairs.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Range` iterator instance that iterates over the key-value pairs within the given range.
+    /// If the Trie is empty, an empty `Range` iterator is returned.
+    ///
+    pub fn range<'a, R>(&'a self, range: R) -> Range<'a, P, V, R>
+    where
+        R: RangeBounds<P> + 'a,
+    {
+        // If the Trie is empty, return an empty Range iterator
+        if self.root.is_none() {
+            return Range::empty(range);
+        }
+
+        let root = self.root.as_ref();
+        Range::new(root, range).decode_keys(self.options.byte_permutation.clone())
+    }
+
+    /// Returns an iterator over at most `limit` key-value pairs within the specified range.
+    ///
+    /// This behaves like `range(range).take(limit)`, but the limit is pushed down into the
+    /// iterator: once `limit` entries have been yielded, its descent state is released and no
+    /// further nodes are visited.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - A range that specifies the lower and upper bounds for the iteration.
+    /// * `limit` - The maximum number of entries to yield.
+    ///
+    pub fn range_limited<'a, R>(&'a self, range: R, limit: usize) -> Range<'a, P, V, R>
+    where
+        R: RangeBounds<P> + 'a,
+    {
+        self.range(range).with_limit(limit)
+    }
+
+    /// Returns an iterator over the key-value pairs from `start` up to, but excluding, `end`.
+    ///
+    /// Every key between the two bounds starts with the bytes the bounds have in common, so
+    /// the scan descends straight to the subtree holding that prefix and the bounds are only
+    /// applied within it. No node outside the subtree is visited, which makes this the cheap
+    /// way to scan part of a partition of keys sharing a leading prefix.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The first key of the range.
+    /// * `end` - The key ending the range, which is not part of it.
+    ///
+    pub fn range_prefix<'a>(&'a self, start: &P, end: &P) -> Range<'a, P, V, std::ops::Range<P>> {
+        let range = start.clone()..end.clone();
+        let common = start.longest_common_prefix(end.as_slice());
+        // The substitution maps every byte on its own, so the keys between the bounds still
+        // share the substituted prefix
+        let prefix = self.options.encode_key(Cow::Borrowed(start));
+        let prefix = &prefix.as_slice()[..common];
+        match self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_prefix_path(root, prefix))
+        {
+            Some((node, depth)) => Range::new(Some(node), range)
+                .at_path(&prefix[..depth])
+                .decode_keys(self.options.byte_permutation.clone()),
+            None => Range::empty(range),
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> Result<(), TrieError> {
+        if self.closed {
+            return Err(TrieError::SnapshotAlreadyClosed);
+        }
+        Ok(())
+    }
+
+    /// Closes the tree, preventing further modifications, and releases associated resources.
+    pub fn close(&mut self) -> Result<(), TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        // Check if there are any active readers for the snapshot
+        if self.snapshot_count() > 0 {
+            return Err(TrieError::SnapshotNotClosed);
+        }
+
+        // Mark the snapshot as closed
+        self.closed = true;
+
+        Ok(())
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Tree<P, V> {
+    /// Freezes the Trie into a read-only `SealedTree`.
+    ///
+    /// The backing stores of the nodes are trimmed to their contents on the way, since a sealed
+    /// Trie never grows again.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `SealedTree` serving the same contents as this Trie.
+    ///
+    pub fn seal(mut self) -> SealedTree<P, V> {
+        self.root = self.root.as_ref().map(Node::shrink_storage);
+        SealedTree { tree: self }
+    }
+}
+
+/// A read-only Trie, created by `Tree::seal`.
+///
+/// A sealed Trie only exposes reads, so it can be shared freely, e.g. behind an `Arc` across
+/// threads, without any risk of accidental writes.
+pub struct SealedTree<P: KeyTrait, V: Clone> {
+    tree: Tree<P, V>,
+}
+
+impl<P: KeyTrait, V: Clone> SealedTree<P, V> {
+    /// Retrieves a key's value at the given version, see `Tree::get`.
+    pub fn get(
+        &self,
+        key: &P,
+        version: impl Into<VersionId>,
+    ) -> Result<(P, V, u64, u64), TrieError> {
+        self.tree.get(key, version)
+    }
+
+    /// Returns an iterator over the key-value pairs, see `Tree::iter`.
+    pub fn iter(&self) -> Iter<'_, P, V> {
+        self.tree.iter()
+    }
+
+    /// Returns an iterator over a range of key-value pairs, see `Tree::range`.
+    pub fn range<'a, R>(&'a self, range: R) -> Range<'a, P, V, R>
+    where
+        R: RangeBounds<P> + 'a,
+    {
+        self.tree.range(range)
+    }
+
+    /// Returns the version of the Trie.
+    pub fn version(&self) -> u64 {
+        self.tree.version()
+    }
+
+    /// Creates a snapshot of the Trie.
+    ///
+    /// The sealed Trie itself can never change, so unlike `Tree::create_snapshot` the snapshot
+    /// is not tracked and needs no closing.
+    ///
+    pub fn snapshot(&self) -> Snapshot<P, V> {
+        let id = self.tree.max_snapshot_id.fetch_add(1, Ordering::SeqCst);
+        let root = self.tree.root.clone();
+        let version = root.as_ref().map_or(1, |root| root.version() + 1);
+        Snapshot::new(id, root, version, self.tree.options.clone())
+    }
+}
+
+impl<P: KeyTrait, V: Clone + PartialEq> Tree<P, V> {
+    /// Inserts a key-value pair unless the key already holds an equal value.
+    ///
+    /// This behaves like `insert`, except that no new version is appended when `value` equals
+    /// the latest value of the key, so re-writing an unchanged value does not grow its history.
+    /// A key that is absent or deleted is always written.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: A reference to the key to be inserted.
+    /// * `value`: The value to be associated with the key.
+    /// * `version`: The version for the insertion, see `insert`.
+    /// * `ts`: The timestamp for the insertion.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if the value was written, or `false` if the key already held it.
+    ///
+    pub fn insert_if_changed(
+        &mut self,
+        key: &P,
+        value: V,
+        version: impl Into<VersionId>,
+        ts: impl Into<Ts>,
+    ) -> Result<bool, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        let encoded = self.options.encode_key(Cow::Borrowed(key));
+        let unchanged = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_twig(root, &encoded))
+            .and_then(|twig| twig.get_latest_value())
+            .is_some_and(|latest| *latest == value);
+        if unchanged {
+            return Ok(false);
+        }
+        self.insert(key, value, version, ts)?;
+        Ok(true)
+    }
+
+    /// Merges the changes made in another Trie since a common ancestor into a new Trie.
+    ///
+    /// This is a three-way merge: every key whose latest value in `theirs` differs from `base`
+    /// is compared with the same key in `self`. If `self` left the key as it was in `base`, the
+    /// value from `theirs` is taken, and if both sides made the same change there is nothing to
+    /// do. Otherwise the key was changed on both sides, and `resolve` decides its value. Keys
+    /// only changed in `self` are kept as they are.
+    ///
+    /// Subtrees `theirs` still shares with `base` are skipped without being walked, so the cost
+    /// of a merge follows the number of changes rather than the size of the Tries. If `self`
+    /// has not changed since `base`, the merge takes the root of `theirs` as a whole. As the
+    /// Tries are compared as stored, they must share their options, as Tries cloned from a
+    /// common ancestor do.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - A snapshot of the common ancestor of both Tries.
+    /// * `theirs` - The Trie whose changes are merged.
+    /// * `resolve` - Called with the key and its values in `base`, `self` and `theirs` for every
+    ///   key changed on both sides, returning the merged value or `None` to delete the key.
+    ///
+    /// # Returns
+    ///
+    /// Returns the merged `Tree`, or an `Err` if writing a merged value fails.
+    ///
+    pub fn three_way_merge<F>(
+        &self,
+        base: &Snapshot<P, V>,
+        theirs: &Tree<P, V>,
+        mut resolve: F,
+    ) -> Result<Tree<P, V>, TrieError>
+    where
+        F: FnMut(&P, Option<&V>, Option<&V>, Option<&V>) -> Option<V>,
+    {
+        let mut merged = self.clone();
+        let unchanged = |root: Option<&Arc<Node<P, V>>>| match (root, base.root.as_ref()) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        if unchanged(self.root.as_ref()) {
+            merged.root = theirs.root.clone();
+            merged
+                .max_ts
+                .fetch_max(theirs.max_ts.load(Ordering::SeqCst), Ordering::SeqCst);
+            #[cfg(feature = "bloom")]
+            merged.rebuild_bloom_filter();
+            return Ok(merged);
+        }
+
+        let mut theirs_twigs = Vec::new();
+        let mut base_twigs = Vec::new();
+        Node::collect_unshared_twigs(
+            theirs.root.as_ref(),
+            base.root.as_ref(),
+            &mut theirs_twigs,
+            &mut base_twigs,
+        );
+        let mut keys: Vec<&P> = theirs_twigs
+            .iter()
+            .chain(base_twigs.iter())
+            .map(|twig| &twig.key)
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        fn find<'a, P: KeyTrait, V: Clone>(
+            root: Option<&'a Arc<Node<P, V>>>,
+            key: &P,
+        ) -> Option<&'a TwigNode<P, V>> {
+            root.and_then(|root| Node::find_twig(root, key))
+        }
+        fn live<P: KeyTrait, V: Clone>(twig: Option<&TwigNode<P, V>>) -> Option<&V> {
+            twig.and_then(|twig| twig.get_latest_live_leaf())
+                .map(|leaf| &leaf.value)
+        }
+        for key in keys {
+            // The twigs hold the keys as stored, while resolve and the writes take them as given
+            let user_key = self.options.decode_key(Cow::Borrowed(key));
+            let base_twig = find(base.root.as_ref(), key);
+            let ours_twig = find(self.root.as_ref(), key);
+            let theirs_twig = find(theirs.root.as_ref(), key);
+            let (base_value, ours_value, theirs_value) =
+                (live(base_twig), live(ours_twig), live(theirs_twig));
+            if theirs_value == base_value || theirs_value == ours_value {
+                continue;
+            }
+
+            let ts_of =
+                |twig: Option<&TwigNode<P, V>>| twig.map_or(0, |twig| twig.latest_version_ts());
+            let (value, ts) = if ours_value == base_value {
+                (theirs_value.cloned(), ts_of(theirs_twig))
+            } else {
+                let value = resolve(&user_key, base_value, ours_value, theirs_value);
+                (value, ts_of(ours_twig).max(ts_of(theirs_twig)))
+            };
+            match value {
+                Some(value) => {
+                    merged.insert(&user_key, value, 0, ts)?;
+                }
+                None => {
+                    merged.delete_logical(&user_key, ts)?;
+                }
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Compares the live contents of the Trie with another Trie.
+    ///
+    /// The twigs of both Tries are walked together in key order, and every key whose latest
+    /// live value differs is yielded, as added if it is only live in `self`, removed if it is
+    /// only live in `other`, or updated. The Tries need not be related, but subtrees they share,
+    /// e.g. when one was cloned from the other, are skipped without being walked.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The Trie to compare with, taken as the older state.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator yielding a `Diff` for every differing key, in key order.
+    ///
+    pub fn diff<'a>(&'a self, other: &'a Tree<P, V>) -> impl Iterator<Item = Diff<V>> + 'a {
+        let mut ours = Vec::new();
+        let mut theirs = Vec::new();
+        Node::collect_unshared_twigs(
+            self.root.as_ref(),
+            other.root.as_ref(),
+            &mut ours,
+            &mut theirs,
+        );
+
+        // Keys are walked in their decoded order, which a byte permutation does not preserve
+        fn live<'a, P: KeyTrait, V: Clone>(
+            twigs: Vec<&'a TwigNode<P, V>>,
+            options: &TreeOptions,
+        ) -> std::iter::Peekable<std::vec::IntoIter<(Vec<u8>, &'a V)>> {
+            let mut entries: Vec<_> = twigs
+                .into_iter()
+                .filter_map(|twig| {
+                    let leaf = twig.get_latest_live_leaf()?;
+                    let key = match &options.byte_permutation {
+                        Some(permutation) => permutation.decode(twig.key.as_slice()),
+                        None => twig.key.as_slice().to_vec(),
+                    };
+                    Some((key, &leaf.value))
+                })
+                .collect();
+            if options.byte_permutation.is_some() {
+                entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            }
+            entries.into_iter().peekable()
+        }
+        let mut ours = live(ours, &self.options);
+        let mut theirs = live(theirs, &other.options);
+
+        std::iter::from_fn(move || loop {
+            let order = match (ours.peek(), theirs.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some((a, _)), Some((b, _))) => a.cmp(b),
+            };
+            match order {
+                std::cmp::Ordering::Less => {
+                    let (key, value) = ours.next()?;
+                    return Some(Diff::Added(key, value.clone()));
+                }
+                std::cmp::Ordering::Greater => {
+                    let (key, value) = theirs.next()?;
+                    return Some(Diff::Removed(key, value.clone()));
+                }
+                std::cmp::Ordering::Equal => {
+                    let (key, new) = ours.next()?;
+                    let (_, old) = theirs.next()?;
+                    if new != old {
+                        return Some(Diff::Updated(key, old.clone(), new.clone()));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Checks whether two Tries hold the same live contents.
+    ///
+    /// Two Tries are considered equal if they contain the same keys and the latest value of each
+    /// key is equal. Versions, timestamps and older values are ignored. Subtrees shared between
+    /// the Tries, e.g. after cloning a snapshot, are not walked.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The Trie to compare with.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if both Tries map the same keys to equal latest values.
+    ///
+    pub fn contents_eq(&self, other: &Tree<P, V>) -> bool {
+        self.eq_by(
+            other,
+            &|a, b| match (a.get_latest_live_leaf(), b.get_latest_live_leaf()) {
+                (Some(la), Some(lb)) => la.value == lb.value,
+                (None, None) => true,
+                _ => false,
+            },
+        )
+    }
+
+    /// Checks whether two Tries hold the same contents and version history.
+    ///
+    /// Unlike `contents_eq`, every stored value of a key must match, including its version and
+    /// timestamp.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The Trie to compare with.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if both Tries hold identical histories for every key.
+    ///
+    pub fn history_eq(&self, other: &Tree<P, V>) -> bool {
+        self.eq_by(other, &|a, b| {
+            a.values.len() == b.values.len()
+                && a.values.iter().zip(b.values.iter()).all(|(la, lb)| {
+                    la.version == lb.version
+                        && la.ts == lb.ts
+                        && la.deleted == lb.deleted
+                        && la.value == lb.value
+                })
+        })
+    }
+
+    fn eq_by<F>(&self, other: &Tree<P, V>, twig_eq: &F) -> bool
+    where
+        F: Fn(&TwigNode<P, V>, &TwigNode<P, V>) -> bool,
+    {
+        match (&self.root, &other.root) {
+            (None, None) => true,
+            (Some(a), Some(b)) => Node::subtree_eq(a, b, twig_eq),
+            (Some(root), None) | (None, Some(root)) => {
+                let mut twigs = Vec::new();
+                Node::collect_twigs(root, &mut twigs);
+                twigs.is_empty()
+            }
+        }
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Tree<P, V> {
+    /// Computes a hash of the contents of the Trie.
+    ///
+    /// The hash is a 64-bit FNV-1a over the latest entry of every key, fed in key order, so
+    /// Tries holding the same entries hash equally regardless of how their nodes are laid out.
+    /// Each entry is fed as its key and its encoded value, each as a u32 length followed by the
+    /// bytes, then its version and timestamp as u64s, with all integers little-endian. Neither
+    /// the hash nor the encoding depends on the build or the platform, so the hashes of
+    /// replicas can be compared to check that they converged.
+    ///
+    /// # Arguments
+    ///
+    /// * `encode`: A function encoding a value as bytes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the hash of the Trie's contents.
+    ///
+    pub fn content_hash<F>(&self, mut encode: F) -> u64
+    where
+        F: FnMut(&V) -> Vec<u8>,
+    {
+        let mut hash = FNV_OFFSET_BASIS;
+        let feed_bytes = |hash: u64, bytes: &[u8]| {
+            let hash = fnv1a(hash, &(bytes.len() as u32).to_le_bytes());
+            fnv1a(hash, bytes)
+        };
+        for (key, value, version, ts) in self.iter() {
+            hash = feed_bytes(hash, &key);
+            hash = feed_bytes(hash, &encode(value));
+            hash = fnv1a(hash, &version.to_le_bytes());
+            hash = fnv1a(hash, &ts.to_le_bytes());
+        }
+        hash
+    }
+}
+
+impl<P: KeyTrait, V: Clone + PartialEq> PartialEq for Tree<P, V> {
+    /// Compares the live contents of two Tries, see `Tree::contents_eq`.
+    fn eq(&self, other: &Self) -> bool {
+        self.contents_eq(other)
+    }
+}
+
+impl<const N: usize, V: Clone> Tree<FixedKey<N>, V> {
+    /// Converts the Trie into one keyed by `VariableKey`.
+    ///
+    /// Every key is carried over byte for byte, so the new Trie orders its keys exactly as
+    /// this one does, and every key keeps its whole history, with the versions and timestamps
+    /// it was written at. The nodes are rebuilt in the same shape, while the stored values are
+    /// shared rather than copied.
+    ///
+    /// Keys built from integers hold no terminator, so a variable-length key added to the new
+    /// Trie later must not start with the bytes of a converted key, nor be a prefix of one.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Tree` keyed by `VariableKey` holding the same keys and history.
+    ///
+    pub fn into_variable(self) -> Tree<VariableKey, V> {
+        let mut tree = Tree::with_options(self.options.clone());
+        tree.max_active_snapshots = self.max_active_snapshots;
+        tree.max_ts = AtomicU64::new(self.max_ts.load(Ordering::SeqCst));
+        tree.root = self
+            .root
+            .as_ref()
+            .map(|root| Arc::new(Node::convert_keys(root)));
+
+        // The new tree starts with an empty filter, so fill it from the keys it received
+        #[cfg(feature = "bloom")]
+        tree.rebuild_bloom_filter();
+
+        tree
+    }
+
+    /// Creates an iterator over the Trie's key-value pairs yielding keys as fixed-size arrays.
+    ///
+    /// This behaves like `iter`, but every key is copied into a `[u8; N]` on the stack rather
+    /// than into a newly allocated `Vec`, which saves an allocation per key. Keys shorter than
+    /// `N` bytes, such as those built from integers narrower than the key, are padded with
+    /// zero bytes.
+    ///
+    /// # Returns
+    ///
+    /// Returns an iterator yielding the key, latest value and version of each key, in key order.
+    ///
+    pub fn iter_fixed(&self) -> impl Iterator<Item = ([u8; N], &V, u64)> + '_ {
+        let permutation = self.options.byte_permutation.as_deref();
+        TwigIter::new(self.root.as_ref()).filter_map(move |twig| {
+            let leaf = twig.get_latest_live_leaf()?;
+            let bytes = twig.key.as_slice();
+            let mut key = [0; N];
+            key[..bytes.len()].copy_from_slice(bytes);
+            if let Some(permutation) = permutation {
+                for byte in &mut key[..bytes.len()] {
+                    *byte = permutation.inverse[*byte as usize];
+                }
+            }
+            Some((key, &leaf.value, leaf.version))
+        })
+    }
+}
+
+/*
+    Test cases for Adaptive Radix Tree
+*/
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "trace")]
+    use super::TraceStep;
+    use super::{Node, NodeKind, NodeType, Tree, TreeOptions, KV};
+    use crate::node::{FlatNode, LeafValue, NodeTrait};
+    use crate::{FixedKey, Key, VariableKey};
+
+    use std::fs::File;
+    use std::io::{self, BufRead, BufReader};
+    use std::sync::Arc;
+
+    fn read_words_from_file(file_path: &str) -> io::Result<Vec<String>> {
+        let file = File::open(file_path)?;
+        let reader = BufReader::new(file);
+        let words: Vec<String> = reader.lines().map_while(Result::ok).collect();
+        Ok(words)
+    }
+
+    #[test]
+    fn insert_search_delete_words() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+        let file_path = "testdata/words.txt";
+
+        if let Ok(words) = read_words_from_file(file_path) {
+            // Insertion phase
+            for word in &words {
+                let key = &VariableKey::from_str(word);
+                tree.insert(key, 1, 0, 0).unwrap();
+            }
+
+            // Search phase
+            for word in &words {
+                let key = VariableKey::from_str(word);
+                let (_, val, _, _) = tree.get(&key, 0).unwrap();
+                assert_eq!(val, 1);
+            }
+
+            // Deletion phase
+            for word in &words {
+                let key = VariableKey::from_str(word);
+                assert!(tree.remove(&key).unwrap());
+            }
+        } else if let Err(err) = read_words_from_file(file_path) {
+            eprintln!("Error reading file: {}", err);
+        }
+
+        assert_eq!(tree.version(), 0);
+    }
+
+    #[test]
+    fn string_insert_delete() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion phase
+        let insert_words = [
+            "a", "aa", "aal", "aalii", "abc", "abcd", "abcde", "xyz", "axyz",
+        ];
+
+        for word in &insert_words {
+            tree.insert(&VariableKey::from_str(word), 1, 0, 0).unwrap();
+        }
+
+        // Deletion phase
+        for word in &insert_words {
+            assert!(tree.remove(&VariableKey::from_str(word)).unwrap());
+        }
+    }
+
+    #[test]
+    fn string_long() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion phase
+        let words_to_insert = [
+            ("amyelencephalia", 1),
+            ("amyelencephalic", 2),
+            ("amyelencephalous", 3),
+        ];
+
+        for (word, val) in &words_to_insert {
+            tree.insert(&VariableKey::from_str(word), *val, 0, 0)
+                .unwrap();
+        }
+
+        // Verification phase
+        for (word, expected_val) in &words_to_insert {
+            let (_, val, _, _) = tree.get(&VariableKey::from_str(word), 0).unwrap();
+            assert_eq!(val, *expected_val);
+        }
+    }
+
+    #[test]
+    fn root_set_get() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion phase
+        let key = VariableKey::from_str("abc");
+        let value = 1;
+        tree.insert(&key, value, 0, 0).unwrap();
+
+        // Verification phase
+        let (_, val, _ts, _) = tree.get(&key, 0).unwrap();
+        assert_eq!(val, value);
+    }
+
+    #[test]
+    fn string_duplicate_insert() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // First insertion
+        let key = VariableKey::from_str("abc");
+        let value = 1;
+        let result = tree.insert(&key, value, 0, 0).expect("Failed to insert");
+        assert!(result.is_none());
+
+        // Second insertion (duplicate)
+        let result = tree.insert(&key, value, 0, 0).expect("Failed to insert");
+        assert!(result.is_some());
+    }
+
+    // Inserting a single value into the tree and removing it should result in a nil tree root.
+    #[test]
+    fn insert_and_remove() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion
+        let key = VariableKey::from_str("test");
+        let value = 1;
+        tree.insert(&key, value, 0, 0).unwrap();
+
+        // Removal
+        assert!(tree.remove(&key).unwrap());
+
+        // Verification
+        assert!(tree.get(&key, 0).is_err());
+    }
+
+    #[test]
+    fn inserting_keys_with_common_prefix() {
+        let key1 = VariableKey::from_str("foo");
+        let key2 = VariableKey::from_str("foo2");
+
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion
+        tree.insert(&key1, 1, 0, 0).unwrap();
+        tree.insert(&key2, 1, 0, 0).unwrap();
+
+        // Removal
+        assert!(tree.remove(&key1).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert_eq!(root.node_type_name(), "Node1");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    // Inserting Two values into the tree and removing one of them
+    // should result in a tree root of type twig
+    #[test]
+    fn insert2_and_remove1_and_root_should_be_node1() {
+        let key1 = VariableKey::from_str("test1");
+        let key2 = VariableKey::from_str("test2");
+
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion
+        tree.insert(&key1, 1, 0, 0).unwrap();
+        tree.insert(&key2, 1, 0, 0).unwrap();
+
+        // Removal
+        assert!(tree.remove(&key1).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert_eq!(root.node_type_name(), "Node1");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    // // Inserting Two values into a tree and deleting them both
+    // // should result in a nil tree root
+    // // This tests the expansion of the root into a NODE4 and
+    // // successfully collapsing into a twig and then nil upon successive removals
+    // #[test]
+    // fn insert2_and_remove2_and_root_should_be_nil() {
+    //     let key1 = &VariableKey::from_str("test1");
+    //     let key2 = &VariableKey::from_str("test2");
+
+    //     let mut tree = Tree::<VariableKey, i32>::new();
+    //     tree.insert(key1, 1, 0, 0);
+    //     tree.insert(key2, 1, 0);
+
+    //     assert_eq!(tree.remove(key1), true);
+    //     assert_eq!(tree.remove(key2), true);
+
+    //     assert!(tree.root.is_none());
+    // }
+
+    // Inserting Five values into a tree and deleting one of them
+    // should result in a tree root of type NODE4
+    // This tests the expansion of the root into a NODE16 and
+    // successfully collapsing into a NODE4 upon successive removals
+    #[test]
+    fn insert5_and_remove1_and_root_should_be_node4() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion
+        for i in 0..5u32 {
+            let key = VariableKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0).unwrap();
+        }
+
+        // Removal
+        let key_to_remove = VariableKey::from_slice(&1u32.to_be_bytes());
+        assert!(tree.remove(&key_to_remove).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node4");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    //     // Inserting Five values into a tree and deleting all of them
+    //     // should result in a tree root of type nil
+    //     // This tests the expansion of the root into a NODE16 and
+    //     // successfully collapsing into a NODE4, twig, then nil
+    //     #[test]
+    //     fn insert5_and_remove5_and_root_should_be_nil() {
+    //         let mut tree = Tree::<VariableKey, i32>::new();
+
+    //         for i in 0..5u32 {
+    //             let key = &VariableKey::from_slice(&i.to_be_bytes());
+    //             tree.insert(key, 1);
+    //         }
+
+    //         for i in 0..5u32 {
+    //             let key = &VariableKey::from_slice(&i.to_be_bytes());
+    //             tree.remove(key);
+    //         }
+
+    //         assert!(tree.root.is_none());
+    //     }
+
+    // Inserting 17 values into a tree and deleting one of them should
+    // result in a tree root of type NODE16
+    // This tests the expansion of the root into a NODE48, and
+    // successfully collapsing into a NODE16
+    #[test]
+    fn insert17_and_remove1_and_root_should_be_node16() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion
+        for i in 0..17u32 {
+            let key = VariableKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0).unwrap();
+        }
+
+        // Removal
+        let key_to_remove = VariableKey::from_slice(&2u32.to_be_bytes());
+        assert!(tree.remove(&key_to_remove).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node16");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    #[test]
+    fn insert17_and_root_should_be_node48() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion
+        for i in 0..17u32 {
+            let key = VariableKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0).unwrap();
+        }
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node48");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    // // Inserting 17 values into a tree and removing them all should
+    // // result in a tree of root type nil
+    // // This tests the expansion of the root into a NODE48, and
+    // // successfully collapsing into a NODE16, NODE4, twig, and then nil
+    // #[test]
+    // fn insert17_and_remove17_and_root_should_be_nil() {
+    //     let mut tree = Tree::<VariableKey, i32>::new();
+
+    //     for i in 0..17u32 {
+    //         let key = VariableKey::from_slice(&i.to_be_bytes());
+    //         tree.insert(&key, 1);
+    //     }
+
+    //     for i in 0..17u32 {
+    //         let key = VariableKey::from_slice(&i.to_be_bytes());
+    //         tree.remove(&key);
+    //     }
+
+    //     assert!(tree.root.is_none());
+    // }
+
+    // Inserting 49 values into a tree and removing one of them should
+    // result in a tree root of type NODE48
+    // This tests the expansion of the root into a NODE256, and
+    // successfully collapasing into a NODE48
+    #[test]
+    fn insert49_and_remove1_and_root_should_be_node48() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion
+        for i in 0..49u32 {
+            let key = VariableKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0).unwrap();
+        }
+
+        // Removal
+        let key_to_remove = VariableKey::from_slice(&2u32.to_be_bytes());
+        assert!(tree.remove(&key_to_remove).unwrap());
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node48");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    #[test]
+    fn insert49_and_root_should_be_node248() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertion
+        for i in 0..49u32 {
+            let key = VariableKey::from_slice(&i.to_be_bytes());
+            tree.insert(&key, 1, 0, 0).unwrap();
+        }
+
+        // Root verification
+        if let Some(root) = &tree.root {
+            assert!(root.is_inner());
+            assert_eq!(root.node_type_name(), "Node256");
+        } else {
+            panic!("Tree root is None");
+        }
+    }
+
+    //     // // Inserting 49 values into a tree and removing all of them should
+    //     // // result in a nil tree root
+    //     // // This tests the expansion of the root into a NODE256, and
+    //     // // successfully collapsing into a Node48, Node16, Node4, twig, and finally nil
+    //     // #[test]
+    //     // fn insert49_and_remove49_and_root_should_be_nil() {
+    //     //     let mut tree = Tree::<VariableKey, i32>::new();
+
+    //     //     for i in 0..49u32 {
+    //     //         let key = &VariableKey::from_slice(&i.to_be_bytes());
+    //     //         tree.insert(key, 1);
+    //     //     }
+
+    //     //     for i in 0..49u32 {
+    //     //         let key = VariableKey::from_slice(&i.to_be_bytes());
+    //     //         assert_eq!(tree.remove(&key), true);
+    //     //     }
+
+    //     //     assert!(tree.root.is_none());
+    //     // }
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[allow(clippy::upper_case_acronyms)]
+    struct KVT {
+        k: Vec<u8>,   // Key
+        version: u64, // version
+    }
+
+    #[test]
+    fn timed_insertion() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+
+        let kvts = [
+            KVT {
+                k: b"key1_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key2_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key3_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key4_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key5_0".to_vec(),
+                version: 0,
+            },
+            KVT {
+                k: b"key6_0".to_vec(),
+                version: 0,
+            },
+        ];
+
+        // Insertion
+        for (idx, kvt) in kvts.iter().enumerate() {
+            let ts = if kvt.version == 0 {
+                idx as u64 + 1
+            } else {
+                kvt.version
+            };
+            assert!(tree
+                .insert(&VariableKey::from(kvt.k.clone()), 1, ts, 0)
+                .is_ok());
+        }
+
+        // Verification
+        for (curr_version, kvt) in (1..).zip(kvts.iter()) {
+            let key = VariableKey::from(kvt.k.clone());
+            let (_, val, version, _ts) = tree.get(&key, 0).unwrap();
+            assert_eq!(val, 1);
+
+            if kvt.version == 0 {
+                assert_eq!(curr_version, version);
+            } else {
+                assert_eq!(kvt.version, version);
+            }
+        }
+
+        // Root's version should match the greatest inserted version
+        assert_eq!(kvts.len() as u64, tree.version());
+    }
+
+    #[test]
+    fn timed_insertion_update_same_key() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+
+        let key1 = &VariableKey::from_str("key_1");
+
+        // insert key1 with version 0
+        assert!(tree.insert(key1, 1, 0, 1).is_ok());
+        // update key1 with version 0
+        assert!(tree.insert(key1, 1, 0, 3).is_ok());
+
+        // get key1 should return version 2 as the same key was inserted and updated
+        let (_, val, version, ts) = tree.get(key1, 0).unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(version, 2);
+        assert_eq!(ts, 3);
+
+        // update key1 with older version should fail
+        assert!(tree.insert(key1, 1, 1, 0).is_err());
+        assert_eq!(tree.version(), 2);
+
+        // update key1 with newer version should pass
+        assert!(tree.insert(key1, 1, 8, 5).is_ok());
+        let (_, val, version, ts) = tree.get(key1, 0).unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(version, 8);
+        assert_eq!(ts, 5);
+
+        assert_eq!(tree.version(), 8);
+    }
+
+    #[test]
+    fn timed_insertion_update_non_increasing_version() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+
+        let key1 = VariableKey::from_str("key_1");
+        let key2 = VariableKey::from_str("key_2");
+
+        // Initial insertion
+        assert!(tree.insert(&key1, 1, 10, 0).is_ok());
+        let initial_version_key1 = tree.version();
+
+        // Attempt update with non-increasing version
+        assert!(tree.insert(&key1, 1, 2, 0).is_err());
+        assert_eq!(initial_version_key1, tree.version());
+        let (_, val, version, _) = tree.get(&key1, 0).unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(version, 10);
+
+        // Insert another key
+        assert!(tree.insert(&key2, 1, 15, 0).is_ok());
+        let initial_version_key2 = tree.version();
+
+        // Attempt update with non-increasing version for the second key
+        assert!(tree.insert(&key2, 1, 11, 0).is_err());
+        assert_eq!(initial_version_key2, tree.version());
+        let (_, val, version, _ts) = tree.get(&key2, 0).unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(version, 15);
+
+        // Check if the max version of the tree is the max of the two inserted versions
+        assert_eq!(tree.version(), 15);
+    }
+
+    #[test]
+    fn timed_insertion_update_equal_to_root_version() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+
+        let key1 = VariableKey::from_str("key_1");
+        let key2 = VariableKey::from_str("key_2");
+
+        // Initial insertion
+        assert!(tree.insert(&key1, 1, 10, 0).is_ok());
+        let initial_version = tree.version();
+
+        // Attempt update with version equal to root's version
+        assert!(tree.insert(&key2, 1, initial_version, 0).is_err());
+    }
+
+    #[test]
+    fn timed_deletion_check_root_ts() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+
+        // Initial insertions
+        assert!(tree
+            .insert(&VariableKey::from_str("key_1"), 1, 0, 0)
+            .is_ok());
+        assert!(tree
+            .insert(&VariableKey::from_str("key_2"), 1, 0, 0)
+            .is_ok());
+        assert_eq!(tree.version(), 2);
+
+        // Deletions
+        assert!(tree.remove(&VariableKey::from_str("key_1")).unwrap());
+        assert!(tree.remove(&VariableKey::from_str("key_2")).unwrap());
+        assert_eq!(tree.version(), 0);
+    }
+
+    fn from_be_bytes_key(k: &[u8]) -> u64 {
+        let padded_k = if k.len() < 8 {
+            let mut new_k = vec![0; 8];
+            new_k[8 - k.len()..].copy_from_slice(k);
+            new_k
+        } else {
+            k.to_vec()
+        };
+
+        let k_slice = &padded_k[..8];
+        u64::from_be_bytes(k_slice.try_into().unwrap())
+    }
+
+    #[test]
+    fn iter_seq_u16() {
+        let mut tree = Tree::<FixedKey<16>, u16>::new();
+
+        // Insertion
+        for i in 0..u16::MAX {
+            let key: FixedKey<16> = i.into();
+            tree.insert(&key, i, 0, i as u64).unwrap();
+        }
+
+        // Iteration and verification
+        let mut len = 0usize;
+        let mut expected = 0u16;
+
+        let tree_iter = tree.iter();
+        for tree_entry in tree_iter {
+            let k = from_be_bytes_key(&tree_entry.0);
+            assert_eq!(expected as u64, k);
+            let ts = tree_entry.3;
+            assert_eq!(expected as u64, *ts);
+            expected = expected.wrapping_add(1);
+            len += 1;
+        }
+
+        // Final assertion
+        assert_eq!(len, u16::MAX as usize);
+    }
+
+    #[test]
+    fn iter_seq_u8() {
+        let mut tree: Tree<FixedKey<32>, u8> = Tree::<FixedKey<32>, u8>::new();
+
+        // Insertion
+        for i in 0..u8::MAX {
+            let key: FixedKey<32> = i.into();
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+
+        // Iteration and verification
+        let mut len = 0usize;
+        let mut expected = 0u8;
+
+        let tree_iter = tree.iter();
+        for tree_entry in tree_iter {
+            let k = from_be_bytes_key(&tree_entry.0);
+            assert_eq!(expected as u64, k);
+            expected = expected.wrapping_add(1);
+            len += 1;
+        }
+
+        // Final assertion
+        assert_eq!(len, u8::MAX as usize);
+    }
+
+    #[test]
+    fn range_seq_u8() {
+        let mut tree: Tree<FixedKey<8>, u8> = Tree::<FixedKey<8>, u8>::new();
+
+        let max = u8::MAX;
+        // Insertion
+        for i in 0..=max {
+            let key: FixedKey<8> = i.into();
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+
+        // Test inclusive range
+        let start_key: FixedKey<8> = 5u8.into();
+        let end_key: FixedKey<8> = max.into();
+        let mut len = 0usize;
+        for _ in tree.range(start_key..=end_key) {
+            len += 1;
+        }
+        assert_eq!(len, max as usize - 4);
+
+        // Test exclusive range
+        let start_key: FixedKey<8> = 5u8.into();
+        let end_key: FixedKey<8> = max.into();
+        let mut len = 0usize;
+        for _ in tree.range(start_key..end_key) {
+            len += 1;
+        }
+        assert_eq!(len, max as usize - 5);
+
+        // Test range with different start and end keys
+        let start_key: FixedKey<8> = 3u8.into();
+        let end_key: FixedKey<8> = 7u8.into();
+        let mut len = 0usize;
+        for _ in tree.range(start_key..=end_key) {
+            len += 1;
+        }
+        assert_eq!(len, 5);
+
+        // Test range with all keys
+        let start_key: FixedKey<8> = 0u8.into();
+        let end_key: FixedKey<8> = max.into();
+        let mut len = 0usize;
+        for _ in tree.range(start_key..=end_key) {
+            len += 1;
+        }
+        assert_eq!(len, 256);
+    }
+
+    #[test]
+    fn range_seq_u16() {
+        let mut tree: Tree<FixedKey<16>, u16> = Tree::<FixedKey<16>, u16>::new();
+
+        let max = u16::MAX;
+        // Insertion
+        for i in 0..=max {
+            let key: FixedKey<16> = i.into();
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+
+        let mut len = 0usize;
+        let start_key: FixedKey<16> = 0u8.into();
+        let end_key: FixedKey<16> = max.into();
+
+        for _ in tree.range(start_key..=end_key) {
+            len += 1;
+        }
+        assert_eq!(len, max as usize + 1);
+    }
+
+    #[test]
+    fn same_key_with_versions() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // Insertions
+        let key1 = VariableKey::from_str("abc");
+        let key2 = VariableKey::from_str("efg");
+        tree.insert(&key1, 1, 0, 0).unwrap();
+        tree.insert(&key1, 2, 10, 0).unwrap();
+        tree.insert(&key2, 3, 11, 0).unwrap();
+
+        // Versioned retrievals and assertions
+        let (_, val, _, _) = tree.get(&key1, 1).unwrap();
+        assert_eq!(val, 1);
+        let (_, val, _, _) = tree.get(&key1, 10).unwrap();
+        assert_eq!(val, 2);
+        let (_, val, _, _) = tree.get(&key2, 11).unwrap();
+        assert_eq!(val, 3);
+
+        // Iteration and verification
+        let mut len = 0;
+        let tree_iter = tree.iter();
+        for _ in tree_iter {
+            len += 1;
+        }
+        assert_eq!(len, 2);
+    }
+
+    #[test]
+    fn bulk_insert() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+        let curr_version = tree.version();
+        // Create a vector of KV<P, V>
+        let kv_pairs = vec![
+            KV {
+                key: VariableKey::from_str("key_1"),
+                value: 1,
+                version: 0,
+                ts: 0,
+            },
+            KV {
+                key: VariableKey::from_str("key_2"),
+                value: 1,
+                version: 2,
+                ts: 0,
+            },
+            KV {
+                key: VariableKey::from_str("key_3"),
+                value: 1,
+                version: curr_version + 1,
+                ts: 0,
+            },
+            KV {
+                key: VariableKey::from_str("key_4"),
+                value: 1,
+                version: curr_version + 1,
+                ts: 0,
+            },
+            KV {
+                key: VariableKey::from_str("key_5"),
+                value: 1,
+                version: curr_version + 2,
+                ts: 0,
+            },
+            KV {
+                key: VariableKey::from_str("key_6"),
+                value: 1,
+                version: 0,
+                ts: 0,
+            },
+        ];
+
+        assert!(tree.bulk_insert(&kv_pairs).is_ok());
+        assert!(tree.version() == curr_version + 2);
+
+        for kv in kv_pairs {
+            let (_, val, version, _) = tree.get(&kv.key, 0).unwrap();
+            assert_eq!(val, kv.value);
+            if kv.version == 0 {
+                assert_eq!(version, curr_version + 1);
+            } else {
+                assert_eq!(version, kv.version);
+            }
+        }
+        assert!(tree
+            .insert(&VariableKey::from_str("key_7"), 1, 0, 0)
+            .is_ok());
+        assert!(tree.version() == curr_version + 3);
+    }
+
+    #[test]
+    fn latest_ts() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+
+        let key1 = VariableKey::from_str("key_1");
+        let key2 = VariableKey::from_str("key_2");
+        tree.insert(&key1, 1, 0, 5).unwrap();
+        tree.insert(&key1, 2, 0, 9).unwrap();
+        tree.insert(&key2, 3, 0, 7).unwrap();
+
+        // latest_ts should match the ts returned alongside get
+        for key in [&key1, &key2] {
+            let (_, _, _, ts) = tree.get(key, 0).unwrap();
+            assert_eq!(tree.latest_ts(key), Some(ts));
+        }
+        assert_eq!(tree.latest_ts(&key1), Some(9));
+
+        // Absent keys have no timestamp
+        assert_eq!(tree.latest_ts(&VariableKey::from_str("key_3")), None);
+        assert_eq!(tree.latest_ts(&VariableKey::from_str("key")), None);
+    }
+
+    #[test]
+    fn split_off() {
+        let words = [
+            "a", "aa", "aal", "aalii", "abc", "abcd", "abcde", "axyz", "b", "bcd", "xyz",
+        ];
+        let split_keys = ["", "a", "aal", "ab", "abcd", "abd", "b", "c", "zzz"];
+
+        for split_key in split_keys {
+            let mut tree = Tree::<VariableKey, i32>::new();
+            for (i, word) in words.iter().enumerate() {
+                tree.insert(&VariableKey::from_str(word), i as i32, 0, 0)
+                    .unwrap();
+            }
+            let original: Vec<(Vec<u8>, i32)> = tree.iter().map(|(k, v, _, _)| (k, *v)).collect();
+
+            let split_key = VariableKey::from_str(split_key);
+            let right = tree.split_off(&split_key).unwrap();
+
+            let left_items: Vec<(Vec<u8>, i32)> = tree.iter().map(|(k, v, _, _)| (k, *v)).collect();
+            let right_items: Vec<(Vec<u8>, i32)> =
+                right.iter().map(|(k, v, _, _)| (k, *v)).collect();
+
+            // Keys land on the correct side of the split
+            assert!(left_items
+                .iter()
+                .all(|(k, _)| k < &split_key.to_slice().to_vec()));
+            assert!(right_items
+                .iter()
+                .all(|(k, _)| k >= &split_key.to_slice().to_vec()));
+
+            // The union of both halves equals the original tree
+            let mut union = left_items.clone();
+            union.extend(right_items);
+            assert_eq!(union, original);
+
+            // Both halves remain fully readable
+            for (k, v) in &union {
+                let key = VariableKey::from(k.clone());
+                let found = tree.get(&key, 0).or_else(|_| right.get(&key, 0));
+                assert_eq!(found.unwrap().1, *v);
+            }
+        }
+
+        // The boundary key lands in the right half
+        let mut tree = Tree::<FixedKey<8>, u16>::new();
+        for i in 0..300u16 {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
+        }
+        let boundary: FixedKey<8> = 150u16.into();
+        let right = tree.split_off(&boundary).unwrap();
+        assert!(tree.get(&boundary, 0).is_err());
+        assert_eq!(right.get(&boundary, 0).unwrap().1, 150);
+        assert_eq!(tree.iter().count(), 150);
+        assert_eq!(right.iter().count(), 150);
+    }
+
+    #[test]
+    fn estimate_len() {
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        assert_eq!(tree.estimate_len(), 0);
+
+        tree.insert(&1u64.into(), 1, 0, 0).unwrap();
+        assert_eq!(tree.estimate_len(), 1);
+
+        // Keys spread uniformly over the key space
+        let num_keys = 50_000u64;
+        let stride = u64::MAX / num_keys;
+        for i in 0..num_keys {
+            let key: FixedKey<16> = (i * stride).into();
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+
+        let estimate = tree.estimate_len() as f64;
+        let actual = tree.iter().count() as f64;
+        assert!(
+            (estimate - actual).abs() / actual < 0.25,
+            "estimate {} too far from actual {}",
+            estimate,
+            actual
+        );
+    }
+
+    #[test]
+    fn empty_key() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+
+        // The empty string is encoded as a lone terminator
+        let empty = VariableKey::from_str("");
+        let key_a = VariableKey::from_str("a");
+        tree.insert(&key_a, 2, 0, 0).unwrap();
+        tree.insert(&empty, 1, 0, 0).unwrap();
+
+        // Both keys are independently retrievable
+        assert_eq!(tree.get(&empty, 0).unwrap().1, 1);
+        assert_eq!(tree.get(&key_a, 0).unwrap().1, 2);
+
+        // The empty key iterates first
+        let keys: Vec<Vec<u8>> = tree.iter().map(|(k, _, _, _)| k).collect();
+        assert_eq!(keys, vec![vec![0, 1], vec![b'a', 0, 1]]);
+
+        // Zero-length keys are rejected rather than corrupting the tree
+        let zero_len = VariableKey::from(vec![]);
+        assert!(tree.insert(&zero_len, 3, 0, 0).is_err());
+        assert!(tree.get(&zero_len, 0).is_err());
+        assert!(!tree.remove(&zero_len).unwrap());
+        assert_eq!(tree.iter().count(), 2);
+
+        // FixedKey terminates the empty string as well
+        let mut tree = Tree::<FixedKey<8>, i32>::new();
+        tree.insert(&FixedKey::create_key(b"a"), 2, 0, 0).unwrap();
+        tree.insert(&FixedKey::create_key(b""), 1, 0, 0).unwrap();
+        assert_eq!(tree.get(&FixedKey::create_key(b""), 0).unwrap().1, 1);
+        assert!(tree.insert(&FixedKey::from_slice(&[]), 3, 0, 0).is_err());
+    }
+
+    #[test]
+    fn fixed_key_decode_round_trip() {
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+
+        let inputs: Vec<u64> = (0..1000u64).map(|i| i * 7_919 + (i << 40)).collect();
+        for i in &inputs {
+            tree.insert(&(*i).into(), *i, 0, 0).unwrap();
+        }
+
+        // Iterated keys decode back to the inserted integers, in order
+        let decoded: Vec<u64> = tree
+            .iter()
+            .map(|(k, v, _, _)| {
+                let n = FixedKey::<16>::to_u64(&k);
+                assert_eq!(n, *v);
+                n
+            })
+            .collect();
+        let mut expected = inputs.clone();
+        expected.sort();
+        assert_eq!(decoded, expected);
+
+        // Narrower and wider encodings round-trip too
+        let key: FixedKey<16> = 0xABu8.into();
+        assert_eq!(FixedKey::<16>::to_u64(key.as_slice()), 0xAB);
+        let key: FixedKey<16> = u128::MAX.into();
+        assert_eq!(FixedKey::<16>::to_u128(key.as_slice()), u128::MAX);
+        assert_eq!(FixedKey::<16>::decode(key.as_slice()), u128::MAX);
+    }
+
+    #[test]
+    fn iter_nodes() {
+        let mut tree = Tree::<FixedKey<16>, u16>::new();
+        assert_eq!(tree.iter_nodes(3).count(), 0);
+
+        for i in 0..u16::MAX {
+            let key: FixedKey<16> = i.into();
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+
+        // Only the root is visited at depth 0
+        let nodes: Vec<_> = tree.iter_nodes(0).collect();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].0, 0);
+        assert_eq!(nodes[0].2, NodeKind::Node256);
+
+        // The nodes at depth 1 match the root's children
+        let root = tree.root.as_ref().unwrap();
+        let expected: Vec<(Vec<u8>, usize)> = root
+            .iter()
+            .map(|(_, child)| (child.prefix().as_slice().to_vec(), child.num_children()))
+            .collect();
+        let depth1: Vec<(Vec<u8>, usize)> = tree
+            .iter_nodes(1)
+            .filter(|(depth, _, _, _)| *depth == 1)
+            .map(|(_, prefix, _, num_children)| (prefix, num_children))
+            .collect();
+        assert_eq!(depth1, expected);
+
+        // Twigs are never yielded and nothing beyond the depth limit is visited
+        assert!(tree
+            .iter_nodes(10)
+            .all(|(depth, _, kind, _)| depth <= 1 && kind != NodeKind::Twig));
+    }
+
+    fn node_kind_for_fan_out(options: TreeOptions, fan_out: u8) -> (Vec<NodeKind>, Vec<NodeKind>) {
+        let mut tree = Tree::<VariableKey, u8>::with_options(options);
+        let mut grown = Vec::new();
+        for i in 0..fan_out {
+            tree.insert(&VariableKey::from_slice(&[i, 0]), i, 0, 0)
+                .unwrap();
+            grown.push(tree.root.as_ref().unwrap().node_kind());
+        }
+
+        // Walk back down, recording the root kind while at least two children remain
+        let mut shrunk = Vec::new();
+        for i in (2..fan_out).rev() {
+            tree.remove(&VariableKey::from_slice(&[i, 0])).unwrap();
+            shrunk.push(tree.root.as_ref().unwrap().node_kind());
+        }
+        shrunk.reverse();
+        (grown, shrunk)
+    }
+
+    #[test]
+    fn tree_options_node_growth() {
+        // The defaults follow the classic Node4, Node16, Node48, Node256 progression. A node
+        // grows as soon as it fills up, and shrinks once it drops below the next size down.
+        let (grown, shrunk) = node_kind_for_fan_out(TreeOptions::default(), 50);
+        assert_eq!(grown[1], NodeKind::Node4);
+        assert_eq!(grown[2], NodeKind::Node4);
+        assert_eq!(grown[3], NodeKind::Node16);
+        assert_eq!(grown[15], NodeKind::Node48);
+        assert_eq!(grown[47], NodeKind::Node256);
+        assert_eq!(shrunk[2], NodeKind::Node4);
+        assert_eq!(shrunk[3], NodeKind::Node16);
+        assert_eq!(shrunk[15], NodeKind::Node48);
+        assert_eq!(shrunk[46], NodeKind::Node48);
+        assert_eq!(shrunk[47], NodeKind::Node256);
+
+        // A wider small node and no Node48
+        let options = TreeOptions::new()
+            .with_small_node_width(8)
+            .with_skip_node48(true);
+        let (grown, shrunk) = node_kind_for_fan_out(options, 20);
+        assert_eq!(grown[1], NodeKind::Node8);
+        assert_eq!(grown[6], NodeKind::Node8);
+        assert_eq!(grown[7], NodeKind::Node16);
+        assert_eq!(grown[14], NodeKind::Node16);
+        assert_eq!(grown[15], NodeKind::Node256);
+        assert!(!grown.contains(&NodeKind::Node4));
+        assert!(!grown.contains(&NodeKind::Node48));
+        assert_eq!(shrunk[0], NodeKind::Node8);
+        assert_eq!(shrunk[6], NodeKind::Node8);
+        assert_eq!(shrunk[7], NodeKind::Node16);
+        assert_eq!(shrunk[14], NodeKind::Node16);
+        assert_eq!(shrunk[15], NodeKind::Node256);
+        assert!(!shrunk.contains(&NodeKind::Node48));
+
+        // Every key remains reachable after growing through the custom layout
+        let options = TreeOptions::new().with_skip_node48(true);
+        let mut tree = Tree::<VariableKey, u8>::with_options(options);
+        for i in 0..=u8::MAX {
+            tree.insert(&VariableKey::from_slice(&[i, 0]), i, 0, 0)
+                .unwrap();
+        }
+        for i in 0..=u8::MAX {
+            let (_, v, _, _) = tree.get(&VariableKey::from_slice(&[i, 0]), 0).unwrap();
+            assert_eq!(v, i);
+        }
+    }
+
+    #[test]
+    fn tree_eq() {
+        let words = [
+            "apple",
+            "apricot",
+            "banana",
+            "blueberry",
+            "cherry",
+            "cranberry",
+        ];
+
+        let mut a = Tree::<VariableKey, i32>::new();
+        // A differently laid out tree, filled in reverse order
+        let mut b = Tree::<VariableKey, i32>::with_options(
+            TreeOptions::new()
+                .with_small_node_width(8)
+                .with_skip_node48(true),
+        );
+        assert!(a == b);
+
+        for (i, word) in words.iter().enumerate() {
+            a.insert(&VariableKey::from_str(word), i as i32, 0, 0)
+                .unwrap();
+        }
+        for (i, word) in words.iter().enumerate().rev() {
+            b.insert(&VariableKey::from_str(word), i as i32, 0, 0)
+                .unwrap();
+        }
+        assert!(a == b);
+        assert!(a.contents_eq(&b));
+
+        // A tree always equals itself and its clone by snapshot root
+        let mut c = Tree::<VariableKey, i32>::new();
+        c.root = a.root.clone();
+        assert!(a.history_eq(&c));
+
+        // Differing values
+        b.insert(&VariableKey::from_str("cherry"), 100, 0, 0)
+            .unwrap();
+        assert!(a != b);
+        b.insert(&VariableKey::from_str("cherry"), 4, 0, 0).unwrap();
+        assert!(a == b);
+        // Same contents, different history
+        assert!(!a.history_eq(&b));
+
+        // Differing keys
+        c.insert(&VariableKey::from_str("date"), 6, 0, 0).unwrap();
+        assert!(a != c);
+        assert!(!a.history_eq(&c));
+        c.remove(&VariableKey::from_str("date")).unwrap();
+        assert!(a == c);
+
+        let empty = Tree::<VariableKey, i32>::new();
+        assert!(a != empty);
+        assert!(empty != a);
+    }
+
+    #[test]
+    fn merge_iter() {
+        let mut newest = Tree::<VariableKey, &str>::new();
+        let mut middle = Tree::<VariableKey, &str>::new();
+        let mut oldest = Tree::<VariableKey, &str>::new();
+        for key in ["b", "d"] {
+            newest
+                .insert(&VariableKey::from_str(key), "newest", 0, 0)
+                .unwrap();
+        }
+        for key in ["a", "b", "c", "e"] {
+            middle
+                .insert(&VariableKey::from_str(key), "middle", 0, 0)
+                .unwrap();
+        }
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            oldest
+                .insert(&VariableKey::from_str(key), "oldest", 0, 0)
+                .unwrap();
+        }
+
+        let merged: Vec<(Vec<u8>, &str)> = Tree::merge_iter(&[&newest, &middle, &oldest])
+            .map(|(key, value, _)| (key, *value))
+            .collect();
+        let expected: Vec<(Vec<u8>, &str)> = [
+            ("a", "middle"),
+            ("b", "newest"),
+            ("c", "middle"),
+            ("d", "newest"),
+            ("e", "middle"),
+            ("f", "oldest"),
+        ]
+        .iter()
+        .map(|(key, value)| (VariableKey::from_str(key).as_slice().to_vec(), *value))
+        .collect();
+        assert_eq!(merged, expected);
+
+        // Reversing the priority lets the oldest tree shadow all others
+        assert!(Tree::merge_iter(&[&oldest, &middle, &newest]).all(|(_, v, _)| *v == "oldest"));
+
+        // Empty trees contribute nothing
+        let empty = Tree::<VariableKey, &str>::new();
+        assert_eq!(Tree::merge_iter(&[&empty, &newest, &empty]).count(), 2);
+        assert_eq!(Tree::<VariableKey, &str>::merge_iter(&[]).count(), 0);
+    }
+
+    #[test]
+    fn shared_node_count() {
+        let mut tree = Tree::<FixedKey<16>, u16>::new();
+        for i in 0..1000u16 {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
+        }
+
+        // A fresh snapshot shares every node
+        let snap = tree.create_snapshot().unwrap();
+        let (shared, unique) = tree.shared_node_count(&snap);
+        assert!(shared > 1000);
+        assert_eq!(unique, 0);
 
-        if self.root.is_none() {
-            return Err(TrieError::Other("cannot read from empty tree".to_string()));
-        }
+        // Updating a single key copies only the nodes on its path from the root
+        let key: FixedKey<16> = 500u16.into();
+        tree.insert(&key, 0, 0, 0).unwrap();
+        let (new_shared, unique) = tree.shared_node_count(&snap);
 
-        let root = self.root.as_ref().unwrap();
-        let mut commit_version = version;
-        if commit_version == 0 {
-            commit_version = root.version();
+        let mut path_len = 0;
+        let mut depth = 0;
+        let mut node = tree.root.as_ref();
+        while let Some(cur) = node {
+            path_len += 1;
+            if cur.is_twig() {
+                break;
+            }
+            depth += cur.prefix().len();
+            node = cur.find_child(key.at(depth));
         }
-
-        Node::get_recurse(root, key, commit_version)
+        assert_eq!(unique, path_len);
+        assert_eq!(new_shared + unique, shared);
     }
 
-    /// Retrieves the latest version of the Trie.
-    ///
-    /// This function returns the version of the latest version of the Trie. If the Trie is empty,
-    /// it returns `0`.
-    ///
-    /// # Returns
-    ///
-    /// Returns the version of the latest version of the Trie, or `0` if the Trie is empty.
-    ///
-    pub fn version(&self) -> u64 {
-        match &self.root {
-            None => 0,
-            Some(root) => root.version(),
+    #[test]
+    fn drain() {
+        let mut tree = Tree::<FixedKey<16>, u16>::new();
+        for i in 0..1000u16 {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
         }
+        let snap = tree.create_snapshot().unwrap();
+
+        // Dropping the iterator early leaves the rest of the entries in place
+        let first: Vec<_> = tree.drain().take(10).collect();
+        assert_eq!(first.len(), 10);
+        for (i, (key, value, version)) in first.into_iter().enumerate() {
+            assert_eq!(key, FixedKey::<16>::from(i as u16).as_slice());
+            assert_eq!(value, i as u16);
+            assert_eq!(version, i as u64 + 1);
+        }
+        assert_eq!(tree.iter().count(), 990);
+        assert!(tree.get(&5u16.into(), 0).is_err());
+        assert!(tree.get(&10u16.into(), 0).is_ok());
+
+        // Draining the rest yields every remaining entry once and empties the tree
+        let values: Vec<u16> = tree.drain().map(|(_, value, _)| value).collect();
+        assert_eq!(values, (10..1000).collect::<Vec<u16>>());
+        assert!(tree.root.is_none());
+        assert_eq!(tree.iter().count(), 0);
+        assert_eq!(tree.drain().count(), 0);
+
+        // The snapshot still holds its own view
+        assert_eq!(snap.get(&500u16.into(), 0).unwrap().0, 500);
     }
 
-    /// Creates a new snapshot of the Trie.
-    ///
-    /// This function creates a snapshot of the current state of the Trie. If successful, it returns
-    /// a `Snapshot` that can be used to interact with the newly created snapshot.
-    ///
-    /// # Returns
-    ///
-    /// Returns a `Result` containing the `Snapshot` if the snapshot is created successfully,
-    /// or an `Err` with an appropriate error message if creation fails.
-    ///
-    pub fn create_snapshot(&mut self) -> Result<Snapshot<P, V>, TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    #[test]
+    fn keys_and_values() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+        assert_eq!(tree.keys().count(), 0);
+        assert_eq!(tree.values().count(), 0);
+
+        let words = ["abc", "abd", "b", "bcd", "xyz"];
+        for (i, word) in words.iter().enumerate() {
+            let key = VariableKey::from_str(word);
+            tree.insert(&key, i as i32, 0, 0).unwrap();
+            // A second version of the key must not produce a duplicate
+            tree.insert(&key, i as i32 * 10, 0, 0).unwrap();
+        }
 
-        if self.snapshots.len() >= self.max_active_snapshots as usize {
-            return Err(TrieError::Other(
-                "max number of snapshots reached".to_string(),
-            ));
+        let keys: Vec<Vec<u8>> = tree.keys().collect();
+        let iter_keys: Vec<Vec<u8>> = tree.iter().map(|(key, _, _, _)| key).collect();
+        assert_eq!(keys, iter_keys);
+        assert_eq!(keys.len(), words.len());
+
+        let values: Vec<i32> = tree.values().copied().collect();
+        assert_eq!(values, vec![0, 10, 20, 30, 40]);
+    }
+
+    #[cfg(feature = "bloom")]
+    #[test]
+    fn bloom_filter() {
+        let options = TreeOptions::new().with_bloom_filter(1000);
+        let mut tree = Tree::<FixedKey<16>, u64>::with_options(options);
+        for i in (0..2000u64).step_by(2) {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
         }
 
-        // Increment the snapshot ID atomically
-        let new_snapshot_id = self.max_snapshot_id.fetch_add(1, Ordering::SeqCst);
-        self.snapshots.insert(new_snapshot_id);
+        // Inserted keys are always found, absent keys are not
+        for i in 0..2000u64 {
+            let result = tree.get(&i.into(), 0);
+            if i % 2 == 0 {
+                assert_eq!(result.unwrap().1, i);
+            } else {
+                assert!(matches!(result, Err(super::TrieError::KeyNotFound)));
+            }
+        }
 
-        let root = self.root.as_ref().cloned();
-        let version = self.root.as_ref().map_or(1, |root| root.version() + 1);
-        let new_snapshot = Snapshot::new(new_snapshot_id, root, version);
+        // Removed keys stay in the filter until it is rebuilt
+        for i in (0..1000u64).step_by(2) {
+            tree.remove(&i.into()).unwrap();
+        }
+        let key: FixedKey<16> = 0u64.into();
+        assert!(tree.bloom.as_ref().unwrap().may_contain(key.as_slice()));
+        tree.rebuild_bloom_filter();
+        assert!(!tree.bloom.as_ref().unwrap().may_contain(key.as_slice()));
+        assert!(tree.get(&key, 0).is_err());
+        assert_eq!(tree.get(&1000u64.into(), 0).unwrap().1, 1000);
 
-        Ok(new_snapshot)
+        // Both halves of a split keep finding their keys
+        let right = tree.split_off(&1500u64.into()).unwrap();
+        assert_eq!(tree.get(&1498u64.into(), 0).unwrap().1, 1498);
+        assert_eq!(right.get(&1500u64.into(), 0).unwrap().1, 1500);
     }
 
-    /// Closes a snapshot and removes it from the list of active snapshots.
-    ///
-    /// This function takes a `snapshot_id` as an argument and closes the corresponding snapshot.
-    /// If the snapshot exists, it is removed from the active snapshots list. If the snapshot is not
-    /// found, an `Err` is returned with a `TrieError::SnapshotNotFound` variant.
-    ///
-    /// # Arguments
-    ///
-    /// * `snapshot_id` - The ID of the snapshot to be closed and removed.
-    ///
-    /// # Returns
-    ///
-    /// Returns `Ok(())` if the snapshot is successfully closed and removed. Returns an `Err`
-    /// with `TrieError::SnapshotNotFound` if the snapshot with the given ID is not found.
-    ///
-    pub(crate) fn close_snapshot(&mut self, snapshot_id: u64) -> Result<(), TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    #[cfg(feature = "stream")]
+    #[test]
+    fn stream() {
+        use std::future::poll_fn;
+        use std::pin::Pin;
+        use std::task::Poll;
 
-        if self.snapshots.remove(&snapshot_id) {
-            Ok(())
-        } else {
-            Err(TrieError::SnapshotNotFound)
+        use futures_core::Stream;
+
+        let mut tree = Tree::<FixedKey<16>, u16>::new();
+        for i in 0..1000u16 {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
         }
-    }
 
-    /// Returns the count of active snapshots.
-    ///
-    /// This function returns the number of currently active snapshots in the Trie.
-    ///
-    /// # Returns
-    ///
-    /// Returns a `Result` containing the count of active snapshots if successful, or an `Err`
-    /// if there is an issue retrieving the snapshot count.
-    ///
-    pub fn snapshot_count(&self) -> usize {
-        self.snapshots.len()
+        // Poll the stream to completion, counting how often it yields to the runtime
+        let mut stream = tree.stream();
+        let mut items = Vec::new();
+        let mut pending = 0;
+        futures_executor::block_on(poll_fn(|cx| loop {
+            match Pin::new(&mut stream).poll_next(cx) {
+                Poll::Ready(Some((k, v, ver, _))) => items.push((k, *v, *ver)),
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => {
+                    pending += 1;
+                    return Poll::Pending;
+                }
+            }
+        }));
+
+        let expected: Vec<_> = tree.iter().map(|(k, v, ver, _)| (k, *v, *ver)).collect();
+        assert_eq!(items, expected);
+        assert!(pending > 0);
     }
 
-    /// Creates an iterator over the Trie's key-value pairs.
-    ///
-    /// This function creates and returns an iterator that can be used to traverse the key-value pairs
-    /// stored in the Trie. The iterator starts from the root of the Trie.
-    ///
-    /// # Returns
-    ///
-    /// Returns an `Iter` instance that iterates over the key-value pairs in the Trie.
-    ///
-    pub fn iter(&self) -> Iter<P, V> {
-        Iter::new(self.root.as_ref())
+    #[test]
+    fn get_all_at_ts() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+        let key_a = VariableKey::from_str("a");
+        let key_b = VariableKey::from_str("b");
+        let missing = VariableKey::from_str("c");
+        assert_eq!(
+            tree.get_all_at_ts(std::slice::from_ref(&key_a), 0).unwrap(),
+            vec![None]
+        );
+
+        tree.insert(&key_a, 1, 0, 0).unwrap();
+        tree.insert(&key_b, 1, 0, 0).unwrap();
+        let read_version = tree.version();
+
+        // Read the first key, then write the second before reading it
+        let first = tree
+            .get_all_at_ts(std::slice::from_ref(&key_a), read_version)
+            .unwrap();
+        tree.insert(&key_b, 2, 0, 0).unwrap();
+        tree.insert(&key_a, 2, 0, 0).unwrap();
+        let both = tree
+            .get_all_at_ts(
+                &[key_a.clone(), key_b.clone(), missing.clone()],
+                read_version,
+            )
+            .unwrap();
+        assert_eq!(first, vec![Some(1)]);
+        assert_eq!(both, vec![Some(1), Some(1), None]);
+
+        // Reading at the latest version sees both writes
+        assert_eq!(
+            tree.get_all_at_ts(&[key_a, key_b, missing], 0).unwrap(),
+            vec![Some(2), Some(2), None]
+        );
     }
 
-    /// Returns an iterator over a range of key-value pairs within the Trie.
-    ///
-    /// This function creates and returns an iterator that iterates over key-value pairs in the Trie,
-    /// starting from the provided `start_key` and following the specified `range` bounds. The iterator
-    /// iterates within the specified key range.
-    ///
-    /// # Arguments
-    ///
-    /// * `range` - A range that specifies the bounds for iterating over key-value pairs.
-    ///
-    /// # Returns
-    ///
-    /// Returns a `Range` iterator instance that iterates over the key-value pairs within the given range.
-    /// If the Trie is empty, an empty `Range` iterator is returned.
-    ///
-    pub fn range<'a, R>(
-        &'a self,
-        range: R,
-    ) -> impl Iterator<Item = (Vec<u8>, &'a V, &'a u64, &'a u64)>
-    where
-        R: RangeBounds<P> + 'a,
-    {
-        // If the Trie is empty, return an empty Range iterator
-        if self.root.is_none() {
-            return Range::empty(range);
+    #[cfg(feature = "trace")]
+    #[test]
+    fn get_with_trace() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+        for (i, word) in ["abc", "abd", "b"].iter().enumerate() {
+            tree.insert(&VariableKey::from_str(word), i as i32, 0, 0)
+                .unwrap();
         }
 
-        let root = self.root.as_ref();
-        return Range::new(root, range);
+        let step = |kind, matched: &[u8], byte, child_found| TraceStep {
+            kind,
+            matched: matched.to_vec(),
+            byte,
+            child_found,
+        };
+
+        let (value, trace) = tree
+            .get_with_trace(&VariableKey::from_str("abd"), 0)
+            .unwrap();
+        assert_eq!(value, Some(1));
+        assert_eq!(
+            trace,
+            vec![
+                step(NodeKind::Node4, b"", Some(b'a'), true),
+                step(NodeKind::Node4, b"ab", Some(b'd'), true),
+                step(NodeKind::Twig, b"d\0\x01", None, false),
+            ]
+        );
+
+        // The trace shows where the lookup of a missing key diverged
+        let (value, trace) = tree
+            .get_with_trace(&VariableKey::from_str("abx"), 0)
+            .unwrap();
+        assert_eq!(value, None);
+        assert_eq!(
+            trace.last(),
+            Some(&step(NodeKind::Node4, b"ab", Some(b'x'), false))
+        );
+
+        let (value, trace) = tree
+            .get_with_trace(&VariableKey::from_str("bc"), 0)
+            .unwrap();
+        assert_eq!(value, None);
+        assert_eq!(trace.last(), Some(&step(NodeKind::Twig, b"b", None, false)));
+
+        // Keys are translated under a byte permutation, and a closed Trie cannot be traced
+        let mut tree =
+            Tree::<VariableKey, i32>::with_options(TreeOptions::new().with_byte_permutation(3));
+        for (i, word) in ["abc", "abd", "b"].iter().enumerate() {
+            tree.insert(&VariableKey::from_str(word), i as i32, 0, 0)
+                .unwrap();
+        }
+        let (value, trace) = tree
+            .get_with_trace(&VariableKey::from_str("abd"), 0)
+            .unwrap();
+        assert_eq!(value, Some(1));
+        assert_eq!(trace.len(), 3);
+        assert_eq!(
+            tree.avg_get_depth(&[VariableKey::from_str("b")]).unwrap(),
+            2.0
+        );
+        tree.close().unwrap();
+        assert!(tree
+            .get_with_trace(&VariableKey::from_str("abd"), 0)
+            .is_err());
+        assert!(tree.avg_get_depth(&[]).is_err());
     }
 
-    fn is_closed(&self) -> Result<(), TrieError> {
-        if self.closed {
-            return Err(TrieError::SnapshotAlreadyClosed);
+    #[cfg(feature = "trace")]
+    #[test]
+    fn avg_get_depth() {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random_bytes = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        };
+
+        let mut random = Tree::<FixedKey<16>, usize>::new();
+        let random_keys: Vec<FixedKey<16>> = (0..1000)
+            .map(|_| FixedKey::from_slice(&random_bytes(16)))
+            .collect();
+        for (i, key) in random_keys.iter().enumerate() {
+            random.insert(key, i, 0, 0).unwrap();
         }
-        Ok(())
+        let depth = random.avg_get_depth(&random_keys).unwrap();
+        assert!((2.0..=4.0).contains(&depth), "depth {}", depth);
+
+        // A long shared prefix is compressed into a single node rather than a chain of them
+        let mut prefixed = Tree::<VariableKey, usize>::new();
+        let prefix = "p".repeat(200);
+        let prefixed_keys: Vec<VariableKey> = (0..1000)
+            .map(|i| VariableKey::from_str(&format!("{}{}", prefix, i)))
+            .collect();
+        for (i, key) in prefixed_keys.iter().enumerate() {
+            prefixed.insert(key, i, 0, 0).unwrap();
+        }
+        let depth = prefixed.avg_get_depth(&prefixed_keys).unwrap();
+        assert!((2.0..=5.0).contains(&depth), "depth {}", depth);
+
+        assert_eq!(prefixed.avg_get_depth(&[]).unwrap(), 0.0);
+        assert_eq!(
+            Tree::<VariableKey, usize>::new()
+                .avg_get_depth(&prefixed_keys)
+                .unwrap(),
+            0.0
+        );
     }
 
-    /// Closes the tree, preventing further modifications, and releases associated resources.
-    pub fn close(&mut self) -> Result<(), TrieError> {
-        // Check if the tree is already closed
-        self.is_closed()?;
+    #[test]
+    fn no_history() {
+        let mut tree =
+            Tree::<VariableKey, i32>::with_options(TreeOptions::new().with_keep_history(false));
+        let words = ["a", "ab", "abc", "b"];
+        for round in 0..5 {
+            for word in words {
+                tree.insert(&VariableKey::from_str(word), round, 0, 0)
+                    .unwrap();
+            }
+        }
 
-        // Check if there are any active readers for the snapshot
-        if self.snapshot_count() > 0 {
-            return Err(TrieError::SnapshotNotClosed);
+        // Every key holds a single value, and older versions read that same value
+        for word in words {
+            let key = VariableKey::from_str(word);
+            let twig = Node::find_twig(tree.root.as_ref().unwrap(), &key).unwrap();
+            assert_eq!(twig.values.len(), 1);
+            assert_eq!(tree.get(&key, 1).unwrap().1, 4);
+            assert_eq!(tree.get(&key, 0).unwrap().1, 4);
         }
 
-        // Mark the snapshot as closed
-        self.closed = true;
+        // The default keeps every version
+        let mut tree = Tree::<VariableKey, i32>::new();
+        let key = VariableKey::from_str("a");
+        for round in 0..5 {
+            tree.insert(&key, round, 0, 0).unwrap();
+        }
+        let twig = Node::find_twig(tree.root.as_ref().unwrap(), &key).unwrap();
+        assert_eq!(twig.values.len(), 5);
+        assert_eq!(tree.get(&key, 1).unwrap().1, 0);
+    }
 
-        Ok(())
+    #[test]
+    fn cursor() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+        assert!(tree.cursor().next().is_none());
+        assert!(tree.cursor().seek(&VariableKey::from_str("a")).is_none());
+
+        let words = ["abc", "abcd", "abd", "b", "bcd", "bce", "xyz"];
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(&VariableKey::from_str(word), i as i32, 0, 0)
+                .unwrap();
+        }
+        let key = |word: &str| VariableKey::from_str(word).as_slice().to_vec();
+
+        // Walking forward and backward visits every key in order
+        let mut cursor = tree.cursor();
+        assert!(cursor.current().is_none());
+        let mut forward = Vec::new();
+        while let Some((k, v)) = cursor.next() {
+            forward.push((k.to_vec(), *v));
+        }
+        let expected: Vec<_> = tree.iter().map(|(k, v, _, _)| (k, *v)).collect();
+        assert_eq!(forward, expected);
+        let mut backward = Vec::new();
+        while let Some((k, v)) = cursor.prev() {
+            backward.push((k.to_vec(), *v));
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+
+        // Seeking lands on the first key greater than or equal to the target
+        let mut cursor = tree.cursor();
+        assert_eq!(
+            cursor.seek(&VariableKey::from_str("abd")).unwrap().0,
+            key("abd")
+        );
+        assert_eq!(
+            cursor.seek(&VariableKey::from_slice(b"abc")).unwrap().0,
+            key("abc")
+        );
+        assert_eq!(
+            cursor.seek(&VariableKey::from_str("abce")).unwrap().0,
+            key("abd")
+        );
+        assert_eq!(
+            cursor.seek(&VariableKey::from_str("bc")).unwrap().0,
+            key("bcd")
+        );
+        assert_eq!(
+            cursor.seek(&VariableKey::from_slice(b"a")).unwrap().0,
+            key("abc")
+        );
+        assert_eq!(
+            cursor.seek(&VariableKey::from_str("c")).unwrap().0,
+            key("xyz")
+        );
+        assert!(cursor.seek(&VariableKey::from_str("xz")).is_none());
+
+        // Seek, next and prev return to the same item
+        let (k, v) = cursor.seek(&VariableKey::from_str("b")).unwrap();
+        assert_eq!((k.to_vec(), *v), (key("b"), 3));
+        assert_eq!(cursor.next().unwrap().0, key("bcd"));
+        assert_eq!(cursor.prev().unwrap().0, key("b"));
+        assert_eq!(cursor.current().unwrap().0, key("b"));
+
+        // Moving before the first key yields None
+        cursor.seek(&VariableKey::from_str("abc"));
+        assert!(cursor.prev().is_none());
+        assert!(cursor.current().is_none());
+        assert_eq!(cursor.next().unwrap().0, key("abc"));
+
+        // Seeking between keys of a deeper tree lands on the next key
+        let mut tree = Tree::<FixedKey<16>, u16>::new();
+        for i in (0..2000u16).step_by(2) {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
+        }
+        let mut cursor = tree.cursor();
+        for i in 0..1998u16 {
+            let (_, v) = cursor.seek(&i.into()).unwrap();
+            assert_eq!(*v, i + i % 2);
+        }
     }
-}
 
-/*
-    Test cases for Adaptive Radix Tree
-*/
+    #[test]
+    fn content_hash() {
+        let encode = |value: &u64| value.to_le_bytes().to_vec();
+        let build = |options: TreeOptions, value: u64| {
+            let mut tree = Tree::<FixedKey<16>, u64>::with_options(options);
+            for i in 0..500u64 {
+                tree.insert(&i.into(), if i == 250 { value } else { i }, 0, 0)
+                    .unwrap();
+            }
+            tree
+        };
 
-#[cfg(test)]
-mod tests {
-    use super::{Tree, KV};
-    use crate::{FixedKey, VariableKey};
+        let a = build(TreeOptions::new(), 250);
+        let b = build(TreeOptions::new().with_small_node_width(8), 250);
+        assert_eq!(a.content_hash(encode), b.content_hash(encode));
+        assert_ne!(
+            a.content_hash(encode),
+            Tree::<FixedKey<16>, u64>::new().content_hash(encode)
+        );
 
-    use std::fs::File;
-    use std::io::{self, BufRead, BufReader};
+        // A single differing value changes the hash
+        let c = build(TreeOptions::new(), 0);
+        assert_ne!(a.content_hash(encode), c.content_hash(encode));
 
-    fn read_words_from_file(file_path: &str) -> io::Result<Vec<String>> {
-        let file = File::open(file_path)?;
-        let reader = BufReader::new(file);
-        let words: Vec<String> = reader.lines().filter_map(|line| line.ok()).collect();
-        Ok(words)
+        // The hash is fixed by the encoding, so it never changes across builds
+        let mut tree = Tree::<VariableKey, u64>::new();
+        assert_eq!(tree.content_hash(encode), 0xcbf2_9ce4_8422_2325);
+        tree.insert(&VariableKey::from_str("a"), 1, 0, 7).unwrap();
+        assert_eq!(tree.content_hash(encode), 0xef8f_e3ac_bd8e_0bf5);
     }
 
     #[test]
-    fn insert_search_delete_words() {
-        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
-        let file_path = "testdata/words.txt";
+    fn add_child_grows_full_node() {
+        let options = TreeOptions::default();
+        let twig = |i: u8| {
+            let key = VariableKey::from_slice(&[i, 0]);
+            Node::<VariableKey, u8>::new_twig(key.clone(), key, LeafValue::new(i, 0, 0), &options)
+        };
 
-        if let Ok(words) = read_words_from_file(file_path) {
-            // Insertion phase
-            for word in &words {
-                let key = &VariableKey::from_str(word);
-                tree.insert(key, 1, 0, 0);
-            }
+        // A Node4 holding four children, which the tree itself never leaves behind
+        let mut n4 =
+            FlatNode::<VariableKey, Node<VariableKey, u8>, 4>::new(VariableKey::from_slice(&[]));
+        for i in 0..4 {
+            n4 = n4.add_child(i, twig(i));
+        }
+        let node = Node {
+            node_type: NodeType::Node4(n4),
+        };
 
-            // Search phase
-            for word in &words {
-                let key = VariableKey::from_str(word);
-                let (_, val, _, _) = tree.get(&key, 0).unwrap();
-                assert_eq!(val, 1);
-            }
+        let node = node.add_child(4, twig(4), &options);
+        assert_eq!(node.node_kind(), NodeKind::Node16);
+        assert_eq!(node.num_children(), 5);
+        for i in 0..5 {
+            assert!(node.find_child(i).is_some());
+        }
+    }
 
-            // Deletion phase
-            for word in &words {
-                let key = VariableKey::from_str(word);
-                assert!(tree.remove(&key).unwrap());
-            }
-        } else if let Err(err) = read_words_from_file(file_path) {
-            eprintln!("Error reading file: {}", err);
+    #[test]
+    fn kv_adapters() {
+        let mut tree = Tree::<VariableKey, u32>::new();
+        for (i, key) in ["apple", "banana", "cherry", "date"].iter().enumerate() {
+            let key = VariableKey::from_str(key);
+            tree.insert(&key, i as u32, 0, 0).unwrap();
+            tree.insert(&key, i as u32 + 10, 0, 0).unwrap();
         }
 
-        assert_eq!(tree.version(), 0);
+        let expected: Vec<_> = tree.iter().map(|(k, v, _, _)| (k, v)).collect();
+        assert_eq!(tree.iter().kv().collect::<Vec<_>>(), expected);
+        assert_eq!(expected.len(), 4);
+        assert_eq!(*expected[0].1, 10);
+
+        let start = VariableKey::from_str("banana");
+        let end = VariableKey::from_str("date");
+        let expected: Vec<_> = tree
+            .range(start.clone()..end.clone())
+            .map(|(k, v, _, _)| (k, v))
+            .collect();
+        assert_eq!(tree.range(start..end).kv().collect::<Vec<_>>(), expected);
+        assert_eq!(expected.len(), 2);
     }
 
     #[test]
-    fn string_insert_delete() {
-        let mut tree = Tree::<VariableKey, i32>::new();
+    fn long_common_prefix() {
+        // Prefixes are stored in the key type itself, so a compressed path can be as long as
+        // the longest key the type can hold.
+        let shared = "a-rather-long-shared-prefix/";
+        let suffixes = ["x", "y", "yy", "z0", "z1"];
+
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for (i, suffix) in suffixes.iter().enumerate() {
+            let key = VariableKey::from_str(&format!("{}{}", shared, suffix));
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+        assert!(tree.root.as_ref().unwrap().prefix().len() >= shared.len());
+        for (i, suffix) in suffixes.iter().enumerate() {
+            let key = VariableKey::from_str(&format!("{}{}", shared, suffix));
+            assert_eq!(tree.get(&key, 0).unwrap().1, i);
+        }
+        assert!(tree
+            .get(&VariableKey::from_str(&format!("{}w", shared)), 0)
+            .is_err());
 
-        // Insertion phase
-        let insert_words = [
-            "a", "aa", "aal", "aalii", "abc", "abcd", "abcde", "xyz", "axyz",
-        ];
+        let mut tree = Tree::<FixedKey<32>, usize>::new();
+        for (i, suffix) in suffixes.iter().enumerate() {
+            let key = FixedKey::<32>::from_str(&format!("{}{}", shared, suffix));
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+        assert!(tree.root.as_ref().unwrap().prefix().len() >= shared.len());
+        for (i, suffix) in suffixes.iter().enumerate() {
+            let key = FixedKey::<32>::from_str(&format!("{}{}", shared, suffix));
+            assert_eq!(tree.get(&key, 0).unwrap().1, i);
+        }
 
-        for word in &insert_words {
-            tree.insert(&VariableKey::from_str(word), 1, 0, 0);
+        // Removing keys re-merges the long prefixes without losing the siblings
+        tree.remove(&FixedKey::<32>::from_str(&format!("{}yy", shared)))
+            .unwrap();
+        for (i, suffix) in suffixes.iter().enumerate().filter(|(_, s)| **s != "yy") {
+            let key = FixedKey::<32>::from_str(&format!("{}{}", shared, suffix));
+            assert_eq!(tree.get(&key, 0).unwrap().1, i);
         }
+    }
 
-        // Deletion phase
-        for word in &insert_words {
-            assert!(tree.remove(&VariableKey::from_str(word)).unwrap());
+    #[test]
+    fn snapshot_values_at() {
+        let mut tree = Tree::<VariableKey, &str>::new();
+        let a = VariableKey::from_str("a");
+        let b = VariableKey::from_str("b");
+        let c = VariableKey::from_str("c");
+
+        tree.insert(&a, "a1", 0, 10).unwrap();
+        tree.insert(&b, "b1", 0, 20).unwrap();
+        tree.insert(&a, "a2", 0, 30).unwrap();
+        tree.insert(&c, "c1", 0, 40).unwrap();
+        tree.insert(&b, "b2", 0, 50).unwrap();
+
+        let at = |ts| -> Vec<(Vec<u8>, &str)> { tree.snapshot_values_at(ts).collect() };
+        let key = |k: &VariableKey| k.as_slice().to_vec();
+
+        assert!(at(5).is_empty());
+        assert_eq!(at(10), vec![(key(&a), "a1")]);
+        assert_eq!(at(25), vec![(key(&a), "a1"), (key(&b), "b1")]);
+        assert_eq!(at(30), vec![(key(&a), "a2"), (key(&b), "b1")]);
+        assert_eq!(
+            at(45),
+            vec![(key(&a), "a2"), (key(&b), "b1"), (key(&c), "c1")]
+        );
+        assert_eq!(
+            at(u64::MAX),
+            vec![(key(&a), "a2"), (key(&b), "b2"), (key(&c), "c1")]
+        );
+    }
+
+    #[test]
+    fn range_limited() {
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        for i in 0..1000u64 {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
+        }
+
+        let start: FixedKey<16> = 100u64.into();
+        let end: FixedKey<16> = 900u64.into();
+        for limit in [0, 1, 10, 500, 800, 2000] {
+            let limited: Vec<_> = tree
+                .range_limited(start.clone()..end.clone(), limit)
+                .collect();
+            let taken: Vec<_> = tree.range(start.clone()..end.clone()).take(limit).collect();
+            assert_eq!(limited, taken);
         }
+
+        // Draining a limited range visits no more nodes than taking from a plain range, and
+        // far fewer than the full scan
+        let mut limited = tree.range_limited(start.clone()..end.clone(), 10);
+        assert_eq!(limited.by_ref().count(), 10);
+        let mut taken = tree.range(start.clone()..end.clone());
+        assert_eq!(taken.by_ref().take(10).count(), 10);
+        let mut full = tree.range(start..end);
+        assert_eq!(full.by_ref().count(), 800);
+        assert_eq!(limited.visited, taken.visited);
+        assert!(limited.visited * 5 < full.visited);
     }
 
     #[test]
-    fn string_long() {
-        let mut tree = Tree::<VariableKey, i32>::new();
+    fn iter_distinct_prefix() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        let mut keys = Vec::new();
+        for user in 0..20 {
+            for ts in 0..50 {
+                keys.push(format!("{:04}:{:04}", user * 7, 1000 - ts * 3));
+            }
+        }
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(&VariableKey::from_str(key), i, 0, 0).unwrap();
+        }
 
-        // Insertion phase
-        let words_to_insert = [
-            ("amyelencephalia", 1),
-            ("amyelencephalic", 2),
-            ("amyelencephalous", 3),
-        ];
+        // One result per user, holding the smallest key of that user
+        let mut distinct = tree.iter_distinct_prefix(4);
+        let firsts: Vec<_> = distinct.by_ref().map(|(k, _, _, _)| k).collect();
+        let mut expected: Vec<_> = (0..20)
+            .map(|user| {
+                VariableKey::from_str(&format!("{:04}:{:04}", user * 7, 1000 - 49 * 3))
+                    .as_slice()
+                    .to_vec()
+            })
+            .collect();
+        expected.sort();
+        assert_eq!(firsts, expected);
+
+        // The rest of each group is skipped rather than walked
+        let mut all = tree.iter_distinct_prefix(usize::MAX);
+        assert_eq!(all.by_ref().count(), keys.len());
+        assert!(distinct.visited * 10 < all.visited);
+
+        // Grouping by the full key length yields every key, grouping by nothing just the first
+        let mut sorted: Vec<_> = tree.keys().collect();
+        sorted.sort();
+        assert_eq!(
+            tree.iter_distinct_prefix(9)
+                .map(|(k, _, _, _)| k)
+                .collect::<Vec<_>>(),
+            sorted
+        );
+        assert_eq!(
+            tree.iter_distinct_prefix(0)
+                .map(|(k, _, _, _)| k)
+                .collect::<Vec<_>>(),
+            vec![sorted[0].clone()]
+        );
+    }
 
-        for (word, val) in &words_to_insert {
-            tree.insert(&VariableKey::from_str(word), *val, 0, 0);
+    #[test]
+    fn seal() {
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        for i in 0..2000u64 {
+            tree.insert(&(i * 3).into(), i, 0, 0).unwrap();
         }
-
-        // Verification phase
-        for (word, expected_val) in &words_to_insert {
-            let (_, val, _, _) = tree.get(&VariableKey::from_str(word), 0).unwrap();
-            assert_eq!(val, *expected_val);
+        for i in 0..2000u64 {
+            if i % 7 != 0 {
+                tree.remove(&(i * 3).into()).unwrap();
+            }
+        }
+        tree.insert(&21u64.into(), 100, 0, 0).unwrap();
+
+        let expected: Vec<_> = tree
+            .iter()
+            .map(|(k, v, version, ts)| (k, *v, *version, *ts))
+            .collect();
+        let lookups: Vec<_> = (0..6000u64).map(|i| tree.get(&i.into(), 0).ok()).collect();
+        let old = tree.get(&21u64.into(), 8).unwrap();
+        let version = tree.version();
+
+        let sealed = tree.seal();
+        assert_eq!(sealed.version(), version);
+        assert_eq!(
+            sealed
+                .iter()
+                .map(|(k, v, version, ts)| (k, *v, *version, *ts))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        for (i, lookup) in lookups.into_iter().enumerate() {
+            assert_eq!(sealed.get(&(i as u64).into(), 0).ok(), lookup);
+        }
+        assert_eq!(sealed.get(&21u64.into(), 8).unwrap(), old);
+
+        let start: FixedKey<16> = 300u64.into();
+        let end: FixedKey<16> = 900u64.into();
+        assert_eq!(
+            sealed.range(start.clone()..end.clone()).count(),
+            expected
+                .iter()
+                .filter(|(k, ..)| (start.as_slice()..end.as_slice()).contains(&k.as_slice()))
+                .count()
+        );
+        let snapshot = sealed.snapshot();
+        assert_eq!(snapshot.get(&21u64.into(), 0).unwrap().0, 100);
+
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&sealed);
+        let sealed = Arc::new(sealed);
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let sealed = sealed.clone();
+                std::thread::spawn(move || sealed.iter().count())
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected.len());
         }
     }
 
-    #[test]
-    fn root_set_get() {
-        let mut tree = Tree::<VariableKey, i32>::new();
+    // Checks that every inner node's version bounds match the versions stored below it.
+    fn check_version_bounds(node: &Node<VariableKey, u64>) -> (u64, u64) {
+        use crate::node::Version;
 
-        // Insertion phase
-        let key = VariableKey::from_str("abc");
-        let value = 1;
-        tree.insert(&key, value, 0, 0);
+        let bounds = match &node.node_type {
+            NodeType::Twig(twig) => {
+                let versions: Vec<_> = twig.iter().map(|v| v.version).collect();
+                (versions[0], *versions.last().unwrap())
+            }
+            _ => node.iter().fold((u64::MAX, 0), |(lo, hi), (_, child)| {
+                let (child_lo, child_hi) = check_version_bounds(child);
+                (lo.min(child_lo), hi.max(child_hi))
+            }),
+        };
+        assert_eq!((node.min_version(), node.version()), bounds);
+        bounds
+    }
 
-        // Verification phase
-        let (_, val, _ts, _) = tree.get(&key, 0).unwrap();
-        assert_eq!(val, value);
+    #[test]
+    fn version_bounds() {
+        let mut tree = Tree::<VariableKey, u64>::new();
+        let key = |batch: u64, i: u64| VariableKey::from_str(&format!("{:02}:{:03}", batch, i));
+
+        // Ten batches written one after another, each under its own first key byte range
+        for batch in 0..10 {
+            for i in 0..60 {
+                tree.insert(&key(batch, i), batch, 0, 0).unwrap();
+            }
+            check_version_bounds(tree.root.as_ref().unwrap());
+        }
+        // Overwrite part of batch 2 later on, and drop part of batch 5
+        for i in 0..10 {
+            tree.insert(&key(2, i), 100, 0, 0).unwrap();
+            tree.remove(&key(5, i)).unwrap();
+            check_version_bounds(tree.root.as_ref().unwrap());
+        }
+
+        // Versions 181..=240 were written by batch 3 alone
+        let mut scan = tree.scan_versions(181, 240);
+        let found: Vec<_> = scan.by_ref().map(|(k, v, _, _)| (k, *v)).collect();
+        assert_eq!(
+            found,
+            (0..60)
+                .map(|i| (key(3, i).as_slice().to_vec(), 3))
+                .collect::<Vec<_>>()
+        );
+        let mut all = tree.scan_versions(0, u64::MAX);
+        assert_eq!(all.by_ref().count(), 600 + 10 - 10);
+        assert!(scan.visited * 5 < all.visited);
+
+        // Both the original and the overwriting versions of batch 2 lie in a wide window
+        let overwritten: Vec<_> = tree
+            .scan_versions(121, u64::MAX)
+            .filter(|(k, _, _, _)| k.starts_with(b"02:00"))
+            .map(|(_, v, _, _)| *v)
+            .collect();
+        assert_eq!(overwritten.len(), 20);
+        assert!(tree.scan_versions(10_000, 20_000).next().is_none());
     }
 
     #[test]
-    fn string_duplicate_insert() {
-        let mut tree = Tree::<VariableKey, i32>::new();
+    fn try_iter() {
+        let mut tree = Tree::<VariableKey, u64>::new();
+        assert!(tree.try_iter().next().is_none());
+        for i in 0..100u64 {
+            tree.insert(&VariableKey::from_str(&format!("key{:03}", i)), i, 0, 0)
+                .unwrap();
+        }
 
-        // First insertion
-        let key = VariableKey::from_str("abc");
-        let value = 1;
-        let result = tree.insert(&key, value, 0, 0).expect("Failed to insert");
-        assert!(result.is_none());
+        // A sound tree yields exactly what iter does
+        let entries: Vec<_> = tree.try_iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries, tree.iter().collect::<Vec<_>>());
 
-        // Second insertion (duplicate)
-        let result = tree.insert(&key, value, 0, 0).expect("Failed to insert");
-        assert!(result.is_some());
+        // Graft a twig holding no value into the tree
+        let broken = VariableKey::from_str("key050x");
+        let empty_twig = Node {
+            node_type: NodeType::Twig(crate::node::TwigNode::new(broken.clone(), broken.clone())),
+        };
+        let mut root = tree.root.as_ref().unwrap().clone_node();
+        let first = root.iter().next().map(|(k, _)| k).unwrap();
+        let (_, child) = root.find_child_pos(first).unwrap();
+        let mut child = child.clone_node();
+        child = child.add_child(0xff, empty_twig, &TreeOptions::default());
+        root = root.replace_child(first, Arc::new(child));
+        tree.root = Some(Arc::new(root));
+
+        let results: Vec<_> = tree.try_iter().collect();
+        let err = results.iter().position(Result::is_err).unwrap();
+        assert_eq!(err, results.len() - 1);
+        assert!(results[..err].iter().all(Result::is_ok));
+
+        // The infallible iterator skips over it and carries on
+        assert_eq!(tree.iter().count(), 100);
     }
 
-    // Inserting a single value into the tree and removing it should result in a nil tree root.
     #[test]
-    fn insert_and_remove() {
-        let mut tree = Tree::<VariableKey, i32>::new();
+    fn put() {
+        let mut tree = Tree::<VariableKey, u64>::new();
+        for i in 0..100u64 {
+            tree.insert(
+                &VariableKey::from_str(&format!("{:02}:{}", i % 10, i)),
+                i,
+                0,
+                0,
+            )
+            .unwrap();
+        }
 
-        // Insertion
-        let key = VariableKey::from_str("test");
-        let value = 1;
-        tree.insert(&key, value, 0, 0);
+        let key = VariableKey::from_str("05:new");
+        let new_tree = tree.put(&key, 1000, 0, 0).unwrap();
+        assert_eq!(new_tree.get(&key, 0).unwrap().1, 1000);
+        assert!(tree.get(&key, 0).is_err());
+        assert_eq!(tree.iter().count(), 100);
+        assert_eq!(new_tree.iter().count(), 101);
+
+        // Only the path to the new key was copied
+        let old_root = tree.root.as_ref().unwrap();
+        let new_root = new_tree.root.as_ref().unwrap();
+        assert!(!Arc::ptr_eq(old_root, new_root));
+        let mut shared = 0;
+        for (k, child) in old_root.iter() {
+            let new_child = new_root.find_child(k).unwrap();
+            if k == b'5' {
+                assert!(!Arc::ptr_eq(child, new_child));
+            } else {
+                assert!(Arc::ptr_eq(child, new_child));
+                shared += 1;
+            }
+        }
+        assert!(shared > 0);
 
-        // Removal
-        assert!(tree.remove(&key).unwrap());
+        // Stale versions are rejected just like with insert
+        assert!(new_tree.put(&key, 1, 1, 0).is_err());
+    }
 
-        // Verification
+    #[test]
+    fn remove_version() {
+        let mut tree = Tree::<VariableKey, &str>::new();
+        let key = VariableKey::from_str("key");
+        let other = VariableKey::from_str("other");
+        tree.insert(&key, "v1", 1, 10).unwrap();
+        tree.insert(&key, "v2", 2, 20).unwrap();
+        tree.insert(&other, "o3", 3, 30).unwrap();
+        tree.insert(&key, "v4", 4, 40).unwrap();
+
+        // Removing a middle version keeps its neighbours
+        assert_eq!(tree.remove_version(&key, 2).unwrap(), Some("v2"));
+        assert_eq!(tree.remove_version(&key, 2).unwrap(), None);
+        assert_eq!(tree.remove_version(&key, 3).unwrap(), None);
+        assert_eq!(tree.get(&key, 1).unwrap().1, "v1");
+        assert_eq!(tree.get(&key, 2).unwrap().1, "v1");
+        assert_eq!(tree.get(&key, 0).unwrap(), (key.clone(), "v4", 4, 40));
+        assert_eq!(tree.latest_ts(&key), Some(40));
+
+        // Removing the latest version exposes the previous one
+        assert_eq!(tree.remove_version(&key, 4).unwrap(), Some("v4"));
+        assert_eq!(tree.get(&key, 0).unwrap(), (key.clone(), "v1", 1, 10));
+        assert_eq!(tree.latest_ts(&key), Some(10));
+        assert_eq!(tree.version(), 3);
+
+        // Removing the last version detaches the twig
+        assert_eq!(tree.remove_version(&key, 1).unwrap(), Some("v1"));
         assert!(tree.get(&key, 0).is_err());
+        assert_eq!(
+            tree.keys().collect::<Vec<_>>(),
+            vec![other.as_slice().to_vec()]
+        );
+        assert_eq!(tree.remove_version(&other, 3).unwrap(), Some("o3"));
+        assert!(tree.root.is_none());
     }
 
     #[test]
-    fn inserting_keys_with_common_prefix() {
-        let key1 = VariableKey::from_str("foo");
-        let key2 = VariableKey::from_str("foo2");
+    fn iterators_are_fused() {
+        fn assert_fused<I: std::iter::FusedIterator>(_: &I) {}
 
-        let mut tree = Tree::<VariableKey, i32>::new();
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        for i in 0..50u64 {
+            tree.insert(&(i * 2).into(), i, 0, 0).unwrap();
+        }
 
-        // Insertion
-        tree.insert(&key1, 1, 0, 0);
-        tree.insert(&key2, 1, 0, 0);
+        let mut iter = tree.iter();
+        assert_fused(&iter);
+        assert_eq!(iter.by_ref().count(), 50);
+        for _ in 0..5 {
+            assert!(iter.next().is_none());
+        }
 
-        // Removal
-        assert!(tree.remove(&key1).unwrap());
+        // Stops at the end bound, in the middle of the descent
+        let start: FixedKey<16> = 10u64.into();
+        let end: FixedKey<16> = 21u64.into();
+        let mut range = tree.range(start..end);
+        assert_fused(&range);
+        assert_eq!(range.by_ref().count(), 6);
+        for _ in 0..5 {
+            assert!(range.next().is_none());
+        }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert_eq!(root.node_type_name(), "Node1");
-        } else {
-            panic!("Tree root is None");
+        // Runs off the end of the Trie
+        let start: FixedKey<16> = 90u64.into();
+        let mut range = tree.range(start..);
+        assert_eq!(range.by_ref().count(), 5);
+        for _ in 0..5 {
+            assert!(range.next().is_none());
+        }
+
+        // Stops at the limit
+        let mut limited = tree.range_limited(.., 3);
+        assert_eq!(limited.by_ref().count(), 3);
+        for _ in 0..5 {
+            assert!(limited.next().is_none());
+        }
+
+        let empty = Tree::<FixedKey<16>, u64>::new();
+        let mut iter = empty.iter();
+        for _ in 0..5 {
+            assert!(iter.next().is_none());
         }
     }
 
-    // Inserting Two values into the tree and removing one of them
-    // should result in a tree root of type twig
     #[test]
-    fn insert2_and_remove1_and_root_should_be_node1() {
-        let key1 = VariableKey::from_str("test1");
-        let key2 = VariableKey::from_str("test2");
-
-        let mut tree = Tree::<VariableKey, i32>::new();
+    fn get_at_timestamps() {
+        let mut tree = Tree::<VariableKey, u32>::new();
+        let key = VariableKey::from_str("timeline");
+        for (i, ts) in [10, 20, 30, 40, 50].iter().enumerate() {
+            tree.insert(&key, i as u32, 0, *ts).unwrap();
+        }
+        tree.insert(&VariableKey::from_str("other"), 99, 0, 5)
+            .unwrap();
+
+        let ts_list = [35, 5, 10, 50, 60, 11, 0, 49];
+        let values = tree.get_at_timestamps(&key, &ts_list);
+        assert_eq!(
+            values,
+            vec![
+                Some(2),
+                None,
+                Some(0),
+                Some(4),
+                Some(4),
+                Some(0),
+                None,
+                Some(3)
+            ]
+        );
 
-        // Insertion
-        tree.insert(&key1, 1, 0, 0);
-        tree.insert(&key2, 1, 0, 0);
+        // Every timestamp agrees with the per-timestamp lookup
+        let twig = Node::find_twig(tree.root.as_ref().unwrap(), &key).unwrap();
+        for (ts, value) in ts_list.iter().zip(&values) {
+            assert_eq!(twig.get_leaf_by_ts(*ts).map(|leaf| leaf.value), *value);
+        }
 
-        // Removal
-        assert!(tree.remove(&key1).unwrap());
+        assert_eq!(
+            tree.get_at_timestamps(&VariableKey::from_str("missing"), &[10, 60]),
+            vec![None, None]
+        );
+        assert!(tree.get_at_timestamps(&key, &[]).is_empty());
+    }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert_eq!(root.node_type_name(), "Node1");
-        } else {
-            panic!("Tree root is None");
+    #[test]
+    fn fill_histogram() {
+        let tree = Tree::<FixedKey<16>, u64>::new();
+        assert_eq!(tree.fill_histogram(), Default::default());
+
+        // Keys 0..1100 share their first six bytes, branch into five values at the seventh and
+        // into every value at the last, but for the last 76 keys
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        for i in 0..1100u64 {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
+        }
+        let histogram = tree.fill_histogram();
+
+        // The root has five of sixteen children
+        assert_eq!(histogram.buckets(NodeKind::Node16), [0, 1, 0, 0]);
+        // Four full Node256s, and one with 76 children
+        assert_eq!(histogram.buckets(NodeKind::Node256), [0, 1, 0, 4]);
+        assert_eq!(histogram.nodes(NodeKind::Node256), 5);
+        for kind in [
+            NodeKind::Node1,
+            NodeKind::Node4,
+            NodeKind::Node48,
+            NodeKind::Twig,
+        ] {
+            assert_eq!(histogram.nodes(kind), 0);
         }
     }
 
-    // // Inserting Two values into a tree and deleting them both
-    // // should result in a nil tree root
-    // // This tests the expansion of the root into a NODE4 and
-    // // successfully collapsing into a twig and then nil upon successive removals
-    // #[test]
-    // fn insert2_and_remove2_and_root_should_be_nil() {
-    //     let key1 = &VariableKey::from_str("test1");
-    //     let key2 = &VariableKey::from_str("test2");
+    #[test]
+    fn insert_auto() {
+        let mut tree = Tree::<VariableKey, u32>::new();
+        let key = VariableKey::from_str("counter");
+
+        let stamps: Vec<u64> = (0..5).map(|i| tree.insert_auto(&key, i).unwrap()).collect();
+        assert!(stamps.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Every stamp reads back its own value, by version and by timestamp
+        for (i, stamp) in stamps.iter().enumerate() {
+            assert_eq!(
+                tree.get(&key, *stamp).unwrap(),
+                (key.clone(), i as u32, *stamp, *stamp)
+            );
+        }
+        let values = tree.get_at_timestamps(&key, &stamps);
+        assert_eq!(values, (0..5).map(Some).collect::<Vec<_>>());
 
-    //     let mut tree = Tree::<VariableKey, i32>::new();
-    //     tree.insert(key1, 1, 0, 0);
-    //     tree.insert(key2, 1, 0);
+        // A manual insert far ahead pushes the counter past its timestamp
+        let other = VariableKey::from_str("other");
+        tree.insert(&other, 100, 0, 1000).unwrap();
+        let stamp = tree.insert_auto(&key, 5).unwrap();
+        assert!(stamp > 1000);
+        assert_eq!(tree.get(&key, 0).unwrap().3, stamp);
 
-    //     assert_eq!(tree.remove(key1), true);
-    //     assert_eq!(tree.remove(key2), true);
+        // And a manual version ahead of every timestamp does too
+        tree.insert(&other, 101, 5000, 0).unwrap();
+        assert!(tree.insert_auto(&other, 102).unwrap() > 5000);
 
-    //     assert!(tree.root.is_none());
-    // }
+        assert!(tree.insert_auto(&VariableKey::from(vec![]), 0).is_err());
+    }
 
-    // Inserting Five values into a tree and deleting one of them
-    // should result in a tree root of type NODE4
-    // This tests the expansion of the root into a NODE16 and
-    // successfully collapsing into a NODE4 upon successive removals
     #[test]
-    fn insert5_and_remove1_and_root_should_be_node4() {
-        let mut tree = Tree::<VariableKey, i32>::new();
-
-        // Insertion
-        for i in 0..5u32 {
-            let key = VariableKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+    fn remove_range_at() {
+        let mut tree = Tree::<VariableKey, u32>::new();
+        let key = |i: u32| VariableKey::from_str(&format!("key{:02}", i));
+        for i in 0..10 {
+            tree.insert(&key(i), i, 0, 10).unwrap();
         }
-
-        // Removal
-        let key_to_remove = VariableKey::from_slice(&1u32.to_be_bytes());
-        assert!(tree.remove(&key_to_remove).unwrap());
-
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node4");
-        } else {
-            panic!("Tree root is None");
+        // One older version of an untouched key, and one newer version of a deleted key
+        tree.insert(&key(0), 100, 0, 15).unwrap();
+        let before = tree.version();
+
+        assert_eq!(tree.remove_range_at(key(3)..key(7), 20).unwrap(), 4);
+        assert_eq!(tree.version(), before + 1);
+        // Deleting again finds nothing left to delete
+        assert_eq!(tree.remove_range_at(key(3)..key(7), 25).unwrap(), 0);
+
+        // Reads before the deletion still see the keys, reads at it do not
+        for i in 3..7 {
+            assert_eq!(
+                tree.get_at_timestamps(&key(i), &[19, 20]),
+                vec![Some(i), None]
+            );
+            assert!(tree.get(&key(i), 0).is_err());
+            assert_eq!(tree.get(&key(i), before).unwrap().1, i);
+        }
+        assert_eq!(tree.snapshot_values_at(19).count(), 10);
+        assert_eq!(tree.snapshot_values_at(20).count(), 6);
+        assert_eq!(tree.iter().count(), 6);
+        assert_eq!(tree.try_iter().filter(|entry| entry.is_ok()).count(), 6);
+        let mut cursor = tree.cursor();
+        let mut seen = Vec::new();
+        while let Some((k, _)) = cursor.next() {
+            seen.push(k.to_vec());
         }
+        assert_eq!(seen, tree.keys().collect::<Vec<_>>());
+        assert_eq!(cursor.seek(&key(3)).unwrap().0, key(7).as_slice());
+        assert_eq!(tree.get(&key(7), 0).unwrap().1, 7);
+
+        // A reinserted key is visible again, with nothing to replace
+        assert_eq!(tree.insert(&key(6), 60, 0, 30).unwrap(), None);
+        assert_eq!(tree.get(&key(6), 0).unwrap().1, 60);
+
+        // Up to the deletion nothing can be reclaimed but the old version of key 0
+        assert_eq!(tree.gc(19).unwrap(), 1);
+        assert_eq!(tree.snapshot_values_at(19).count(), 10);
+
+        // Past it the deleted keys are reclaimed, and the reinserted one keeps only its new value
+        assert_eq!(tree.gc(20).unwrap(), 8);
+        assert_eq!(tree.snapshot_values_at(19).count(), 6);
+        for i in 3..6 {
+            assert!(Node::find_twig(tree.root.as_ref().unwrap(), &key(i)).is_none());
+        }
+        let twig = Node::find_twig(tree.root.as_ref().unwrap(), &key(6)).unwrap();
+        assert_eq!(twig.values.len(), 1);
+        assert_eq!(tree.iter().count(), 7);
+        assert_eq!(tree.gc(30).unwrap(), 0);
     }
 
-    //     // Inserting Five values into a tree and deleting all of them
-    //     // should result in a tree root of type nil
-    //     // This tests the expansion of the root into a NODE16 and
-    //     // successfully collapsing into a NODE4, twig, then nil
-    //     #[test]
-    //     fn insert5_and_remove5_and_root_should_be_nil() {
-    //         let mut tree = Tree::<VariableKey, i32>::new();
+    #[test]
+    fn replace() {
+        let mut tree = Tree::<VariableKey, u32>::new();
+        let key = VariableKey::from_str("present");
+        let absent = VariableKey::from_str("absent");
+        assert_eq!(tree.replace(&key, 1, 0, 0).unwrap(), None);
+        assert!(tree.root.is_none());
+
+        tree.insert(&key, 1, 0, 10).unwrap();
+        let version = tree.version();
+
+        // A present key gets a new version, keeping the old one
+        assert_eq!(tree.replace(&key, 2, 0, 20).unwrap(), Some(1));
+        assert_eq!(tree.version(), version + 1);
+        assert_eq!(
+            tree.get(&key, 0).unwrap(),
+            (key.clone(), 2, version + 1, 20)
+        );
+        assert_eq!(tree.get(&key, version).unwrap().1, 1);
 
-    //         for i in 0..5u32 {
-    //             let key = &VariableKey::from_slice(&i.to_be_bytes());
-    //             tree.insert(key, 1);
-    //         }
+        // An absent key is not created, and the tree is left as it was
+        let root = tree.root.clone().unwrap();
+        assert_eq!(tree.replace(&absent, 3, 0, 30).unwrap(), None);
+        assert!(Arc::ptr_eq(&root, tree.root.as_ref().unwrap()));
+        assert!(tree.get(&absent, 0).is_err());
 
-    //         for i in 0..5u32 {
-    //             let key = &VariableKey::from_slice(&i.to_be_bytes());
-    //             tree.remove(key);
-    //         }
+        // Nor is a deleted one
+        tree.remove_range_at(key.clone()..=key.clone(), 40).unwrap();
+        assert_eq!(tree.replace(&key, 4, 0, 50).unwrap(), None);
+        assert!(tree.get(&key, 0).is_err());
 
-    //         assert!(tree.root.is_none());
-    //     }
+        assert!(tree.replace(&key, 5, 1, 60).is_err());
+    }
 
-    // Inserting 17 values into a tree and deleting one of them should
-    // result in a tree root of type NODE16
-    // This tests the expansion of the root into a NODE48, and
-    // successfully collapsing into a NODE16
     #[test]
-    fn insert17_and_remove1_and_root_should_be_node16() {
-        let mut tree = Tree::<VariableKey, i32>::new();
-
-        // Insertion
-        for i in 0..17u32 {
-            let key = VariableKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+    fn iter_from() {
+        let words = [
+            "apple",
+            "banana",
+            "kiwi",
+            "lemon",
+            "lime",
+            "m",
+            "mango",
+            "melon",
+            "nectarine",
+            "orange",
+            "zucchini",
+        ];
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(&VariableKey::from_str(word), i, 0, 0).unwrap();
         }
+        let keys = |start: &str| -> Vec<Vec<u8>> {
+            tree.iter_from(&VariableKey::from_str(start))
+                .map(|(key, _, _, _)| key)
+                .collect()
+        };
+        let expected = |start: &str| -> Vec<Vec<u8>> {
+            words
+                .iter()
+                .map(|word| VariableKey::from_str(word))
+                .filter(|key| key >= &VariableKey::from_str(start))
+                .map(|key| key.as_slice().to_vec())
+                .collect()
+        };
 
-        // Removal
-        let key_to_remove = VariableKey::from_slice(&2u32.to_be_bytes());
-        assert!(tree.remove(&key_to_remove).unwrap());
+        assert_eq!(keys("m"), expected("m"));
+        assert_eq!(keys("m").len(), 6);
+        for start in [
+            "", "a", "apple", "b", "lim", "limes", "ma", "mz", "zucchini", "zz",
+        ] {
+            assert_eq!(keys(start), expected(start), "starting at {:?}", start);
+        }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node16");
-        } else {
-            panic!("Tree root is None");
+        // The raw bytes of a start key may lie between keys at any depth
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        for i in (0..2000u64).step_by(7) {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
+        }
+        for start in [0u64, 1, 7, 500, 1001, 1995, 1996, 5000] {
+            let values: Vec<u64> = tree
+                .iter_from(&start.into())
+                .map(|(_, v, _, _)| *v)
+                .collect();
+            let expected: Vec<u64> = (0..2000).step_by(7).filter(|i| *i >= start).collect();
+            assert_eq!(values, expected);
         }
+
+        let empty = Tree::<FixedKey<16>, u64>::new();
+        assert_eq!(empty.iter_from(&1u64.into()).count(), 0);
     }
 
     #[test]
-    fn insert17_and_root_should_be_node48() {
-        let mut tree = Tree::<VariableKey, i32>::new();
-
-        // Insertion
-        for i in 0..17u32 {
-            let key = VariableKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+    fn twigs_hold_a_single_key() {
+        // Keys sharing a compressed prefix still end up in twigs of their own
+        let words = ["foo", "foobar", "foobaz", "fo", "food"];
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(&VariableKey::from_str(word), i, 0, 0).unwrap();
         }
-
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node48");
-        } else {
-            panic!("Tree root is None");
+        tree.insert(&VariableKey::from_str("foo"), 10, 0, 0)
+            .unwrap();
+
+        let root = tree.root.as_ref().unwrap();
+        for (i, word) in words.iter().enumerate() {
+            let key = VariableKey::from_str(word);
+            let twig = Node::find_twig(root, &key).unwrap();
+            assert_eq!(twig.key, key);
+            let expected = if i == 0 { 2 } else { 1 };
+            assert_eq!(twig.values.len(), expected);
         }
+        let twig = Node::find_twig(root, &VariableKey::from_str("foo")).unwrap();
+        assert_eq!(twig.get_latest_value(), Some(&10));
+        assert_eq!(twig.get_leaf_by_version(1).unwrap().value, 0);
     }
 
-    // // Inserting 17 values into a tree and removing them all should
-    // // result in a tree of root type nil
-    // // This tests the expansion of the root into a NODE48, and
-    // // successfully collapsing into a NODE16, NODE4, twig, and then nil
-    // #[test]
-    // fn insert17_and_remove17_and_root_should_be_nil() {
-    //     let mut tree = Tree::<VariableKey, i32>::new();
-
-    //     for i in 0..17u32 {
-    //         let key = VariableKey::from_slice(&i.to_be_bytes());
-    //         tree.insert(&key, 1);
-    //     }
+    #[test]
+    fn for_each() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        let empty = Tree::<VariableKey, usize>::new();
+        empty.for_each(|_, _, _| panic!("an empty tree has no entries"));
+
+        for i in 0..500usize {
+            let key = VariableKey::from_str(&format!("key{}", i * 7 % 500));
+            tree.insert(&key, i, 0, i as u64).unwrap();
+        }
+        tree.insert(&VariableKey::from_str("key3"), 1000, 0, 1000)
+            .unwrap();
+        tree.remove_range_at(
+            VariableKey::from_str("key4")..VariableKey::from_str("key5"),
+            2000,
+        )
+        .unwrap();
+
+        let mut visited = Vec::new();
+        tree.for_each(|key, value, ts| visited.push((key.to_vec(), *value, ts)));
+        let expected: Vec<_> = tree
+            .iter()
+            .map(|(key, value, _, ts)| (key, *value, *ts))
+            .collect();
+        assert_eq!(visited, expected);
+        assert!(visited.contains(&(
+            VariableKey::from_str("key3").as_slice().to_vec(),
+            1000,
+            1000
+        )));
+    }
 
-    //     for i in 0..17u32 {
-    //         let key = VariableKey::from_slice(&i.to_be_bytes());
-    //         tree.remove(&key);
-    //     }
+    #[test]
+    fn insert_owned() {
+        let mut borrowed = Tree::<VariableKey, usize>::new();
+        let mut owned = Tree::<VariableKey, usize>::new();
+        assert!(matches!(
+            owned.insert_owned(VariableKey::from(vec![]), 0, 0, 0),
+            Err(super::TrieError::IllegalArguments)
+        ));
+
+        for i in 0..300usize {
+            let key = VariableKey::from_str(&format!("key{}", i % 200));
+            let expected = borrowed.insert(&key, i, 0, i as u64).unwrap();
+            assert_eq!(owned.insert_owned(key, i, 0, i as u64).unwrap(), expected);
+        }
 
-    //     assert!(tree.root.is_none());
-    // }
+        assert!(owned.contents_eq(&borrowed));
+        assert!(owned.history_eq(&borrowed));
+        assert_eq!(
+            owned.get(&VariableKey::from_str("key42"), 0).unwrap().1,
+            242
+        );
+    }
 
-    // Inserting 49 values into a tree and removing one of them should
-    // result in a tree root of type NODE48
-    // This tests the expansion of the root into a NODE256, and
-    // successfully collapasing into a NODE48
     #[test]
-    fn insert49_and_remove1_and_root_should_be_node48() {
-        let mut tree = Tree::<VariableKey, i32>::new();
+    fn range_count_and_select() {
+        fn check<R>(tree: &Tree<FixedKey<16>, u64>, range: R)
+        where
+            R: std::ops::RangeBounds<FixedKey<16>> + Clone,
+        {
+            assert_eq!(tree.range_count(range.clone()), tree.range(range).count());
+        }
+
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        assert_eq!(tree.range_count(..), 0);
+        assert!(tree.select(0).is_none());
+
+        // Spread the keys so that nodes of every width are built and later shrunk again
+        let mut seed = 7u64;
+        let mut next = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            seed >> 48
+        };
+        let keys: Vec<u64> = (0..3000).map(|_| next() % 20_000).collect();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(&(*key).into(), *key, 0, i as u64).unwrap();
+        }
+        for key in keys.iter().step_by(3) {
+            tree.remove(&(*key).into()).unwrap();
+        }
+        let lo: FixedKey<16> = 5_000u64.into();
+        let hi: FixedKey<16> = 6_000u64.into();
+        tree.remove_range_at(lo.clone()..hi.clone(), 10_000)
+            .unwrap();
+
+        let bounds: Vec<u64> = (0..40).map(|_| next() % 21_000).collect();
+        for pair in bounds.chunks(2) {
+            let (a, b) = (pair[0].min(pair[1]), pair[0].max(pair[1]));
+            let (a, b): (FixedKey<16>, FixedKey<16>) = (a.into(), b.into());
+            check(&tree, a.clone()..b.clone());
+            check(&tree, a.clone()..=b.clone());
+            check(&tree, ..b.clone());
+            check(&tree, a.clone()..);
+            check(
+                &tree,
+                (std::ops::Bound::Excluded(a), std::ops::Bound::Included(b)),
+            );
+        }
+        check(&tree, ..);
+        check(&tree, lo.clone()..hi.clone());
+        assert_eq!(tree.range_count(lo..hi), 0);
+
+        let entries: Vec<_> = tree.iter().collect();
+        for (rank, (key, value, version, ts)) in entries.iter().enumerate() {
+            let (k, v, ver, t) = tree.select(rank).unwrap();
+            assert_eq!(k.as_slice(), key.as_slice());
+            assert_eq!((v, ver, t), (**value, **version, **ts));
+        }
+        assert!(tree.select(entries.len()).is_none());
+    }
 
-        // Insertion
-        for i in 0..49u32 {
-            let key = VariableKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+    #[test]
+    fn nth_and_rank() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        assert!(tree.nth(0).is_none());
+        assert!(tree.rank(&VariableKey::from_str("key1")).is_none());
+
+        for i in 0..1000usize {
+            let key = VariableKey::from_str(&format!("key{}", i * 37 % 1000));
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+        for i in (0..1000usize).step_by(7) {
+            tree.remove(&VariableKey::from_str(&format!("key{}", i)))
+                .unwrap();
+        }
+        tree.remove_range_at(
+            VariableKey::from_str("key5")..VariableKey::from_str("key6"),
+            1,
+        )
+        .unwrap();
+
+        let entries: Vec<_> = tree.iter().map(|(key, value, _, _)| (key, value)).collect();
+        for (index, entry) in entries.iter().enumerate() {
+            assert_eq!(tree.nth(index).as_ref(), Some(entry));
+            let key = VariableKey::from(entry.0.clone());
+            assert_eq!(tree.rank(&key), Some(index));
         }
+        assert!(tree.nth(entries.len()).is_none());
 
-        // Removal
-        let key_to_remove = VariableKey::from_slice(&2u32.to_be_bytes());
-        assert!(tree.remove(&key_to_remove).unwrap());
+        // Removed and tombstoned keys have no position
+        assert!(tree.rank(&VariableKey::from_str("key7")).is_none());
+        assert!(tree.rank(&VariableKey::from_str("key501")).is_none());
+    }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node48");
-        } else {
-            panic!("Tree root is None");
+    #[test]
+    fn fold_prefix() {
+        let mut tree = Tree::<VariableKey, u64>::new();
+        assert_eq!(tree.fold_prefix(b"", 0, |acc, _, _, _| acc + 1), 0);
+
+        for tenant in 1..=3u64 {
+            for i in 0..100u64 {
+                let key = VariableKey::from_str(&format!("tenant{}/object{}", tenant, i));
+                tree.insert(&key, tenant * 1000 + i, 0, 0).unwrap();
+            }
         }
+        tree.insert(&VariableKey::from_str("tenant"), 7, 0, 0)
+            .unwrap();
+        tree.remove(&VariableKey::from_str("tenant2/object5"))
+            .unwrap();
+
+        let sum_under = |prefix: &[u8]| {
+            tree.fold_prefix(prefix, 0, |acc, key, value, _| {
+                assert!(key.starts_with(prefix));
+                acc + value
+            })
+        };
+        for prefix in [
+            &b""[..],
+            b"ten",
+            b"tenant",
+            b"tenant2",
+            b"tenant2/",
+            b"tenant2/object1",
+            b"tenant3/object99",
+        ] {
+            let expected: u64 = tree
+                .iter()
+                .filter(|(key, _, _, _)| key.starts_with(prefix))
+                .map(|(_, value, _, _)| *value)
+                .sum();
+            assert_eq!(sum_under(prefix), expected);
+        }
+        assert_eq!(
+            sum_under(b"tenant2/"),
+            (0..100).map(|i| 2000 + i).sum::<u64>() - 2005
+        );
+
+        // Prefixes that no key starts with skip the descent
+        assert_eq!(tree.fold_prefix(b"tenant4", 42, |acc, _, _, _| acc + 1), 42);
+        assert_eq!(
+            tree.fold_prefix(b"tenant1/object1000", 0, |acc, _, _, _| acc + 1),
+            0
+        );
+        assert_eq!(tree.fold_prefix(b"x", 0, |acc, _, _, _| acc + 1), 0);
     }
 
     #[test]
-    fn insert49_and_root_should_be_node248() {
-        let mut tree = Tree::<VariableKey, i32>::new();
+    fn max_key_len() {
+        // The terminator of a string key counts towards the limit
+        let mut tree =
+            Tree::<VariableKey, i32>::with_options(TreeOptions::new().with_max_key_len(5));
+        let below = VariableKey::from_str("ab");
+        let at = VariableKey::from_str("abc");
+        let above = VariableKey::from_str("abcd");
+        assert_eq!(at.len(), 5);
+
+        tree.insert(&below, 1, 0, 0).unwrap();
+        tree.insert(&at, 2, 0, 0).unwrap();
+        assert!(matches!(
+            tree.insert(&above, 3, 0, 0),
+            Err(super::TrieError::KeyTooLong)
+        ));
+        assert!(matches!(
+            tree.bulk_insert(&[KV::new(above.clone(), 3, 0, 0)]),
+            Err(super::TrieError::KeyTooLong)
+        ));
+        let mut snapshot = tree.create_snapshot().unwrap();
+        assert!(matches!(
+            snapshot.insert(&above, 3, 0),
+            Err(super::TrieError::KeyTooLong)
+        ));
+        assert!(tree.get(&above, 0).is_err());
+        assert_eq!(tree.iter().count(), 2);
+
+        // Without a limit any length is accepted
+        let mut unbounded = Tree::<VariableKey, i32>::new();
+        unbounded
+            .insert(&VariableKey::from_str(&"a".repeat(10_000)), 1, 0, 0)
+            .unwrap();
+    }
 
-        // Insertion
-        for i in 0..49u32 {
-            let key = VariableKey::from_slice(&i.to_be_bytes());
-            tree.insert(&key, 1, 0, 0);
+    #[test]
+    fn shrink_to_fit() {
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        assert_eq!(tree.shrink_to_fit().unwrap(), 0);
+
+        for version in 1..=3u64 {
+            for i in 0..20_000u64 {
+                tree.insert(&(i * 13).into(), i, 0, version).unwrap();
+            }
+        }
+        for i in 0..20_000u64 {
+            if i % 10 != 0 {
+                tree.remove(&(i * 13).into()).unwrap();
+            }
         }
 
-        // Root verification
-        if let Some(root) = &tree.root {
-            assert!(root.is_inner());
-            assert_eq!(root.node_type_name(), "Node256");
-        } else {
-            panic!("Tree root is None");
+        let before = tree.estimate_memory();
+        let entries: Vec<_> = tree
+            .iter()
+            .map(|(key, value, version, ts)| (key, *value, *version, *ts))
+            .collect();
+        let reclaimed = tree.shrink_to_fit().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(tree.estimate_memory(), before - reclaimed);
+
+        // The contents are unchanged, and a second pass finds nothing left to trim
+        let after: Vec<_> = tree
+            .iter()
+            .map(|(key, value, version, ts)| (key, *value, *version, *ts))
+            .collect();
+        assert_eq!(after, entries);
+        assert_eq!(tree.shrink_to_fit().unwrap(), 0);
+
+        // A node left wider than its children need steps down to the smallest node type
+        let options = TreeOptions::new();
+        let mut wide = Node::<FixedKey<16>, u64> {
+            node_type: NodeType::Node256(crate::node::Node256::new(FixedKey::from_slice(&[]))),
+        };
+        for k in [3u8, 1, 2] {
+            let key = FixedKey::from_slice(&[k]);
+            wide = wide.add_child(
+                k,
+                Node::new_twig(key.clone(), key, LeafValue::new(k as u64, 1, 0), &options),
+                &options,
+            );
         }
+        let fitted = Node::shrink_to_fit(&Arc::new(wide), &options);
+        assert_eq!(fitted.node_kind(), NodeKind::Node4);
+        let keys: Vec<u8> = fitted.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![1, 2, 3]);
     }
 
-    //     // // Inserting 49 values into a tree and removing all of them should
-    //     // // result in a nil tree root
-    //     // // This tests the expansion of the root into a NODE256, and
-    //     // // successfully collapsing into a Node48, Node16, Node4, twig, and finally nil
-    //     // #[test]
-    //     // fn insert49_and_remove49_and_root_should_be_nil() {
-    //     //     let mut tree = Tree::<VariableKey, i32>::new();
+    #[test]
+    fn insert_returning() {
+        let mut tree = Tree::<VariableKey, &str>::new();
+        let key = VariableKey::from_str("account");
+
+        assert_eq!(tree.insert_returning(&key, "opened", 0, 10).unwrap(), None);
+        assert_eq!(
+            tree.insert_returning(&key, "funded", 0, 20).unwrap(),
+            Some("opened")
+        );
+        assert_eq!(
+            tree.insert_returning(&key, "frozen", 0, 30).unwrap(),
+            Some("funded")
+        );
 
-    //     //     for i in 0..49u32 {
-    //     //         let key = &VariableKey::from_slice(&i.to_be_bytes());
-    //     //         tree.insert(key, 1);
-    //     //     }
+        // Every image stays in the history of the key
+        let history: Vec<_> = Node::find_twig(tree.root.as_ref().unwrap(), &key)
+            .unwrap()
+            .iter()
+            .map(|leaf| (leaf.value, leaf.ts))
+            .collect();
+        assert_eq!(
+            history,
+            vec![("opened", 10), ("funded", 20), ("frozen", 30)]
+        );
 
-    //     //     for i in 0..49u32 {
-    //     //         let key = VariableKey::from_slice(&i.to_be_bytes());
-    //     //         assert_eq!(tree.remove(&key), true);
-    //     //     }
+        // A key removed by a tombstone has no previous value
+        tree.remove_range_at(key.clone()..=key.clone(), 40).unwrap();
+        assert_eq!(
+            tree.insert_returning(&key, "reopened", 0, 50).unwrap(),
+            None
+        );
+    }
 
-    //     //     assert!(tree.root.is_none());
-    //     // }
+    #[test]
+    fn recent() {
+        let mut tree = Tree::<VariableKey, u64>::new();
+        assert!(tree.recent(10).is_empty());
+
+        let mut writes = Vec::new();
+        for version in 1..=600u64 {
+            let key = format!("key{}", version * 7919 % 250);
+            tree.insert(&VariableKey::from_str(&key), version, version, version * 10)
+                .unwrap();
+            writes.push((key, version));
+        }
 
-    #[derive(Debug, Clone, PartialEq)]
-    struct KVT {
-        k: Vec<u8>,   // Key
-        version: u64, // version
+        // Removed keys lose their whole history, tombstones are not writes of their own
+        tree.remove(&VariableKey::from_str("key42")).unwrap();
+        writes.retain(|(key, _)| key != "key42");
+        tree.remove_range_at(
+            VariableKey::from_str("key7")..VariableKey::from_str("key8"),
+            7000,
+        )
+        .unwrap();
+
+        writes.reverse();
+        for n in [0, 1, 5, 100, writes.len(), writes.len() + 10] {
+            let expected: Vec<_> = writes
+                .iter()
+                .take(n)
+                .map(|(key, version)| {
+                    let key = VariableKey::from_str(key).as_slice().to_vec();
+                    (key, *version, version * 10)
+                })
+                .collect();
+            assert_eq!(tree.recent(n), expected);
+        }
     }
 
     #[test]
-    fn timed_insertion() {
-        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+    fn clone_shares_root() {
+        let mut t1 = Tree::<VariableKey, i32>::new();
+        for i in 0..100 {
+            t1.insert(&VariableKey::from_str(&format!("key{}", i)), i, 0, 0)
+                .unwrap();
+        }
+        let t2 = t1.clone();
+        assert!(Arc::ptr_eq(
+            t1.root.as_ref().unwrap(),
+            t2.root.as_ref().unwrap()
+        ));
+
+        let key = VariableKey::from_str("key5");
+        t1.insert(&key, 500, 0, 0).unwrap();
+        t1.remove(&VariableKey::from_str("key6")).unwrap();
+        t1.insert(&VariableKey::from_str("new"), 1, 0, 0).unwrap();
+
+        assert!(!Arc::ptr_eq(
+            t1.root.as_ref().unwrap(),
+            t2.root.as_ref().unwrap()
+        ));
+        assert_eq!(t2.get(&key, 0).unwrap().1, 5);
+        assert!(t2.get(&VariableKey::from_str("key6"), 0).is_ok());
+        assert!(t2.get(&VariableKey::from_str("new"), 0).is_err());
+        assert_eq!(t2.iter().count(), 100);
+        assert_eq!(t1.get(&key, 0).unwrap().1, 500);
+        assert_eq!(t1.iter().count(), 100);
+    }
 
-        let kvts = vec![
-            KVT {
-                k: b"key1_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key2_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key3_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key4_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key5_0".to_vec(),
-                version: 0,
-            },
-            KVT {
-                k: b"key6_0".to_vec(),
-                version: 0,
-            },
-        ];
+    #[test]
+    fn delete_logical() {
+        let mut tree = Tree::<VariableKey, &str>::new();
+        let key = VariableKey::from_str("user");
+        let other = VariableKey::from_str("other");
+        assert_eq!(tree.delete_logical(&key, 5).unwrap(), None);
+
+        tree.insert(&key, "alice", 0, 10).unwrap();
+        tree.insert(&key, "alicia", 0, 20).unwrap();
+        tree.insert(&other, "bob", 0, 20).unwrap();
+        let version = tree.version();
+
+        assert_eq!(tree.delete_logical(&key, 30).unwrap(), Some("alicia"));
+        assert_eq!(tree.delete_logical(&key, 35).unwrap(), None);
+        assert_eq!(
+            tree.delete_logical(&VariableKey::from_str("nobody"), 35)
+                .unwrap(),
+            None
+        );
 
-        // Insertion
-        for (idx, kvt) in kvts.iter().enumerate() {
-            let ts = if kvt.version == 0 {
-                idx as u64 + 1
-            } else {
-                kvt.version
-            };
-            assert!(tree
-                .insert(&VariableKey::from(kvt.k.clone()), 1, ts, 0)
-                .is_ok());
+        // Present before the deletion, absent at and after it
+        assert_eq!(
+            tree.get_at_timestamps(&key, &[5, 10, 25, 29, 30, 40]),
+            vec![
+                None,
+                Some("alice"),
+                Some("alicia"),
+                Some("alicia"),
+                None,
+                None
+            ]
+        );
+        assert!(tree.get(&key, 0).is_err());
+        assert_eq!(tree.get(&key, version).unwrap().1, "alicia");
+        assert_eq!(tree.get(&other, 0).unwrap().1, "bob");
+        let keys: Vec<_> = tree.iter().map(|(key, _, _, _)| key).collect();
+        assert_eq!(keys, vec![other.as_slice().to_vec()]);
+
+        // The key can be written again
+        tree.insert(&key, "alex", 0, 50).unwrap();
+        assert_eq!(
+            tree.get_at_timestamps(&key, &[40, 50]),
+            vec![None, Some("alex")]
+        );
+    }
+
+    #[test]
+    fn clone_range() {
+        fn nodes<'a>(
+            node: &'a Arc<Node<FixedKey<8>, u16>>,
+            out: &mut Vec<&'a Arc<Node<FixedKey<8>, u16>>>,
+        ) {
+            out.push(node);
+            for (_, child) in node.iter() {
+                nodes(child, out);
+            }
         }
 
-        // Verification
-        let mut curr_version = 1;
-        for kvt in &kvts {
-            let key = VariableKey::from(kvt.k.clone());
-            let (_, val, version, _ts) = tree.get(&key, 0).unwrap();
-            assert_eq!(val, 1);
+        let mut tree = Tree::<FixedKey<8>, u16>::new();
+        for i in 0..1000u16 {
+            tree.insert(&i.into(), i, 0, i as u64).unwrap();
+        }
+        tree.insert(&300u16.into(), 3000, 0, 2000).unwrap();
+        let start: FixedKey<8> = 250u16.into();
+        let end: FixedKey<8> = 800u16.into();
+
+        let cloned = tree.clone_range(start.clone()..end.clone());
+        let expected: Vec<_> = tree.range(start.clone()..end.clone()).collect();
+        assert_eq!(cloned.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(cloned.iter().count(), 550);
+
+        // History comes along with the keys
+        let key: FixedKey<8> = 300u16.into();
+        assert_eq!(
+            cloned.get_at_timestamps(&key, &[1000, 2000]),
+            vec![Some(300), Some(3000)]
+        );
 
-            if kvt.version == 0 {
-                assert_eq!(curr_version, version);
-            } else {
-                assert_eq!(kvt.version, version);
+        // The inner nodes for the high bytes 1 and 2 lie wholly inside the range and are shared
+        let mut source_nodes = Vec::new();
+        nodes(tree.root().unwrap(), &mut source_nodes);
+        let mut cloned_nodes = Vec::new();
+        nodes(cloned.root().unwrap(), &mut cloned_nodes);
+        let shared_inner = cloned_nodes
+            .iter()
+            .filter(|node| !node.is_twig())
+            .filter(|node| source_nodes.iter().any(|other| Arc::ptr_eq(node, other)))
+            .count();
+        assert_eq!(shared_inner, 2);
+        assert!(!Arc::ptr_eq(tree.root().unwrap(), cloned.root().unwrap()));
+
+        // The source is left untouched, and empty or whole ranges behave as expected
+        assert_eq!(tree.iter().count(), 1000);
+        assert!(tree
+            .clone_range(end.clone()..start.clone())
+            .root()
+            .is_none());
+        let whole = tree.clone_range(..);
+        assert!(Arc::ptr_eq(tree.root().unwrap(), whole.root().unwrap()));
+    }
+
+    #[test]
+    fn inline_values() {
+        let mut trees = [
+            Tree::<VariableKey, u64>::new(),
+            Tree::<VariableKey, u64>::with_options(TreeOptions::new().with_inline_values(true)),
+        ];
+        for tree in &mut trees {
+            for i in 0..500u64 {
+                let key = VariableKey::from_str(&format!("key{}", i % 200));
+                tree.insert(&key, i, 0, i).unwrap();
             }
+            tree.remove(&VariableKey::from_str("key7")).unwrap();
+            tree.delete_logical(&VariableKey::from_str("key8"), 600)
+                .unwrap();
+            tree.remove_version(&VariableKey::from_str("key9"), 410)
+                .unwrap();
+            tree.gc(100).unwrap();
+        }
 
-            curr_version += 1;
+        // Both layouts read the same at every version and timestamp
+        let [shared, inline] = &trees;
+        assert!(shared.history_eq(inline));
+        assert_eq!(
+            shared.iter().collect::<Vec<_>>(),
+            inline.iter().collect::<Vec<_>>()
+        );
+        for i in 0..200 {
+            let key = VariableKey::from_str(&format!("key{}", i));
+            for version in [0, 150, 300, 450] {
+                assert_eq!(
+                    shared.get(&key, version).ok(),
+                    inline.get(&key, version).ok()
+                );
+            }
+            let ts_list = [50, 250, 450, 650];
+            assert_eq!(
+                shared.get_at_timestamps(&key, &ts_list),
+                inline.get_at_timestamps(&key, &ts_list)
+            );
         }
+        assert!(inline.estimate_memory() < shared.estimate_memory());
+    }
 
-        // Root's version should match the greatest inserted version
-        assert_eq!(kvts.len() as u64, tree.version());
+    #[test]
+    fn three_way_merge() {
+        let key = |i: u32| VariableKey::from_str(&format!("key{:03}", i));
+        let mut tree = Tree::<VariableKey, String>::new();
+        for i in 0..100 {
+            tree.insert(&key(i), "base".to_string(), 0, 1).unwrap();
+        }
+        let base = tree.create_snapshot().unwrap();
+
+        let mut ours = tree.clone();
+        let mut theirs = tree.clone();
+        ours.insert(&key(1), "ours".to_string(), 0, 2).unwrap();
+        ours.remove(&key(2)).unwrap();
+        ours.insert(&key(200), "ours".to_string(), 0, 2).unwrap();
+        theirs.insert(&key(3), "theirs".to_string(), 0, 3).unwrap();
+        theirs.remove(&key(4)).unwrap();
+        theirs
+            .insert(&key(300), "theirs".to_string(), 0, 3)
+            .unwrap();
+
+        // Conflicting changes, a change both sides agree on, and a change to a deleted key
+        ours.insert(&key(5), "ours".to_string(), 0, 2).unwrap();
+        theirs.insert(&key(5), "theirs".to_string(), 0, 3).unwrap();
+        ours.insert(&key(6), "same".to_string(), 0, 2).unwrap();
+        theirs.insert(&key(6), "same".to_string(), 0, 3).unwrap();
+        ours.delete_logical(&key(7), 2).unwrap();
+        theirs.insert(&key(7), "theirs".to_string(), 0, 3).unwrap();
+
+        let mut conflicts = Vec::new();
+        let merged = ours
+            .three_way_merge(&base, &theirs, |key, base, ours, theirs| {
+                conflicts.push((
+                    key.to_slice().to_vec(),
+                    base.cloned(),
+                    ours.cloned(),
+                    theirs.cloned(),
+                ));
+                Some(format!("{}+{}", ours?, theirs?))
+            })
+            .unwrap();
+
+        let s = |v: &str| Some(v.to_string());
+        assert_eq!(
+            conflicts,
+            vec![
+                (
+                    key(5).to_slice().to_vec(),
+                    s("base"),
+                    s("ours"),
+                    s("theirs")
+                ),
+                (key(7).to_slice().to_vec(), s("base"), None, s("theirs")),
+            ]
+        );
+        let value = |i: u32| merged.get(&key(i), 0).ok().map(|(_, value, _, _)| value);
+        assert_eq!(value(0), s("base"));
+        assert_eq!(value(1), s("ours"));
+        assert_eq!(value(2), None);
+        assert_eq!(value(3), s("theirs"));
+        assert_eq!(value(4), None);
+        assert_eq!(value(5), s("ours+theirs"));
+        assert_eq!(value(6), s("same"));
+        assert_eq!(value(7), None);
+        assert_eq!(value(200), s("ours"));
+        assert_eq!(value(300), s("theirs"));
+        assert_eq!(merged.iter().count(), 99);
+
+        // Without changes of its own, the merge takes the other side as a whole
+        let merged = tree
+            .three_way_merge(&base, &theirs, |_, _, _, _| unreachable!())
+            .unwrap();
+        assert!(Arc::ptr_eq(merged.root().unwrap(), theirs.root().unwrap()));
+
+        // Keys are passed to resolve and written back as given under a byte permutation
+        let mut tree =
+            Tree::<VariableKey, String>::with_options(TreeOptions::new().with_byte_permutation(9));
+        for i in 0..100 {
+            tree.insert(&key(i), "base".to_string(), 0, 1).unwrap();
+        }
+        let base = tree.create_snapshot().unwrap();
+        let mut ours = tree.clone();
+        let mut theirs = tree.clone();
+        ours.insert(&key(1), "ours".to_string(), 0, 2).unwrap();
+        theirs.insert(&key(3), "theirs".to_string(), 0, 3).unwrap();
+        theirs.remove(&key(4)).unwrap();
+        ours.insert(&key(5), "ours".to_string(), 0, 2).unwrap();
+        theirs.insert(&key(5), "theirs".to_string(), 0, 3).unwrap();
+        let mut conflicts = Vec::new();
+        let merged = ours
+            .three_way_merge(&base, &theirs, |key, _, ours, theirs| {
+                conflicts.push(key.to_slice().to_vec());
+                Some(format!("{}+{}", ours?, theirs?))
+            })
+            .unwrap();
+        assert_eq!(conflicts, vec![key(5).to_slice().to_vec()]);
+        let value = |i: u32| merged.get(&key(i), 0).ok().map(|(_, value, _, _)| value);
+        assert_eq!(value(0), s("base"));
+        assert_eq!(value(1), s("ours"));
+        assert_eq!(value(3), s("theirs"));
+        assert_eq!(value(4), None);
+        assert_eq!(value(5), s("ours+theirs"));
+        assert_eq!(merged.iter().count(), 99);
     }
 
     #[test]
-    fn timed_insertion_update_same_key() {
-        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+    fn byte_permutation() {
+        let words = [
+            "apple",
+            "apricot",
+            "banana",
+            "blueberry",
+            "cherry",
+            "date",
+            "fig",
+        ];
+        let mut plain = Tree::<VariableKey, usize>::new();
+        let options = TreeOptions::new().with_byte_permutation(42);
+        let mut permuted = Tree::<VariableKey, usize>::with_options(options.clone());
+        for (i, word) in words.iter().enumerate() {
+            let key = VariableKey::from_str(word);
+            plain.insert(&key, i, 0, i as u64).unwrap();
+            permuted.insert(&key, i, 0, i as u64).unwrap();
+        }
+        let mut bulk = Tree::<VariableKey, usize>::with_options(options);
+        let kvs: Vec<_> = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| KV::new(VariableKey::from_str(word), i, 0, 0))
+            .collect();
+        bulk.bulk_insert(&kvs).unwrap();
+
+        // Point operations see the keys as they were given
+        for (i, word) in words.iter().enumerate() {
+            let key = VariableKey::from_str(word);
+            let (found, value, _, _) = permuted.get(&key, 0).unwrap();
+            assert_eq!((found, value), (key.clone(), i));
+            assert_eq!(bulk.get(&key, 0).unwrap().1, i);
+            assert_eq!(permuted.latest_ts(&key), Some(i as u64));
+        }
+        assert!(permuted.get(&VariableKey::from_str("grape"), 0).is_err());
+
+        // Iteration yields the original keys, in the order of the permuted ones
+        let mut keys: Vec<Vec<u8>> = permuted.keys().collect();
+        assert_ne!(keys, plain.keys().collect::<Vec<_>>());
+        assert_eq!(keys, bulk.keys().collect::<Vec<_>>());
+        let root = permuted.root().unwrap();
+        let mut stored = Vec::new();
+        Node::collect_twigs(root, &mut stored);
+        assert!(stored
+            .iter()
+            .all(|twig| !keys.contains(&twig.key.to_slice().to_vec())));
+        keys.sort();
+        assert_eq!(keys, plain.keys().collect::<Vec<_>>());
+
+        assert!(permuted.remove(&VariableKey::from_str("date")).unwrap());
+        assert!(permuted.get(&VariableKey::from_str("date"), 0).is_err());
+        assert_eq!(permuted.iter().count(), words.len() - 1);
+
+        // The same seed always gives the same layout
+        let mut again =
+            Tree::<VariableKey, usize>::with_options(TreeOptions::new().with_byte_permutation(42));
+        for (i, word) in words.iter().enumerate() {
+            again.insert(&VariableKey::from_str(word), i, 0, 0).unwrap();
+        }
+        assert_eq!(
+            again.keys().collect::<Vec<_>>(),
+            bulk.keys().collect::<Vec<_>>()
+        );
+    }
 
-        let key1 = &VariableKey::from_str("key_1");
+    #[test]
+    fn byte_permutation_all_apis() {
+        fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+            items.sort();
+            items
+        }
+        let k = |word: &str| VariableKey::from_str(word);
+
+        let mut plain = Tree::<VariableKey, usize>::new();
+        let mut permuted =
+            Tree::<VariableKey, usize>::with_options(TreeOptions::new().with_byte_permutation(7));
+        let words: Vec<String> = (0..200)
+            .map(|i| format!("k{:03}", i))
+            .chain(["apple", "apricot", "banana"].map(String::from))
+            .collect();
+        for (i, word) in words.iter().enumerate() {
+            plain.insert(&k(word), i, 0, i as u64).unwrap();
+            permuted.insert(&k(word), i, 0, i as u64).unwrap();
+        }
+        let keys: Vec<Vec<u8>> = plain.keys().collect();
+        let (lo, hi) = (k("k050"), k("k150"));
+
+        // Ranges, their counts and copies hold the same keys, in another order
+        let range = |tree: &Tree<VariableKey, usize>| {
+            let entries = tree
+                .range(lo.clone()..hi.clone())
+                .map(|(key, v, _, _)| (key, *v));
+            sorted(entries.collect::<Vec<_>>())
+        };
+        assert_eq!(range(&permuted), range(&plain));
+        assert_eq!(range(&permuted).len(), 100);
+        let limited: Vec<_> = permuted.range_limited(lo.clone()..hi.clone(), 10).collect();
+        assert_eq!(limited.len(), 10);
+        assert!(limited.iter().all(
+            |(key, _, _, _)| lo.as_slice() <= key.as_slice() && key.as_slice() < hi.as_slice()
+        ));
+        let prefix = |tree: &Tree<VariableKey, usize>| {
+            let keys = tree
+                .range_prefix(&k("k050"), &k("k060"))
+                .map(|(key, _, _, _)| key);
+            sorted(keys.collect::<Vec<_>>())
+        };
+        assert_eq!(prefix(&permuted), prefix(&plain));
+        assert_eq!(prefix(&permuted).len(), 10);
+        assert_eq!(permuted.range_count(lo.clone()..hi.clone()), 100);
+        assert_eq!(permuted.approx_range_count(lo.clone()..hi.clone()), 100);
+        assert_eq!(
+            sorted(
+                permuted
+                    .clone_range(lo.clone()..hi.clone())
+                    .keys()
+                    .collect()
+            ),
+            plain
+                .clone_range(lo.clone()..hi.clone())
+                .keys()
+                .collect::<Vec<_>>()
+        );
 
-        // insert key1 with version 0
-        assert!(tree.insert(key1, 1, 0, 1).is_ok());
-        // update key1 with version 0
-        assert!(tree.insert(key1, 1, 0, 3).is_ok());
+        // Positions follow the iteration order, and round-trip through the keys
+        let in_order: Vec<Vec<u8>> = permuted.keys().collect();
+        for (i, key) in in_order.iter().enumerate() {
+            assert_eq!(permuted.nth(i).unwrap().0, *key);
+            assert_eq!(permuted.select(i).unwrap().0.as_slice(), key.as_slice());
+            assert_eq!(permuted.rank(&VariableKey::from(key.clone())), Some(i));
+        }
+        assert_eq!(permuted.rank(&k("k999")), None);
+        assert_eq!(sorted(in_order), keys);
+
+        // Scans hand out the keys as given
+        let mut folded = Vec::new();
+        permuted.fold_prefix(b"k1", (), |_, key, _, _| folded.push(key.to_vec()));
+        let mut expected = Vec::new();
+        plain.fold_prefix(b"k1", (), |_, key, _, _| expected.push(key.to_vec()));
+        assert_eq!(sorted(folded), expected);
+        assert_eq!(expected.len(), 100);
+        let mut visited = Vec::new();
+        permuted.for_each(|key, _, _| visited.push(key.to_vec()));
+        assert_eq!(sorted(visited), keys);
+        assert_eq!(
+            sorted(permuted.snapshot_values_at(50).collect()),
+            plain.snapshot_values_at(50).collect::<Vec<_>>()
+        );
+        assert_eq!(permuted.recent(5), plain.recent(5));
+        let groups = |tree: &Tree<VariableKey, usize>| {
+            let groups = tree
+                .iter_distinct_prefix(2)
+                .map(|(key, _, _, _)| key[..2].to_vec());
+            sorted(groups.collect::<Vec<_>>())
+        };
+        assert_eq!(groups(&permuted), groups(&plain));
+        assert_eq!(
+            sorted(
+                permuted
+                    .scan_versions(0, u64::MAX)
+                    .map(|(key, _, _, _)| key)
+                    .collect()
+            ),
+            plain
+                .scan_versions(0, u64::MAX)
+                .map(|(key, _, _, _)| key)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            sorted(permuted.try_iter().map(|entry| entry.unwrap().0).collect()),
+            keys
+        );
+        assert_eq!(
+            Tree::merge_iter(&[&permuted, &plain])
+                .map(|(key, v, _)| (key, *v))
+                .collect::<Vec<_>>(),
+            plain
+                .iter()
+                .map(|(key, v, _, _)| (key, *v))
+                .collect::<Vec<_>>()
+        );
 
-        // get key1 should return version 2 as the same key was inserted and updated
-        let (_, val, version, ts) = tree.get(key1, 0).unwrap();
-        assert_eq!(val, 1);
-        assert_eq!(version, 2);
-        assert_eq!(ts, 3);
+        // The cursor seeks to a key as given and walks in the iteration order
+        let mut cursor = permuted.cursor();
+        assert_eq!(cursor.seek(&k("k100")).unwrap().0, k("k100").as_slice());
+        let next = cursor.next().unwrap().0.to_vec();
+        assert_eq!(cursor.prev().unwrap().0, k("k100").as_slice());
+        let mut walk = vec![cursor
+            .seek(&VariableKey::from(permuted.nth(0).unwrap().0))
+            .unwrap()
+            .0
+            .to_vec()];
+        while let Some((key, _)) = cursor.next() {
+            walk.push(key.to_vec());
+        }
+        assert_eq!(walk, permuted.keys().collect::<Vec<_>>());
+        assert!(walk.contains(&next));
+
+        // Snapshots and their readers translate keys both ways
+        let mut snap = permuted.create_snapshot().unwrap();
+        assert_eq!(snap.get(&k("k005"), 0).unwrap().0, 5);
+        snap.insert(&k("zebra"), 999, 0).unwrap();
+        assert_eq!(snap.get(&k("zebra"), 0).unwrap().0, 999);
+        assert_eq!(
+            sorted(
+                snap.range(lo.clone()..hi.clone())
+                    .map(|(key, v, _, _)| (key, *v))
+                    .collect()
+            ),
+            range(&plain)
+        );
+        {
+            let reader = snap.new_reader().unwrap();
+            let read: Vec<_> = reader.iter().map(|(key, _, _, _)| key).collect();
+            assert!(read.contains(&k("zebra").as_slice().to_vec()));
+            assert_eq!(reader.range(lo.clone()..hi.clone()).count(), 100);
+        }
+        assert!(snap.remove(&k("zebra")).unwrap());
+        assert!(snap.get(&k("zebra"), 0).is_err());
+
+        // Splits and range removals partition the keys as given
+        let mut left = permuted.clone_range(..);
+        let right = left.split_off(&k("k100")).unwrap();
+        let mut plain_left = plain.clone_range(..);
+        let plain_right = plain_left.split_off(&k("k100")).unwrap();
+        assert_eq!(
+            sorted(left.keys().collect()),
+            plain_left.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            sorted(right.keys().collect()),
+            plain_right.keys().collect::<Vec<_>>()
+        );
+        let mut removed = permuted.clone_range(..);
+        assert_eq!(
+            removed
+                .remove_range_at(lo.clone()..hi.clone(), 1000)
+                .unwrap(),
+            100
+        );
+        assert_eq!(removed.len(), words.len() - 100);
+        assert_eq!(removed.range_count(lo.clone()..hi.clone()), 0);
+
+        // Exports do not depend on the permutation
+        let mut bytes = Vec::new();
+        permuted
+            .export(&mut bytes, |v| v.to_le_bytes().to_vec())
+            .unwrap();
+        let imported = Tree::<VariableKey, usize>::import(bytes.as_slice(), |bytes| {
+            usize::from_le_bytes(bytes.try_into().unwrap())
+        })
+        .unwrap();
+        assert_eq!(imported.keys().collect::<Vec<_>>(), keys);
+
+        let drained: Vec<_> = permuted.drain().map(|(key, v, _)| (key, v)).collect();
+        assert_eq!(
+            sorted(drained),
+            plain
+                .iter()
+                .map(|(key, v, _, _)| (key, *v))
+                .collect::<Vec<_>>()
+        );
+        assert!(permuted.is_empty());
+    }
 
-        // update key1 with older version should fail
-        assert!(tree.insert(key1, 1, 1, 0).is_err());
-        assert_eq!(tree.version(), 2);
+    #[test]
+    fn multi_remove() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for i in 0..2000usize {
+            tree.insert(&VariableKey::from_str(&format!("key{}", i)), i, 0, 0)
+                .unwrap();
+        }
+        tree.delete_logical(&VariableKey::from_str("key5"), 1)
+            .unwrap();
+        assert_eq!(tree.len(), 1999);
+        assert_eq!(tree.get(&VariableKey::from_str("key5"), 6).unwrap().1, 5);
+
+        // Unsorted, with duplicates, missing keys, a deleted key and whole subtrees
+        let mut names: Vec<String> = (0..2000).step_by(7).map(|i| format!("key{}", i)).collect();
+        names.extend((100..200).map(|i| format!("key{}", i)));
+        names.extend(["key21", "key5", "missing", "key", "key14"].map(String::from));
+        names.reverse();
+        let keys: Vec<VariableKey> = names
+            .iter()
+            .map(|name| VariableKey::from_str(name))
+            .collect();
+
+        let mut expected_tree = tree.clone();
+        let expected: Vec<Option<usize>> = keys
+            .iter()
+            .map(|key| {
+                let value = expected_tree.get(key, 0).ok().map(|(_, value, _, _)| value);
+                expected_tree.remove(key).unwrap();
+                value
+            })
+            .collect();
+
+        let removed = tree.multi_remove(&keys).unwrap();
+        assert_eq!(removed, expected);
+        assert_eq!(removed.iter().filter(|value| value.is_some()).count(), 372);
+        assert_eq!(tree.len(), 1999 - 372);
+        assert_eq!(tree.len(), expected_tree.len());
+        assert!(tree.history_eq(&expected_tree));
+        // The history of a removed key goes with it
+        assert!(tree.get(&VariableKey::from_str("key5"), 6).is_err());
+
+        // Removing everything leaves an empty tree
+        let all: Vec<VariableKey> = tree.keys().map(VariableKey::from).collect();
+        assert_eq!(tree.multi_remove(&all).unwrap().len(), all.len());
+        assert!(tree.is_empty());
+        assert!(tree.root().is_none());
+    }
 
-        // update key1 with newer version should pass
-        assert!(tree.insert(key1, 1, 8, 5).is_ok());
-        let (_, val, version, ts) = tree.get(key1, 0).unwrap();
-        assert_eq!(val, 1);
-        assert_eq!(version, 8);
-        assert_eq!(ts, 5);
+    #[test]
+    fn version_count() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        let key = VariableKey::from_str("counter");
+        let other = VariableKey::from_str("counter2");
+        assert_eq!(tree.version_count(&key), 0);
+
+        for i in 0..10 {
+            tree.insert(&key, i, 0, i as u64 * 10).unwrap();
+        }
+        tree.insert(&other, 0, 0, 0).unwrap();
+        assert_eq!(tree.version_count(&key), 10);
+        assert_eq!(tree.version_count(&other), 1);
+        assert_eq!(tree.version_count(&VariableKey::from_str("count")), 0);
+
+        // Tombstones are stored versions too, until gc reclaims the history
+        tree.delete_logical(&key, 100).unwrap();
+        assert_eq!(tree.version_count(&key), 11);
+        tree.gc(55).unwrap();
+        assert_eq!(tree.version_count(&key), 6);
+    }
 
-        assert_eq!(tree.version(), 8);
+    #[test]
+    fn iter_with_history() {
+        let mut tree = Tree::<VariableKey, &str>::new();
+        let key = |name: &str| VariableKey::from_str(name);
+        tree.insert(&key("b"), "b1", 0, 10).unwrap();
+        tree.insert(&key("a"), "a1", 0, 10).unwrap();
+        tree.insert(&key("b"), "b3", 0, 30).unwrap();
+        // Written later, but stamped between the other two versions
+        tree.insert(&key("b"), "b2", 0, 20).unwrap();
+        tree.insert(&key("c"), "c1", 0, 5).unwrap();
+        tree.insert(&key("c"), "c2", 0, 15).unwrap();
+        tree.delete_logical(&key("c"), 25).unwrap();
+        tree.insert(&key("d"), "d1", 0, 1).unwrap();
+        tree.remove(&key("d")).unwrap();
+
+        let history: Vec<_> = tree.iter_with_history().collect();
+        assert_eq!(
+            history,
+            vec![
+                (key("a").as_slice().to_vec(), vec![("a1", 10)]),
+                (
+                    key("b").as_slice().to_vec(),
+                    vec![("b1", 10), ("b2", 20), ("b3", 30)]
+                ),
+                (key("c").as_slice().to_vec(), vec![("c1", 5), ("c2", 15)]),
+            ]
+        );
+        assert_eq!(
+            Tree::<VariableKey, &str>::new().iter_with_history().count(),
+            0
+        );
     }
 
     #[test]
-    fn timed_insertion_update_non_increasing_version() {
-        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+    fn into_variable() {
+        let mut tree = Tree::<FixedKey<8>, u64>::new();
+        for i in (0..3000u64).rev() {
+            tree.insert(&(i * 977).into(), i, 0, i).unwrap();
+        }
+        tree.insert(&(5 * 977u64).into(), 50, 0, 5000).unwrap();
+        tree.delete_logical(&(7 * 977u64).into(), 6000).unwrap();
+        let expected: Vec<(Vec<u8>, u64, u64, u64)> = tree
+            .iter()
+            .map(|(key, value, version, ts)| (key, *value, *version, *ts))
+            .collect();
+        let fixed_key: FixedKey<8> = (5 * 977u64).into();
+        let expected_history = tree.get_at_timestamps(&fixed_key, &[4, 5, 4999, 5000]);
+        let expected_kinds = tree.fill_histogram();
+
+        let variable = tree.into_variable();
+        let converted: Vec<(Vec<u8>, u64, u64, u64)> = variable
+            .iter()
+            .map(|(key, value, version, ts)| (key, *value, *version, *ts))
+            .collect();
+        assert_eq!(converted, expected);
+        assert_eq!(converted.len(), 2999);
+        assert!(converted.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        // The history of every key comes along, tombstones included
+        let key = VariableKey::from(fixed_key.as_slice().to_vec());
+        assert_eq!(
+            variable.get_at_timestamps(&key, &[4, 5, 4999, 5000]),
+            expected_history
+        );
+        assert_eq!(variable.get_at_timestamps(&key, &[4999]), vec![Some(5)]);
+        let deleted = VariableKey::from((7 * 977u64).to_be_bytes().to_vec());
+        assert_eq!(
+            variable.get_at_timestamps(&deleted, &[10, 6000]),
+            vec![Some(7), None]
+        );
+        assert_eq!(variable.fill_histogram(), expected_kinds);
+        assert_eq!(variable.len(), 2999);
+    }
 
-        let key1 = VariableKey::from_str("key_1");
-        let key2 = VariableKey::from_str("key_2");
+    #[test]
+    fn compact_versions_before() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        let key = VariableKey::from_str("counter");
+        let deleted = VariableKey::from_str("deleted");
+        for i in 1..=6 {
+            tree.insert(&key, i, 0, i as u64 * 10).unwrap();
+        }
+        tree.insert(&deleted, 0, 0, 10).unwrap();
+        tree.delete_logical(&deleted, 20).unwrap();
+
+        let ts_list = [5, 25, 30, 35, 40, 60, 100];
+        let before = tree.get_at_timestamps(&key, &ts_list);
+        assert_eq!(tree.compact_versions_before(35).unwrap(), 3);
+        assert_eq!(tree.version_count(&key), 4);
+
+        // Reads at and after the cutoff are unchanged
+        let after = tree.get_at_timestamps(&key, &ts_list);
+        assert_eq!(after[3..], before[3..]);
+        // Reads before the cutoff see the collapsed history
+        assert_eq!(after[..3], [None, None, Some(3)]);
+
+        // Unlike gc, the tombstone is kept with its key
+        assert_eq!(tree.version_count(&deleted), 1);
+        assert_eq!(tree.get_at_timestamps(&deleted, &[40]), [None]);
+        assert_eq!(tree.compact_versions_before(35).unwrap(), 0);
+        assert_eq!(tree.gc(35).unwrap(), 1);
+        assert_eq!(tree.version_count(&deleted), 0);
+    }
 
-        // Initial insertion
-        assert!(tree.insert(&key1, 1, 10, 0).is_ok());
-        let initial_version_key1 = tree.version();
+    #[test]
+    fn iter_pinned() {
+        let mut tree = Tree::<FixedKey<8>, u64>::new();
+        for i in 0..100u64 {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
+        }
+        let expected: Vec<_> = tree
+            .iter()
+            .map(|(key, value, version, _)| (key, *value, *version))
+            .collect();
+
+        // The iteration is started before the writes and finished after them
+        let mut iter = tree.iter_pinned();
+        let first: Vec<_> = iter.by_ref().take(10).collect();
+        for i in 0..100u64 {
+            tree.insert(&i.into(), i + 1000, 0, 0).unwrap();
+        }
+        tree.insert(&1000u64.into(), 0, 0, 0).unwrap();
+        tree.remove(&50u64.into()).unwrap();
+        let rest: Vec<_> = iter.collect();
+        assert_eq!([first, rest].concat(), expected);
+
+        assert_eq!(tree.iter_pinned().count(), 100);
+        assert!(tree.iter_pinned().all(|(_, value, _)| value != 50 + 1000));
+        assert_eq!(Tree::<FixedKey<8>, u64>::new().iter_pinned().count(), 0);
+    }
 
-        // Attempt update with non-increasing version
-        assert!(tree.insert(&key1, 1, 2, 0).is_err());
-        assert_eq!(initial_version_key1, tree.version());
-        let (_, val, version, _) = tree.get(&key1, 0).unwrap();
-        assert_eq!(val, 1);
-        assert_eq!(version, 10);
+    #[test]
+    fn diff() {
+        use super::Diff;
 
-        // Insert another key
-        assert!(tree.insert(&key2, 1, 15, 0).is_ok());
-        let initial_version_key2 = tree.version();
+        let mut old = Tree::<VariableKey, usize>::new();
+        for i in 0..50 {
+            old.insert(&VariableKey::from_str(&format!("key{}", i)), i, 0, 0)
+                .unwrap();
+        }
+        assert_eq!(old.diff(&old.clone()).count(), 0);
+
+        // Built independently, so no subtree is shared
+        let mut new = Tree::<VariableKey, usize>::new();
+        for i in 0..50 {
+            let value = if i == 7 { 700 } else { i };
+            if i != 20 {
+                new.insert(&VariableKey::from_str(&format!("key{}", i)), value, 0, 0)
+                    .unwrap();
+            }
+        }
+        new.insert(&VariableKey::from_str("key5a"), 1, 0, 0)
+            .unwrap();
+        let key = |name: &str| VariableKey::from_str(name).as_slice().to_vec();
+        let expected = vec![
+            Diff::Removed(key("key20"), 20),
+            Diff::Added(key("key5a"), 1),
+            Diff::Updated(key("key7"), 7, 700),
+        ];
+        assert_eq!(new.diff(&old).collect::<Vec<_>>(), expected);
+
+        // A clone changed in one place shares everything else with the original
+        let mut changed = old.clone();
+        changed.remove(&VariableKey::from_str("key3")).unwrap();
+        assert_eq!(
+            changed.diff(&old).collect::<Vec<_>>(),
+            vec![Diff::Removed(key("key3"), 3)]
+        );
+        assert_eq!(
+            old.diff(&changed).collect::<Vec<_>>(),
+            vec![Diff::Added(key("key3"), 3)]
+        );
+    }
 
-        // Attempt update with non-increasing version for the second key
-        assert!(tree.insert(&key2, 1, 11, 0).is_err());
-        assert_eq!(initial_version_key2, tree.version());
-        let (_, val, version, _ts) = tree.get(&key2, 0).unwrap();
-        assert_eq!(val, 1);
-        assert_eq!(version, 15);
+    #[test]
+    fn send_sync() {
+        use crate::concurrent::ConcurrentTree;
+        use crate::iter::{IterationPointer, OwnedIter};
+        use crate::node::{Node256, Node48, TwigNode};
+        use crate::snapshot::Snapshot;
+        use crate::KeyTrait;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        // Checked for any keys and values that are Send and Sync, not just the ones below
+        fn check<P: KeyTrait + Send + Sync, V: Clone + Send + Sync>() {
+            assert_send_sync::<Tree<P, V>>();
+            assert_send_sync::<Snapshot<P, V>>();
+            assert_send_sync::<ConcurrentTree<P, V>>();
+            assert_send_sync::<IterationPointer<P, V>>();
+            assert_send_sync::<OwnedIter<P, V>>();
+            assert_send_sync::<Node<P, V>>();
+            assert_send_sync::<TwigNode<P, V>>();
+            assert_send_sync::<FlatNode<P, Node<P, V>, 16>>();
+            assert_send_sync::<Node48<P, Node<P, V>>>();
+            assert_send_sync::<Node256<P, Node<P, V>>>();
+        }
+        check::<FixedKey<16>, u64>();
+        check::<VariableKey, String>();
+
+        let mut tree = Tree::<VariableKey, String>::new();
+        tree.insert(&VariableKey::from_str("key"), "value".to_string(), 0, 0)
+            .unwrap();
+        let mut snapshot = tree.create_snapshot().unwrap();
+        let reader = snapshot.new_reader().unwrap();
+        let handle = std::thread::spawn(move || {
+            (
+                snapshot.get(&VariableKey::from_str("key"), 0).is_ok(),
+                reader.iter().count(),
+            )
+        });
+        assert_eq!(handle.join().unwrap(), (true, 1));
+    }
 
-        // Check if the max version of the tree is the max of the two inserted versions
-        assert_eq!(tree.version(), 15);
+    #[test]
+    fn purge_key() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        let key = VariableKey::from_str("user");
+        let sibling = VariableKey::from_str("user1");
+        assert_eq!(tree.purge_key(&key).unwrap(), 0);
+
+        for i in 0..5 {
+            tree.insert(&key, i, 0, i as u64).unwrap();
+            tree.insert(&sibling, i + 10, 0, i as u64).unwrap();
+        }
+        tree.delete_logical(&key, 10).unwrap();
+        assert_eq!(tree.purge_key(&key).unwrap(), 6);
+        assert_eq!(tree.version_count(&key), 0);
+        assert!(tree
+            .get_at_timestamps(&key, &[0, 4])
+            .iter()
+            .all(Option::is_none));
+        assert_eq!(tree.purge_key(&key).unwrap(), 0);
+
+        // Only the purged key lost its history
+        assert_eq!(tree.version_count(&sibling), 5);
+        assert_eq!(tree.get_at_timestamps(&sibling, &[2]), [Some(12)]);
+        assert_eq!(tree.purge_key(&sibling).unwrap(), 5);
+        assert!(tree.is_empty());
+        assert!(tree.root.is_none());
     }
 
     #[test]
-    fn timed_insertion_update_equal_to_root_version() {
-        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+    fn gc_step() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for i in 0..100 {
+            let key = VariableKey::from_str(&format!("key{}", i));
+            for ts in 0..4 {
+                tree.insert(&key, i, 0, ts).unwrap();
+            }
+            if i % 10 == 0 {
+                tree.delete_logical(&key, 1).unwrap();
+            }
+        }
+        let mut expected = tree.clone();
+        let total = expected.gc(2).unwrap();
 
-        let key1 = VariableKey::from_str("key_1");
-        let key2 = VariableKey::from_str("key_2");
+        let mut steps = 0;
+        let mut reclaimed = 0;
+        let mut cursor = None;
+        loop {
+            let progress = tree.gc_step(2, 5, cursor).unwrap();
+            assert!(progress.reclaimed <= 5);
+            reclaimed += progress.reclaimed;
+            steps += 1;
+            cursor = progress.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(reclaimed, total);
+        assert!(steps >= total / 5);
+        assert!(tree.history_eq(&expected));
+        assert_eq!(tree.len(), expected.len());
+
+        // Nothing is left to reclaim, and a twig over the budget is reclaimed as a whole
+        assert_eq!(tree.gc_step(2, 5, None).unwrap().reclaimed, 0);
+        // key0 is left with a live version and a tombstone hiding it, both reclaimed
+        let progress = tree.gc_step(3, 0, None).unwrap();
+        assert_eq!(progress.reclaimed, 2);
+        assert_eq!(tree.version_count(&VariableKey::from_str("key0")), 0);
+        assert!(progress.cursor.is_some());
+    }
 
-        // Initial insertion
-        assert!(tree.insert(&key1, 1, 10, 0).is_ok());
-        let initial_version = tree.version();
+    #[test]
+    fn range_prefix() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        let key = |partition: usize, i: usize| {
+            VariableKey::from_str(&format!("part{}/key{:03}", partition, i))
+        };
+        for partition in 0..10 {
+            for i in 0..100 {
+                tree.insert(&key(partition, i), i, 0, 0).unwrap();
+            }
+        }
 
-        // Attempt update with version equal to root's version
-        assert!(tree.insert(&key2, 1, initial_version, 0).is_err());
+        let (start, end) = (key(7, 20), key(7, 60));
+        let mut scan = tree.range_prefix(&start, &end);
+        let mut range = tree.range(start.clone()..end.clone());
+        let expected: Vec<_> = range.by_ref().collect();
+        assert_eq!(scan.by_ref().collect::<Vec<_>>(), expected);
+        assert_eq!(expected.len(), 40);
+        // Only the nodes of the partition's subtree are visited
+        assert!(scan.visited <= 100 + 10);
+        assert!(scan.visited * 5 < range.visited);
+
+        // Bounds without any key between them, or in the wrong order
+        assert_eq!(tree.range_prefix(&key(11, 0), &key(11, 5)).count(), 0);
+        assert_eq!(tree.range_prefix(&end, &start).count(), 0);
+        assert_eq!(tree.range_prefix(&key(1, 99), &key(2, 1)).count(), 2);
+        assert_eq!(
+            Tree::<VariableKey, usize>::new()
+                .range_prefix(&start, &end)
+                .count(),
+            0
+        );
     }
 
     #[test]
-    fn timed_deletion_check_root_ts() {
-        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
-
-        // Initial insertions
-        assert!(tree
-            .insert(&VariableKey::from_str("key_1"), 1, 0, 0)
-            .is_ok());
-        assert!(tree
-            .insert(&VariableKey::from_str("key_2"), 1, 0, 0)
-            .is_ok());
-        assert_eq!(tree.version(), 2);
+    fn insert_if_changed() {
+        let mut tree = Tree::<VariableKey, &str>::new();
+        let key = VariableKey::from_str("config");
+        assert!(tree.insert_if_changed(&key, "on", 0, 1).unwrap());
+        assert!(!tree.insert_if_changed(&key, "on", 0, 2).unwrap());
+        assert!(!tree.insert_if_changed(&key, "on", 0, 3).unwrap());
+        assert_eq!(tree.version_count(&key), 1);
+
+        assert!(tree.insert_if_changed(&key, "off", 0, 4).unwrap());
+        assert_eq!(tree.version_count(&key), 2);
+        // Only the latest value counts, not older ones
+        assert!(tree.insert_if_changed(&key, "on", 0, 5).unwrap());
+        assert_eq!(tree.version_count(&key), 3);
+
+        // A deleted key is written again, even with the value it held
+        tree.delete_logical(&key, 6).unwrap();
+        assert!(tree.insert_if_changed(&key, "on", 0, 7).unwrap());
+        assert_eq!(tree.get(&key, 0).unwrap().1, "on");
+    }
 
-        // Deletions
-        assert!(tree.remove(&VariableKey::from_str("key_1")).unwrap());
-        assert!(tree.remove(&VariableKey::from_str("key_2")).unwrap());
-        assert_eq!(tree.version(), 0);
+    #[test]
+    fn get_ref() {
+        let mut tree = Tree::<VariableKey, String>::new();
+        let key = VariableKey::from_str("blob");
+        tree.insert(&key, "a".repeat(1 << 16), 0, 10).unwrap();
+        tree.insert(&key, "b".repeat(1 << 16), 0, 20).unwrap();
+
+        // The reference points at the stored value rather than a copy of it
+        let stored = tree.iter().next().unwrap().1.as_ptr();
+        let value = tree.get_ref(&key, 25).unwrap();
+        assert_eq!(value.as_ptr(), stored);
+        assert_eq!(tree.get_ref(&key, 30).unwrap().as_ptr(), stored);
+        assert_ne!(tree.get(&key, 0).unwrap().1.as_ptr(), stored);
+
+        assert!(tree.get_ref(&key, 15).unwrap().starts_with('a'));
+        assert_eq!(tree.get_ref(&key, 5), None);
+        assert_eq!(tree.get_ref(&VariableKey::from_str("other"), 30), None);
+        tree.delete_logical(&key, 40).unwrap();
+        assert_eq!(tree.get_ref(&key, 40), None);
     }
 
-    fn from_be_bytes_key(k: &[u8]) -> u64 {
-        let padded_k = if k.len() < 8 {
-            let mut new_k = vec![0; 8];
-            new_k[8 - k.len()..].copy_from_slice(k);
-            new_k
-        } else {
-            k.to_vec()
-        };
+    #[test]
+    fn validate_unchanged() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        let (a, b, c) = (
+            VariableKey::from_str("a"),
+            VariableKey::from_str("b"),
+            VariableKey::from_str("c"),
+        );
+        tree.insert(&a, 1, 0, 10).unwrap();
+        tree.insert(&b, 2, 0, 20).unwrap();
+
+        let reads: Vec<_> = [&a, &b, &c]
+            .into_iter()
+            .map(|key| (key.clone(), tree.latest_ts(key)))
+            .collect();
+        assert!(tree.validate_unchanged(&reads));
+        assert!(tree.validate_unchanged(&[]));
+
+        // Writes to keys outside the read set leave it valid
+        tree.insert(&VariableKey::from_str("d"), 4, 0, 30).unwrap();
+        assert!(tree.validate_unchanged(&reads));
+
+        // Rewriting a key, even with the same value, deleting it or creating it is a conflict
+        for write in [0, 1, 2] {
+            let mut tree = tree.clone();
+            match write {
+                0 => tree.insert(&a, 1, 0, 40).map(|_| ()).unwrap(),
+                1 => tree.delete_logical(&b, 40).map(|_| ()).unwrap(),
+                _ => tree.insert(&c, 3, 0, 40).map(|_| ()).unwrap(),
+            }
+            assert!(!tree.validate_unchanged(&reads));
+        }
 
-        let k_slice = &padded_k[..8];
-        u64::from_be_bytes(k_slice.try_into().unwrap())
+        // A rewrite with the same timestamp only shows in the version
+        let reads: Vec<_> = [&a, &c]
+            .into_iter()
+            .map(|key| (key.clone(), tree.latest_version(key)))
+            .collect();
+        assert!(tree.validate_versions_unchanged(&reads));
+        let ts_reads = [(a.clone(), tree.latest_ts(&a))];
+        tree.insert(&a, 5, 0, 10).unwrap();
+        assert!(tree.validate_unchanged(&ts_reads));
+        assert!(!tree.validate_versions_unchanged(&reads));
     }
 
     #[test]
-    fn iter_seq_u16() {
-        let mut tree = Tree::<FixedKey<16>, u16>::new();
-
-        // Insertion
-        for i in 0..u16::MAX {
-            let key: FixedKey<16> = i.into();
-            tree.insert(&key, i, 0, i as u64);
+    fn iter_fixed() {
+        for options in [
+            TreeOptions::new(),
+            TreeOptions::new().with_byte_permutation(7),
+        ] {
+            let mut tree = Tree::<FixedKey<16>, u64>::with_options(options);
+            for i in 0..1000u64 {
+                tree.insert(&(i * 7919).into(), i, 0, 0).unwrap();
+            }
+            tree.insert(&FixedKey::from_str("fifteen bytes!!"), 1, 0, 0)
+                .unwrap();
+            tree.remove(&7919u64.into()).unwrap();
+
+            let fixed: Vec<_> = tree.iter_fixed().collect();
+            let expected: Vec<_> = tree.iter().collect();
+            assert_eq!(fixed.len(), expected.len());
+            for ((key, value, version), (bytes, v, ver, _)) in fixed.into_iter().zip(expected) {
+                // Keys shorter than the array are padded with zeroes
+                assert_eq!(key[..bytes.len()], bytes[..]);
+                assert!(key[bytes.len()..].iter().all(|&byte| byte == 0));
+                assert_eq!((value, version), (v, *ver));
+            }
         }
+    }
 
-        // Iteration and verification
-        let mut len = 0usize;
-        let mut expected = 0u16;
+    #[test]
+    fn ts_range() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        assert_eq!(tree.ts_range(), None);
 
-        let tree_iter = tree.iter();
-        for tree_entry in tree_iter {
-            let k = from_be_bytes_key(&tree_entry.0);
-            assert_eq!(expected as u64, k);
-            let ts = tree_entry.3;
-            assert_eq!(expected as u64, *ts);
-            expected = expected.wrapping_add(1);
-            len += 1;
+        let key = |i: usize| VariableKey::from_str(&format!("key{}", i));
+        for (i, ts) in [50, 20, 90, 35, 70].into_iter().enumerate() {
+            tree.insert(&key(i), i, 0, ts).unwrap();
         }
-
-        // Final assertion
-        assert_eq!(len, u16::MAX as usize);
+        assert_eq!(tree.ts_range(), Some((20, 90)));
+
+        // Older versions still count, until they are reclaimed
+        tree.insert(&key(1), 1, 0, 60).unwrap();
+        assert_eq!(tree.ts_range(), Some((20, 90)));
+        tree.gc(100).unwrap();
+        assert_eq!(tree.ts_range(), Some((35, 90)));
+        tree.insert(&key(9), 9, 0, 5).unwrap();
+        assert_eq!(tree.ts_range(), Some((5, 90)));
+        tree.remove(&key(2)).unwrap();
+        assert_eq!(tree.ts_range(), Some((5, 70)));
     }
 
     #[test]
-    fn iter_seq_u8() {
-        let mut tree: Tree<FixedKey<32>, u8> = Tree::<FixedKey<32>, u8>::new();
-
-        // Insertion
-        for i in 0..u8::MAX {
-            let key: FixedKey<32> = i.into();
-            tree.insert(&key, i, 0, 0);
+    fn common_prefix_len() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+        assert_eq!(tree.common_prefix_len(&VariableKey::from_str("apple")), 0);
+        for (i, word) in ["apple", "apply", "banana", "band"].iter().enumerate() {
+            tree.insert(&VariableKey::from_str(word), i as i32, 0, 0)
+                .unwrap();
         }
 
-        // Iteration and verification
-        let mut len = 0usize;
-        let mut expected = 0u8;
+        // A stored key matches in full, terminator included
+        let apple = VariableKey::from_str("apple");
+        assert_eq!(tree.common_prefix_len(&apple), apple.as_slice().len());
 
-        let tree_iter = tree.iter();
-        for tree_entry in tree_iter {
-            let k = from_be_bytes_key(&tree_entry.0);
-            assert_eq!(expected as u64, k);
-            expected = expected.wrapping_add(1);
-            len += 1;
-        }
+        // Partial and overlong queries stop where the Trie diverges
+        let prefix = VariableKey::from_slice(b"ban");
+        assert_eq!(tree.common_prefix_len(&prefix), 3);
+        assert_eq!(
+            tree.common_prefix_len(&VariableKey::from_str("appliance")),
+            4
+        );
+        assert_eq!(tree.common_prefix_len(&VariableKey::from_str("bandana")), 4);
 
-        // Final assertion
-        assert_eq!(len, u8::MAX as usize);
+        // A key diverging at the first byte matches nothing
+        assert_eq!(tree.common_prefix_len(&VariableKey::from_str("cherry")), 0);
     }
 
     #[test]
-    fn range_seq_u8() {
-        let mut tree: Tree<FixedKey<8>, u8> = Tree::<FixedKey<8>, u8>::new();
-
-        let max = u8::MAX;
-        // Insertion
-        for i in 0..=max {
-            let key: FixedKey<8> = i.into();
-            tree.insert(&key, i, 0, 0);
-        }
-
-        // Test inclusive range
-        let start_key: FixedKey<8> = 5u8.into();
-        let end_key: FixedKey<8> = max.into();
-        let mut len = 0usize;
-        for _ in tree.range(start_key..=end_key) {
-            len += 1;
+    fn retain_versions() {
+        let mut tree = Tree::<VariableKey, u64>::new();
+        let sensor = VariableKey::from_str("sensor");
+        let gauge = VariableKey::from_str("gauge");
+        for ts in [100, 400, 900, 1200, 1800, 2500, 2600, 2999] {
+            tree.insert(&sensor, ts, 0, ts).unwrap();
         }
-        assert_eq!(len, max as usize - 4);
+        tree.insert(&gauge, 1, 0, 10).unwrap();
+        tree.insert(&gauge, 2, 0, 20).unwrap();
+        tree.delete_logical(&gauge, 30).unwrap();
+
+        // Keep at most one version per 1000-ts bucket, the latest one
+        let dropped = tree
+            .retain_versions(|_, versions| {
+                let mut kept: Vec<(u64, u64)> = Vec::new();
+                for (value, ts) in versions.drain(..).rev() {
+                    if kept.last().is_none_or(|(_, last)| last / 1000 != ts / 1000) {
+                        kept.push((value, ts));
+                    }
+                }
+                kept.reverse();
+                *versions = kept;
+            })
+            .unwrap();
+        assert_eq!(dropped, 6);
+
+        let history: Vec<_> = tree.iter_with_history().collect();
+        let values: Vec<u64> = history[1].1.iter().map(|(value, _)| *value).collect();
+        assert_eq!(history[1].0, sensor.as_slice());
+        assert_eq!(values, vec![900, 1800, 2999]);
+        assert_eq!(tree.get_ref(&sensor, 1500), Some(&900));
+        assert_eq!(tree.version_count(&gauge), 2);
+        assert!(tree.get(&gauge, 0).is_err());
+
+        // Dropping every live version removes the key along with its tombstone
+        assert_eq!(
+            tree.retain_versions(|_, versions| versions.clear())
+                .unwrap(),
+            5
+        );
+        assert!(tree.is_empty());
+        assert_eq!(tree.version_count(&gauge), 0);
 
-        // Test exclusive range
-        let start_key: FixedKey<8> = 5u8.into();
-        let end_key: FixedKey<8> = max.into();
-        let mut len = 0usize;
-        for _ in tree.range(start_key..end_key) {
-            len += 1;
-        }
-        assert_eq!(len, max as usize - 5);
+        // A version the key does not hold is rejected without changing anything
+        tree.insert(&sensor, 1, 0, 10).unwrap();
+        assert!(tree
+            .retain_versions(|_, versions| versions.push((2, 20)))
+            .is_err());
+        assert_eq!(tree.version_count(&sensor), 1);
+    }
 
-        // Test range with different start and end keys
-        let start_key: FixedKey<8> = 3u8.into();
-        let end_key: FixedKey<8> = 7u8.into();
-        let mut len = 0usize;
-        for _ in tree.range(start_key..=end_key) {
-            len += 1;
+    #[test]
+    fn find() {
+        let mut tree = Tree::<VariableKey, u64>::new();
+        for i in 0..100u64 {
+            let key = VariableKey::from_str(&format!("key{:03}", i));
+            tree.insert(&key, i, 0, 10).unwrap();
+            tree.insert(&key, i * 2, 0, 20).unwrap();
         }
-        assert_eq!(len, 5);
+        tree.delete_logical(&VariableKey::from_str("key050"), 30)
+            .unwrap();
+
+        // Only keys whose latest value is in the range are yielded, in key order
+        let found: Vec<_> = tree.find(|value| (90..110).contains(value)).collect();
+        let expected: Vec<_> = (45..55)
+            .filter(|i| *i != 50)
+            .map(|i| {
+                (
+                    VariableKey::from_str(&format!("key{:03}", i))
+                        .as_slice()
+                        .to_vec(),
+                    i * 2,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found
+                .into_iter()
+                .map(|(key, value)| (key, *value))
+                .collect::<Vec<_>>(),
+            expected
+        );
 
-        // Test range with all keys
-        let start_key: FixedKey<8> = 0u8.into();
-        let end_key: FixedKey<8> = max.into();
-        let mut len = 0usize;
-        for _ in tree.range(start_key..=end_key) {
-            len += 1;
-        }
-        assert_eq!(len, 256);
+        // Point-in-time reads test the value as of the timestamp
+        let found: Vec<u64> = tree
+            .find_at(15, |value| (90..110).contains(value))
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(found, (90..100).collect::<Vec<_>>());
+        let found = tree.find_at(25, |value| *value == 100).count();
+        assert_eq!(found, 1);
+        assert_eq!(tree.find_at(35, |value| *value == 100).count(), 0);
+        assert_eq!(tree.find_at(5, |_| true).count(), 0);
     }
 
     #[test]
-    fn range_seq_u16() {
-        let mut tree: Tree<FixedKey<16>, u16> = Tree::<FixedKey<16>, u16>::new();
-
-        let max = u16::MAX;
-        // Insertion
-        for i in 0..=max {
-            let key: FixedKey<16> = i.into();
-            tree.insert(&key, i, 0, 0);
+    fn prefix_conflict() {
+        // Unterminated keys can be prefixes of each other, which the Trie cannot store
+        let raw = |bytes: &[u8]| VariableKey::from(bytes.to_vec());
+        let mut tree = Tree::<VariableKey, i32>::new();
+        tree.insert(&raw(b"ab"), 1, 0, 0).unwrap();
+        assert!(matches!(
+            tree.insert(&raw(b"abc"), 2, 0, 0),
+            Err(super::TrieError::PrefixConflict)
+        ));
+
+        tree.insert(&raw(b"xyz1"), 3, 0, 0).unwrap();
+        tree.insert(&raw(b"xyz2"), 4, 0, 0).unwrap();
+        for key in [&b"a"[..], b"xy", b"xyz", b"xyz12"] {
+            assert!(matches!(
+                tree.insert(&raw(key), 5, 0, 0),
+                Err(super::TrieError::PrefixConflict)
+            ));
         }
 
-        let mut len = 0usize;
-        let start_key: FixedKey<16> = 0u8.into();
-        let end_key: FixedKey<16> = max.into();
+        // The failed inserts leave the Trie as it was
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.get(&raw(b"ab"), 0).unwrap().1, 1);
+        tree.insert(&raw(b"xyz3"), 6, 0, 0).unwrap();
+        assert_eq!(tree.len(), 4);
 
-        for _ in tree.range(start_key..=end_key) {
-            len += 1;
-        }
-        assert_eq!(len, max as usize + 1);
+        // Terminated keys never conflict
+        let mut tree = Tree::<VariableKey, i32>::new();
+        tree.insert(&VariableKey::from_str("ab"), 1, 0, 0).unwrap();
+        tree.insert(&VariableKey::from_str("abc"), 2, 0, 0).unwrap();
+        tree.insert(&VariableKey::from_slice(b"a"), 3, 0, 0)
+            .unwrap();
+        assert_eq!(tree.len(), 3);
     }
 
     #[test]
-    fn same_key_with_versions() {
+    fn prefix_bounds() {
         let mut tree = Tree::<VariableKey, i32>::new();
+        assert_eq!(tree.prefix_bounds(b"a"), None);
+        for key in [
+            "user/1/name",
+            "user/1/email",
+            "user/2/name",
+            "user/3/age",
+            "group/1",
+        ] {
+            tree.insert(&VariableKey::from_str(key), 0, 0, 0).unwrap();
+        }
+        let bytes = |key: &str| VariableKey::from_str(key).as_slice().to_vec();
 
-        // Insertions
-        let key1 = VariableKey::from_str("abc");
-        let key2 = VariableKey::from_str("efg");
-        tree.insert(&key1, 1, 0, 0);
-        tree.insert(&key1, 2, 10, 0);
-        tree.insert(&key2, 3, 11, 0);
+        // Several keys
+        assert_eq!(
+            tree.prefix_bounds(b"user/"),
+            Some((bytes("user/1/email"), bytes("user/3/age")))
+        );
+        assert_eq!(
+            tree.prefix_bounds(b""),
+            Some((bytes("group/1"), bytes("user/3/age")))
+        );
 
-        // Versioned retrievals and assertions
-        let (_, val, _, _) = tree.get(&key1, 1).unwrap();
-        assert_eq!(val, 1);
-        let (_, val, _, _) = tree.get(&key1, 10).unwrap();
-        assert_eq!(val, 2);
-        let (_, val, _, _) = tree.get(&key2, 11).unwrap();
-        assert_eq!(val, 3);
+        // A single key, and a prefix ending inside a compressed path
+        assert_eq!(
+            tree.prefix_bounds(b"user/2"),
+            Some((bytes("user/2/name"), bytes("user/2/name")))
+        );
+        assert_eq!(
+            tree.prefix_bounds(b"gro"),
+            Some((bytes("group/1"), bytes("group/1")))
+        );
 
-        // Iteration and verification
-        let mut len = 0;
-        let tree_iter = tree.iter();
-        for _ in tree_iter {
-            len += 1;
+        // Absent prefixes, and a prefix whose only key is deleted
+        assert_eq!(tree.prefix_bounds(b"user/4"), None);
+        assert_eq!(tree.prefix_bounds(b"x"), None);
+        tree.delete_logical(&VariableKey::from_str("user/3/age"), 1)
+            .unwrap();
+        assert_eq!(tree.prefix_bounds(b"user/3"), None);
+        assert_eq!(
+            tree.prefix_bounds(b"user/"),
+            Some((bytes("user/1/email"), bytes("user/2/name")))
+        );
+    }
+
+    #[test]
+    fn approx_range_count() {
+        let mut tree = Tree::<FixedKey<16>, u64>::new();
+        assert_eq!(tree.approx_range_count(..), 0);
+        let size = 100_000u64;
+        for i in 0..size {
+            tree.insert(&i.into(), i, 0, 0).unwrap();
         }
-        assert_eq!(len, 2);
+
+        // On evenly spread keys, a subtree at the depth the descent stops holds 256 keys
+        for step in 1..100u64 {
+            let lo = step * 1987 % size;
+            let hi = size.min(lo + step * step * 211);
+            let range = FixedKey::from(lo)..FixedKey::from(hi);
+            let exact = tree.range_count(range.clone());
+            let approx = tree.approx_range_count(range);
+            assert!(approx.abs_diff(exact) <= 256, "{} vs {}", approx, exact);
+        }
+        assert_eq!(tree.approx_range_count(..), size as usize);
     }
 
     #[test]
-    fn bulk_insert() {
-        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
-        let curr_version = tree.version();
-        // Create a vector of KV<P, V>
-        let kv_pairs = vec![
-            KV {
-                key: VariableKey::from_str("key_1"),
-                value: 1,
-                version: 0,
-                ts: 0,
-            },
-            KV {
-                key: VariableKey::from_str("key_2"),
-                value: 1,
-                version: 2,
-                ts: 0,
-            },
-            KV {
-                key: VariableKey::from_str("key_3"),
-                value: 1,
-                version: curr_version + 1,
-                ts: 0,
-            },
-            KV {
-                key: VariableKey::from_str("key_4"),
-                value: 1,
-                version: curr_version + 1,
-                ts: 0,
-            },
-            KV {
-                key: VariableKey::from_str("key_5"),
-                value: 1,
-                version: curr_version + 2,
-                ts: 0,
-            },
-            KV {
-                key: VariableKey::from_str("key_6"),
-                value: 1,
-                version: 0,
-                ts: 0,
-            },
+    fn replace_prefix() {
+        let key = |name: &str| VariableKey::from_str(name);
+        let mut tree = Tree::<VariableKey, i32>::new();
+        for (i, name) in ["idx/a", "idx/b", "idx/c", "other", "idy"]
+            .iter()
+            .enumerate()
+        {
+            tree.insert(&key(name), i as i32, 0, 0).unwrap();
+        }
+        tree.delete_logical(&key("idx/c"), 1).unwrap();
+        let snapshot = tree.create_snapshot().unwrap();
+
+        let entries = vec![
+            KV::new(key("idx/b"), 10, 0, 2),
+            KV::new(key("idx/d"), 11, 0, 2),
         ];
+        assert_eq!(tree.replace_prefix(b"idx/", &entries).unwrap(), (2, 2));
+
+        // The tree shows the new subtree, with no history of the old one
+        let keys: Vec<_> = tree.iter().map(|(k, v, _, _)| (k, *v)).collect();
+        let expected: Vec<_> = [("idx/b", 10), ("idx/d", 11), ("idy", 4), ("other", 3)]
+            .iter()
+            .map(|(name, value)| (key(name).as_slice().to_vec(), *value))
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(tree.version_count(&key("idx/b")), 1);
+        assert_eq!(tree.version_count(&key("idx/c")), 0);
+
+        // A snapshot taken before still sees the old subtree
+        assert_eq!(snapshot.get(&key("idx/a"), 0).unwrap().0, 0);
+        assert_eq!(snapshot.get(&key("idx/b"), 0).unwrap().0, 1);
+        assert!(snapshot.get(&key("idx/d"), 0).is_err());
+
+        // Keys outside the prefix are rejected without changing anything
+        let stray = vec![
+            KV::new(key("idx/e"), 12, 0, 3),
+            KV::new(key("idz"), 13, 0, 3),
+        ];
+        assert!(tree.replace_prefix(b"idx/", &stray).is_err());
+        assert_eq!(tree.iter().count(), 4);
 
-        assert!(tree.bulk_insert(&kv_pairs).is_ok());
-        assert!(tree.version() == curr_version + 2);
+        // Replacing with nothing removes the prefix, here the whole Trie
+        assert_eq!(tree.replace_prefix(b"", &[]).unwrap(), (4, 0));
+        assert!(tree.is_empty());
+    }
 
-        for kv in kv_pairs {
-            let (_, val, version, _) = tree.get(&kv.key, 0).unwrap();
-            assert_eq!(val, kv.value);
-            if kv.version == 0 {
-                assert_eq!(version, curr_version + 1);
-            } else {
-                assert_eq!(version, kv.version);
+    #[test]
+    fn iter_size_hint() {
+        let mut tree = Tree::<VariableKey, usize>::new();
+        assert_eq!(tree.iter().size_hint(), (0, Some(0)));
+        let key = |partition: usize, i: usize| {
+            VariableKey::from_str(&format!("part{}/key{:03}", partition, i))
+        };
+        for partition in 0..10 {
+            for i in 0..100 {
+                tree.insert(&key(partition, i), i, 0, 0).unwrap();
             }
         }
-        assert!(tree
-            .insert(&VariableKey::from_str("key_7"), 1, 0, 0)
-            .is_ok());
-        assert!(tree.version() == curr_version + 3);
+        tree.delete_logical(&key(3, 3), 1).unwrap();
+        let len = tree.len();
+
+        // A full scan knows its length up front, so collecting allocates exactly once
+        let mut iter = tree.iter();
+        assert_eq!(iter.size_hint(), (len, Some(len)));
+        iter.nth(9);
+        assert_eq!(iter.len(), len - 10);
+        let entries: Vec<_> = tree.iter().collect();
+        assert_eq!(entries.len(), len);
+        assert_eq!(entries.capacity(), len);
+        assert_eq!(tree.iter_from(&key(9, 50)).size_hint(), (50, Some(50)));
+
+        // Ranges count the keys left from the cached subtree counts
+        let mut range = tree.range(key(3, 0)..key(4, 10));
+        assert_eq!(range.size_hint(), (109, Some(109)));
+        range.nth(49);
+        assert_eq!(range.size_hint(), (59, Some(59)));
+        let mut scan = tree.range_prefix(&key(7, 20), &key(7, 60));
+        assert_eq!(scan.size_hint(), (40, Some(40)));
+        scan.next();
+        assert_eq!(scan.size_hint(), (39, Some(39)));
+        let limited = tree.range_limited(key(5, 0)..key(6, 0), 30);
+        assert_eq!(limited.size_hint(), (30, Some(30)));
+        assert_eq!(
+            tree.range(VariableKey::from_str("q")..).size_hint(),
+            (0, Some(0))
+        );
+    }
+
+    #[test]
+    fn insert_with_ttl() {
+        let key = |name: &str| VariableKey::from_str(name);
+        let mut tree =
+            Tree::<VariableKey, i32>::with_options(TreeOptions::new().with_keep_history(true));
+        tree.insert_with_ttl(&key("session"), 1, 0, 10, 100)
+            .unwrap();
+        tree.insert(&key("config"), 2, 0, 10).unwrap();
+        tree.insert(&key("token"), 3, 0, 10).unwrap();
+        tree.insert_with_ttl(&key("token"), 4, 0, 20, 50).unwrap();
+
+        // A value reads present before its expiry and absent from it on
+        assert_eq!(tree.get_ref(&key("session"), 99), Some(&1));
+        assert_eq!(tree.get_ref(&key("session"), 100), None);
+        assert_eq!(
+            tree.get_at_timestamps(&key("token"), &[15, 30, 50]),
+            vec![Some(3), Some(4), None]
+        );
+        assert_eq!(tree.get_ref(&key("config"), u64::MAX), Some(&2));
+
+        // The latest value reads the same way against the current time
+        assert_eq!(tree.get_live(&key("token"), 49), Some(4));
+        assert_eq!(tree.get_live(&key("token"), 50), None);
+        assert_eq!(tree.get_live(&key("session"), 100), None);
+        assert_eq!(tree.get_live(&key("config"), u64::MAX), Some(2));
+
+        // Nothing has expired yet at 40
+        assert_eq!(tree.purge_expired(40).unwrap(), 0);
+        assert_eq!(tree.len(), 3);
+
+        // Only the expired token goes at 60, with its whole history
+        assert_eq!(tree.purge_expired(60).unwrap(), 2);
+        assert_eq!(tree.version_count(&key("token")), 0);
+        assert_eq!(tree.get_ref(&key("session"), 60), Some(&1));
+        assert_eq!(tree.purge_expired(100).unwrap(), 1);
+        let keys: Vec<_> = tree.iter().map(|(k, _, _, _)| k).collect();
+        assert_eq!(keys, vec![key("config").as_slice().to_vec()]);
     }
 }
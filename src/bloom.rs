@@ -0,0 +1,89 @@
+//! This module defines a Bloom filter over key bytes, used to skip lookups of absent keys.
+
+// Number of filter bits reserved for each expected key, giving roughly a 1% false positive rate
+const BITS_PER_KEY: usize = 10;
+
+// Number of bit positions probed for each key
+const NUM_HASHES: u64 = 7;
+
+/// A Bloom filter answering whether a key may have been inserted.
+///
+/// Bits can only be set, never cleared, so removed keys keep reporting as possibly present until
+/// the filter is cleared and rebuilt. The filter never reports an inserted key as absent.
+#[derive(Clone)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+}
+
+impl BloomFilter {
+    /// Creates a new, empty filter sized for the expected number of keys.
+    pub(crate) fn new(expected_keys: usize) -> Self {
+        let num_words = (expected_keys.max(1) * BITS_PER_KEY).div_ceil(64);
+        Self {
+            bits: vec![0; num_words],
+            num_bits: num_words as u64 * 64,
+        }
+    }
+
+    /// Records the key in the filter.
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        let (h1, h2) = Self::hash(key);
+        for i in 0..NUM_HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns `false` if the key was definitely never inserted.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let (h1, h2) = Self::hash(key);
+        (0..NUM_HASHES).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    /// Resets the filter so that every key reports as absent.
+    pub(crate) fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    // Derives the two base hashes used for double hashing, using FNV-1a with two offsets.
+    #[inline]
+    fn hash(key: &[u8]) -> (u64, u64) {
+        let mut h1: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut h2: u64 = 0x8422_2325_cbf2_9ce4;
+        for &byte in key {
+            h1 = (h1 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+            h2 = (h2 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        // An odd step visits distinct positions for every probe.
+        (h1, h2 | 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+
+    #[test]
+    fn no_false_negatives() {
+        let mut filter = BloomFilter::new(1000);
+        for i in 0..1000u32 {
+            filter.insert(&i.to_be_bytes());
+        }
+        for i in 0..1000u32 {
+            assert!(filter.may_contain(&i.to_be_bytes()));
+        }
+
+        // Most absent keys are rejected
+        let false_positives = (1000..11000u32)
+            .filter(|i| filter.may_contain(&i.to_be_bytes()))
+            .count();
+        assert!(false_positives < 500);
+
+        filter.clear();
+        assert!(!filter.may_contain(&0u32.to_be_bytes()));
+    }
+}
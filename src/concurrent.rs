@@ -0,0 +1,168 @@
+//! This module defines the ConcurrentTree struct for sharing a Trie across threads.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::art::{Node, Tree, TreeOptions, TrieError};
+use crate::node::Version;
+use crate::snapshot::Snapshot;
+use crate::{KeyTrait, Ts, VersionId};
+
+/// A Trie that can be shared across threads, with a single writer and many readers.
+///
+/// Writes are serialized through a lock around the underlying `Tree`. After each write the new
+/// root is published, and readers take a snapshot by cloning the published root. As the Trie is
+/// copy-on-write, a snapshot is a consistent view that later writes never touch, and reading it
+/// takes no locks at all. Readers and the writer only contend for the pointer swap.
+pub struct ConcurrentTree<P: KeyTrait, V: Clone> {
+    /// The tree all writes go through.
+    writer: Mutex<Tree<P, V>>,
+    /// The root as of the last completed write.
+    root: RwLock<Option<Arc<Node<P, V>>>>,
+    /// The options of the tree, handed to every snapshot.
+    options: TreeOptions,
+    /// The ID assigned to the next snapshot.
+    next_snapshot_id: AtomicU64,
+}
+
+impl<P: KeyTrait, V: Clone> ConcurrentTree<P, V> {
+    pub fn new() -> Self {
+        ConcurrentTree::with_options(TreeOptions::default())
+    }
+
+    /// Creates an empty concurrent Trie with the given options.
+    pub fn with_options(options: TreeOptions) -> Self {
+        ConcurrentTree {
+            writer: Mutex::new(Tree::with_options(options.clone())),
+            root: RwLock::new(None),
+            options,
+            next_snapshot_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs a mutation against the underlying Trie and publishes the resulting root.
+    ///
+    /// Writers are serialized, so the closure has exclusive access to the Trie. Snapshots taken
+    /// while it runs keep seeing the previous root.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The mutation to apply.
+    ///
+    /// # Returns
+    ///
+    /// Returns whatever the closure returns.
+    ///
+    pub fn write<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Tree<P, V>) -> T,
+    {
+        let mut tree = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let result = f(&mut tree);
+        *self.root.write().unwrap_or_else(|e| e.into_inner()) = tree.root.clone();
+        result
+    }
+
+    /// Inserts a key-value pair, see `Tree::insert`.
+    pub fn insert(
+        &self,
+        key: &P,
+        value: V,
+        version: impl Into<VersionId>,
+        ts: impl Into<Ts>,
+    ) -> Result<Option<V>, TrieError> {
+        self.write(|tree| tree.insert(key, value, version, ts))
+    }
+
+    /// Removes a key, see `Tree::remove`.
+    pub fn remove(&self, key: &P) -> Result<bool, TrieError> {
+        self.write(|tree| tree.remove(key))
+    }
+
+    /// Takes a snapshot of the Trie as of the last completed write.
+    ///
+    /// The snapshot is owned by the caller and can be read, or even modified, without affecting
+    /// the shared Trie or other readers.
+    ///
+    pub fn snapshot(&self) -> Snapshot<P, V> {
+        let root = self.root.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let version = root.as_ref().map_or(1, |root| root.version() + 1);
+        let id = self.next_snapshot_id.fetch_add(1, Ordering::SeqCst);
+        Snapshot::new(id, root, version, self.options.clone())
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Default for ConcurrentTree<P, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::ConcurrentTree;
+    use crate::FixedKey;
+
+    #[test]
+    fn readers_see_consistent_snapshots() {
+        let tree = Arc::new(ConcurrentTree::<FixedKey<16>, u64>::new());
+        const ROUNDS: u64 = 200;
+        const KEYS_PER_ROUND: u64 = 5;
+
+        let writer = {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    // Each write adds a batch of keys at once, all holding the round number
+                    tree.write(|tree| {
+                        for i in 0..KEYS_PER_ROUND {
+                            let key: FixedKey<16> = (round * KEYS_PER_ROUND + i).into();
+                            tree.insert(&key, round, 0, 0).unwrap();
+                        }
+                    });
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    loop {
+                        let snapshot = tree.snapshot();
+                        let entries: Vec<_> = snapshot.range(..).map(|(_, v, _, _)| *v).collect();
+
+                        // A snapshot only ever holds whole batches, in order
+                        let len = entries.len() as u64;
+                        assert_eq!(len % KEYS_PER_ROUND, 0);
+                        for (i, value) in entries.iter().enumerate() {
+                            assert_eq!(*value, i as u64 / KEYS_PER_ROUND);
+                        }
+
+                        // Snapshots never go back in time
+                        assert!(len >= last);
+                        last = len;
+                        if len == ROUNDS * KEYS_PER_ROUND {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let snapshot = tree.snapshot();
+        let key: FixedKey<16> = 42u64.into();
+        assert_eq!(snapshot.get(&key, 0).unwrap().0, 42 / KEYS_PER_ROUND);
+        assert!(tree.remove(&key).unwrap());
+        assert!(snapshot.get(&key, 0).is_ok());
+        assert!(tree.snapshot().get(&key, 0).is_err());
+    }
+}
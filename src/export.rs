@@ -0,0 +1,270 @@
+//! This module defines a binary export format for a Trie, with an optional value dictionary.
+use std::hash::Hash;
+use std::io::{Read, Write};
+
+use hashbrown::HashMap;
+
+use crate::art::{Tree, TrieError, KV};
+use crate::iter::TwigIter;
+use crate::node::{LeafValue, TwigNode};
+use crate::KeyTrait;
+
+// Every export starts with the magic bytes, followed by a byte naming the value layout.
+//
+// With the dictionary layout the distinct values come next, as a u64 count followed by each
+// value. Then come the entries, as a u64 count followed by each key, its version and timestamp
+// as u64s, and its value, written inline or as the u32 index of the value in the dictionary.
+// Keys and values are written as a u32 length followed by the bytes, and all integers are
+// little-endian.
+const MAGIC: &[u8; 4] = b"TART";
+const LAYOUT_INLINE: u8 = 0;
+const LAYOUT_DICTIONARY: u8 = 1;
+
+fn io_error(err: std::io::Error) -> TrieError {
+    TrieError::Other(format!("export I/O failed: {}", err))
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> Result<(), TrieError> {
+    writer.write_all(&value.to_le_bytes()).map_err(io_error)
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> Result<(), TrieError> {
+    writer.write_all(&value.to_le_bytes()).map_err(io_error)
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), TrieError> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| TrieError::Other(format!("cannot export {} bytes", bytes.len())))?;
+    write_u32(writer, len)?;
+    writer.write_all(bytes).map_err(io_error)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, TrieError> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf).map_err(io_error)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, TrieError> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf).map_err(io_error)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>, TrieError> {
+    let len = read_u32(reader)? as usize;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).map_err(io_error)?;
+    Ok(buf)
+}
+
+impl<P: KeyTrait, V: Clone> Tree<P, V> {
+    // Collects the twigs holding a live key, along with their latest value.
+    fn live_entries(&self) -> Vec<(&TwigNode<P, V>, &LeafValue<V>)> {
+        TwigIter::new(self.root.as_ref())
+            .filter_map(|twig| twig.get_latest_live_leaf().map(|leaf| (twig, leaf)))
+            .collect()
+    }
+
+    // Writes the header of an entry, everything but its value. The key is written as given to
+    // the tree, so that the export does not depend on the byte permutation, if any.
+    fn write_entry<W: Write>(
+        &self,
+        writer: &mut W,
+        twig: &TwigNode<P, V>,
+        leaf: &LeafValue<V>,
+    ) -> Result<(), TrieError> {
+        match &self.options.byte_permutation {
+            Some(permutation) => write_bytes(writer, &permutation.decode(twig.key.as_slice()))?,
+            None => write_bytes(writer, twig.key.as_slice())?,
+        }
+        write_u64(writer, leaf.version)?;
+        write_u64(writer, leaf.ts)
+    }
+
+    /// Exports the Trie in a binary format, with every value written inline.
+    ///
+    /// The latest value of every key is written along with its version and timestamp, while
+    /// older versions are left out. Values are turned into bytes by the given function, so the
+    /// format does not depend on any particular serialization library.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`: The destination of the export.
+    /// * `encode`: A function encoding a value as bytes.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `Err` if writing fails.
+    ///
+    pub fn export<W, F>(&self, mut writer: W, mut encode: F) -> Result<(), TrieError>
+    where
+        W: Write,
+        F: FnMut(&V) -> Vec<u8>,
+    {
+        let entries = self.live_entries();
+        writer.write_all(MAGIC).map_err(io_error)?;
+        writer.write_all(&[LAYOUT_INLINE]).map_err(io_error)?;
+        write_u64(&mut writer, entries.len() as u64)?;
+        for (twig, leaf) in entries {
+            self.write_entry(&mut writer, twig, leaf)?;
+            write_bytes(&mut writer, &encode(&leaf.value))?;
+        }
+        Ok(())
+    }
+
+    /// Reads a Trie written by `export` or `export_dedup`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader`: The source of the export.
+    /// * `decode`: A function decoding a value from the bytes `encode` produced for it.
+    ///
+    /// # Returns
+    ///
+    /// Returns the Trie holding the exported keys, or an `Err` if reading fails or the input is
+    /// not an export.
+    ///
+    pub fn import<R, F>(mut reader: R, mut decode: F) -> Result<Tree<P, V>, TrieError>
+    where
+        R: Read,
+        F: FnMut(&[u8]) -> V,
+    {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header).map_err(io_error)?;
+        if &header[..4] != MAGIC {
+            return Err(TrieError::Other("not a Trie export".to_string()));
+        }
+
+        let dictionary = match header[4] {
+            LAYOUT_INLINE => None,
+            LAYOUT_DICTIONARY => {
+                let len = read_u64(&mut reader)?;
+                let values = (0..len)
+                    .map(|_| read_bytes(&mut reader).map(|bytes| decode(&bytes)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Some(values)
+            }
+            layout => {
+                return Err(TrieError::Other(format!(
+                    "unknown export layout {}",
+                    layout
+                )))
+            }
+        };
+
+        // The entries are in key order rather than version order, which only a bulk insert into
+        // an empty Trie accepts.
+        let len = read_u64(&mut reader)?;
+        let mut entries = Vec::new();
+        for _ in 0..len {
+            let key = P::from(read_bytes(&mut reader)?.as_slice());
+            let version = read_u64(&mut reader)?;
+            let ts = read_u64(&mut reader)?;
+            let value = match &dictionary {
+                None => decode(&read_bytes(&mut reader)?),
+                Some(values) => {
+                    let index = read_u32(&mut reader)? as usize;
+                    values.get(index).cloned().ok_or_else(|| {
+                        TrieError::Other(format!("value index {} out of bounds", index))
+                    })?
+                }
+            };
+            entries.push(KV::new(key, value, version, ts));
+        }
+        let mut tree = Tree::new();
+        tree.bulk_insert(&entries)?;
+        Ok(tree)
+    }
+}
+
+impl<P: KeyTrait, V: Clone + Hash + Eq> Tree<P, V> {
+    /// Exports the Trie in a binary format, writing every distinct value only once.
+    ///
+    /// This behaves like `export`, but the distinct values are written up front as a dictionary
+    /// and each key refers to its value by index. When many keys hold equal values, such as
+    /// flags or enum-like states, the output is much smaller than with `export`. The result is
+    /// read back with `import` as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`: The destination of the export.
+    /// * `encode`: A function encoding a value as bytes, called once per distinct value.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `Err` if writing fails.
+    ///
+    pub fn export_dedup<W, F>(&self, mut writer: W, mut encode: F) -> Result<(), TrieError>
+    where
+        W: Write,
+        F: FnMut(&V) -> Vec<u8>,
+    {
+        let entries = self.live_entries();
+        let mut values: Vec<&V> = Vec::new();
+        let mut indexes: HashMap<&V, u32> = HashMap::new();
+        let mut entry_indexes = Vec::with_capacity(entries.len());
+        for (_, leaf) in &entries {
+            let index = match indexes.get(&leaf.value) {
+                Some(index) => *index,
+                None => {
+                    let index = u32::try_from(values.len()).map_err(|_| {
+                        TrieError::Other("too many distinct values to export".to_string())
+                    })?;
+                    values.push(&leaf.value);
+                    indexes.insert(&leaf.value, index);
+                    index
+                }
+            };
+            entry_indexes.push(index);
+        }
+
+        writer.write_all(MAGIC).map_err(io_error)?;
+        writer.write_all(&[LAYOUT_DICTIONARY]).map_err(io_error)?;
+        write_u64(&mut writer, values.len() as u64)?;
+        for value in values {
+            write_bytes(&mut writer, &encode(value))?;
+        }
+        write_u64(&mut writer, entries.len() as u64)?;
+        for ((twig, leaf), index) in entries.into_iter().zip(entry_indexes) {
+            self.write_entry(&mut writer, twig, leaf)?;
+            write_u32(&mut writer, index)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::art::Tree;
+    use crate::VariableKey;
+
+    #[test]
+    fn export_dedup_round_trip() {
+        let states = ["pending", "active", "suspended", "closed"].map(|state| state.repeat(8));
+        let mut tree = Tree::<VariableKey, String>::new();
+        for i in 0..5000usize {
+            let key = VariableKey::from_str(&format!("account{}", i));
+            tree.insert(&key, states[i % 7 % 4].clone(), 0, i as u64)
+                .unwrap();
+        }
+        tree.remove(&VariableKey::from_str("account42")).unwrap();
+
+        let encode = |value: &String| value.as_bytes().to_vec();
+        let decode = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).unwrap();
+        let mut inline = Vec::new();
+        tree.export(&mut inline, encode).unwrap();
+        let mut dedup = Vec::new();
+        tree.export_dedup(&mut dedup, encode).unwrap();
+        assert!(dedup.len() * 2 < inline.len());
+
+        let expected: Vec<_> = tree.iter().collect();
+        for bytes in [inline, dedup] {
+            let imported = Tree::<VariableKey, String>::import(bytes.as_slice(), decode).unwrap();
+            assert_eq!(imported.iter().collect::<Vec<_>>(), expected);
+        }
+
+        assert!(Tree::<VariableKey, String>::import(&b"TARX\0"[..], decode).is_err());
+        assert!(Tree::<VariableKey, String>::import(&b"TART\x07"[..], decode).is_err());
+    }
+}
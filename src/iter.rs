@@ -1,15 +1,33 @@
+use std::borrow::Cow;
 use std::collections::{Bound, VecDeque};
+use std::iter::FusedIterator;
 use std::ops::RangeBounds;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use crate::art::{Node, NodeType};
+use hashbrown::HashSet;
+
+use crate::art::{BytePermutation, Node, NodeKind, NodeType, Tree, TrieError};
+use crate::node::{TwigNode, Version};
 use crate::KeyTrait;
 
+// Copies a stored key, restoring the bytes a byte permutation substituted, if any.
+fn decoded<P: KeyTrait>(key: &P, permutation: Option<&BytePermutation>) -> Vec<u8> {
+    match permutation {
+        Some(permutation) => permutation.decode(key.as_slice()),
+        None => key.as_slice().to_vec(),
+    }
+}
+
 // TODO: need to add more tests for snapshot readers
 /// A structure representing a pointer for iterating over the Trie's key-value pairs.
 pub struct IterationPointer<P: KeyTrait, V: Clone> {
+    #[allow(dead_code)]
     pub(crate) id: u64,
     root: Arc<Node<P, V>>,
+    // The open readers of the snapshot the pointer was created from, if any
+    readers: Option<Arc<Mutex<HashSet<u64>>>>,
+    // The byte permutation the keys below the root are stored with, if any
+    permutation: Option<Arc<BytePermutation>>,
 }
 
 impl<P: KeyTrait, V: Clone> IterationPointer<P, V> {
@@ -21,7 +39,27 @@ impl<P: KeyTrait, V: Clone> IterationPointer<P, V> {
     /// * `id` - The ID of the snapshot.
     ///
     pub fn new(root: Arc<Node<P, V>>, id: u64) -> IterationPointer<P, V> {
-        IterationPointer { id, root }
+        IterationPointer {
+            id,
+            root,
+            readers: None,
+            permutation: None,
+        }
+    }
+
+    // Creates a reader registered with a snapshot, which it deregisters from when dropped.
+    pub(crate) fn registered(
+        root: Arc<Node<P, V>>,
+        id: u64,
+        readers: Arc<Mutex<HashSet<u64>>>,
+        permutation: Option<Arc<BytePermutation>>,
+    ) -> IterationPointer<P, V> {
+        IterationPointer {
+            id,
+            root,
+            readers: Some(readers),
+            permutation,
+        }
     }
 
     /// Returns an iterator over the key-value pairs within the Trie.
@@ -30,8 +68,8 @@ impl<P: KeyTrait, V: Clone> IterationPointer<P, V> {
     ///
     /// Returns an Iter iterator instance.
     ///
-    pub fn iter(&self) -> Iter<P, V> {
-        Iter::new(Some(&self.root))
+    pub fn iter(&self) -> Iter<'_, P, V> {
+        Iter::new(Some(&self.root)).decode_keys(self.permutation.clone())
     }
 
     pub fn range<'a, R>(
@@ -41,12 +79,24 @@ impl<P: KeyTrait, V: Clone> IterationPointer<P, V> {
     where
         R: RangeBounds<P> + 'a,
     {
-        return Range::new(Some(&self.root), range);
+        Range::new(Some(&self.root), range).decode_keys(self.permutation.clone())
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Drop for IterationPointer<P, V> {
+    fn drop(&mut self) {
+        if let Some(readers) = &self.readers {
+            readers
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&self.id);
+        }
     }
 }
 
 /// An iterator over the nodes in the Trie.
 struct NodeIter<'a, P: KeyTrait, V: Clone> {
+    #[allow(clippy::type_complexity)]
     node: Box<dyn Iterator<Item = (u8, &'a Arc<Node<P, V>>)> + 'a>,
 }
 
@@ -75,9 +125,304 @@ impl<'a, P: KeyTrait, V: Clone> Iterator for NodeIter<'a, P, V> {
     }
 }
 
+/// An iterator over the twigs in the Trie, in key order.
+pub(crate) struct TwigIter<'a, P: KeyTrait, V: Clone> {
+    root: Option<&'a TwigNode<P, V>>,
+    iters: Vec<NodeIter<'a, P, V>>,
+}
+
+impl<'a, P: KeyTrait, V: Clone> TwigIter<'a, P, V> {
+    /// Creates a new TwigIter instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - An optional reference to the root node of the Trie.
+    ///
+    pub(crate) fn new(node: Option<&'a Arc<Node<P, V>>>) -> Self {
+        match node.map(|node| &node.node_type) {
+            Some(NodeType::Twig(twig)) => Self {
+                root: Some(twig),
+                iters: Vec::new(),
+            },
+            Some(_) => Self {
+                root: None,
+                iters: vec![NodeIter::new(node.unwrap().iter())],
+            },
+            None => Self {
+                root: None,
+                iters: Vec::new(),
+            },
+        }
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for TwigIter<'a, P, V> {
+    type Item = &'a TwigNode<P, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(twig) = self.root.take() {
+            return Some(twig);
+        }
+
+        while let Some(iter) = self.iters.last_mut() {
+            match iter.next() {
+                Some((_, child)) => match &child.node_type {
+                    NodeType::Twig(twig) => return Some(twig),
+                    _ => self.iters.push(NodeIter::new(child.iter())),
+                },
+                None => {
+                    self.iters.pop();
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// An iterator yielding the smallest key of each group of keys sharing a prefix of a fixed
+/// length, in key order.
+pub struct DistinctPrefix<'a, P: KeyTrait, V: Clone> {
+    // Each inner node on the path, with the number of key bytes shared by its whole subtree
+    iters: Vec<(usize, NodeIter<'a, P, V>)>,
+    root: Option<&'a TwigNode<P, V>>,
+    prefix_len: usize,
+    permutation: Option<Arc<BytePermutation>>,
+    // Number of nodes pulled from the descent, to check which subtrees were skipped
+    #[cfg(test)]
+    pub(crate) visited: usize,
+}
+
+impl<'a, P: KeyTrait, V: Clone> DistinctPrefix<'a, P, V> {
+    /// Creates a new DistinctPrefix instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - An optional reference to the root node of the Trie.
+    /// * `prefix_len` - The length of the prefix keys are grouped by.
+    ///
+    pub(crate) fn new(node: Option<&'a Arc<Node<P, V>>>, prefix_len: usize) -> Self {
+        let mut iter = Self {
+            iters: Vec::new(),
+            root: None,
+            prefix_len,
+            permutation: None,
+            #[cfg(test)]
+            visited: 0,
+        };
+        match node.map(|node| (node, &node.node_type)) {
+            Some((_, NodeType::Twig(twig))) => iter.root = Some(twig),
+            Some((node, _)) => iter
+                .iters
+                .push((node.prefix().len(), NodeIter::new(node.iter()))),
+            None => {}
+        }
+        iter
+    }
+
+    /// Restores the keys a byte permutation substituted, if any, before yielding them. The
+    /// substitution maps every byte on its own, so it keeps the groups of keys intact.
+    pub(crate) fn decode_keys(mut self, permutation: Option<Arc<BytePermutation>>) -> Self {
+        self.permutation = permutation;
+        self
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for DistinctPrefix<'a, P, V> {
+    type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(twig) = self.root.take() {
+            return twig.get_latest_live_leaf().map(|v| {
+                let key = decoded(&twig.key, self.permutation.as_deref());
+                (key, &v.value, &v.version, &v.ts)
+            });
+        }
+
+        while let Some((depth, iter)) = self.iters.last_mut() {
+            let depth = *depth;
+            let Some((_, child)) = iter.next() else {
+                self.iters.pop();
+                continue;
+            };
+            #[cfg(test)]
+            {
+                self.visited += 1;
+            }
+
+            let NodeType::Twig(twig) = &child.node_type else {
+                let child_depth = depth + child.prefix().len();
+                self.iters.push((child_depth, NodeIter::new(child.iter())));
+                continue;
+            };
+            let Some(v) = twig.get_latest_live_leaf() else {
+                continue;
+            };
+
+            // Every subtree whose keys all share at least the group prefix belongs to the group
+            // just emitted, so none of it needs to be visited.
+            while self
+                .iters
+                .last()
+                .is_some_and(|(depth, _)| *depth >= self.prefix_len)
+            {
+                self.iters.pop();
+            }
+            let key = decoded(&twig.key, self.permutation.as_deref());
+            return Some((key, &v.value, &v.version, &v.ts));
+        }
+
+        None
+    }
+}
+
+/// An iterator over every stored version within a window of versions, in key order and then
+/// version order.
+pub struct VersionScan<'a, P: KeyTrait, V: Clone> {
+    iters: Vec<NodeIter<'a, P, V>>,
+    leafs: VecDeque<(&'a P, &'a V, &'a u64, &'a u64)>,
+    lo: u64,
+    hi: u64,
+    permutation: Option<Arc<BytePermutation>>,
+    // Number of nodes pulled from the descent, to check which subtrees were pruned
+    #[cfg(test)]
+    pub(crate) visited: usize,
+}
+
+impl<'a, P: KeyTrait, V: Clone> VersionScan<'a, P, V> {
+    /// Creates a new VersionScan instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - An optional reference to the root node of the Trie.
+    /// * `lo` - The oldest version to yield.
+    /// * `hi` - The newest version to yield.
+    ///
+    pub(crate) fn new(node: Option<&'a Arc<Node<P, V>>>, lo: u64, hi: u64) -> Self {
+        let mut scan = Self {
+            iters: Vec::new(),
+            leafs: VecDeque::new(),
+            lo,
+            hi,
+            permutation: None,
+            #[cfg(test)]
+            visited: 0,
+        };
+        if let Some(node) = node {
+            scan.visit(node);
+        }
+        scan
+    }
+
+    /// Restores the keys a byte permutation substituted, if any, before yielding them.
+    pub(crate) fn decode_keys(mut self, permutation: Option<Arc<BytePermutation>>) -> Self {
+        self.permutation = permutation;
+        self
+    }
+
+    // Queues the matching values of a twig, or descends into an inner node, unless the
+    // versions below the node lie entirely outside the window.
+    fn visit(&mut self, node: &'a Node<P, V>) {
+        if node.version() < self.lo || node.min_version() > self.hi {
+            return;
+        }
+        match &node.node_type {
+            NodeType::Twig(twig) => {
+                let (lo, hi) = (self.lo, self.hi);
+                self.leafs.extend(
+                    twig.iter()
+                        .filter(|v| !v.is_deleted() && (lo..=hi).contains(&v.version))
+                        .map(|v| (&twig.key, &v.value, &v.version, &v.ts)),
+                );
+            }
+            _ => self.iters.push(NodeIter::new(node.iter())),
+        }
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for VersionScan<'a, P, V> {
+    type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.leafs.is_empty() {
+            let iter = self.iters.last_mut()?;
+            match iter.next() {
+                Some((_, child)) => {
+                    #[cfg(test)]
+                    {
+                        self.visited += 1;
+                    }
+                    self.visit(child);
+                }
+                None => {
+                    self.iters.pop();
+                }
+            }
+        }
+
+        let permutation = self.permutation.as_deref();
+        self.leafs
+            .pop_front()
+            .map(|leaf| (decoded(leaf.0, permutation), leaf.1, leaf.2, leaf.3))
+    }
+}
+
+/// A structural iterator over the inner nodes in the Trie, bounded by depth.
+pub struct NodeWalk<'a, P: KeyTrait, V: Clone> {
+    stack: Vec<(usize, &'a Arc<Node<P, V>>)>,
+    max_depth: usize,
+}
+
+impl<'a, P: KeyTrait, V: Clone> NodeWalk<'a, P, V> {
+    /// Creates a new NodeWalk instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - An optional reference to the root node of the Trie.
+    /// * `max_depth` - The deepest level to visit, where the root is at depth `0`.
+    ///
+    pub(crate) fn new(node: Option<&'a Arc<Node<P, V>>>, max_depth: usize) -> Self {
+        Self {
+            stack: node.map(|node| (0, node)).into_iter().collect(),
+            max_depth,
+        }
+    }
+}
+
+impl<'a, P: KeyTrait, V: Clone> Iterator for NodeWalk<'a, P, V> {
+    type Item = (usize, Vec<u8>, NodeKind, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((depth, node)) = self.stack.pop() {
+            if node.is_twig() {
+                continue;
+            }
+
+            // Push the children in reverse so they are visited in key order.
+            if depth < self.max_depth {
+                let children: Vec<_> = node.iter().collect();
+                for (_, child) in children.into_iter().rev() {
+                    self.stack.push((depth + 1, child));
+                }
+            }
+
+            return Some((
+                depth,
+                node.prefix().as_slice().to_vec(),
+                node.node_kind(),
+                node.num_children(),
+            ));
+        }
+
+        None
+    }
+}
+
 /// An iterator over key-value pairs in the Trie.
 pub struct Iter<'a, P: KeyTrait + 'a, V: Clone> {
     inner: Box<dyn Iterator<Item = (Vec<u8>, &'a V, &'a u64, &'a u64)> + 'a>,
+    // Number of keys left to yield, known up front from the cached subtree counts
+    remaining: usize,
     _marker: std::marker::PhantomData<P>,
 }
 
@@ -92,22 +437,209 @@ impl<'a, P: KeyTrait + 'a, V: Clone> Iter<'a, P, V> {
         if let Some(node) = node {
             Self {
                 inner: Box::new(IterState::new(node)),
+                remaining: node.leaf_count(),
                 _marker: Default::default(),
             }
         } else {
             Self {
                 inner: Box::new(std::iter::empty()),
+                remaining: 0,
                 _marker: Default::default(),
             }
         }
     }
+
+    /// Creates a new Iter instance starting at the first key greater than or equal to `key`.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - An optional reference to the root node of the Trie.
+    /// * `key` - The key to start at.
+    ///
+    pub(crate) fn from_key(node: Option<&'a Arc<Node<P, V>>>, key: &P) -> Self {
+        match node {
+            Some(node) => Self {
+                inner: Box::new(IterState::seek(node, key)),
+                remaining: Node::range_count_recurse(
+                    node,
+                    &mut Vec::new(),
+                    &(Bound::Included(key), Bound::Unbounded),
+                    usize::MAX,
+                ),
+                _marker: Default::default(),
+            },
+            None => Self::new(None),
+        }
+    }
+
+    /// Restores the keys a byte permutation substituted, if any, before yielding them.
+    pub(crate) fn decode_keys(self, permutation: Option<Arc<BytePermutation>>) -> Self {
+        match permutation {
+            Some(permutation) => Self {
+                inner: Box::new(self.inner.map(move |(key, value, version, ts)| {
+                    (permutation.decode(&key), value, version, ts)
+                })),
+                remaining: self.remaining,
+                _marker: Default::default(),
+            },
+            None => self,
+        }
+    }
+
+    /// Collects the entries and yields them sorted by key, for keys whose order a byte
+    /// permutation scrambled.
+    pub(crate) fn sort_keys(self) -> Self {
+        let remaining = self.remaining;
+        let mut entries: Vec<_> = self.inner.collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Self {
+            inner: Box::new(entries.into_iter()),
+            remaining,
+            _marker: Default::default(),
+        }
+    }
+
+    /// Drops the version and timestamp from each entry, yielding just the key and
+    /// its latest value.
+    pub fn kv(self) -> impl Iterator<Item = (Vec<u8>, &'a V)> {
+        self.map(|(key, value, _, _)| (key, value))
+    }
 }
 
 impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for Iter<'a, P, V> {
     type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next()
+        let item = self.inner.next();
+        if item.is_some() {
+            self.remaining = self.remaining.saturating_sub(1);
+        }
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> ExactSizeIterator for Iter<'a, P, V> {}
+
+// The inner iterator is either empty or an `IterState`, both of which are fused, possibly with
+// its keys decoded, which keeps it fused.
+impl<'a, P: KeyTrait + 'a, V: Clone> FusedIterator for Iter<'a, P, V> {}
+
+/// An iterator over key-value pairs in the Trie that reports structural inconsistencies.
+///
+/// Where `Iter` silently skips over malformed nodes, `TryIter` yields a `TrieError` for the
+/// first one it meets and then ends the iteration.
+pub struct TryIter<'a, P: KeyTrait + 'a, V: Clone> {
+    root: Option<&'a Node<P, V>>,
+    iters: Vec<NodeIter<'a, P, V>>,
+    last_key: Option<&'a P>,
+    failed: bool,
+    permutation: Option<Arc<BytePermutation>>,
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> TryIter<'a, P, V> {
+    /// Creates a new TryIter instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - An optional reference to the root node of the Trie.
+    ///
+    pub(crate) fn new(node: Option<&'a Arc<Node<P, V>>>) -> Self {
+        Self {
+            root: node.map(|node| node.as_ref()),
+            iters: Vec::new(),
+            last_key: None,
+            failed: false,
+            permutation: None,
+        }
+    }
+
+    /// Restores the keys a byte permutation substituted, if any, before yielding them. The
+    /// structure is still checked on the keys as stored.
+    pub(crate) fn decode_keys(mut self, permutation: Option<Arc<BytePermutation>>) -> Self {
+        self.permutation = permutation;
+        self
+    }
+
+    // Checks a node reached by the descent, returning the entry to yield for a twig.
+    #[allow(clippy::type_complexity)]
+    fn visit(
+        &mut self,
+        node: &'a Node<P, V>,
+    ) -> Result<Option<(Vec<u8>, &'a V, &'a u64, &'a u64)>, TrieError> {
+        let NodeType::Twig(twig) = &node.node_type else {
+            if node.num_children() == 0 {
+                return Err(TrieError::Other(format!(
+                    "inner node with prefix {:?} has no children",
+                    node.prefix().as_slice()
+                )));
+            }
+            self.iters.push(NodeIter::new(node.iter()));
+            return Ok(None);
+        };
+
+        let Some(v) = twig.get_latest_leaf() else {
+            return Err(TrieError::Other(format!(
+                "twig for key {:?} holds no value",
+                twig.key.as_slice()
+            )));
+        };
+        if self.last_key.is_some_and(|last| last >= &twig.key) {
+            return Err(TrieError::Other(format!(
+                "twig for key {:?} is out of key order",
+                twig.key.as_slice()
+            )));
+        }
+        self.last_key = Some(&twig.key);
+        // A deleted key is well-formed, it just has no value to yield
+        if v.is_deleted() {
+            return Ok(None);
+        }
+        Ok(Some((
+            decoded(&twig.key, self.permutation.as_deref()),
+            &v.value,
+            &v.version,
+            &v.ts,
+        )))
+    }
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for TryIter<'a, P, V> {
+    type Item = Result<(Vec<u8>, &'a V, &'a u64, &'a u64), TrieError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        loop {
+            let node = match self.root.take() {
+                Some(root) => root,
+                None => {
+                    let iter = self.iters.last_mut()?;
+                    match iter.next() {
+                        Some((_, child)) => child.as_ref(),
+                        None => {
+                            self.iters.pop();
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            match self.visit(node) {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => {}
+                Err(err) => {
+                    self.failed = true;
+                    self.iters.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
     }
 }
 
@@ -129,7 +661,7 @@ impl<'a, P: KeyTrait + 'a, V: Clone> IterState<'a, P, V> {
         let mut leafs = VecDeque::new();
 
         if let NodeType::Twig(twig) = &node.node_type {
-            let val = twig.get_latest_leaf();
+            let val = twig.get_latest_live_leaf();
             if let Some(v) = val {
                 leafs.push_back((&twig.key, &v.value, &v.version, &v.ts));
             }
@@ -147,6 +679,60 @@ impl<'a, P: KeyTrait + 'a, V: Clone> IterState<'a, P, V> {
         }
     }
 
+    // Positions the descent at the first key greater than or equal to `key`. The children
+    // before the key's path are skipped at every level, so none of the smaller keys are visited.
+    fn seek(node: &'a Node<P, V>, key: &P) -> Self {
+        let mut state = Self::empty();
+        let key = key.as_slice();
+        let mut node = node;
+        let mut depth = 0;
+
+        loop {
+            if let NodeType::Twig(twig) = &node.node_type {
+                if twig.key.as_slice() >= key {
+                    if let Some(v) = twig.get_latest_live_leaf() {
+                        state
+                            .leafs
+                            .push_back((&twig.key, &v.value, &v.version, &v.ts));
+                    }
+                }
+                return state;
+            }
+
+            let key_prefix = &key[depth.min(key.len())..];
+            let prefix = node.prefix();
+            let lcp = prefix.longest_common_prefix(key_prefix);
+
+            // If the node's prefix diverges from the key, the whole subtree lies on one side.
+            if lcp < prefix.len() {
+                if lcp >= key_prefix.len() || prefix.at(lcp) > key_prefix[lcp] {
+                    state.iters.push(NodeIter::new(node.iter()));
+                }
+                return state;
+            }
+
+            // If the key ends at this node, every key below it is greater.
+            if key_prefix.len() == prefix.len() {
+                state.iters.push(NodeIter::new(node.iter()));
+                return state;
+            }
+
+            // Skip the smaller children, and descend into the one on the key's path, if any,
+            // after queueing the greater ones.
+            let k = key_prefix[prefix.len()];
+            let mut children = node.iter_range(k, u8::MAX).peekable();
+            let next = children.next_if(|(byte, _)| *byte == k);
+            state.iters.push(NodeIter::new(children));
+            match next {
+                Some((_, child)) => {
+                    depth += prefix.len();
+                    node = child;
+                }
+                None => return state,
+            }
+        }
+    }
+
     fn forward_scan<R>(node: &'a Node<P, V>, range: &R) -> Self
     where
         R: RangeBounds<P>,
@@ -155,7 +741,7 @@ impl<'a, P: KeyTrait + 'a, V: Clone> IterState<'a, P, V> {
         let mut iters = Vec::new();
         if let NodeType::Twig(twig) = &node.node_type {
             if range.contains(&twig.key) {
-                let val = twig.get_latest_leaf();
+                let val = twig.get_latest_live_leaf();
                 if let Some(v) = val {
                     leafs.push_back((&twig.key, &v.value, &v.version, &v.ts));
                 }
@@ -180,12 +766,12 @@ impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for IterState<'a, P, V> {
                 }
                 Some(other) => {
                     if let NodeType::Twig(twig) = &other.1.node_type {
-                        let val = twig.get_latest_leaf();
+                        let val = twig.get_latest_live_leaf();
                         if let Some(v) = val {
                             self.leafs
                                 .push_back((&twig.key, &v.value, &v.version, &v.ts));
+                            break;
                         }
-                        break;
                     } else {
                         self.iters.push(NodeIter::new(other.1.iter()));
                     }
@@ -199,9 +785,456 @@ impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for IterState<'a, P, V> {
     }
 }
 
+// Once both the descent stack and the buffered leaves are empty, nothing ever refills them.
+impl<'a, P: KeyTrait + 'a, V: Clone> FusedIterator for IterState<'a, P, V> {}
+
+/// An iterator merging several Tries in key order, with earlier Tries shadowing later ones.
+pub struct MergeIter<'a, P: KeyTrait + 'a, V: Clone> {
+    iters: Vec<std::iter::Peekable<Iter<'a, P, V>>>,
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> MergeIter<'a, P, V> {
+    /// Creates a new MergeIter instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `iters` - The iterators to merge, ordered from highest to lowest priority.
+    ///
+    pub(crate) fn new(iters: Vec<Iter<'a, P, V>>) -> Self {
+        Self {
+            iters: iters.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<'a, P: KeyTrait + 'a, V: Clone> Iterator for MergeIter<'a, P, V> {
+    type Item = (Vec<u8>, &'a V, &'a u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Find the smallest key at the head of the iterators. On ties the earliest iterator wins.
+        let mut min: Option<(usize, &Vec<u8>)> = None;
+        for (idx, iter) in self.iters.iter_mut().enumerate() {
+            if let Some((key, _, _, _)) = iter.peek() {
+                if min.is_none_or(|(_, min_key)| key < min_key) {
+                    min = Some((idx, key));
+                }
+            }
+        }
+        let min_idx = min.map(|(idx, _)| idx);
+
+        let (key, value, version, _) = self.iters[min_idx?].next()?;
+
+        // Skip the shadowed entries for the same key in lower priority iterators.
+        for iter in self.iters.iter_mut() {
+            while iter.next_if(|(k, _, _, _)| *k == key).is_some() {}
+        }
+
+        Some((key, value, version))
+    }
+}
+
+/// An iterator that removes and yields the key-value pairs of a Trie in key order.
+pub struct Drain<'a, P: KeyTrait, V: Clone> {
+    tree: &'a mut Tree<P, V>,
+}
+
+impl<'a, P: KeyTrait, V: Clone> Drain<'a, P, V> {
+    /// Creates a new Drain instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree` - The Trie to drain.
+    ///
+    pub(crate) fn new(tree: &'a mut Tree<P, V>) -> Self {
+        Self { tree }
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Iterator for Drain<'_, P, V> {
+    type Item = (Vec<u8>, V, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value, version) = {
+            let (key, value, version, _) = self.tree.iter().next()?;
+            (key, value.clone(), *version)
+        };
+
+        // Removing the entry releases the nodes that only it was holding on to.
+        self.tree.remove(&P::from(key.as_slice())).ok()?;
+        Some((key, value, version))
+    }
+}
+
+/// An iterator over the key-value pairs of a pinned root of the Trie.
+///
+/// The iterator holds its own reference to the root it was created from rather than borrowing
+/// the Trie, so the Trie can be written to while it is alive. Since nodes are copied on write,
+/// those writes never show up in the iteration.
+pub struct OwnedIter<P: KeyTrait, V: Clone> {
+    // The children still to visit at every level of the descent, in key order
+    stack: Vec<std::vec::IntoIter<Arc<Node<P, V>>>>,
+    permutation: Option<Arc<BytePermutation>>,
+}
+
+impl<P: KeyTrait, V: Clone> OwnedIter<P, V> {
+    /// Creates a new OwnedIter instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The root node to pin, if any.
+    /// * `permutation` - The byte permutation to decode the keys with, if any.
+    ///
+    pub(crate) fn new(
+        root: Option<Arc<Node<P, V>>>,
+        permutation: Option<Arc<BytePermutation>>,
+    ) -> Self {
+        Self {
+            stack: root
+                .map(|root| vec![root].into_iter())
+                .into_iter()
+                .collect(),
+            permutation,
+        }
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Iterator for OwnedIter<P, V> {
+    type Item = (Vec<u8>, V, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(children) = self.stack.last_mut() {
+            let Some(node) = children.next() else {
+                self.stack.pop();
+                continue;
+            };
+            match &node.node_type {
+                NodeType::Twig(twig) => {
+                    if let Some(leaf) = twig.get_latest_live_leaf() {
+                        let key = match &self.permutation {
+                            Some(permutation) => permutation.decode(twig.key.as_slice()),
+                            None => twig.key.as_slice().to_vec(),
+                        };
+                        return Some((key, leaf.value.clone(), leaf.version));
+                    }
+                }
+                _ => {
+                    let children: Vec<_> = node.iter().map(|(_, child)| child.clone()).collect();
+                    self.stack.push(children.into_iter());
+                }
+            }
+        }
+        None
+    }
+}
+
+// Once the descent stack is empty, nothing ever refills it.
+impl<P: KeyTrait, V: Clone> FusedIterator for OwnedIter<P, V> {}
+
+/// A stream over the key-value pairs in the Trie that periodically yields to the async runtime.
+///
+/// The stream borrows the Trie, so the Trie must outlive it and cannot be modified while the
+/// stream is alive.
+#[cfg(feature = "stream")]
+pub struct IterStream<'a, P: KeyTrait + 'a, V: Clone> {
+    iter: Iter<'a, P, V>,
+    yield_every: usize,
+    since_yield: usize,
+}
+
+#[cfg(feature = "stream")]
+impl<'a, P: KeyTrait + 'a, V: Clone> IterStream<'a, P, V> {
+    /// Creates a new IterStream instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `iter` - The iterator to advance.
+    /// * `yield_every` - The number of items produced between yields to the runtime.
+    ///
+    pub(crate) fn new(iter: Iter<'a, P, V>, yield_every: usize) -> Self {
+        Self {
+            iter,
+            yield_every: yield_every.max(1),
+            since_yield: 0,
+        }
+    }
+}
+
+// The stream never relies on being pinned, so it can be moved between polls.
+#[cfg(feature = "stream")]
+impl<'a, P: KeyTrait + 'a, V: Clone> Unpin for IterStream<'a, P, V> {}
+
+#[cfg(feature = "stream")]
+impl<'a, P: KeyTrait + 'a, V: Clone> futures_core::Stream for IterStream<'a, P, V> {
+    type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Hand control back to the runtime, asking to be polled again straight away.
+        if this.since_yield >= this.yield_every {
+            this.since_yield = 0;
+            cx.waker().wake_by_ref();
+            return std::task::Poll::Pending;
+        }
+
+        this.since_yield += 1;
+        std::task::Poll::Ready(this.iter.next())
+    }
+}
+
+/// A stateful cursor over the key-value pairs in the Trie that can move in both directions.
+///
+/// A cursor is either positioned at a key or unpositioned, which is its state when created and
+/// after it moves past either end of the Trie. Moving forward from an unpositioned cursor goes
+/// to the first key, and moving backward goes to the last key.
+pub struct Cursor<'a, P: KeyTrait, V: Clone> {
+    root: Option<&'a Arc<Node<P, V>>>,
+    // The children of each inner node on the path to the current twig, and the index taken.
+    #[allow(clippy::type_complexity)]
+    stack: Vec<(Vec<(u8, &'a Arc<Node<P, V>>)>, usize)>,
+    current: Option<&'a TwigNode<P, V>>,
+    permutation: Option<Arc<BytePermutation>>,
+}
+
+impl<'a, P: KeyTrait, V: Clone> Cursor<'a, P, V> {
+    /// Creates a new, unpositioned Cursor instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - An optional reference to the root node of the Trie.
+    /// * `permutation` - The byte permutation the keys are stored with, if any.
+    ///
+    pub(crate) fn new(
+        root: Option<&'a Arc<Node<P, V>>>,
+        permutation: Option<Arc<BytePermutation>>,
+    ) -> Self {
+        Self {
+            root,
+            stack: Vec::new(),
+            current: None,
+            permutation,
+        }
+    }
+
+    /// Returns the key and value at the cursor without moving it.
+    ///
+    /// The key is borrowed from the Trie, unless a byte permutation has to be undone on it.
+    pub fn current(&self) -> Option<(Cow<'a, [u8]>, &'a V)> {
+        let twig = self.current?;
+        let leaf = twig.get_latest_live_leaf()?;
+        let key = match &self.permutation {
+            Some(permutation) => Cow::Owned(permutation.decode(twig.key.as_slice())),
+            None => Cow::Borrowed(twig.key.as_slice()),
+        };
+        Some((key, &leaf.value))
+    }
+
+    /// Moves the cursor to the first key greater than or equal to `key` and returns it.
+    ///
+    /// Under a byte permutation, keys are ordered as stored, as for `Tree::iter`.
+    pub fn seek(&mut self, key: &P) -> Option<(Cow<'a, [u8]>, &'a V)> {
+        let key = match &self.permutation {
+            Some(permutation) => Cow::Owned(P::from(permutation.encode(key.as_slice()).as_slice())),
+            None => Cow::Borrowed(key),
+        };
+        self.position_at(&key);
+        self.skip_deleted(true);
+        self.current()
+    }
+
+    // Positions the cursor at the first twig whose key is greater than or equal to `key`.
+    fn position_at(&mut self, key: &P) {
+        self.stack.clear();
+        self.current = None;
+        let Some(mut node) = self.root else {
+            return;
+        };
+        let key_slice = key.as_slice();
+        let mut depth = 0;
+
+        loop {
+            if let NodeType::Twig(twig) = &node.node_type {
+                self.current = Some(twig);
+                if twig.key.as_slice() < key_slice {
+                    self.advance();
+                }
+                return;
+            }
+
+            let key_prefix = &key_slice[depth.min(key_slice.len())..];
+            let prefix = node.prefix();
+            let lcp = prefix.longest_common_prefix(key_prefix);
+
+            // If the node's prefix diverges from the key, the whole subtree lies on one side.
+            if lcp < prefix.len() {
+                if lcp < key_prefix.len() && prefix.at(lcp) < key_prefix[lcp] {
+                    self.descend_last(node);
+                    self.advance();
+                } else {
+                    self.descend_first(node);
+                }
+                return;
+            }
+
+            // If the key ends at this node, every key below it is greater.
+            if key_prefix.len() == prefix.len() {
+                self.descend_first(node);
+                return;
+            }
+
+            // Follow the child for the key's next byte, or the first greater one.
+            let k = key_prefix[prefix.len()];
+            let children: Vec<_> = node.iter().collect();
+            match children.iter().position(|(byte, _)| *byte >= k) {
+                Some(idx) => {
+                    let (byte, child) = children[idx];
+                    self.stack.push((children, idx));
+                    if byte == k {
+                        depth += prefix.len();
+                        node = child;
+                    } else {
+                        self.descend_first(child);
+                        return;
+                    }
+                }
+                None => {
+                    self.descend_last(node);
+                    self.advance();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves the cursor to the next key and returns it.
+    ///
+    /// Returns `None` and leaves the cursor unpositioned when moving past the last key.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(Cow<'a, [u8]>, &'a V)> {
+        if self.current.is_none() {
+            if let Some(root) = self.root {
+                self.descend_first(root);
+            }
+        } else {
+            self.advance();
+        }
+        self.skip_deleted(true);
+        self.current()
+    }
+
+    /// Moves the cursor to the previous key and returns it.
+    ///
+    /// Returns `None` and leaves the cursor unpositioned when moving before the first key.
+    pub fn prev(&mut self) -> Option<(Cow<'a, [u8]>, &'a V)> {
+        if self.current.is_none() {
+            if let Some(root) = self.root {
+                self.descend_last(root);
+            }
+        } else {
+            self.retreat();
+        }
+        self.skip_deleted(false);
+        self.current()
+    }
+
+    // Moves past twigs without a visible value, such as deleted keys, in the given direction.
+    fn skip_deleted(&mut self, forward: bool) {
+        while self
+            .current
+            .is_some_and(|twig| twig.get_latest_live_leaf().is_none())
+        {
+            if forward {
+                self.advance();
+            } else {
+                self.retreat();
+            }
+        }
+    }
+
+    // Descends from the node to its first twig, extending the path.
+    fn descend_first(&mut self, node: &'a Arc<Node<P, V>>) {
+        let mut node = node;
+        loop {
+            if let NodeType::Twig(twig) = &node.node_type {
+                self.current = Some(twig);
+                return;
+            }
+            let children: Vec<_> = node.iter().collect();
+            let Some(&(_, child)) = children.first() else {
+                self.current = None;
+                return;
+            };
+            self.stack.push((children, 0));
+            node = child;
+        }
+    }
+
+    // Descends from the node to its last twig, extending the path.
+    fn descend_last(&mut self, node: &'a Arc<Node<P, V>>) {
+        let mut node = node;
+        loop {
+            if let NodeType::Twig(twig) = &node.node_type {
+                self.current = Some(twig);
+                return;
+            }
+            let children: Vec<_> = node.iter().collect();
+            let Some(&(_, child)) = children.last() else {
+                self.current = None;
+                return;
+            };
+            let idx = children.len() - 1;
+            self.stack.push((children, idx));
+            node = child;
+        }
+    }
+
+    // Moves from the current twig to the next one, climbing until a later sibling exists.
+    fn advance(&mut self) {
+        while let Some((children, idx)) = self.stack.last_mut() {
+            if *idx + 1 < children.len() {
+                *idx += 1;
+                let child = children[*idx].1;
+                self.descend_first(child);
+                return;
+            }
+            self.stack.pop();
+        }
+        self.current = None;
+    }
+
+    // Moves from the current twig to the previous one, climbing until an earlier sibling exists.
+    fn retreat(&mut self) {
+        while let Some((children, idx)) = self.stack.last_mut() {
+            if *idx > 0 {
+                *idx -= 1;
+                let child = children[*idx].1;
+                self.descend_last(child);
+                return;
+            }
+            self.stack.pop();
+        }
+        self.current = None;
+    }
+}
+
 pub struct Range<'a, K: KeyTrait, V: Clone, R> {
     forward: IterState<'a, K, V>,
     range: R,
+    remaining: Option<usize>,
+    // The node the scan started at and the key bytes leading to it, which `size_hint` counts
+    // the keys in the range below, and the number of keys yielded so far
+    root: Option<&'a Arc<Node<K, V>>>,
+    path: Vec<u8>,
+    yielded: usize,
+    // The byte permutation the keys are stored with, if any, under which the keys in the range
+    // are scattered over the whole subtree
+    permutation: Option<Arc<BytePermutation>>,
+    // Number of nodes pulled from the descent, to check how far a scan went
+    #[cfg(test)]
+    pub(crate) visited: usize,
 }
 
 impl<'a, K: KeyTrait, V: Clone, R> Range<'a, K, V, R>
@@ -213,6 +1246,13 @@ where
         Self {
             forward: IterState::empty(),
             range,
+            remaining: None,
+            root: None,
+            path: Vec::new(),
+            yielded: 0,
+            permutation: None,
+            #[cfg(test)]
+            visited: 0,
         }
     }
 
@@ -224,13 +1264,59 @@ where
             Self {
                 forward: IterState::forward_scan(node, &range),
                 range,
+                remaining: None,
+                root: Some(node),
+                path: Vec::new(),
+                yielded: 0,
+                permutation: None,
+                #[cfg(test)]
+                visited: 0,
             }
         } else {
-            Self {
-                forward: IterState::empty(),
-                range,
+            Self::empty(range)
+        }
+    }
+
+    /// Sets the key bytes leading to the node the scan starts at, for a scan that does not
+    /// start at the root.
+    pub(crate) fn at_path(mut self, path: &[u8]) -> Self {
+        self.path = path.to_vec();
+        self
+    }
+
+    /// Tests the keys against the range and yields them with the bytes a byte permutation
+    /// substituted restored, if any. The substitution does not preserve the order of the keys,
+    /// so the whole subtree is scanned, in the order of the stored keys.
+    pub(crate) fn decode_keys(mut self, permutation: Option<Arc<BytePermutation>>) -> Self {
+        if permutation.is_some() {
+            self.forward = self
+                .root
+                .map_or_else(IterState::empty, |root| IterState::new(root));
+            if self.remaining == Some(0) {
+                self.forward = IterState::empty();
             }
         }
+        self.permutation = permutation;
+        self
+    }
+
+    /// Caps the number of entries the range yields. Once the limit is reached the descent
+    /// state is dropped, so no further subtrees are visited.
+    pub(crate) fn with_limit(mut self, limit: usize) -> Self {
+        self.remaining = Some(limit);
+        if limit == 0 {
+            self.forward = IterState::empty();
+        }
+        self
+    }
+
+    /// Drops the version and timestamp from each entry, yielding just the key and
+    /// its latest value.
+    pub fn kv(self) -> impl Iterator<Item = (Vec<u8>, &'a V)>
+    where
+        K: 'a,
+    {
+        self.map(|(key, value, _, _)| (key, value))
     }
 }
 
@@ -238,19 +1324,78 @@ impl<'a, K: 'a + KeyTrait, V: Clone, R: RangeBounds<K>> Iterator for Range<'a, K
     type Item = (Vec<u8>, &'a V, &'a u64, &'a u64);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+
+        let item = match &self.permutation {
+            Some(permutation) => {
+                let range = &self.range;
+                self.forward.find_map(|(key, value, version, ts)| {
+                    let key = permutation.decode(&key);
+                    range
+                        .contains(&K::from(key.as_slice()))
+                        .then_some((key, value, version, ts))
+                })
+            }
+            None => self.next_in_order(),
+        };
+
+        if item.is_some() {
+            self.yielded += 1;
+        }
+        if let (Some(_), Some(remaining)) = (&item, self.remaining.as_mut()) {
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.forward = IterState::empty();
+            }
+        }
+        item
+    }
+
+    // The keys in the range are counted from the cached subtree counts, which visits the
+    // nodes along the two bounds. This is only done when asked, so that short scans do not
+    // pay for it. Under a byte permutation only the keys left in the subtree bound the count.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.permutation.is_some() {
+            let total = self.root.map_or(0, |root| root.leaf_count());
+            let mut len = total.saturating_sub(self.yielded);
+            if let Some(remaining) = self.remaining {
+                len = len.min(remaining);
+            }
+            return (0, Some(len));
+        }
+        let total = self.root.map_or(0, |root| {
+            Node::range_count_recurse(root, &mut self.path.clone(), &self.range, usize::MAX)
+        });
+        let mut len = total.saturating_sub(self.yielded);
+        if let Some(remaining) = self.remaining {
+            len = len.min(remaining);
+        }
+        (len, Some(len))
+    }
+}
+
+impl<'a, K: 'a + KeyTrait, V: Clone, R: RangeBounds<K>> Range<'a, K, V, R> {
+    // Yields the next key in the range, descending in key order and stopping at the end bound.
+    fn next_in_order(&mut self) -> Option<(Vec<u8>, &'a V, &'a u64, &'a u64)> {
         while let Some(node) = self.forward.iters.last_mut() {
             let e = node.next();
             match e {
                 Some(other) => {
+                    #[cfg(test)]
+                    {
+                        self.visited += 1;
+                    }
                     if let NodeType::Twig(twig) = &other.1.node_type {
                         if self.range.contains(&twig.key) {
-                            let val = twig.get_latest_leaf();
+                            let val = twig.get_latest_live_leaf();
                             if let Some(v) = val {
                                 self.forward
                                     .leafs
                                     .push_back((&twig.key, &v.value, &v.version, &v.ts));
+                                break;
                             }
-                            break;
                         } else {
                             match self.range.end_bound() {
                                 Bound::Included(k) if &twig.key > k => self.forward.iters.clear(),
@@ -274,3 +1419,7 @@ impl<'a, K: 'a + KeyTrait, V: Clone, R: RangeBounds<K>> Iterator for Range<'a, K
             .map(|leaf| (leaf.0.as_slice().to_vec(), leaf.1, leaf.2, leaf.3))
     }
 }
+
+// Leaving the range or hitting the limit clears the descent state, and an exhausted
+// `IterState` stays exhausted, so every call after the first `None` returns `None` too.
+impl<'a, K: 'a + KeyTrait, V: Clone, R: RangeBounds<K>> FusedIterator for Range<'a, K, V, R> {}
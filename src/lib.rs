@@ -1,9 +1,15 @@
 // #[allow(warnings)]
 pub mod art;
+#[cfg(feature = "bloom")]
+mod bloom;
+pub mod concurrent;
+pub mod export;
 pub mod iter;
 pub mod node;
 pub mod snapshot;
+pub mod txn;
 
+use std::borrow::Cow;
 use std::cmp::{Ord, Ordering, PartialOrd};
 use std::fmt::Debug;
 
@@ -12,13 +18,26 @@ use std::fmt::Debug;
 // ART nodes often only store partial keys, which are the differing prefixes of the keys.
 // This approach significantly reduces the memory requirements of the data structure.
 // Key is a trait that provides an abstraction for partial keys.
+// Compressed prefixes are stored in the key type itself, so a prefix can never outgrow its
+// storage: it is bounded by the capacity of a FixedKey, and unbounded for a VariableKey.
 pub trait Key {
     fn at(&self, pos: usize) -> u8;
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn prefix_before(&self, length: usize) -> Self;
     fn prefix_after(&self, start: usize) -> Self;
     fn longest_common_prefix(&self, slice: &[u8]) -> usize;
     fn as_slice(&self) -> &[u8];
+    // Returns the bytes the key is ordered by. The tree lays keys out in the byte order of their
+    // encoding, so this is the one place a key type decides its ordering, and its Ord impl has to
+    // agree with it. All the other methods operate on these same bytes, so a type that wants an
+    // order other than the natural byte order of its data, like ReverseKey, holds the encoded
+    // bytes rather than the data.
+    fn encode_ordered(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_slice())
+    }
 }
 
 pub trait KeyTrait:
@@ -47,6 +66,11 @@ impl<T: Key + Clone + PartialOrd + PartialEq + Ord + Debug + for<'a> From<&'a [u
 // no characters can come after it. Therefore no string with a null-byte can be a prefix of any other,
 // because no string can have any characters after the NULL byte!
 //
+// This only holds for data without NULL bytes, so VariableKey escapes them, as described below.
+// It also gives the empty string a well-defined key: it is encoded as the lone terminator, which
+// sorts before every other key. A zero-length key, on the other hand, would be a prefix of every
+// key in the tree, so the tree rejects it on insert.
+//
 #[derive(Clone, Debug, Eq)]
 pub struct FixedKey<const SIZE: usize> {
     content: [u8; SIZE],
@@ -94,6 +118,7 @@ impl<const SIZE: usize> FixedKey<SIZE> {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
         assert!(s.len() < SIZE, "data length is greater than array length");
         let mut arr = [0; SIZE];
@@ -113,6 +138,25 @@ impl<const SIZE: usize> FixedKey<SIZE> {
             len: s.len() + 1,
         }
     }
+
+    // Decodes big-endian key bytes, as produced by the integer From impls, back into a number
+    pub fn decode(bytes: &[u8]) -> u128 {
+        assert!(bytes.len() <= 16, "data length is greater than u128");
+        bytes
+            .iter()
+            .fold(0u128, |acc, &byte| (acc << 8) | byte as u128)
+    }
+
+    // Decodes key bytes produced by From<u64> (or a narrower integer) back into a u64
+    pub fn to_u64(bytes: &[u8]) -> u64 {
+        assert!(bytes.len() <= 8, "data length is greater than u64");
+        Self::decode(bytes) as u64
+    }
+
+    // Decodes key bytes produced by From<u128> (or a narrower integer) back into a u128
+    pub fn to_u128(bytes: &[u8]) -> u128 {
+        Self::decode(bytes)
+    }
 }
 
 impl<const SIZE: usize> Key for FixedKey<SIZE> {
@@ -173,12 +217,24 @@ impl<const N: usize> From<u16> for FixedKey<N> {
     }
 }
 
+impl<const N: usize> From<u32> for FixedKey<N> {
+    fn from(data: u32) -> Self {
+        Self::from_slice(data.to_be_bytes().as_ref())
+    }
+}
+
 impl<const N: usize> From<u64> for FixedKey<N> {
     fn from(data: u64) -> Self {
         Self::from_slice(data.to_be_bytes().as_ref())
     }
 }
 
+impl<const N: usize> From<u128> for FixedKey<N> {
+    fn from(data: u128) -> Self {
+        Self::from_slice(data.to_be_bytes().as_ref())
+    }
+}
+
 impl<const N: usize> From<&str> for FixedKey<N> {
     fn from(data: &str) -> Self {
         Self::from_str(data)
@@ -196,24 +252,40 @@ impl<const N: usize> From<&String> for FixedKey<N> {
     }
 }
 
-// A VariableKey is a variable-length datatype with NULL byte appended to it.
+// A VariableKey is a variable-length datatype with a terminator appended to it.
+//
+// As explained above, no key stored in the tree may be a prefix of another key. The
+// terminating constructors (key, from_slice, terminate, from_str, from_string and
+// from_slice_with_termination) guarantee this the way KeyBuilder does for byte strings: every
+// NULL byte of the data is escaped as 0x00 0xFF, and VariableKey::TERMINATOR, 0x00 0x01, is
+// appended. A NULL byte in an encoded key is thus always followed by 0xFF within the data or
+// by 0x01 at its end, so no encoded key is a prefix of another, whatever bytes the data holds
+// ("a" and "a\0" included). The encoding also keeps the order of the data, with a key ordered
+// before every key it is a prefix of ("ab" < "abc").
+//
+// from_slice_with_terminator appends a single byte of the caller's choice without escaping,
+// which keeps keys prefix-free only if that byte cannot occur at the end of the data. For
+// UTF-8 strings that is 0b10111111, a continuation byte that never follows a complete
+// character, though with it a key sorts after the keys it is a prefix of.
+//
+// from and From<&[u8]> take the bytes as they are. They are meant for data that is prefix-free
+// by construction, such as fixed-width integers or keys built by KeyBuilder, and for the
+// partial keys the tree builds internally.
 #[derive(Clone, PartialEq, PartialOrd, Ord, Eq, Debug)]
 pub struct VariableKey {
     data: Vec<u8>,
 }
 
 impl VariableKey {
+    /// The bytes appended by the terminating constructors, after escaping the data.
+    pub const TERMINATOR: [u8; 2] = [0, 1];
+
     pub fn key(src: &[u8]) -> Self {
-        let mut data = Vec::with_capacity(src.len() + 1);
-        data.extend_from_slice(src);
-        data.push(0);
-        Self { data }
+        Self::escaped(src)
     }
 
     pub fn from_slice(src: &[u8]) -> Self {
-        Self {
-            data: Vec::from(src),
-        }
+        Self::escaped(src)
     }
 
     pub fn to_slice(&self) -> &[u8] {
@@ -221,24 +293,16 @@ impl VariableKey {
     }
 
     pub fn terminate(&self) -> Self {
-        let mut data = Vec::with_capacity(self.data.len() + 1);
-        data.extend_from_slice(&self.data);
-        data.push(0);
-        Self { data }
+        Self::escaped(&self.data)
     }
 
     pub fn from_string(s: &String) -> Self {
-        let mut data = Vec::with_capacity(s.len() + 1);
-        data.extend_from_slice(s.as_bytes());
-        data.push(0);
-        Self { data }
+        Self::escaped(s.as_bytes())
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
-        let mut data = Vec::with_capacity(s.len() + 1);
-        data.extend_from_slice(s.as_bytes());
-        data.push(0);
-        Self { data }
+        Self::escaped(s.as_bytes())
     }
 
     pub fn from(data: Vec<u8>) -> Self {
@@ -246,28 +310,50 @@ impl VariableKey {
     }
 
     pub fn from_slice_with_termination(src: &[u8]) -> Self {
+        Self::escaped(src)
+    }
+
+    // Creates a new instance from the slice with every NULL byte escaped and the terminator
+    // appended
+    fn escaped(src: &[u8]) -> Self {
+        let nulls = src.iter().filter(|&&byte| byte == 0).count();
+        let mut data = Vec::with_capacity(src.len() + nulls + Self::TERMINATOR.len());
+        for &byte in src {
+            data.push(byte);
+            if byte == 0 {
+                data.push(0xFF);
+            }
+        }
+        data.extend_from_slice(&Self::TERMINATOR);
+        Self { data }
+    }
+
+    // Creates a new instance from the slice with the given terminator byte appended
+    pub fn from_slice_with_terminator(src: &[u8], terminator: u8) -> Self {
         let mut data = Vec::with_capacity(src.len() + 1);
         data.extend_from_slice(src);
-        data.push(0);
+        data.push(terminator);
         Self { data }
     }
 }
 
 impl From<&[u8]> for VariableKey {
     fn from(src: &[u8]) -> Self {
-        Self::from_slice(src)
+        Self {
+            data: Vec::from(src),
+        }
     }
 }
 
 impl Key for VariableKey {
     fn prefix_before(&self, length: usize) -> Self {
         assert!(length <= self.data.len());
-        VariableKey::from_slice(&self.data[..length])
+        VariableKey::from(self.data[..length].to_vec())
     }
 
     fn prefix_after(&self, start: usize) -> Self {
         assert!(start <= self.data.len());
-        VariableKey::from_slice(&self.data[start..self.data.len()])
+        VariableKey::from(self.data[start..self.data.len()].to_vec())
     }
 
     #[inline(always)]
@@ -296,6 +382,187 @@ impl Key for VariableKey {
     }
 }
 
+// A ReverseKey orders keys in the reverse order of another key type, so iterating a tree of
+// ReverseKeys yields the largest keys first, e.g. the newest of big-endian timestamps.
+//
+// The encoding is the byte complement of the inner key. Complementing every byte reverses the
+// order of any two keys that differ at some byte, and the set of keys stays prefix-free if the
+// inner keys are, so fixed-width keys and terminated variable keys can both be reversed.
+// Iteration yields the encoded bytes, which ReverseKey::decode turns back into the inner key.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ReverseKey<K> {
+    encoded: K,
+}
+
+impl<K: KeyTrait> ReverseKey<K> {
+    pub fn new(key: &K) -> Self {
+        Self {
+            encoded: K::from(&Self::complement(key.as_slice())[..]),
+        }
+    }
+
+    // Returns the key this key was created from
+    pub fn into_inner(self) -> K {
+        Self::decode(self.encoded.as_slice())
+    }
+
+    // Decodes the encoded bytes of a ReverseKey, as yielded by the tree's iterators, back into
+    // the inner key
+    pub fn decode(bytes: &[u8]) -> K {
+        K::from(&Self::complement(bytes)[..])
+    }
+
+    fn complement(bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|byte| !byte).collect()
+    }
+}
+
+impl<K: KeyTrait> PartialOrd for ReverseKey<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: KeyTrait> Ord for ReverseKey<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.encode_ordered().cmp(&other.encode_ordered())
+    }
+}
+
+// Wraps bytes that are already encoded, such as the partial keys the tree builds internally
+impl<K: KeyTrait> From<&[u8]> for ReverseKey<K> {
+    fn from(src: &[u8]) -> Self {
+        Self {
+            encoded: K::from(src),
+        }
+    }
+}
+
+impl<K: KeyTrait> Key for ReverseKey<K> {
+    fn prefix_before(&self, length: usize) -> Self {
+        Self {
+            encoded: self.encoded.prefix_before(length),
+        }
+    }
+
+    fn prefix_after(&self, start: usize) -> Self {
+        Self {
+            encoded: self.encoded.prefix_after(start),
+        }
+    }
+
+    #[inline(always)]
+    fn at(&self, pos: usize) -> u8 {
+        self.encoded.at(pos)
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.encoded.len()
+    }
+
+    fn longest_common_prefix(&self, key: &[u8]) -> usize {
+        self.encoded.longest_common_prefix(key)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self.encoded.as_slice()
+    }
+}
+
+// A KeyBuilder encodes a tuple of fields into a single key whose bytes sort in tuple order.
+// Integers are written big-endian, with the sign bit of signed integers flipped so negative
+// values sort first. Byte strings are written with every NULL byte escaped as 0x00 0xFF and are
+// terminated by 0x00 0x01, so a field sorts before any longer field it is a prefix of whatever
+// follows it.
+// Keys built with the same sequence of field types are never prefixes of each other.
+#[derive(Clone, Default, Debug)]
+pub struct KeyBuilder {
+    data: Vec<u8>,
+}
+
+impl KeyBuilder {
+    pub fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub fn push_u64(mut self, value: u64) -> Self {
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn push_i64(mut self, value: i64) -> Self {
+        self.data
+            .extend_from_slice(&((value as u64) ^ (1 << 63)).to_be_bytes());
+        self
+    }
+
+    pub fn push_bytes(mut self, value: &[u8]) -> Self {
+        for &byte in value {
+            self.data.push(byte);
+            if byte == 0 {
+                self.data.push(0xFF);
+            }
+        }
+        self.data.extend_from_slice(&[0, 1]);
+        self
+    }
+
+    pub fn push_str(self, value: &str) -> Self {
+        self.push_bytes(value.as_bytes())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn build(self) -> VariableKey {
+        VariableKey::from(self.data)
+    }
+}
+
+// Versions and timestamps are both bare u64s, which makes it easy to pass one for the other.
+// The wrappers below let the compiler tell them apart. The Tree methods taking a version or
+// a timestamp accept anything that converts into the wrapper, so plain u64s keep working, but
+// a typed Ts passed where a VersionId is expected, or the other way round, does not compile.
+// Both are transparent, so they cost nothing over the u64 they wrap.
+
+/// The version of a write, as passed to `Tree::insert`, `Tree::get` and the other methods
+/// taking a version.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VersionId(pub u64);
+
+/// The timestamp of a write or a read, as passed to `Tree::insert`, `Tree::get_ref` and the
+/// other methods taking a timestamp.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ts(pub u64);
+
+impl From<u64> for VersionId {
+    fn from(version: u64) -> Self {
+        VersionId(version)
+    }
+}
+
+impl From<VersionId> for u64 {
+    fn from(version: VersionId) -> Self {
+        version.0
+    }
+}
+
+impl From<u64> for Ts {
+    fn from(ts: u64) -> Self {
+        Ts(ts)
+    }
+}
+
+impl From<Ts> for u64 {
+    fn from(ts: Ts) -> Self {
+        ts.0
+    }
+}
+
 /*
     Sparse Array implementation
 */
@@ -392,7 +659,27 @@ impl<X, const WIDTH: usize> SparseVector<X, WIDTH> {
     /// This function removes the element at the given position from the SparseVector, returning it if it exists.
     #[inline]
     pub fn erase(&mut self, pos: usize) -> Option<X> {
-        self.storage[pos].take()
+        self.storage.get_mut(pos).and_then(Option::take)
+    }
+
+    /// This function returns the number of slots the SparseVector can hold without reallocating.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.storage.capacity()
+    }
+
+    /// This function returns the number of used (non-None) slots in the SparseVector.
+    #[inline]
+    pub fn used_len(&self) -> usize {
+        self.storage.iter().filter(|x| x.is_some()).count()
+    }
+
+    /// This function drops the free slots after the last used element and releases the spare capacity
+    /// of the backing store. Later calls to `set` or `push` grow the store again on demand.
+    pub fn shrink_to_fit(&mut self) {
+        let len = self.last_used_pos().map_or(0, |pos| pos + 1);
+        self.storage.truncate(len);
+        self.storage.shrink_to_fit();
     }
 
     /// This function clears the SparseVector, removing all elements.
@@ -425,11 +712,31 @@ impl<X, const WIDTH: usize> SparseVector<X, WIDTH> {
             .enumerate()
             .filter_map(|(i, x)| x.as_ref().map(|v| (i, v)))
     }
+
+    /// This function returns an iterator over pairs of positions and references to the used (non-None) elements whose
+    /// positions lie in the inclusive range from `lo` to `hi`, without visiting any slot outside of it.
+    pub fn iter_range(&self, lo: usize, hi: usize) -> impl DoubleEndedIterator<Item = (usize, &X)> {
+        let end = hi.saturating_add(1).min(self.storage.len());
+        let slots = self.storage.get(lo..end).unwrap_or_default();
+        slots
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, x)| x.as_ref().map(|v| (lo + i, v)))
+    }
+
+    /// This function returns an iterator over pairs of positions and mutable references to all the used (non-None) elements in the SparseVector.
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (usize, &mut X)> {
+        self.storage
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, x)| x.as_mut().map(|v| (i, v)))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SparseVector;
+    use super::{FixedKey, Key, KeyBuilder, ReverseKey, SparseVector, Ts, VariableKey, VersionId};
+    use crate::art::Tree;
 
     #[test]
     fn new() {
@@ -509,6 +816,32 @@ mod tests {
         assert!(!v.is_empty());
     }
 
+    #[test]
+    fn shrink_to_fit() {
+        let mut v: SparseVector<i32, 10> = SparseVector::new();
+        for i in 0..10 {
+            v.push(i);
+        }
+        assert_eq!(v.used_len(), 10);
+        for i in 2..10 {
+            v.erase(i);
+        }
+        assert_eq!(v.used_len(), 2);
+
+        v.shrink_to_fit();
+        assert_eq!(v.capacity(), 2);
+        assert_eq!(v.erase(5), None);
+
+        // The store grows again on demand
+        assert_eq!(v.first_free_pos(), 2);
+        v.set(7, 7);
+        assert_eq!(v.push(2), 2);
+        assert_eq!(v.get(7), Some(&7));
+        assert_eq!(v.used_len(), 4);
+        let keys: Vec<usize> = v.iter_keys().collect();
+        assert_eq!(keys, vec![0, 1, 2, 7]);
+    }
+
     #[test]
     fn iter_keys() {
         let mut v: SparseVector<i32, 10> = SparseVector::new();
@@ -526,4 +859,219 @@ mod tests {
         let values: Vec<(usize, &i32)> = v.iter().collect();
         assert_eq!(values, vec![(0, &5), (1, &6)]);
     }
+
+    #[test]
+    fn iter_mut() {
+        let mut v: SparseVector<i32, 10> = SparseVector::new();
+        v.set(1, 5);
+        v.set(4, 6);
+        v.set(7, 7);
+        v.erase(4);
+        let mut visited = Vec::new();
+        for (i, value) in v.iter_mut() {
+            visited.push(i);
+            *value *= 10;
+        }
+        assert_eq!(visited, vec![1, 7]);
+        let values: Vec<(usize, &i32)> = v.iter().collect();
+        assert_eq!(values, vec![(1, &50), (7, &70)]);
+        assert_eq!(v.get(4), None);
+    }
+
+    #[test]
+    fn key_builder_tuple_order() {
+        // (user_id, name, balance) tuples listed in ascending tuple order
+        let tuples: Vec<(u64, &[u8], i64)> = vec![
+            (1, b"", -5),
+            (1, b"", 3),
+            (1, b"\0", i64::MIN),
+            (1, b"\0\0", 0),
+            (1, b"\0a", 0),
+            (1, b"a", i64::MIN),
+            (1, b"a", -1),
+            (1, b"a", 0),
+            (1, b"a", i64::MAX),
+            (1, b"a\0", 0),
+            (1, b"ab", 0),
+            (1, b"a\xff", 0),
+            (1, b"b", 0),
+            (2, b"", 0),
+            (u64::MAX, b"", 0),
+        ];
+        let keys: Vec<Vec<u8>> = tuples
+            .iter()
+            .map(|(id, name, balance)| {
+                KeyBuilder::new()
+                    .push_u64(*id)
+                    .push_bytes(name)
+                    .push_i64(*balance)
+                    .as_slice()
+                    .to_vec()
+            })
+            .collect();
+
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} >= {:?}", pair[0], pair[1]);
+        }
+
+        // No key is a prefix of another, so all of them can be stored in a tree
+        for (i, a) in keys.iter().enumerate() {
+            for b in keys.iter().skip(i + 1) {
+                assert!(!b.starts_with(a));
+            }
+        }
+
+        let key = KeyBuilder::new().push_str("ab").push_u64(7).build();
+        assert_eq!(key.as_slice(), &[b'a', b'b', 0, 1, 0, 0, 0, 0, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn variable_key_terminator() {
+        // Terminated keys never nest, even when the data of one is a prefix of another
+        let words = ["abc", "", "ab", "b", "a", "abcd"];
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(&VariableKey::from_str(word), i, 0, 0).unwrap();
+        }
+        for (i, word) in words.iter().enumerate() {
+            assert_eq!(tree.get(&VariableKey::from_str(word), 0).unwrap().1, i);
+        }
+        let mut sorted = words.to_vec();
+        sorted.sort();
+        assert_eq!(
+            tree.keys().collect::<Vec<_>>(),
+            sorted
+                .iter()
+                .map(|word| VariableKey::from_str(word).as_slice().to_vec())
+                .collect::<Vec<_>>()
+        );
+
+        // Keys built from slices are terminated the same way
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for (i, bytes) in [&b"abc"[..], b"ab", b"abcd"].iter().enumerate() {
+            tree.insert(&VariableKey::from_slice(bytes), i, 0, 0)
+                .unwrap();
+        }
+        assert_eq!(tree.get(&VariableKey::from_slice(b"ab"), 0).unwrap().1, 1);
+        assert_eq!(tree.get(&VariableKey::from_str("abc"), 0).unwrap().1, 0);
+        let values: Vec<_> = tree.iter().map(|(_, v, _, _)| *v).collect();
+        assert_eq!(values, vec![1, 0, 2]);
+
+        // Data holding NULL bytes is escaped, so it neither nests nor changes the order
+        let data: [&[u8]; 7] = [b"a\0b", b"a", b"\0", b"a\0", b"a\0\0", b"", b"a\x01"];
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for (i, bytes) in data.iter().enumerate() {
+            tree.insert(&VariableKey::from_slice(bytes), i, 0, 0)
+                .unwrap();
+        }
+        for (i, bytes) in data.iter().enumerate() {
+            assert_eq!(tree.get(&VariableKey::from_slice(bytes), 0).unwrap().1, i);
+        }
+        let values: Vec<_> = tree.iter().map(|(_, v, _, _)| *v).collect();
+        assert_eq!(values, vec![5, 2, 1, 3, 4, 0, 6]);
+        assert_eq!(
+            VariableKey::from_slice(b"a\0").as_slice(),
+            &[b'a', 0, 0xFF, 0, 1]
+        );
+
+        // A terminator of the caller's choice is appended as it is, and must not end any data
+        let terminator = 0b1011_1111;
+        let data: [&[u8]; 4] = [b"a", b"a\0", b"a\0b", "a\u{3ff}".as_bytes()];
+        let mut tree = Tree::<VariableKey, usize>::new();
+        for (i, bytes) in data.iter().enumerate() {
+            let key = VariableKey::from_slice_with_terminator(bytes, terminator);
+            tree.insert(&key, i, 0, 0).unwrap();
+        }
+        for (i, bytes) in data.iter().enumerate() {
+            let key = VariableKey::from_slice_with_terminator(bytes, terminator);
+            assert_eq!(tree.get(&key, 0).unwrap().1, i);
+        }
+        assert_eq!(tree.iter().count(), data.len());
+    }
+
+    #[test]
+    fn reverse_key() {
+        let mut tree = Tree::<ReverseKey<FixedKey<8>>, u64>::new();
+        for i in [7u64, 300, 0, 42, u64::MAX, 1 << 40, 5] {
+            tree.insert(&ReverseKey::new(&i.into()), i, 0, 0).unwrap();
+        }
+
+        // Integers come out largest first
+        let values: Vec<_> = tree.iter().map(|(_, v, _, _)| *v).collect();
+        assert_eq!(values, vec![u64::MAX, 1 << 40, 300, 42, 7, 5, 0]);
+        let keys: Vec<_> = tree
+            .keys()
+            .map(|key| FixedKey::<8>::to_u64(ReverseKey::<FixedKey<8>>::decode(&key).as_slice()))
+            .collect();
+        assert_eq!(keys, values);
+
+        // Ranges are given in the reversed order too
+        let start = ReverseKey::new(&300u64.into());
+        let end = ReverseKey::new(&5u64.into());
+        let values: Vec<_> = tree.range(start..end).map(|(_, v, _, _)| *v).collect();
+        assert_eq!(values, vec![300, 42, 7]);
+
+        let key = ReverseKey::<FixedKey<8>>::new(&42u64.into());
+        assert_eq!(tree.get(&key, 0).unwrap().1, 42);
+        assert_eq!(key.into_inner(), 42u64.into());
+
+        // Terminated variable keys stay prefix-free, with longer keys now sorting first
+        let mut tree = Tree::<ReverseKey<VariableKey>, usize>::new();
+        for (i, word) in ["a", "ab", "b", "abc"].iter().enumerate() {
+            tree.insert(&ReverseKey::new(&VariableKey::from_str(word)), i, 0, 0)
+                .unwrap();
+        }
+        let values: Vec<_> = tree.iter().map(|(_, v, _, _)| *v).collect();
+        assert_eq!(values, vec![2, 3, 1, 0]);
+    }
+
+    #[test]
+    fn fixed_key_compares_only_its_length() {
+        // A FixedKey keeps the length of its content apart from its storage, so the unused
+        // tail of the array never takes part in comparisons.
+        let short = FixedKey::<8>::from_slice(b"ab");
+        let terminated = FixedKey::<8>::from_slice(b"ab\0");
+        assert_eq!(short.len(), 2);
+        assert_eq!(short.as_slice(), b"ab");
+        assert_eq!(terminated.len(), 3);
+        assert_ne!(short, terminated);
+        assert!(short < terminated);
+        assert_eq!(FixedKey::<8>::create_key(b"ab"), terminated);
+        assert_eq!(FixedKey::<8>::from_str("ab"), terminated);
+
+        // Prefixes cut from longer keys compare equal to keys created at that length
+        let long = FixedKey::<8>::from_slice(b"abcdefgh");
+        assert_eq!(long.prefix_before(2), short);
+        assert_eq!(long.prefix_after(6), FixedKey::<8>::from_slice(b"gh"));
+        assert_eq!(short.longest_common_prefix(b"abc"), 2);
+        assert_eq!(terminated.longest_common_prefix(b"ab\0\0"), 3);
+    }
+
+    #[test]
+    fn typed_version_and_ts() {
+        assert_eq!(std::mem::size_of::<VersionId>(), std::mem::size_of::<u64>());
+        assert_eq!(std::mem::size_of::<Ts>(), std::mem::size_of::<u64>());
+        assert_eq!(std::mem::align_of::<Ts>(), std::mem::align_of::<u64>());
+
+        let mut tree = Tree::<VariableKey, &str>::new();
+        let key = VariableKey::from_str("key");
+        tree.insert(&key, "typed", VersionId(1), Ts(100)).unwrap();
+        tree.insert(&key, "bare", 2, 50).unwrap();
+        tree.insert_raw(&key, "raw", 3, 10).unwrap();
+
+        let (_, value, version, ts) = tree.get(&key, VersionId(2)).unwrap();
+        assert_eq!((value, version, ts), ("bare", 2, 50));
+        assert_eq!(tree.get(&key, 1).unwrap().1, "typed");
+        assert_eq!(tree.get_ref(&key, Ts(10)), Some(&"raw"));
+
+        // The other methods taking a version or a timestamp take the wrappers too
+        assert_eq!(
+            tree.remove_version(&key, VersionId(1)).unwrap(),
+            Some("typed")
+        );
+        assert_eq!(tree.delete_logical(&key, Ts(200)).unwrap(), Some("raw"));
+        assert!(tree.get_ref(&key, Ts(200)).is_none());
+        assert_eq!(u64::from(VersionId::from(7)), 7);
+        assert_eq!(u64::from(Ts::from(7)), 7);
+    }
 }
@@ -1,4 +1,5 @@
 use std::mem::MaybeUninit;
+use std::ops::Deref;
 use std::sync::Arc;
 
 use crate::{KeyTrait, SparseVector};
@@ -11,22 +12,87 @@ pub trait NodeTrait<N> {
     fn clone(&self) -> Self;
     fn add_child(&self, key: u8, node: N) -> Self;
     fn find_child(&self, key: u8) -> Option<&Arc<N>>;
+    /// Like `find_child`, but also returns the slot the child lives in, which can
+    /// be handed to `replace_child_at` without searching for the key again.
+    fn find_child_pos(&self, key: u8) -> Option<(usize, &Arc<N>)>;
     fn delete_child(&self, key: u8) -> Self;
     fn num_children(&self) -> usize;
     fn size(&self) -> usize;
     fn replace_child(&self, key: u8, node: Arc<N>) -> Self;
+    fn replace_child_at(&self, pos: usize, node: Arc<N>) -> Self;
 }
 
 pub trait Version {
     fn version(&self) -> u64;
+    /// The oldest version held in the subtree, which together with `version` bounds every
+    /// version stored below the node.
+    fn min_version(&self) -> u64 {
+        self.version()
+    }
+    /// The number of live keys held in the subtree. Inner nodes cache the sum over their
+    /// children, so that counting and ranking keys can skip whole subtrees.
+    fn leaf_count(&self) -> usize {
+        1
+    }
 }
 
 #[derive(Clone)]
 pub struct TwigNode<K: KeyTrait + Clone, V> {
     pub(crate) prefix: K,
     pub(crate) key: K,
-    pub(crate) values: Vec<Arc<LeafValue<V>>>,
+    pub(crate) values: Values<V>,
     pub(crate) version: u64, // Version for the twig node
+    // Whether new values are stored inline rather than behind an Arc
+    pub(crate) inline_values: bool,
+}
+
+// The versions of a key held by a twig. Most keys are written only once or twice between
+// garbage collections, so with the smallvec feature up to two versions are stored in the twig
+// itself, and only keys with a longer history pay for a separate allocation.
+#[cfg(not(feature = "smallvec"))]
+pub(crate) type Values<V> = Vec<Leaf<V>>;
+#[cfg(feature = "smallvec")]
+pub(crate) type Values<V> = smallvec::SmallVec<[Leaf<V>; 2]>;
+
+// A version of a value held by a twig. Shared leaves are reference counted, so that copying
+// a twig on write only bumps a count per version. Inline leaves are stored in the twig itself,
+// which saves an allocation and a pointer chase per version when the value is cheap to copy.
+#[derive(Clone)]
+pub enum Leaf<V> {
+    Shared(Arc<LeafValue<V>>),
+    Inline(LeafValue<V>),
+}
+
+impl<V> Leaf<V> {
+    fn new(leaf: LeafValue<V>, inline: bool) -> Self {
+        if inline {
+            Leaf::Inline(leaf)
+        } else {
+            Leaf::Shared(Arc::new(leaf))
+        }
+    }
+
+    // Returns the leaf by value, copying it only if it is still shared.
+    pub(crate) fn into_leaf_value(self) -> LeafValue<V>
+    where
+        V: Clone,
+    {
+        match self {
+            Leaf::Shared(leaf) => Arc::try_unwrap(leaf).unwrap_or_else(|leaf| (*leaf).clone()),
+            Leaf::Inline(leaf) => leaf,
+        }
+    }
+}
+
+impl<V> Deref for Leaf<V> {
+    type Target = LeafValue<V>;
+
+    fn deref(&self) -> &LeafValue<V> {
+        match self {
+            Leaf::Shared(leaf) => leaf,
+            Leaf::Inline(leaf) => leaf,
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -34,44 +100,104 @@ pub struct LeafValue<V> {
     pub(crate) value: V,
     pub(crate) version: u64,
     pub(crate) ts: u64,
+    // Whether this version deletes the key. A tombstone keeps a copy of the value it deletes,
+    // so no placeholder value is needed, but reads never return it.
+    pub(crate) deleted: bool,
+    // The timestamp from which reads no longer see this version, as if it were a tombstone
+    pub(crate) expires_at: Option<u64>,
 }
 
 impl<V> LeafValue<V> {
     pub fn new(value: V, version: u64, ts: u64) -> Self {
-        LeafValue { value, version, ts }
+        LeafValue {
+            value,
+            version,
+            ts,
+            deleted: false,
+            expires_at: None,
+        }
+    }
+
+    pub fn tombstone(value: V, version: u64, ts: u64) -> Self {
+        LeafValue {
+            value,
+            version,
+            ts,
+            deleted: true,
+            expires_at: None,
+        }
+    }
+
+    // Returns the leaf expiring at the given timestamp, or never if it is None.
+    pub(crate) fn with_expiry(mut self, expires_at: Option<u64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// Returns whether the version has expired as of the given timestamp.
+    pub fn is_expired(&self, ts: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= ts)
     }
 }
 
-impl<K: KeyTrait + Clone, V> TwigNode<K, V> {
+impl<K: KeyTrait + Clone, V: Clone> TwigNode<K, V> {
     pub fn new(prefix: K, key: K) -> Self {
         TwigNode {
             prefix,
             key,
-            values: Vec::new(),
+            values: Values::new(),
             version: 0,
+            inline_values: false,
         }
     }
 
+    // Sets whether values inserted from now on are stored inline rather than behind an Arc.
+    pub fn with_inline_values(mut self, inline_values: bool) -> Self {
+        self.inline_values = inline_values;
+        self
+    }
+
     pub fn version(&self) -> u64 {
-        self.values
-            .iter()
-            .map(|value| value.version)
-            .max()
-            .unwrap_or(self.version)
+        self.get_latest_leaf()
+            .map_or(self.version, |value| value.version)
+    }
+
+    /// Returns the timestamp of the latest version stored in the twig.
+    ///
+    /// This is the timestamp of the version with the highest version number, which is not
+    /// necessarily the largest timestamp the twig holds, as timestamps need not follow the
+    /// version order.
+    pub fn latest_version_ts(&self) -> u64 {
+        self.get_latest_leaf().map_or(0, |leaf| leaf.ts)
     }
 
     pub fn insert(&self, value: V, version: u64, ts: u64) -> TwigNode<K, V> {
-        let mut new_values = self.values.clone();
+        self.insert_leaf(LeafValue::new(value, version, ts))
+    }
 
-        let new_leaf_value = LeafValue::new(value, version, ts);
+    // Returns a twig with a tombstone deleting the key at the given version, or None if the
+    // key is already deleted.
+    pub fn delete(&self, version: u64, ts: u64) -> Option<TwigNode<K, V>>
+    where
+        V: Clone,
+    {
+        let value = self.get_latest_live_leaf()?.value.clone();
+        Some(self.insert_leaf(LeafValue::tombstone(value, version, ts)))
+    }
 
-        // Insert new LeafValue in sorted order
-        let insertion_index =
-            match new_values.binary_search_by(|v| v.version.cmp(&new_leaf_value.version)) {
-                Ok(index) => index,
-                Err(index) => index,
-            };
-        new_values.insert(insertion_index, Arc::new(new_leaf_value));
+    pub(crate) fn insert_leaf(&self, new_leaf_value: LeafValue<V>) -> TwigNode<K, V> {
+        let mut new_values = self.values.clone();
+
+        // Insert new LeafValue in sorted order, after any value with the same version
+        let insertion_index = self.insertion_index(new_leaf_value.version);
+        new_values.insert(
+            insertion_index,
+            Leaf::new(new_leaf_value, self.inline_values),
+        );
 
         let new_version = new_values
             .iter()
@@ -84,54 +210,288 @@ impl<K: KeyTrait + Clone, V> TwigNode<K, V> {
             key: self.key.clone(),
             values: new_values,
             version: new_version,
+            inline_values: self.inline_values,
         }
     }
 
-    pub fn insert_mut(&mut self, value: V, version: u64, ts: u64) {
-        let new_leaf_value = LeafValue::new(value, version, ts);
-
-        // Insert new LeafValue in sorted order
-        let insertion_index = match self
+    /// Returns a twig without the value stored at exactly the given version, along with the
+    /// removed value, or `None` if the twig holds no such version.
+    #[allow(clippy::type_complexity)]
+    pub fn remove_version(&self, version: u64) -> Option<(TwigNode<K, V>, LeafValue<V>)> {
+        let idx = self
             .values
-            .binary_search_by(|v| v.version.cmp(&new_leaf_value.version))
-        {
-            Ok(index) => index,
-            Err(index) => index,
+            .binary_search_by(|v| v.version.cmp(&version))
+            .ok()?;
+        let mut new_values = self.values.clone();
+        let removed = new_values.remove(idx);
+
+        let new_version = new_values
+            .last()
+            .map_or(self.version, |value| value.version);
+        let twig = TwigNode {
+            prefix: self.prefix.clone(),
+            key: self.key.clone(),
+            values: new_values,
+            version: new_version,
+            inline_values: self.inline_values,
         };
-        self.values
-            .insert(insertion_index, Arc::new(new_leaf_value));
+        Some((twig, removed.into_leaf_value()))
+    }
+
+    /// Returns a twig holding only the given value, discarding all earlier versions.
+    pub fn replace(&self, value: V, version: u64, ts: u64) -> TwigNode<K, V> {
+        self.replace_leaf(LeafValue::new(value, version, ts))
+    }
+
+    pub(crate) fn replace_leaf(&self, new_leaf_value: LeafValue<V>) -> TwigNode<K, V> {
+        let version = new_leaf_value.version;
+        let leaf = Leaf::new(new_leaf_value, self.inline_values);
+        TwigNode {
+            prefix: self.prefix.clone(),
+            key: self.key.clone(),
+            values: std::iter::once(leaf).collect(),
+            version,
+            inline_values: self.inline_values,
+        }
+    }
+
+    pub fn insert_mut(&mut self, value: V, version: u64, ts: u64) {
+        self.insert_leaf_mut(LeafValue::new(value, version, ts))
+    }
+
+    pub(crate) fn insert_leaf_mut(&mut self, new_leaf_value: LeafValue<V>) {
+        // Insert new LeafValue in sorted order, after any value with the same version
+        let insertion_index = self.insertion_index(new_leaf_value.version);
+        self.values.insert(
+            insertion_index,
+            Leaf::new(new_leaf_value, self.inline_values),
+        );
 
         self.version = self.version(); // Update LeafNode's version
     }
 
-    pub fn get_latest_leaf(&self) -> Option<&Arc<LeafValue<V>>> {
-        self.values.iter().max_by_key(|value| value.version)
+    // Values are kept sorted by version, and a value written at a version the twig already
+    // holds goes after the existing ones. Ties are thereby broken the same way by every getter:
+    // of two values with the same version the last inserted one wins, and of two values with
+    // the same timestamp the one with the greater version, i.e. usually the later write, wins.
+    fn insertion_index(&self, version: u64) -> usize {
+        self.values
+            .partition_point(|value| value.version <= version)
+    }
+
+    // The values are kept sorted by version, so the latest value is the last one.
+    pub fn get_latest_leaf(&self) -> Option<&LeafValue<V>> {
+        self.values.last().map(|leaf| &**leaf)
+    }
+
+    // Returns the latest value unless the key is deleted. The getters below read the same way:
+    // a key whose selected version is a tombstone has no value.
+    pub fn get_latest_live_leaf(&self) -> Option<&LeafValue<V>> {
+        self.get_latest_leaf().filter(|leaf| !leaf.deleted)
     }
 
     pub fn get_latest_value(&self) -> Option<&V> {
-        self.values
-            .iter()
-            .max_by_key(|value| value.version)
-            .map(|value| &value.value)
+        self.get_latest_live_leaf().map(|value| &value.value)
     }
 
-    pub fn get_leaf_by_version(&self, version: u64) -> Option<Arc<LeafValue<V>>> {
-        self.values
+    pub fn get_leaf_by_version(&self, version: u64) -> Option<&LeafValue<V>> {
+        // Binary search for the first value newer than the version and step back one
+        let idx = self
+            .values
+            .partition_point(|value| value.version <= version);
+        idx.checked_sub(1)
+            .map(|idx| &*self.values[idx])
+            .filter(|leaf| !leaf.deleted)
+    }
+
+    // Returns the newest value written at or before the timestamp. Timestamps are not
+    // required to follow the version order, so this is a scan from the newest version down.
+    // A value that has expired as of the timestamp reads like a tombstone.
+    pub fn get_leaf_by_ts(&self, ts: u64) -> Option<&LeafValue<V>> {
+        self.iter()
+            .rev()
+            .find(|value| value.ts <= ts)
+            .filter(|leaf| !leaf.deleted && !leaf.is_expired(ts))
+    }
+
+    // Returns the value `get_leaf_by_ts` would return for each of the timestamps, in the order
+    // given. Both the values and the timestamps are sorted by timestamp and swept once, keeping
+    // the newest version seen so far, instead of scanning the values for every timestamp.
+    pub fn get_leafs_by_ts(&self, ts_list: &[u64]) -> Vec<Option<&LeafValue<V>>> {
+        let mut by_ts: Vec<usize> = (0..self.values.len()).collect();
+        by_ts.sort_by_key(|&idx| self.values[idx].ts);
+        let mut queries: Vec<usize> = (0..ts_list.len()).collect();
+        queries.sort_by_key(|&idx| ts_list[idx]);
+
+        let mut leafs = vec![None; ts_list.len()];
+        let mut newest: Option<usize> = None;
+        let mut next = by_ts.iter().peekable();
+        for query in queries {
+            while let Some(&&idx) = next.peek() {
+                if self.values[idx].ts > ts_list[query] {
+                    break;
+                }
+                // The values are sorted by version, so a larger index is a newer version
+                newest = newest.max(Some(idx));
+                next.next();
+            }
+            leafs[query] = newest
+                .map(|idx| &*self.values[idx])
+                .filter(|leaf| !leaf.deleted && !leaf.is_expired(ts_list[query]));
+        }
+        leafs
+    }
+
+    // Returns the number of values the heap allocation of the twig has room for, which is zero
+    // while the values are stored in the twig itself.
+    pub(crate) fn heap_capacity(&self) -> usize {
+        #[cfg(feature = "smallvec")]
+        if !self.values.spilled() {
+            return 0;
+        }
+        self.values.capacity()
+    }
+
+    // Estimates the heap memory held by the values of the twig.
+    pub(crate) fn heap_size(&self) -> usize {
+        let leaf_size = std::mem::size_of::<LeafValue<V>>() + 2 * std::mem::size_of::<usize>();
+        let shared = self
+            .values
             .iter()
-            .filter(|value| value.version <= version)
-            .max_by_key(|value| value.version)
-            .cloned()
+            .filter(|leaf| matches!(leaf, Leaf::Shared(_)))
+            .count();
+        self.heap_capacity() * std::mem::size_of::<Leaf<V>>() + shared * leaf_size
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &LeafValue<V>> {
+        self.values.iter().map(|leaf| &**leaf)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &Arc<LeafValue<V>>> {
-        self.values.iter()
+    // Returns a twig without any version if the latest value has expired as of the timestamp,
+    // which detaches the key, or None if it has not. Older versions are left to gc, as dropping
+    // an expired version alone would bring back the value it replaced.
+    pub(crate) fn purge_expired(&self, ts: u64) -> Option<TwigNode<K, V>> {
+        if !self.get_latest_leaf()?.is_expired(ts) {
+            return None;
+        }
+        Some(TwigNode {
+            prefix: self.prefix.clone(),
+            key: self.key.clone(),
+            values: Values::new(),
+            version: self.version,
+            inline_values: self.inline_values,
+        })
+    }
+
+    // Returns a twig without the versions that no read at or after the watermark can see, or
+    // None if there are none. Such a read sees the newest version written at or before the
+    // watermark, or a later one, so every older version is dropped. The version that is kept
+    // is dropped as well if it is a tombstone with nothing older left for it to hide.
+    pub fn gc(&self, watermark: u64) -> Option<TwigNode<K, V>> {
+        self.retain_visible(watermark, true)
+    }
+
+    // Returns a twig keeping only the newest version written at or before the cutoff and the
+    // versions written after it, or None if there is nothing to drop. Unlike gc, a tombstone
+    // is kept, so the key keeps its twig and reads at the cutoff still see it deleted.
+    pub fn compact_before(&self, cutoff: u64) -> Option<TwigNode<K, V>> {
+        self.retain_visible(cutoff, false)
+    }
+
+    // Returns a twig keeping only the live versions matched by the given `(value, ts)` pairs,
+    // which are a subset of the live versions in version order, or None if a pair matches none.
+    // Each pair matches the next live version with its timestamp, and the values of the pairs
+    // are only used for matching. Tombstones are kept unless no live version is left before
+    // them, in which case they have nothing to hide.
+    pub(crate) fn retain_live(&self, retained: Vec<(V, u64)>) -> Option<TwigNode<K, V>> {
+        let mut retained = retained.into_iter().peekable();
+        let mut kept = Values::new();
+        for leaf in self.values.iter() {
+            let keep = if leaf.deleted {
+                !kept.is_empty()
+            } else {
+                retained.next_if(|(_, ts)| *ts == leaf.ts).is_some()
+            };
+            if keep {
+                kept.push(leaf.clone());
+            }
+        }
+        if retained.next().is_some() {
+            return None;
+        }
+
+        let version = kept.last().map_or(self.version, |value| value.version);
+        Some(TwigNode {
+            prefix: self.prefix.clone(),
+            key: self.key.clone(),
+            values: kept,
+            version,
+            inline_values: self.inline_values,
+        })
+    }
+
+    fn retain_visible(&self, ts: u64, drop_tombstone: bool) -> Option<TwigNode<K, V>> {
+        // The values are kept sorted by version, so the newest version is the last match
+        let visible = self.values.iter().rposition(|value| value.ts <= ts);
+        let mut kept: Values<V> = self
+            .values
+            .iter()
+            .enumerate()
+            .filter(|(idx, value)| value.ts > ts || Some(*idx) == visible)
+            .map(|(_, value)| value.clone())
+            .collect();
+        if drop_tombstone
+            && kept
+                .first()
+                .is_some_and(|value| value.deleted && value.ts <= ts)
+        {
+            kept.remove(0);
+        }
+        if kept.len() == self.values.len() {
+            return None;
+        }
+
+        let version = kept.last().map_or(self.version, |value| value.version);
+        Some(TwigNode {
+            prefix: self.prefix.clone(),
+            key: self.key.clone(),
+            values: kept,
+            version,
+            inline_values: self.inline_values,
+        })
     }
 }
 
-impl<K: KeyTrait + Clone, V> Version for TwigNode<K, V> {
+impl<K: KeyTrait + Clone, V: Clone> Version for TwigNode<K, V> {
     fn version(&self) -> u64 {
         self.version
     }
+
+    fn min_version(&self) -> u64 {
+        // The values are kept sorted by version, so the oldest value is the first one.
+        self.values
+            .first()
+            .map_or(self.version, |value| value.version)
+    }
+
+    fn leaf_count(&self) -> usize {
+        // A key whose latest value is a tombstone is no longer visible.
+        match self.get_latest_leaf() {
+            Some(leaf) if !leaf.is_deleted() => 1,
+            _ => 0,
+        }
+    }
+}
+
+// Narrows the key count of a subtree to the width a FlatNode stores it in. A FlatNode cannot
+// count more than u32::MAX keys below it, which debug builds assert rather than let wrap.
+#[inline]
+fn narrow_leaf_count(count: usize) -> u32 {
+    debug_assert!(
+        u32::try_from(count).is_ok(),
+        "FlatNode subtree holds more than u32::MAX keys"
+    );
+    count as u32
 }
 
 // Source: https://www.the-paper-trail.org/post/art-paper-notes/
@@ -153,6 +513,10 @@ impl<K: KeyTrait + Clone, V> Version for TwigNode<K, V> {
 pub struct FlatNode<P: KeyTrait + Clone, N: Version, const WIDTH: usize> {
     pub(crate) prefix: P,
     pub(crate) version: u64,
+    pub(crate) min_version: u64,
+    // Kept at 32 bits so that a Node16 still fits in a cache line, see the cache_line_size
+    // test. Counts are narrowed through narrow_leaf_count, which asserts they fit.
+    pub(crate) leaf_count: u32,
     keys: [u8; WIDTH],
     children: Box<[MaybeUninit<Option<Arc<N>>>; WIDTH]>,
     num_children: u8,
@@ -169,16 +533,39 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
         Self {
             prefix,
             version: 0,
+            min_version: u64::MAX,
+            leaf_count: 0,
             keys: [0; WIDTH],
             children: Box::new(children),
             num_children: 0,
         }
     }
 
+    // Builds a node holding the given children in one pass, rather than cloning the node for
+    // every add_child. The children must be sorted by key, without duplicates.
+    pub(crate) fn from_sorted_children(prefix: P, children: &[(u8, Arc<N>)]) -> Self {
+        assert!(
+            children.len() <= WIDTH,
+            "cannot build a FlatNode of width {} with {} children",
+            WIDTH,
+            children.len()
+        );
+        debug_assert!(children.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let mut node = Self::new(prefix);
+        for (idx, (key, child)) in children.iter().enumerate() {
+            node.insert_child(idx, *key, child.clone());
+        }
+        node.update_version();
+        node
+    }
+
     fn find_pos(&self, key: u8) -> Option<usize> {
-        let idx = (0..self.num_children as usize)
-            .rev()
-            .find(|&i| key < self.keys[i]);
+        // No free slot left, the caller has to grow the node first
+        if self.num_children as usize >= WIDTH {
+            return None;
+        }
+        let idx = (0..self.num_children as usize).find(|&i| key < self.keys[i]);
         idx.or(Some(self.num_children as usize))
     }
 
@@ -188,7 +575,15 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
             .position(|&c| key == c)
     }
 
+    // Copies the children into a node of a different width. Panics if the children do not
+    // fit, rather than writing past the end of the new node.
     pub fn resize<const NEW_WIDTH: usize>(&self) -> FlatNode<P, N, NEW_WIDTH> {
+        assert!(
+            self.num_children as usize <= NEW_WIDTH,
+            "cannot resize a FlatNode with {} children to width {}",
+            self.num_children,
+            NEW_WIDTH
+        );
         let mut new_node = FlatNode::<P, N, NEW_WIDTH>::new(self.prefix.clone());
         for i in 0..self.num_children as usize {
             new_node.keys[i] = self.keys[i];
@@ -198,6 +593,7 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
         }
         new_node.version = self.version;
         new_node.num_children = self.num_children;
+        new_node.leaf_count = self.leaf_count;
         new_node.update_version();
         new_node
     }
@@ -213,6 +609,17 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
         n48
     }
 
+    pub fn grow_node256(&self) -> Node256<P, N> {
+        let mut n256 = Node256::new(self.prefix.clone());
+        for i in 0..self.num_children as usize {
+            if let Some(child) = unsafe { self.children[i].assume_init_ref().as_ref() } {
+                n256.insert_child(self.keys[i], child.clone());
+            }
+        }
+        n256.update_version();
+        n256
+    }
+
     // Helper function to insert a child node at the specified position
     #[inline]
     fn insert_child(&mut self, idx: usize, key: u8, node: Arc<N>) {
@@ -221,10 +628,18 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
             self.children[i + 1] = std::mem::replace(&mut self.children[i], MaybeUninit::new(None));
         }
         self.keys[idx] = key;
+        self.leaf_count += narrow_leaf_count(node.leaf_count());
         self.children[idx].write(Some(node));
         self.num_children += 1;
     }
 
+    #[inline]
+    fn child_leaf_count(&self, idx: usize) -> u32 {
+        unsafe { self.children[idx].assume_init_ref() }
+            .as_ref()
+            .map_or(0, |child| narrow_leaf_count(child.leaf_count()))
+    }
+
     #[inline]
     fn max_child_version(&self) -> u64 {
         self.children.iter().fold(0, |acc, x| {
@@ -236,9 +651,17 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
         })
     }
 
+    #[inline]
+    fn min_child_version(&self) -> u64 {
+        self.iter().fold(u64::MAX, |acc, (_, child)| {
+            std::cmp::min(acc, child.min_version())
+        })
+    }
+
     #[inline]
     fn update_version_to_max_child_version(&mut self) {
         self.version = self.max_child_version();
+        self.min_version = self.min_child_version();
     }
 
     #[inline]
@@ -250,6 +673,7 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
         if self.version < max_child_version {
             self.version = max_child_version;
         }
+        self.min_version = self.min_child_version();
     }
 
     #[inline]
@@ -259,6 +683,18 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
         }
     }
 
+    #[inline]
+    fn update_if_older(&mut self, new_min_version: u64) {
+        if new_min_version < self.min_version {
+            self.min_version = new_min_version;
+        }
+    }
+
+    // Estimates the heap memory held by the node itself, not counting its children.
+    pub(crate) fn heap_size(&self) -> usize {
+        WIDTH * std::mem::size_of::<MaybeUninit<Option<Arc<N>>>>()
+    }
+
     #[inline]
     pub(crate) fn iter(&self) -> impl Iterator<Item = (u8, &Arc<N>)> {
         self.keys
@@ -266,13 +702,20 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> FlatNode<P, N, WIDTH>
             .zip(self.children.iter())
             .take(self.num_children as usize)
             .filter_map(|(&k, c)| {
-                if let Some(child) = unsafe { c.assume_init_ref() } {
-                    Some((k, child))
-                } else {
-                    None
-                }
+                unsafe { c.assume_init_ref() }
+                    .as_ref()
+                    .map(|child| (k, child))
             })
     }
+
+    // Iterates over the children whose key lies between lo and hi, both included. The keys are
+    // sorted, so the bounds of the window are found by binary search.
+    pub(crate) fn iter_range(&self, lo: u8, hi: u8) -> impl Iterator<Item = (u8, &Arc<N>)> {
+        let keys = &self.keys[..self.num_children as usize];
+        let start = keys.partition_point(|&k| k < lo);
+        let end = keys.partition_point(|&k| k <= hi).max(start);
+        self.iter().skip(start).take(end - start)
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> NodeTrait<N> for FlatNode<P, N, WIDTH> {
@@ -285,14 +728,22 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> NodeTrait<N> for FlatN
         }
         new_node.num_children = self.num_children;
         new_node.version = self.version;
+        new_node.min_version = self.min_version;
+        new_node.leaf_count = self.leaf_count;
         new_node
     }
 
     fn replace_child(&self, key: u8, node: Arc<N>) -> Self {
+        let idx = self.index(key).unwrap();
+        self.replace_child_at(idx, node)
+    }
+
+    fn replace_child_at(&self, pos: usize, node: Arc<N>) -> Self {
+        debug_assert!(pos < self.num_children as usize);
         let mut new_node = self.clone();
-        let idx = new_node.index(key).unwrap();
-        new_node.keys[idx] = key;
-        new_node.children[idx] = MaybeUninit::new(Some(node));
+        new_node.leaf_count =
+            self.leaf_count - self.child_leaf_count(pos) + narrow_leaf_count(node.leaf_count());
+        new_node.children[pos] = MaybeUninit::new(Some(node));
         new_node.update_version_to_max_child_version();
 
         new_node
@@ -300,10 +751,13 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> NodeTrait<N> for FlatN
 
     fn add_child(&self, key: u8, node: N) -> Self {
         let mut new_node = self.clone();
-        let idx = self.find_pos(key).expect("node is full");
+        let idx = self
+            .find_pos(key)
+            .unwrap_or_else(|| panic!("cannot add a child to a full FlatNode of width {}", WIDTH));
 
         // Update the version if the new child has a greater version
         new_node.update_if_newer(node.version());
+        new_node.update_if_older(node.min_version());
 
         // Convert the node to Arc<N> and insert it
         new_node.insert_child(idx, key, Arc::new(node));
@@ -316,14 +770,16 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> NodeTrait<N> for FlatN
         child
     }
 
+    fn find_child_pos(&self, key: u8) -> Option<(usize, &Arc<N>)> {
+        let idx = self.index(key)?;
+        let child = unsafe { self.children[idx].assume_init_ref().as_ref() }?;
+        Some((idx, child))
+    }
+
     fn delete_child(&self, key: u8) -> Self {
         let mut new_node = self.clone();
-        let idx = self
-            .keys
-            .iter()
-            .take(self.num_children as usize)
-            .position(|&k| k == key)
-            .unwrap();
+        let idx = self.index(key).unwrap();
+        new_node.leaf_count -= self.child_leaf_count(idx);
         new_node.children[idx] = MaybeUninit::new(None);
         for i in idx..(WIDTH - 1) {
             new_node.keys[i] = self.keys[i + 1];
@@ -355,6 +811,14 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> Version for FlatNode<P
     fn version(&self) -> u64 {
         self.version
     }
+
+    fn min_version(&self) -> u64 {
+        self.min_version
+    }
+
+    fn leaf_count(&self) -> usize {
+        self.leaf_count as usize
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> Drop for FlatNode<P, N, WIDTH> {
@@ -379,6 +843,8 @@ impl<P: KeyTrait + Clone, N: Version, const WIDTH: usize> Drop for FlatNode<P, N
 pub struct Node48<P: KeyTrait + Clone, N: Version> {
     pub(crate) prefix: P,
     pub(crate) version: u64,
+    pub(crate) min_version: u64,
+    pub(crate) leaf_count: usize,
     keys: SparseVector<u8, 256>,
     children: SparseVector<Arc<N>, 48>,
     num_children: u8,
@@ -389,17 +855,32 @@ impl<P: KeyTrait + Clone, N: Version> Node48<P, N> {
         Self {
             prefix,
             version: 0,
+            min_version: u64::MAX,
+            leaf_count: 0,
             keys: SparseVector::new(),
             children: SparseVector::new(),
             num_children: 0,
         }
     }
 
+    // Builds a node holding the given children in one pass, rather than cloning the node for
+    // every add_child. The children must not share a key.
+    pub(crate) fn from_children(prefix: P, children: &[(u8, Arc<N>)]) -> Self {
+        let mut node = Self::new(prefix);
+        for (key, child) in children {
+            debug_assert!(node.keys.get(*key as usize).is_none());
+            node.insert_child(*key, child.clone());
+        }
+        node.update_version();
+        node
+    }
+
     pub fn insert_child(&mut self, key: u8, node: Arc<N>) {
         let pos = self.children.first_free_pos();
         assert!(pos < 48);
 
         self.keys.set(key as usize, pos as u8);
+        self.leaf_count += node.leaf_count();
         self.children.set(pos, node);
         self.num_children += 1;
     }
@@ -432,9 +913,17 @@ impl<P: KeyTrait + Clone, N: Version> Node48<P, N> {
             .fold(0, |acc, x| std::cmp::max(acc, x.1.version()))
     }
 
+    #[inline]
+    fn min_child_version(&self) -> u64 {
+        self.children
+            .iter()
+            .fold(u64::MAX, |acc, x| std::cmp::min(acc, x.1.min_version()))
+    }
+
     #[inline]
     fn update_version_to_max_child_version(&mut self) {
         self.version = self.max_child_version();
+        self.min_version = self.min_child_version();
     }
 
     #[inline]
@@ -446,6 +935,7 @@ impl<P: KeyTrait + Clone, N: Version> Node48<P, N> {
         if self.version < max_child_version {
             self.version = max_child_version;
         }
+        self.min_version = self.min_child_version();
     }
 
     #[inline]
@@ -455,11 +945,41 @@ impl<P: KeyTrait + Clone, N: Version> Node48<P, N> {
         }
     }
 
+    #[inline]
+    fn update_if_older(&mut self, new_min_version: u64) {
+        if new_min_version < self.min_version {
+            self.min_version = new_min_version;
+        }
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (u8, &Arc<N>)> {
         self.keys
             .iter()
             .map(move |(key, pos)| (key as u8, self.children.get(*pos as usize).unwrap()))
     }
+
+    // Iterates over the children whose key lies between lo and hi, both included.
+    pub fn iter_range(&self, lo: u8, hi: u8) -> impl Iterator<Item = (u8, &Arc<N>)> {
+        self.keys
+            .iter_range(lo as usize, hi as usize)
+            .map(move |(key, pos)| (key as u8, self.children.get(*pos as usize).unwrap()))
+    }
+}
+
+impl<P: KeyTrait + Clone, N: Version> Node48<P, N> {
+    // Releases the unused tail of the backing stores, returning whether any memory was freed.
+    pub(crate) fn shrink_storage(&mut self) -> bool {
+        let before = self.keys.capacity() + self.children.capacity();
+        self.keys.shrink_to_fit();
+        self.children.shrink_to_fit();
+        self.keys.capacity() + self.children.capacity() < before
+    }
+
+    // Estimates the heap memory held by the node itself, not counting its children.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.keys.capacity() * std::mem::size_of::<Option<u8>>()
+            + self.children.capacity() * std::mem::size_of::<Option<Arc<N>>>()
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
@@ -467,6 +987,8 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
         Node48 {
             prefix: self.prefix.clone(),
             version: self.version,
+            min_version: self.min_version,
+            leaf_count: self.leaf_count,
             keys: self.keys.clone(),
             children: self.children.clone(),
             num_children: self.num_children,
@@ -474,9 +996,15 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
     }
 
     fn replace_child(&self, key: u8, node: Arc<N>) -> Self {
+        let idx = self.keys.get(key as usize).unwrap();
+        self.replace_child_at(*idx as usize, node)
+    }
+
+    fn replace_child_at(&self, pos: usize, node: Arc<N>) -> Self {
         let mut new_node = self.clone();
-        let idx = new_node.keys.get(key as usize).unwrap();
-        new_node.children.set(*idx as usize, node);
+        let old_count = self.children.get(pos).map_or(0, |child| child.leaf_count());
+        new_node.leaf_count = self.leaf_count - old_count + node.leaf_count();
+        new_node.children.set(pos, node);
         new_node.update_version_to_max_child_version();
 
         new_node
@@ -487,6 +1015,7 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
 
         // Update the version if the new child has a greater version
         new_node.update_if_newer(node.version());
+        new_node.update_if_older(node.min_version());
 
         new_node.insert_child(key, Arc::new(node));
         new_node
@@ -496,9 +1025,17 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
         let pos = self.keys.get(key as usize).unwrap();
         let mut new_node = self.clone();
         new_node.keys.erase(key as usize);
-        new_node.children.erase(*pos as usize);
+        if let Some(child) = new_node.children.erase(*pos as usize) {
+            new_node.leaf_count -= child.leaf_count();
+        }
         new_node.num_children -= 1;
 
+        // Reclaim the trailing free slots once most of the children are gone
+        if new_node.children.used_len() * 2 < new_node.children.capacity() {
+            new_node.keys.shrink_to_fit();
+            new_node.children.shrink_to_fit();
+        }
+
         new_node.update_version_to_max_child_version();
         new_node
     }
@@ -509,6 +1046,12 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node48<P, N> {
         Some(child)
     }
 
+    fn find_child_pos(&self, key: u8) -> Option<(usize, &Arc<N>)> {
+        let idx = *self.keys.get(key as usize)? as usize;
+        let child = self.children.get(idx)?;
+        Some((idx, child))
+    }
+
     fn num_children(&self) -> usize {
         self.num_children as usize
     }
@@ -523,6 +1066,14 @@ impl<P: KeyTrait + Clone, N: Version> Version for Node48<P, N> {
     fn version(&self) -> u64 {
         self.version
     }
+
+    fn min_version(&self) -> u64 {
+        self.min_version
+    }
+
+    fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version> Drop for Node48<P, N> {
@@ -543,8 +1094,10 @@ impl<P: KeyTrait + Clone, N: Version> Drop for Node48<P, N> {
 // A Node256 is a 256-entry array of pointers to children. The pointers are stored in
 // a Vector Array, which is a Vector of length WIDTH (256) that stores the pointers.
 pub struct Node256<P: KeyTrait + Clone, N: Version> {
-    pub(crate) prefix: P,    // Prefix associated with the node
-    pub(crate) version: u64, // Version for node256
+    pub(crate) prefix: P,         // Prefix associated with the node
+    pub(crate) version: u64,      // Version for node256
+    pub(crate) min_version: u64,  // Oldest version below node256
+    pub(crate) leaf_count: usize, // Live keys below node256
 
     children: SparseVector<Arc<N>, 256>,
     num_children: usize,
@@ -555,11 +1108,25 @@ impl<P: KeyTrait + Clone, N: Version> Node256<P, N> {
         Self {
             prefix,
             version: 0,
+            min_version: u64::MAX,
+            leaf_count: 0,
             children: SparseVector::new(),
             num_children: 0,
         }
     }
 
+    // Builds a node holding the given children in one pass, rather than cloning the node for
+    // every add_child. The children must not share a key.
+    pub(crate) fn from_children(prefix: P, children: &[(u8, Arc<N>)]) -> Self {
+        let mut node = Self::new(prefix);
+        for (key, child) in children {
+            debug_assert!(node.children.get(*key as usize).is_none());
+            node.insert_child(*key, child.clone());
+        }
+        node.update_version();
+        node
+    }
+
     pub fn shrink(&self) -> Node48<P, N> {
         let mut indexed = Node48::new(self.prefix.clone());
         let keys: Vec<usize> = self.children.iter_keys().collect();
@@ -571,8 +1138,20 @@ impl<P: KeyTrait + Clone, N: Version> Node256<P, N> {
         indexed
     }
 
+    pub fn shrink_flat<const NEW_WIDTH: usize>(&self) -> FlatNode<P, N, NEW_WIDTH> {
+        let mut fnode = FlatNode::new(self.prefix.clone());
+        for key in self.children.iter_keys() {
+            let child = self.children.get(key).unwrap().clone();
+            let idx = fnode.find_pos(key as u8).expect("node is full");
+            fnode.insert_child(idx, key as u8, child);
+        }
+        fnode.update_version();
+        fnode
+    }
+
     #[inline]
     fn insert_child(&mut self, key: u8, node: Arc<N>) {
+        self.leaf_count += node.leaf_count();
         self.children.set(key as usize, node);
         self.num_children += 1;
     }
@@ -584,9 +1163,17 @@ impl<P: KeyTrait + Clone, N: Version> Node256<P, N> {
             .fold(0, |acc, x| std::cmp::max(acc, x.1.version()))
     }
 
+    #[inline]
+    fn min_child_version(&self) -> u64 {
+        self.children
+            .iter()
+            .fold(u64::MAX, |acc, x| std::cmp::min(acc, x.1.min_version()))
+    }
+
     #[inline]
     fn update_version_to_max_child_version(&mut self) {
         self.version = self.max_child_version();
+        self.min_version = self.min_child_version();
     }
 
     #[inline]
@@ -598,6 +1185,7 @@ impl<P: KeyTrait + Clone, N: Version> Node256<P, N> {
         if self.version < max_child_version {
             self.version = max_child_version;
         }
+        self.min_version = self.min_child_version();
     }
 
     #[inline]
@@ -607,9 +1195,37 @@ impl<P: KeyTrait + Clone, N: Version> Node256<P, N> {
         }
     }
 
+    #[inline]
+    fn update_if_older(&mut self, new_min_version: u64) {
+        if new_min_version < self.min_version {
+            self.min_version = new_min_version;
+        }
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (u8, &Arc<N>)> {
         self.children.iter().map(|(key, node)| (key as u8, node))
     }
+
+    // Iterates over the children whose key lies between lo and hi, both included.
+    pub fn iter_range(&self, lo: u8, hi: u8) -> impl Iterator<Item = (u8, &Arc<N>)> {
+        self.children
+            .iter_range(lo as usize, hi as usize)
+            .map(|(key, node)| (key as u8, node))
+    }
+}
+
+impl<P: KeyTrait + Clone, N: Version> Node256<P, N> {
+    // Releases the unused tail of the backing store, returning whether any memory was freed.
+    pub(crate) fn shrink_storage(&mut self) -> bool {
+        let before = self.children.capacity();
+        self.children.shrink_to_fit();
+        self.children.capacity() < before
+    }
+
+    // Estimates the heap memory held by the node itself, not counting its children.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.children.capacity() * std::mem::size_of::<Option<Arc<N>>>()
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node256<P, N> {
@@ -617,15 +1233,23 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node256<P, N> {
         Self {
             prefix: self.prefix.clone(),
             version: self.version,
+            min_version: self.min_version,
+            leaf_count: self.leaf_count,
             children: self.children.clone(),
             num_children: self.num_children,
         }
     }
 
     fn replace_child(&self, key: u8, node: Arc<N>) -> Self {
+        self.replace_child_at(key as usize, node)
+    }
+
+    fn replace_child_at(&self, pos: usize, node: Arc<N>) -> Self {
         let mut new_node = self.clone();
 
-        new_node.children.set(key as usize, node);
+        let old_count = self.children.get(pos).map_or(0, |child| child.leaf_count());
+        new_node.leaf_count = self.leaf_count - old_count + node.leaf_count();
+        new_node.children.set(pos, node);
         new_node.update_version_to_max_child_version();
         new_node
     }
@@ -636,6 +1260,7 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node256<P, N> {
 
         // Update the version if the new child has a greater version
         new_node.update_if_newer(node.version());
+        new_node.update_if_older(node.min_version());
 
         new_node.insert_child(key, Arc::new(node));
         new_node
@@ -647,13 +1272,25 @@ impl<P: KeyTrait + Clone, N: Version> NodeTrait<N> for Node256<P, N> {
         Some(child)
     }
 
+    #[inline]
+    fn find_child_pos(&self, key: u8) -> Option<(usize, &Arc<N>)> {
+        let child = self.children.get(key as usize)?;
+        Some((key as usize, child))
+    }
+
     #[inline]
     fn delete_child(&self, key: u8) -> Self {
         let mut new_node = self.clone();
         let removed = new_node.children.erase(key as usize);
-        if removed.is_some() {
+        if let Some(child) = removed {
+            new_node.leaf_count -= child.leaf_count();
             new_node.num_children -= 1;
         }
+
+        // Reclaim the trailing free slots once most of the children are gone
+        if new_node.num_children * 2 < new_node.children.capacity() {
+            new_node.children.shrink_to_fit();
+        }
         new_node.update_version_to_max_child_version();
         new_node
     }
@@ -673,6 +1310,14 @@ impl<P: KeyTrait + Clone, N: Version> Version for Node256<P, N> {
     fn version(&self) -> u64 {
         self.version
     }
+
+    fn min_version(&self) -> u64 {
+        self.min_version
+    }
+
+    fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
 }
 
 impl<P: KeyTrait + Clone, N: Version> Drop for Node256<P, N> {
@@ -686,7 +1331,7 @@ impl<P: KeyTrait + Clone, N: Version> Drop for Node256<P, N> {
 mod tests {
     use crate::FixedKey;
 
-    use super::{FlatNode, Node256, Node48, NodeTrait, TwigNode, Version};
+    use super::{FlatNode, Leaf, Node256, Node48, NodeTrait, TwigNode, Version};
     use std::mem::MaybeUninit;
     use std::sync::Arc;
 
@@ -804,6 +1449,34 @@ mod tests {
         assert_eq!(node.num_children(), 0);
     }
 
+    #[test]
+    fn flatnode_keys_sorted() {
+        let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+
+        // Children added in descending and interleaved order are still iterated in key order
+        let mut node = FlatNode::<FixedKey<8>, usize, 16>::new(dummy_prefix);
+        for i in [9u8, 7, 5, 3, 1, 8, 2, 6, 4, 0] {
+            node = node.add_child(i, i as usize);
+        }
+        let keys: Vec<u8> = node.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, (0..10).collect::<Vec<u8>>());
+
+        // The keys stay sorted, and every child findable, as children come and go
+        for i in [0u8, 5, 9] {
+            node = node.delete_child(i);
+        }
+        for i in [200u8, 42, 100, 13, 255] {
+            node = node.add_child(i, i as usize);
+        }
+        let keys: Vec<u8> = node.iter().map(|(k, _)| k).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(keys.len(), 12);
+        for key in 0..=u8::MAX {
+            let expected = keys.contains(&key).then(|| Arc::new(key as usize));
+            assert_eq!(node.find_child(key), expected.as_ref());
+        }
+    }
+
     #[test]
     fn node48() {
         let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
@@ -863,6 +1536,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn node48_shrink_storage() {
+        let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+
+        let mut node = Node48::<FixedKey<8>, u8>::new(dummy_prefix);
+        for i in 0..48 {
+            node = node.add_child(i, i);
+        }
+        assert!(node.children.capacity() >= 48);
+
+        // Heavy deletions release the unused tail of the backing stores
+        for i in (8..48).rev() {
+            node = node.delete_child(i);
+        }
+        assert_eq!(node.children.used_len(), 8);
+        assert!(node.children.capacity() < 48);
+
+        // Inserting afterwards regrows the storage on demand
+        for i in 100..140 {
+            node = node.add_child(i, i);
+        }
+        assert_eq!(node.num_children(), 48);
+        for i in (0..8).chain(100..140) {
+            assert_eq!(node.find_child(i), Some(&i.into()));
+        }
+    }
+
     #[test]
     fn node256() {
         let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
@@ -893,6 +1593,79 @@ mod tests {
         }
     }
 
+    fn find_child_pos_test<N: NodeTrait<usize>>(mut node: N, size: usize) {
+        // Leave a hole so positions and keys stop lining up in the sparse nodes
+        for i in 0..size {
+            node = node.add_child((i * 3) as u8, i);
+        }
+        node = node.delete_child(0);
+
+        for i in 1..size {
+            let key = (i * 3) as u8;
+            let (pos, child) = node.find_child_pos(key).unwrap();
+            assert_eq!(**child, i);
+
+            let replaced = node.replace_child_at(pos, Arc::new(i + 1000));
+            assert_eq!(replaced.num_children(), size - 1);
+            assert_eq!(replaced.find_child(key), Some(&Arc::new(i + 1000)));
+            assert_eq!(
+                replaced.find_child_pos(key).map(|(p, _)| p),
+                Some(pos),
+                "slot moved for key {}",
+                key
+            );
+            for j in (1..size).filter(|&j| j != i) {
+                assert_eq!(replaced.find_child((j * 3) as u8), Some(&Arc::new(j)));
+            }
+        }
+        assert!(node.find_child_pos(0).is_none());
+        assert!(node.find_child_pos(1).is_none());
+    }
+
+    #[test]
+    fn find_child_pos_round_trip() {
+        let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+
+        find_child_pos_test(
+            FlatNode::<FixedKey<8>, usize, 4>::new(dummy_prefix.clone()),
+            4,
+        );
+        find_child_pos_test(
+            FlatNode::<FixedKey<8>, usize, 8>::new(dummy_prefix.clone()),
+            8,
+        );
+        find_child_pos_test(
+            FlatNode::<FixedKey<8>, usize, 16>::new(dummy_prefix.clone()),
+            16,
+        );
+        find_child_pos_test(Node48::<FixedKey<8>, usize>::new(dummy_prefix.clone()), 48);
+        find_child_pos_test(Node256::<FixedKey<8>, usize>::new(dummy_prefix), 80);
+    }
+
+    #[test]
+    #[should_panic(expected = "full FlatNode of width 4")]
+    fn flatnode_add_child_when_full() {
+        let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+
+        let mut node = FlatNode::<FixedKey<8>, usize, 4>::new(dummy_prefix);
+        for i in 0..4 {
+            node = node.add_child(i as u8, i);
+        }
+        let _ = node.add_child(4, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot resize a FlatNode with 5 children to width 4")]
+    fn flatnode_resize_too_small() {
+        let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+
+        let mut node = FlatNode::<FixedKey<8>, usize, 16>::new(dummy_prefix);
+        for i in 0..5 {
+            node = node.add_child(i as u8, i);
+        }
+        let _: FlatNode<FixedKey<8>, usize, 4> = node.resize();
+    }
+
     #[test]
     fn flatnode_update_version() {
         const WIDTH: usize = 4;
@@ -911,6 +1684,8 @@ mod tests {
         let mut parent = FlatNode {
             prefix: dummy_prefix.clone(),
             version: 6,
+            min_version: u64::MAX,
+            leaf_count: 0,
             keys: [0; WIDTH],
             children: Box::new([
                 MaybeUninit::new(Some(Arc::new(child1))),
@@ -952,6 +1727,8 @@ mod tests {
         let mut parent: FlatNode<FixedKey<8>, FlatNode<FixedKey<8>, usize, 1>, 1> = FlatNode {
             prefix: dummy_prefix,
             version: 6,
+            min_version: u64::MAX,
+            leaf_count: 0,
             keys: [0; WIDTH],
             children: Box::new([MaybeUninit::new(Some(Arc::new(child)))]),
             num_children: 1,
@@ -1021,7 +1798,6 @@ mod tests {
         assert_eq!(parent.version(), (WIDTH - 1) as u64);
     }
 
-    // TODO: add more scenarios to this as twig nodes have the actual data with versions
     #[test]
     fn twig_nodes() {
         const WIDTH: usize = 4;
@@ -1044,6 +1820,8 @@ mod tests {
         let mut parent = FlatNode {
             prefix: dummy_prefix,
             version: 0,
+            min_version: u64::MAX,
+            leaf_count: 0,
             keys: [0; WIDTH],
             children: Box::new([
                 MaybeUninit::new(Some(Arc::new(twig1))),
@@ -1072,6 +1850,58 @@ mod tests {
         assert_eq!(new_node.values[0].version, 123);
     }
 
+    #[test]
+    fn twig_version_lookups() {
+        for key in ["a", "foo", "foobar"] {
+            let prefix: FixedKey<8> = FixedKey::create_key(key.as_bytes());
+            let mut node = TwigNode::<FixedKey<8>, u64>::new(prefix.clone(), prefix);
+            assert!(node.get_latest_leaf().is_none());
+            assert!(node.get_leaf_by_version(10).is_none());
+
+            // Insert even versions out of order
+            for i in 0..200u64 {
+                let version = (i * 37 % 200) * 2 + 2;
+                node.insert_mut(version * 10, version, i);
+            }
+            assert_eq!(node.version(), 400);
+            assert_eq!(node.get_latest_value(), Some(&4000));
+            assert_eq!(node.get_latest_leaf().unwrap().version, 400);
+
+            for version in 0..410u64 {
+                let expected = node
+                    .values
+                    .iter()
+                    .filter(|value| value.version <= version)
+                    .max_by_key(|value| value.version)
+                    .map(|value| value.value);
+                let leaf = node.get_leaf_by_version(version);
+                assert_eq!(leaf.map(|leaf| leaf.value), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn twig_tie_break() {
+        let key: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+        let mut node = TwigNode::<FixedKey<8>, u64>::new(key.clone(), key);
+        node.insert_mut(1, 1, 10);
+        node.insert_mut(2, 2, 10);
+        // Written later at the same version and timestamp as the two values before
+        for value in 3..=5 {
+            node.insert_mut(value, 2, 10);
+        }
+        let copied = node.insert(6, 1, 10);
+
+        // The last value inserted at a version wins, and at a timestamp the greatest version
+        for twig in [&node, &copied] {
+            assert_eq!(twig.get_latest_value(), Some(&5));
+            assert_eq!(twig.get_leaf_by_version(2).unwrap().value, 5);
+            assert_eq!(twig.get_leaf_by_ts(10).unwrap().value, 5);
+            assert_eq!(twig.get_leafs_by_ts(&[10])[0].unwrap().value, 5);
+        }
+        assert_eq!(copied.get_leaf_by_version(1).unwrap().value, 6);
+    }
+
     #[test]
     fn twig_insert_mut() {
         let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
@@ -1116,6 +1946,95 @@ mod tests {
         assert_eq!(leaf_by_ts.unwrap().value, 43);
     }
 
+    #[test]
+    fn twig_updates_are_copy_on_write() {
+        let key: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+        let node = TwigNode::<FixedKey<8>, usize>::new(key.clone(), key.clone());
+
+        // Every update returns a new twig, leaving the one it was made from untouched
+        let v1 = node.insert(1, 1, 10);
+        let v2 = v1.insert(2, 2, 20);
+        let v3 = v2.insert(3, 3, 30);
+        assert_eq!(v1.values.len(), 1);
+        assert_eq!(v2.values.len(), 2);
+        assert_eq!(v3.values.len(), 3);
+        assert_eq!(v1.get_latest_value(), Some(&1));
+        assert_eq!(v2.get_latest_value(), Some(&2));
+        assert_eq!(v3.get_latest_value(), Some(&3));
+        assert!(v3
+            .values
+            .iter()
+            .zip(&v2.values)
+            .all(|pair| matches!(pair, (Leaf::Shared(a), Leaf::Shared(b)) if Arc::ptr_eq(a, b))));
+
+        // A twig only ever holds the versions of its own key
+        assert_eq!(v3.key, key);
+        assert!(v3.iter().all(|leaf| leaf.value == leaf.version as usize));
+
+        // Replacing drops the history
+        let replaced = v3.replace(4, 4, 40);
+        assert_eq!(replaced.values.len(), 1);
+        assert_eq!(replaced.version(), 4);
+        assert!(replaced.get_leaf_by_version(3).is_none());
+        assert_eq!(v3.get_leaf_by_version(3).unwrap().value, 3);
+    }
+
+    #[test]
+    fn twig_inline_values() {
+        let key: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+        let node = TwigNode::<FixedKey<8>, usize>::new(key.clone(), key).with_inline_values(true);
+
+        // Inline twigs hold their values directly and read like shared ones
+        let v1 = node.insert(1, 1, 10);
+        let v3 = v1.insert(3, 3, 30).insert(2, 2, 20);
+        assert!(v3.values.iter().all(|leaf| matches!(leaf, Leaf::Inline(_))));
+        assert_eq!(v1.values.len(), 1);
+        assert_eq!(v3.get_latest_value(), Some(&3));
+        assert_eq!(v3.get_leaf_by_version(2).map(|leaf| leaf.value), Some(2));
+        assert_eq!(v3.get_leaf_by_ts(25).map(|leaf| leaf.value), Some(2));
+        let versions: Vec<u64> = v3.iter().map(|leaf| leaf.version).collect();
+        assert_eq!(versions, vec![1, 2, 3]);
+
+        let (v2, removed) = v3.remove_version(3).unwrap();
+        assert_eq!(removed.value, 3);
+        assert_eq!(v2.version, 2);
+        assert!(v2.replace(9, 9, 90).inline_values);
+        assert_eq!(v3.delete(4, 40).unwrap().get_latest_value(), None);
+    }
+
+    #[test]
+    fn twig_get_leaf_by_ts() {
+        let key: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+        let mut node = TwigNode::<FixedKey<8>, usize>::new(key.clone(), key);
+        assert!(node.get_leaf_by_ts(u64::MAX).is_none());
+        assert_eq!(node.latest_version_ts(), 0);
+
+        // Timestamps need not follow the version order
+        node.insert_mut(1, 1, 10);
+        node.insert_mut(2, 2, 30);
+        node.insert_mut(3, 3, 20);
+
+        let value_at = |ts| node.get_leaf_by_ts(ts).map(|leaf| leaf.value);
+        assert_eq!(value_at(9), None);
+        assert_eq!(value_at(10), Some(1));
+        assert_eq!(value_at(19), Some(1));
+        // The newest version written by the timestamp wins
+        assert_eq!(value_at(20), Some(3));
+        assert_eq!(value_at(30), Some(3));
+        assert_eq!(value_at(u64::MAX), Some(3));
+        let all: Vec<_> = node
+            .get_leafs_by_ts(&[30, 9, 20, 10])
+            .into_iter()
+            .map(|leaf| leaf.map(|leaf| leaf.value))
+            .collect();
+        assert_eq!(all, vec![Some(3), None, Some(3), Some(1)]);
+
+        // The twig's version and timestamp are those of its latest version, not the largest
+        // timestamp it holds
+        assert_eq!(node.version(), 3);
+        assert_eq!(node.latest_version_ts(), 20);
+    }
+
     #[test]
     fn twig_iter() {
         let dummy_prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
@@ -1168,4 +2087,117 @@ mod tests {
         assert!(std::mem::size_of::<FlatNode::<FixedKey<8>, usize, 4>>() <= 64);
         assert!(std::mem::size_of::<FlatNode::<FixedKey<8>, usize, 16>>() <= 64);
     }
+
+    fn from_children_test<N: NodeTrait<usize> + Version>(built: N, mut added: N, size: usize) {
+        for i in 0..size {
+            added = added.add_child(i as u8 * 3, 10 + i);
+        }
+
+        assert_eq!(built.num_children(), size);
+        assert_eq!(built.version(), added.version());
+        assert_eq!(built.min_version(), added.min_version());
+        assert_eq!(built.version(), 10 + size as u64 - 1);
+        assert_eq!(built.min_version(), 10);
+        assert_eq!(built.leaf_count(), size);
+        for i in 0..size {
+            assert!(matches!(built.find_child(i as u8 * 3), Some(v) if **v == 10 + i));
+            assert!(built.find_child(i as u8 * 3 + 1).is_none());
+        }
+    }
+
+    #[test]
+    fn from_sorted_children() {
+        let prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+        let children = |size: usize| -> Vec<(u8, Arc<usize>)> {
+            (0..size).map(|i| (i as u8 * 3, Arc::new(10 + i))).collect()
+        };
+
+        from_children_test(
+            FlatNode::<FixedKey<8>, usize, 4>::from_sorted_children(prefix.clone(), &children(3)),
+            FlatNode::new(prefix.clone()),
+            3,
+        );
+        from_children_test(
+            FlatNode::<FixedKey<8>, usize, 16>::from_sorted_children(prefix.clone(), &children(16)),
+            FlatNode::new(prefix.clone()),
+            16,
+        );
+        from_children_test(
+            Node48::<FixedKey<8>, usize>::from_children(prefix.clone(), &children(40)),
+            Node48::new(prefix.clone()),
+            40,
+        );
+        from_children_test(
+            Node256::<FixedKey<8>, usize>::from_children(prefix.clone(), &children(85)),
+            Node256::new(prefix),
+            85,
+        );
+    }
+
+    #[test]
+    fn iter_range() {
+        let prefix: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+        let keys = |iter: &mut dyn Iterator<Item = (u8, &Arc<usize>)>| -> Vec<u8> {
+            iter.map(|(key, child)| {
+                assert_eq!(**child, key as usize);
+                key
+            })
+            .collect()
+        };
+
+        let mut n256 = Node256::<FixedKey<8>, usize>::new(prefix.clone());
+        for i in 0..=255u8 {
+            n256 = n256.add_child(i, i as usize);
+        }
+        assert_eq!(
+            keys(&mut n256.iter_range(10, 20)),
+            (10..=20).collect::<Vec<_>>()
+        );
+        assert_eq!(keys(&mut n256.iter_range(0, 255)).len(), 256);
+        assert_eq!(keys(&mut n256.iter_range(255, 255)), [255]);
+        assert!(keys(&mut n256.iter_range(20, 10)).is_empty());
+
+        // Every third key, so that the bounds fall both on and between keys
+        let mut n48 = Node48::<FixedKey<8>, usize>::new(prefix.clone());
+        let mut n16 = FlatNode::<FixedKey<8>, usize, 16>::new(prefix);
+        for i in 0..16u8 {
+            n48 = n48.add_child(i * 3, i as usize * 3);
+            n16 = n16.add_child(i * 3, i as usize * 3);
+        }
+        for (lo, hi) in [(10, 20), (9, 21), (0, 255), (46, 255), (20, 10), (50, 60)] {
+            let expected: Vec<u8> = (0..16u8)
+                .map(|i| i * 3)
+                .filter(|key| (lo..=hi).contains(key))
+                .collect();
+            assert_eq!(keys(&mut n48.iter_range(lo, hi)), expected);
+            assert_eq!(keys(&mut n16.iter_range(lo, hi)), expected);
+        }
+    }
+
+    #[test]
+    fn twig_values_spill() {
+        // Twigs read the same whether their values fit in the twig or spilled to the heap
+        let key: FixedKey<8> = FixedKey::create_key("foo".as_bytes());
+        let mut node = TwigNode::<FixedKey<8>, usize>::new(key.clone(), key);
+        for (count, version) in [4u64, 1, 3, 2, 5].into_iter().enumerate() {
+            node = node.insert(version as usize, version, version * 10);
+            assert_eq!(node.values.len(), count + 1);
+            #[cfg(feature = "smallvec")]
+            assert_eq!(node.heap_capacity() == 0, count < 2);
+        }
+        let versions: Vec<u64> = node.iter().map(|leaf| leaf.version).collect();
+        assert_eq!(versions, vec![1, 2, 3, 4, 5]);
+        assert_eq!(node.get_leaf_by_version(3).map(|leaf| leaf.value), Some(3));
+        assert_eq!(node.get_leaf_by_ts(45).map(|leaf| leaf.value), Some(4));
+
+        // Shrinking below the inline capacity again keeps every remaining version readable
+        let (node, removed) = node.remove_version(5).unwrap();
+        assert_eq!(removed.value, 5);
+        let node = node.gc(30).unwrap();
+        let versions: Vec<u64> = node.iter().map(|leaf| leaf.version).collect();
+        assert_eq!(versions, vec![3, 4]);
+        assert_eq!(node.get_latest_value(), Some(&4));
+        assert_eq!(node.get_leaf_by_ts(35).map(|leaf| leaf.value), Some(3));
+        assert_eq!(node.replace(9, 9, 90).values.len(), 1);
+    }
 }
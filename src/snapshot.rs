@@ -1,46 +1,71 @@
 //! This module defines the Snapshot struct for managing snapshots within a Trie structure.
-use std::cell::Cell;
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use hashbrown::HashSet;
 
-use crate::art::{Node, TrieError};
-use crate::iter::IterationPointer;
-use crate::node::Version;
-use crate::KeyTrait;
+use crate::art::{Node, TreeOptions, TrieError};
+use crate::iter::{IterationPointer, Range};
+use crate::node::{LeafValue, Version};
+use crate::{KeyTrait, Ts, VersionId};
 
 /// Represents a snapshot of the data within the Trie.
+///
+/// Like `Tree`, a snapshot is `Send` and `Sync` whenever its keys and values are, and the
+/// readers it hands out can be moved to other threads.
 pub struct Snapshot<P: KeyTrait, V: Clone> {
+    #[allow(dead_code)]
     pub(crate) id: u64,
     pub(crate) ts: u64,
     pub(crate) root: Option<Arc<Node<P, V>>>,
-    pub(crate) readers: HashSet<u64>,
-    pub(crate) max_active_readers: Cell<u64>,
+    /// The IDs of the open readers, shared with the readers so they deregister when dropped.
+    pub(crate) readers: Arc<Mutex<HashSet<u64>>>,
+    /// The ID of the last reader handed out. IDs are never reused.
+    pub(crate) last_reader_id: u64,
     pub(crate) closed: bool,
+    pub(crate) options: TreeOptions,
 }
 
 impl<P: KeyTrait, V: Clone> Snapshot<P, V> {
     /// Creates a new Snapshot instance with the provided snapshot_id and root node.
-    pub(crate) fn new(id: u64, root: Option<Arc<Node<P, V>>>, ts: u64) -> Self {
+    pub(crate) fn new(
+        id: u64,
+        root: Option<Arc<Node<P, V>>>,
+        ts: u64,
+        options: TreeOptions,
+    ) -> Self {
         Snapshot {
             id,
             ts,
             root,
-            readers: HashSet::new(),
-            max_active_readers: Cell::new(0),
+            readers: Arc::new(Mutex::new(HashSet::new())),
+            last_reader_id: 0,
             closed: false,
+            options,
         }
     }
 
     /// Inserts a key-value pair into the snapshot.
-    pub fn insert(&mut self, key: &P, value: V, ts: u64) -> Result<(), TrieError> {
+    pub fn insert(&mut self, key: &P, value: V, ts: impl Into<Ts>) -> Result<(), TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
+        let ts = ts.into().0;
+
+        self.options.check_key(key)?;
+        let key = self.options.encode_key(Cow::Borrowed(key));
+
         // Insert the key-value pair into the root node using a recursive function
         match &self.root {
             Some(root) => {
-                let (new_node, _) = match Node::insert_recurse(root, key, value, self.ts, ts, 0) {
+                let (new_node, _) = match Node::insert_recurse(
+                    root,
+                    key,
+                    LeafValue::new(value, self.ts, ts),
+                    0,
+                    &self.options,
+                ) {
                     Ok((new_node, old_node)) => (new_node, old_node),
                     Err(err) => {
                         return Err(err);
@@ -54,9 +79,8 @@ impl<P: KeyTrait, V: Clone> Snapshot<P, V> {
                 self.root = Some(Arc::new(Node::new_twig(
                     key.as_slice().into(),
                     key.as_slice().into(),
-                    value,
-                    self.ts,
-                    ts,
+                    LeafValue::new(value, self.ts, ts),
+                    &self.options,
                 )))
             }
         };
@@ -64,19 +88,44 @@ impl<P: KeyTrait, V: Clone> Snapshot<P, V> {
         Ok(())
     }
 
-    /// Retrieves the value and timestamp associated with the given key from the snapshot.
-    pub fn get(&self, key: &P) -> Result<(V, u64, u64), TrieError> {
+    /// Retrieves the value, version and timestamp associated with the given key from the snapshot.
+    ///
+    /// Reads are served from the root pinned when the snapshot was taken, so they are unaffected
+    /// by later writes to the tree. A `version` of `0` reads the latest value in the snapshot,
+    /// otherwise the latest value at or before `version` is returned.
+    pub fn get(&self, key: &P, version: impl Into<VersionId>) -> Result<(V, u64, u64), TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
+        let version = version.into().0;
+
         // Use a recursive function to get the value and timestamp from the root node
         match self.root.as_ref() {
-            Some(root) => Node::get_recurse(root, key, root.version())
-                .map(|(_, value, version, ts)| (value, version, ts)),
+            Some(root) => {
+                let version = if version == 0 {
+                    root.version()
+                } else {
+                    version
+                };
+                let key = self.options.encode_key(Cow::Borrowed(key));
+                Node::get_recurse(root, &key, version)
+                    .map(|(_, value, version, ts)| (value, version, ts))
+            }
             None => Err(TrieError::KeyNotFound),
         }
     }
 
+    /// Returns an iterator over a range of key-value pairs in the snapshot.
+    ///
+    /// Like `get`, the iterator reads from the snapshot's pinned root and gives a consistent view
+    /// independent of later writes to the tree.
+    pub fn range<'a, R>(&'a self, range: R) -> Range<'a, P, V, R>
+    where
+        R: RangeBounds<P> + 'a,
+    {
+        Range::new(self.root.as_ref(), range).decode_keys(self.options.byte_permutation.clone())
+    }
+
     /// Returns the version of the snapshot.
     pub fn version(&self) -> u64 {
         self.root.as_ref().map_or(0, |root| root.version())
@@ -95,7 +144,7 @@ impl<P: KeyTrait, V: Clone> Snapshot<P, V> {
         self.is_closed()?;
 
         // Check if there are any active readers for the snapshot
-        if self.max_active_readers.get() > 0 {
+        if !self.open_readers().is_empty() {
             return Err(TrieError::SnapshotReadersNotClosed);
         }
 
@@ -113,29 +162,45 @@ impl<P: KeyTrait, V: Clone> Snapshot<P, V> {
             return Err(TrieError::SnapshotEmpty);
         }
 
-        let reader_id = self.max_active_readers.get() + 1;
-        self.max_active_readers.set(reader_id);
-        self.readers.insert(reader_id);
-        Ok(IterationPointer::new(
+        self.last_reader_id += 1;
+        let reader_id = self.last_reader_id;
+        self.open_readers().insert(reader_id);
+        Ok(IterationPointer::registered(
             self.root.as_ref().unwrap().clone(),
             reader_id,
+            self.readers.clone(),
+            self.options.byte_permutation.clone(),
         ))
     }
 
+    // Locks the set of open readers. The set stays consistent even if a holder panicked.
+    fn open_readers(&self) -> MutexGuard<'_, HashSet<u64>> {
+        self.readers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the number of readers that are open, i.e. neither closed nor dropped.
     pub fn active_readers(&self) -> Result<u64, TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
-        Ok(self.max_active_readers.get())
+        Ok(self.open_readers().len() as u64)
+    }
+
+    /// Returns the IDs of the open readers, in the order they were created.
+    ///
+    /// Readers deregister when they are closed or dropped, so an ID that stays listed points
+    /// at a reader that is being kept alive, which is useful to track down leaked readers.
+    pub fn reader_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.open_readers().iter().copied().collect();
+        ids.sort_unstable();
+        ids
     }
 
     pub fn close_reader(&mut self, reader_id: u64) -> Result<(), TrieError> {
         // Check if the snapshot is already closed
         self.is_closed()?;
 
-        self.readers.remove(&reader_id);
-        let readers = self.max_active_readers.get();
-        self.max_active_readers.set(readers - 1);
+        self.open_readers().remove(&reader_id);
         Ok(())
     }
 
@@ -143,19 +208,21 @@ impl<P: KeyTrait, V: Clone> Snapshot<P, V> {
         // Check if the tree is already closed
         self.is_closed()?;
 
+        // Zero-length keys are never stored
+        if key.is_empty() {
+            return Ok(false);
+        }
+
         let (new_root, is_deleted) = match &self.root {
             None => (None, false),
             Some(root) => {
-                if root.is_twig() {
-                    (None, true)
-                } else {
-                    let (new_root, removed) = Node::remove_recurse(root, key, 0);
-                    if removed {
-                        (new_root, true)
-                    } else {
-                        (self.root.clone(), true)
-                    }
-                }
+                let key = self.options.encode_key(Cow::Borrowed(key));
+                let (new_root, removed) = Node::remove_recurse(root, &key, 0, &self.options);
+                // An inner root left without children is dropped
+                (
+                    new_root.filter(|root| root.is_twig() || root.num_children() > 0),
+                    removed,
+                )
             }
         };
 
@@ -206,29 +273,29 @@ mod tests {
         // Keys inserted before snapshot creation should be visible
         let mut snap1 = tree.create_snapshot().unwrap();
         assert_eq!(snap1.id, 0);
-        assert_eq!(snap1.get(&key_1).unwrap(), (1, 1, 0));
+        assert_eq!(snap1.get(&key_1, 0).unwrap(), (1, 1, 0));
 
         let mut snap2 = tree.create_snapshot().unwrap();
         assert_eq!(snap2.id, 1);
-        assert_eq!(snap2.get(&key_1).unwrap(), (1, 1, 0));
+        assert_eq!(snap2.get(&key_1, 0).unwrap(), (1, 1, 0));
 
         assert_eq!(tree.snapshot_count(), 2);
 
         // Keys inserted after snapshot creation should not be visible to other snapshots
         assert!(tree.insert(&key_2, 1, 0, 0).is_ok());
-        assert!(snap1.get(&key_2).is_err());
-        assert!(snap2.get(&key_2).is_err());
+        assert!(snap1.get(&key_2, 0).is_err());
+        assert!(snap2.get(&key_2, 0).is_err());
 
         // Keys inserted after snapshot creation should be visible to the snapshot that inserted them
         assert!(snap1.insert(&key_3_snap1, 2, 0).is_ok());
-        assert_eq!(snap1.get(&key_3_snap1).unwrap(), (2, 2, 0));
+        assert_eq!(snap1.get(&key_3_snap1, 0).unwrap(), (2, 2, 0));
 
         assert!(snap2.insert(&key_3_snap2, 3, 0).is_ok());
-        assert_eq!(snap2.get(&key_3_snap2).unwrap(), (3, 2, 0));
+        assert_eq!(snap2.get(&key_3_snap2, 0).unwrap(), (3, 2, 0));
 
         // Keys inserted after snapshot creation should not be visible to other snapshots
-        assert!(snap1.get(&key_3_snap2).is_err());
-        assert!(snap2.get(&key_3_snap1).is_err());
+        assert!(snap1.get(&key_3_snap2, 0).is_err());
+        assert!(snap2.get(&key_3_snap1, 0).is_err());
 
         assert!(snap1.close().is_ok());
         assert!(snap2.close().is_ok());
@@ -239,6 +306,37 @@ mod tests {
         assert_eq!(tree.snapshot_count(), 0);
     }
 
+    #[test]
+    fn snapshot_get_and_range() {
+        let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
+        for (i, word) in ["a", "b", "c", "d"].iter().enumerate() {
+            tree.insert(&VariableKey::from_str(word), i as i32, 0, 0)
+                .unwrap();
+        }
+        let key_b = VariableKey::from_str("b");
+        tree.insert(&key_b, 10, 0, 0).unwrap();
+
+        let snap = tree.create_snapshot().unwrap();
+
+        // Mutate the tree after the snapshot was taken
+        tree.insert(&key_b, 100, 0, 0).unwrap();
+        tree.insert(&VariableKey::from_str("bb"), 200, 0, 0)
+            .unwrap();
+        tree.remove(&VariableKey::from_str("c")).unwrap();
+
+        // The snapshot keeps returning the pre-mutation values
+        assert_eq!(snap.get(&key_b, 0).unwrap(), (10, 5, 0));
+        assert_eq!(snap.get(&key_b, 4).unwrap(), (1, 2, 0));
+        assert!(snap.get(&VariableKey::from_str("bb"), 0).is_err());
+        assert_eq!(tree.get(&key_b, 0).unwrap().1, 100);
+
+        let range: Vec<i32> = snap
+            .range(VariableKey::from_str("b")..=VariableKey::from_str("d"))
+            .map(|(_, value, _, _)| *value)
+            .collect();
+        assert_eq!(range, vec![10, 2, 3]);
+    }
+
     #[test]
     fn snapshot_readers() {
         let mut tree: Tree<VariableKey, i32> = Tree::<VariableKey, i32>::new();
@@ -276,6 +374,36 @@ mod tests {
         assert!(snap.close().is_ok());
     }
 
+    #[test]
+    fn dropped_readers_deregister() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+        tree.insert(&VariableKey::from_str("key"), 1, 0, 0).unwrap();
+        let mut snap = tree.create_snapshot().unwrap();
+        assert_eq!(snap.active_readers().unwrap(), 0);
+
+        let reader1 = snap.new_reader().unwrap();
+        let reader2 = snap.new_reader().unwrap();
+        let reader3 = snap.new_reader().unwrap();
+        assert_eq!(snap.active_readers().unwrap(), 3);
+        assert_eq!(snap.reader_ids(), vec![1, 2, 3]);
+
+        // Closing and dropping a reader both deregister it, and IDs are never handed out twice
+        snap.close_reader(reader1.id).unwrap();
+        drop(reader1);
+        drop(reader2);
+        assert_eq!(snap.reader_ids(), vec![3]);
+        let reader4 = snap.new_reader().unwrap();
+        assert_eq!(snap.reader_ids(), vec![3, 4]);
+
+        // A leaked reader keeps the snapshot open
+        assert!(snap.close().is_err());
+        drop(reader3);
+        drop(reader4);
+        assert_eq!(snap.active_readers().unwrap(), 0);
+        assert!(snap.reader_ids().is_empty());
+        assert!(snap.close().is_ok());
+    }
+
     fn count_items(reader: &IterationPointer<VariableKey, i32>) -> usize {
         let mut len = 0;
         for _ in reader.iter() {
@@ -0,0 +1,236 @@
+//! This module defines the Txn struct for buffering writes against a pinned Trie root.
+use std::borrow::Cow;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use hashbrown::HashMap;
+
+use crate::art::{Node, Tree, TreeOptions, TrieError};
+use crate::{KeyTrait, Ts, VersionId};
+
+/// A transaction over a Trie, created by `Tree::begin`.
+///
+/// Reads are served from the root pinned when the transaction began, overlaid with the
+/// transaction's own writes, so the transaction sees a consistent view that later writes to
+/// the tree do not touch. Writes are buffered until `commit` applies them to the tree at once,
+/// while `rollback`, or simply dropping the transaction, discards them.
+///
+/// The keys read from the pinned root are recorded, and by default `commit` checks them with
+/// `Tree::validate_versions_unchanged`, rejecting the transaction if any of them was written
+/// since.
+pub struct Txn<P: KeyTrait, V: Clone> {
+    root: Option<Arc<Node<P, V>>>,
+    options: TreeOptions,
+    /// The buffered writes by key, with `None` standing for a removal.
+    writes: HashMap<Vec<u8>, (P, Option<V>)>,
+    /// The keys read from the pinned root, each with its latest version at the time.
+    reads: Vec<(P, Option<VersionId>)>,
+    check_conflicts: bool,
+}
+
+impl<P: KeyTrait, V: Clone> Tree<P, V> {
+    /// Begins a transaction over the current state of the Trie.
+    ///
+    /// The root is pinned rather than copied, which is cheap as the Trie is copy-on-write.
+    ///
+    /// # Returns
+    ///
+    /// Returns the transaction, or an `Err` if the Trie is closed.
+    ///
+    pub fn begin(&self) -> Result<Txn<P, V>, TrieError> {
+        // Check if the tree is already closed
+        self.is_closed()?;
+
+        Ok(Txn {
+            root: self.root.clone(),
+            options: self.options.clone(),
+            writes: HashMap::new(),
+            reads: Vec::new(),
+            check_conflicts: true,
+        })
+    }
+}
+
+impl<P: KeyTrait, V: Clone> Txn<P, V> {
+    /// Sets whether `commit` rejects the transaction if a key it read was written since.
+    pub fn with_conflict_check(mut self, check_conflicts: bool) -> Self {
+        self.check_conflicts = check_conflicts;
+        self
+    }
+
+    /// Retrieves the latest value of a key as seen by the transaction.
+    ///
+    /// A key written by the transaction reads as written, and any other key is read from the
+    /// pinned root and recorded for the conflict check.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key to look up.
+    ///
+    /// # Returns
+    ///
+    /// Returns the value, or `None` if the key is not present, deleted or removed by the
+    /// transaction.
+    ///
+    pub fn get(&mut self, key: &P) -> Option<V> {
+        if let Some((_, value)) = self.writes.get(key.as_slice()) {
+            return value.clone();
+        }
+
+        let encoded = self.options.encode_key(Cow::Borrowed(key));
+        let twig = self
+            .root
+            .as_ref()
+            .and_then(|root| Node::find_twig(root, &encoded));
+        self.reads
+            .push((key.clone(), twig.map(|twig| VersionId(twig.version()))));
+        twig.and_then(|twig| twig.get_latest_value().cloned())
+    }
+
+    /// Buffers the insertion of a key-value pair.
+    pub fn insert(&mut self, key: &P, value: V) {
+        self.writes
+            .insert(key.as_slice().to_vec(), (key.clone(), Some(value)));
+    }
+
+    /// Buffers the removal of a key.
+    pub fn remove(&mut self, key: &P) {
+        self.writes
+            .insert(key.as_slice().to_vec(), (key.clone(), None));
+    }
+
+    /// Applies the buffered writes to the Trie.
+    ///
+    /// Each write gets the next version of the Trie and the given timestamp. Either all writes
+    /// are applied or, if the conflict check fails or a write is rejected, none of them is.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree`: The Trie the transaction began on.
+    /// * `ts`: The timestamp of the writes.
+    ///
+    /// # Returns
+    ///
+    /// Returns an `Err` leaving the Trie unchanged if a key read by the transaction was written
+    /// since it began, or if a write fails.
+    ///
+    pub fn commit(self, tree: &mut Tree<P, V>, ts: impl Into<Ts>) -> Result<(), TrieError> {
+        let ts = ts.into().0;
+        if self.check_conflicts && !tree.validate_versions_unchanged(&self.reads) {
+            return Err(TrieError::Other(
+                "transaction conflicts with a later write".to_string(),
+            ));
+        }
+
+        let root = tree.root.clone();
+        let max_ts = tree.max_ts.load(Ordering::SeqCst);
+        for (_, (key, value)) in self.writes {
+            let result = match value {
+                Some(value) => tree.insert(&key, value, 0, ts).map(|_| ()),
+                None => tree.remove(&key).map(|_| ()),
+            };
+            if let Err(err) = result {
+                tree.root = root;
+                tree.max_ts.store(max_ts, Ordering::SeqCst);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards the buffered writes.
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::art::Tree;
+    use crate::VariableKey;
+
+    fn key(name: &str) -> VariableKey {
+        VariableKey::from_str(name)
+    }
+
+    #[test]
+    fn txn_commit_rollback_conflict() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+        tree.insert(&key("alice"), 100, 0, 1).unwrap();
+        tree.insert(&key("bob"), 50, 0, 1).unwrap();
+
+        // Writes are visible to the transaction at once, and to the tree once committed
+        let mut txn = tree.begin().unwrap();
+        let alice = txn.get(&key("alice")).unwrap();
+        txn.insert(&key("alice"), alice - 30);
+        txn.insert(&key("carol"), 30);
+        txn.remove(&key("bob"));
+        assert_eq!(txn.get(&key("alice")), Some(70));
+        assert_eq!(txn.get(&key("bob")), None);
+        assert_eq!(tree.get(&key("alice"), 0).unwrap().1, 100);
+        txn.commit(&mut tree, 2).unwrap();
+        assert_eq!(tree.get(&key("alice"), 0).unwrap().1, 70);
+        assert_eq!(tree.get(&key("carol"), 0).unwrap().1, 30);
+        assert!(tree.get(&key("bob"), 0).is_err());
+
+        // A rolled back transaction leaves no trace
+        let before: Vec<_> = tree.iter().map(|(k, v, _, _)| (k, *v)).collect();
+        let mut txn = tree.begin().unwrap();
+        txn.insert(&key("dave"), 1);
+        txn.remove(&key("alice"));
+        txn.rollback();
+        let after: Vec<_> = tree.iter().map(|(k, v, _, _)| (k, *v)).collect();
+        assert_eq!(before, after);
+
+        // A key read by the transaction and written since makes the commit fail
+        let mut txn = tree.begin().unwrap();
+        let carol = txn.get(&key("carol")).unwrap();
+        txn.insert(&key("carol"), carol + 1);
+        txn.insert(&key("erin"), 1);
+        tree.insert(&key("carol"), 0, 0, 3).unwrap();
+        assert_eq!(txn.get(&key("carol")), Some(31));
+        assert!(txn.commit(&mut tree, 4).is_err());
+        assert_eq!(tree.get(&key("carol"), 0).unwrap().1, 0);
+        assert!(tree.get(&key("erin"), 0).is_err());
+
+        // So does a key read as absent and written since, unless the check is disabled
+        let mut txn = tree.begin().unwrap();
+        assert_eq!(txn.get(&key("frank")), None);
+        txn.insert(&key("frank"), 1);
+        tree.insert(&key("frank"), 2, 0, 5).unwrap();
+        assert!(txn.commit(&mut tree, 6).is_err());
+        let mut txn = tree.begin().unwrap().with_conflict_check(false);
+        txn.get(&key("frank"));
+        txn.insert(&key("frank"), 3);
+        tree.insert(&key("frank"), 4, 0, 7).unwrap();
+        txn.commit(&mut tree, 8).unwrap();
+        assert_eq!(tree.get(&key("frank"), 0).unwrap().1, 3);
+    }
+
+    #[test]
+    fn txn_conflict_with_same_ts() {
+        // A write stamped with the timestamp of the value read is still a conflict
+        let mut tree = Tree::<VariableKey, i32>::new();
+        tree.insert(&key("k"), 10, 0, 0).unwrap();
+        let mut txn = tree.begin().unwrap();
+        let k = txn.get(&key("k")).unwrap();
+        txn.insert(&key("k"), k + 1);
+        tree.insert(&key("k"), 100, 0, 0).unwrap();
+        assert!(txn.commit(&mut tree, 0).is_err());
+        assert_eq!(tree.get(&key("k"), 0).unwrap().1, 100);
+    }
+
+    #[test]
+    fn txn_commit_is_atomic() {
+        let mut tree = Tree::<VariableKey, i32>::new();
+        tree.insert(&VariableKey::from(b"ab".to_vec()), 1, 0, 1)
+            .unwrap();
+
+        // A write the tree rejects undoes the writes applied before it
+        let mut txn = tree.begin().unwrap();
+        for i in 0..10 {
+            txn.insert(&key(&format!("key{}", i)), i);
+        }
+        txn.insert(&VariableKey::from(b"abc".to_vec()), 2);
+        assert!(txn.commit(&mut tree, 2).is_err());
+        assert_eq!(tree.len(), 1);
+    }
+}